@@ -1,13 +1,58 @@
 use candid::{CandidType, Principal};
 use ic_cdk::{api::time, caller};
+use ic_cdk_macros::*;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
-use crate::error::{Error, Result};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use crate::error::{AnimaError, Result};
+use crate::stable::{RegionKey, StableRegion, ADMIN_MEMORY_ID, SECURITY_LOG_MEMORY_ID};
+use crate::types::security::{SecurityEvent, SecurityEventType, SecurityMetrics};
+
+/// How long a proposed admin has to accept before the offer lapses.
+pub const ADMIN_PROPOSAL_TTL: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// What a principal may do. `SuperAdmin` implies every other role, and the
+/// canister's controllers always hold it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Role {
+    /// Manages admins and anything not delegated to another role.
+    SuperAdmin,
+    /// Moves protocol funds and sets emissions and royalty splits.
+    Treasury,
+    /// Takes down marketplace listings.
+    Moderator,
+    /// Manages accepted tokens and swap fees.
+    PricingManager,
+    /// Pushes token prices.
+    Oracle,
+}
+
+impl Role {
+    fn event_type(self) -> SecurityEventType {
+        match self {
+            Role::Treasury => SecurityEventType::TokenTransfer,
+            Role::Moderator => SecurityEventType::StateModification,
+            _ => SecurityEventType::ConfigurationChange,
+        }
+    }
+}
+
+/// An offer of roles that takes effect only once the candidate accepts it.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AdminProposal {
+    pub roles: BTreeSet<Role>,
+    pub proposed_by: Principal,
+    pub expires_at: u64,
+    /// On acceptance the proposer gives up the offered roles.
+    pub handover: bool,
+}
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct AdminConfig {
-    pub admins: HashSet<Principal>,
-    pub pending_admins: HashMap<Principal, u64>,
+    pub admins: HashMap<Principal, BTreeSet<Role>>,
+    pub pending_admins: HashMap<Principal, AdminProposal>,
     pub metrics_config: MetricsConfig,
 }
 
@@ -28,7 +73,7 @@ pub struct AlertThresholds {
 impl Default for AdminConfig {
     fn default() -> Self {
         Self {
-            admins: HashSet::new(),
+            admins: HashMap::new(),
             pending_admins: HashMap::new(),
             metrics_config: MetricsConfig::default(),
         }
@@ -55,80 +100,352 @@ impl Default for AlertThresholds {
     }
 }
 
-pub fn is_admin(principal: &Principal) -> bool {
-    crate::STATE.with(|state| {
-        let state = state.borrow();
-        state.admin_config.admins.contains(principal)
+impl AdminConfig {
+    /// Whether `principal` was granted `role`, directly or through
+    /// `SuperAdmin`. Controllers are checked separately.
+    pub fn has_role(&self, principal: &Principal, role: Role) -> bool {
+        self.admins.get(principal)
+            .map(|roles| roles.contains(&Role::SuperAdmin) || roles.contains(&role))
+            .unwrap_or(false)
+    }
+
+    pub fn roles_of(&self, principal: &Principal) -> BTreeSet<Role> {
+        self.admins.get(principal).cloned().unwrap_or_default()
+    }
+
+    /// Offers `roles` to `candidate`, replacing any earlier offer. A handover
+    /// can only pass on roles the proposer was granted.
+    pub fn propose(
+        &mut self,
+        candidate: Principal,
+        roles: BTreeSet<Role>,
+        proposer: Principal,
+        handover: bool,
+        now: u64,
+    ) -> Result<AdminProposal> {
+        if roles.is_empty() {
+            return Err(AnimaError::InvalidInput("No roles proposed".to_string()));
+        }
+        if candidate == Principal::anonymous() || candidate == proposer {
+            return Err(AnimaError::InvalidInput("Invalid admin candidate".to_string()));
+        }
+        if handover {
+            let granted = self.roles_of(&proposer);
+            if !roles.is_subset(&granted) {
+                return Err(AnimaError::InvalidInput("Only granted roles can be handed over".to_string()));
+            }
+        }
+        self.prune_expired(now);
+        let proposal = AdminProposal {
+            roles,
+            proposed_by: proposer,
+            expires_at: now.saturating_add(ADMIN_PROPOSAL_TTL),
+            handover,
+        };
+        self.pending_admins.insert(candidate, proposal.clone());
+        Ok(proposal)
+    }
+
+    /// Grants `candidate` the roles offered to them.
+    pub fn accept(&mut self, candidate: Principal, now: u64) -> Result<AdminProposal> {
+        let proposal = self.pending_admins.remove(&candidate)
+            .ok_or_else(|| AnimaError::InvalidInput("No pending admin proposal".to_string()))?;
+        if now > proposal.expires_at {
+            return Err(AnimaError::InvalidInput("Admin proposal has expired".to_string()));
+        }
+        if proposal.handover {
+            self.revoke(proposal.proposed_by, &proposal.roles);
+        }
+        self.admins.entry(candidate).or_default().extend(proposal.roles.iter().copied());
+        Ok(proposal)
+    }
+
+    pub fn cancel(&mut self, candidate: &Principal) -> Result<AdminProposal> {
+        self.pending_admins.remove(candidate)
+            .ok_or_else(|| AnimaError::InvalidInput("No pending admin proposal".to_string()))
+    }
+
+    /// Removes `roles` from `principal`; returns whether any were held.
+    pub fn revoke(&mut self, principal: Principal, roles: &BTreeSet<Role>) -> bool {
+        let Some(granted) = self.admins.get_mut(&principal) else {
+            return false;
+        };
+        let before = granted.len();
+        granted.retain(|role| !roles.contains(role));
+        let changed = granted.len() != before;
+        if granted.is_empty() {
+            self.admins.remove(&principal);
+        }
+        changed
+    }
+
+    fn prune_expired(&mut self, now: u64) {
+        self.pending_admins.retain(|_, proposal| proposal.expires_at >= now);
+    }
+}
+
+thread_local! {
+    static ADMIN_CONFIG: RefCell<AdminConfig> = RefCell::new(AdminConfig::default());
+    static SECURITY_LOG: RefCell<SecurityMetrics> = RefCell::new(SecurityMetrics::default());
+}
+
+const ADMIN_REGION: StableRegion<AdminConfig> = StableRegion::new(ADMIN_MEMORY_ID);
+const SECURITY_LOG_REGION: StableRegion<SecurityMetrics> = StableRegion::new(SECURITY_LOG_MEMORY_ID);
+const ADMIN_KEY: &str = "admin_config";
+const SECURITY_LOG_KEY: &str = "security_log";
+
+pub fn with_admin_config<R>(f: impl FnOnce(&AdminConfig) -> R) -> R {
+    ADMIN_CONFIG.with(|config| f(&config.borrow()))
+}
+
+pub fn with_security_log<R>(f: impl FnOnce(&SecurityMetrics) -> R) -> R {
+    SECURITY_LOG.with(|log| f(&log.borrow()))
+}
+
+pub fn save_stable() -> Result<()> {
+    ADMIN_CONFIG.with(|config| {
+        ADMIN_REGION.save([(RegionKey::singleton(ADMIN_KEY), &*config.borrow())])
+    })?;
+    SECURITY_LOG.with(|log| {
+        SECURITY_LOG_REGION.save([(RegionKey::singleton(SECURITY_LOG_KEY), &*log.borrow())])
     })
 }
 
-pub fn require_admin() -> Result<()> {
-    if !is_admin(&caller()) {
-        Err(Error::NotAuthorized)
-    } else {
-        Ok(())
+pub fn restore_stable() -> Result<()> {
+    if let Some(restored) = ADMIN_REGION.load_singleton(ADMIN_KEY)? {
+        ADMIN_CONFIG.with(|config| *config.borrow_mut() = restored);
+    }
+    if let Some(restored) = SECURITY_LOG_REGION.load_singleton(SECURITY_LOG_KEY)? {
+        SECURITY_LOG.with(|log| *log.borrow_mut() = restored);
     }
+    Ok(())
 }
 
-pub fn add_admin(admin: Principal) -> Result<()> {
-    require_admin()?;
-    
-    crate::STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        state.admin_config.admins.insert(admin);
-        Ok(())
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    ic_cdk::api::is_controller(principal) || with_admin_config(|config| config.has_role(principal, role))
+}
+
+/// Whether `principal` holds any role.
+pub fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal) || with_admin_config(|config| config.admins.contains_key(principal))
+}
+
+/// Appends an event to the audit log.
+pub fn record_event(event_type: SecurityEventType, description: String, actor: Option<Principal>, critical: bool) {
+    let event = SecurityEvent { timestamp: time(), event_type, description, actor };
+    SECURITY_LOG.with(|log| log.borrow_mut().record(event, critical));
+}
+
+/// Restricts an update to holders of `role` and records the call, named by
+/// `action`, in the audit log.
+pub fn require_role(role: Role, action: &str) -> Result<()> {
+    let caller = caller();
+    if !has_role(&caller, role) {
+        SECURITY_LOG.with(|log| log.borrow_mut().record_denied(time()));
+        return Err(AnimaError::NotAuthorized);
+    }
+    record_event(role.event_type(), format!("{} as {:?}", action, role), Some(caller), role == Role::SuperAdmin);
+    Ok(())
+}
+
+/// Offers `roles` to `candidate`, who must call `accept_admin_roles` before
+/// the offer expires. With `handover`, the caller gives those roles up once
+/// the candidate accepts.
+#[update]
+fn propose_admin(candidate: Principal, roles: Vec<Role>, handover: bool) -> Result<AdminProposal> {
+    require_role(Role::SuperAdmin, &format!("propose_admin {} {:?}", candidate, roles))?;
+    let proposer = caller();
+    ADMIN_CONFIG.with(|config| {
+        config.borrow_mut().propose(candidate, roles.into_iter().collect(), proposer, handover, time())
     })
 }
 
-pub fn remove_admin(admin: Principal) -> Result<()> {
-    require_admin()?;
-    
-    // Prevent removing the last admin
-    crate::STATE.with(|state| {
-        let state = state.borrow();
-        if state.admin_config.admins.len() <= 1 {
-            return Err(Error::LastAdmin);
-        }
-        Ok(())
-    })?;
+#[update]
+fn accept_admin_roles() -> Result<Vec<Role>> {
+    let candidate = caller();
+    let proposal = ADMIN_CONFIG.with(|config| config.borrow_mut().accept(candidate, time()))?;
+    record_event(
+        SecurityEventType::AuthenticationAttempt,
+        format!(
+            "accepted {:?} from {}{}",
+            proposal.roles,
+            proposal.proposed_by,
+            if proposal.handover { " (handover)" } else { "" }
+        ),
+        Some(candidate),
+        true,
+    );
+    Ok(proposal.roles.into_iter().collect())
+}
 
-    crate::STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        if !state.admin_config.admins.remove(&admin) {
-            return Err(Error::AdminNotFound);
-        }
+#[update]
+fn cancel_admin_proposal(candidate: Principal) -> Result<()> {
+    require_role(Role::SuperAdmin, &format!("cancel_admin_proposal {}", candidate))?;
+    ADMIN_CONFIG.with(|config| config.borrow_mut().cancel(&candidate)).map(|_| ())
+}
+
+/// Takes `roles` away from `admin`. Controllers keep `SuperAdmin` regardless.
+#[update]
+fn revoke_admin_roles(admin: Principal, roles: Vec<Role>) -> Result<()> {
+    require_role(Role::SuperAdmin, &format!("revoke_admin_roles {} {:?}", admin, roles))?;
+    let roles = roles.into_iter().collect();
+    if ADMIN_CONFIG.with(|config| config.borrow_mut().revoke(admin, &roles)) {
         Ok(())
+    } else {
+        Err(AnimaError::InvalidInput(format!("{} holds none of those roles", admin)))
+    }
+}
+
+#[update]
+fn update_metrics_config(config: MetricsConfig) -> Result<()> {
+    require_role(Role::SuperAdmin, "update_metrics_config")?;
+    ADMIN_CONFIG.with(|admin| admin.borrow_mut().metrics_config = config);
+    Ok(())
+}
+
+#[query]
+fn get_metrics_config() -> MetricsConfig {
+    with_admin_config(|config| config.metrics_config.clone())
+}
+
+#[query]
+fn get_admins() -> Vec<(Principal, Vec<Role>)> {
+    with_admin_config(|config| {
+        config.admins.iter()
+            .map(|(principal, roles)| (*principal, roles.iter().copied().collect()))
+            .collect()
     })
 }
 
-pub fn update_metrics_config(config: MetricsConfig) -> Result<()> {
-    require_admin()?;
-    
-    crate::STATE.with(|state| {
-        let mut state = state.borrow_mut();
-        state.admin_config.metrics_config = config;
-        Ok(())
+#[query]
+fn get_pending_admins() -> Vec<(Principal, AdminProposal)> {
+    let now = time();
+    with_admin_config(|config| {
+        config.pending_admins.iter()
+            .filter(|(_, proposal)| proposal.expires_at >= now)
+            .map(|(principal, proposal)| (*principal, proposal.clone()))
+            .collect()
     })
 }
 
-pub fn verify_admin_access() -> Result<()> {
+#[query]
+fn get_my_roles() -> Vec<Role> {
+    let caller = caller();
+    if ic_cdk::api::is_controller(&caller) {
+        return vec![Role::SuperAdmin];
+    }
+    with_admin_config(|config| config.roles_of(&caller).into_iter().collect())
+}
+
+/// Audit log, oldest first. Pass the last id seen as `start_after` to page.
+/// Readable by any admin.
+#[query]
+fn get_security_events(start_after: Option<u64>, limit: Option<u64>) -> Result<Vec<(u64, SecurityEvent)>> {
     if !is_admin(&caller()) {
-        return Err(Error::NotAuthorized);
+        return Err(AnimaError::NotAuthorized);
     }
-    Ok(())
+    let limit = limit.map(|l| l as usize).unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    Ok(with_security_log(|log| log.events(start_after, limit)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::security::MAX_SECURITY_EVENTS;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn roles(roles: &[Role]) -> BTreeSet<Role> {
+        roles.iter().copied().collect()
+    }
 
     #[test]
-    fn test_admin_verification() {
-        // Test implementation
+    fn test_super_admin_implies_every_role() {
+        let mut config = AdminConfig::default();
+        config.admins.insert(principal(1), roles(&[Role::SuperAdmin]));
+        config.admins.insert(principal(2), roles(&[Role::Oracle]));
+
+        assert!(config.has_role(&principal(1), Role::Treasury));
+        assert!(config.has_role(&principal(2), Role::Oracle));
+        assert!(!config.has_role(&principal(2), Role::PricingManager));
+        assert!(!config.has_role(&principal(3), Role::Oracle));
     }
 
     #[test]
-    fn test_admin_management() {
-        // Test implementation
+    fn test_roles_granted_only_on_acceptance() {
+        let mut config = AdminConfig::default();
+        config.propose(principal(2), roles(&[Role::Treasury]), principal(1), false, 0).unwrap();
+        assert!(!config.has_role(&principal(2), Role::Treasury));
+
+        config.accept(principal(2), ADMIN_PROPOSAL_TTL).unwrap();
+        assert!(config.has_role(&principal(2), Role::Treasury));
+        assert!(config.accept(principal(2), ADMIN_PROPOSAL_TTL).is_err());
+    }
+
+    #[test]
+    fn test_expired_proposal_cannot_be_accepted() {
+        let mut config = AdminConfig::default();
+        config.propose(principal(2), roles(&[Role::Moderator]), principal(1), false, 0).unwrap();
+        assert!(config.accept(principal(2), ADMIN_PROPOSAL_TTL + 1).is_err());
+        assert!(config.admins.is_empty());
+        assert!(config.pending_admins.is_empty());
+    }
+
+    #[test]
+    fn test_handover_moves_roles_to_candidate() {
+        let mut config = AdminConfig::default();
+        config.admins.insert(principal(1), roles(&[Role::SuperAdmin, Role::Treasury]));
+        assert!(config.propose(principal(2), roles(&[Role::Oracle]), principal(1), true, 0).is_err());
+
+        config.propose(principal(2), roles(&[Role::SuperAdmin]), principal(1), true, 0).unwrap();
+        assert!(config.has_role(&principal(1), Role::Oracle));
+        config.accept(principal(2), 1).unwrap();
+
+        assert_eq!(config.roles_of(&principal(1)), roles(&[Role::Treasury]));
+        assert_eq!(config.roles_of(&principal(2)), roles(&[Role::SuperAdmin]));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_revoke_drops_empty_admins() {
+        let mut config = AdminConfig::default();
+        config.admins.insert(principal(2), roles(&[Role::Oracle, Role::Moderator]));
+        assert!(config.revoke(principal(2), &roles(&[Role::Oracle])));
+        assert!(!config.revoke(principal(2), &roles(&[Role::Oracle])));
+        assert!(config.revoke(principal(2), &roles(&[Role::Moderator])));
+        assert!(!config.admins.contains_key(&principal(2)));
+    }
+
+    #[test]
+    fn test_invalid_proposals_refused() {
+        let mut config = AdminConfig::default();
+        assert!(config.propose(principal(2), BTreeSet::new(), principal(1), false, 0).is_err());
+        assert!(config.propose(Principal::anonymous(), roles(&[Role::Oracle]), principal(1), false, 0).is_err());
+        assert!(config.propose(principal(1), roles(&[Role::Oracle]), principal(1), false, 0).is_err());
+    }
+
+    #[test]
+    fn test_security_log_pages_and_stays_bounded() {
+        let mut log = SecurityMetrics::new(0);
+        for i in 0..MAX_SECURITY_EVENTS as u64 + 5 {
+            let event = SecurityEvent {
+                timestamp: i,
+                event_type: SecurityEventType::ConfigurationChange,
+                description: format!("event {}", i),
+                actor: None,
+            };
+            log.record(event, false);
+        }
+        log.record_denied(1);
+
+        assert_eq!(log.event_log.len(), MAX_SECURITY_EVENTS);
+        assert_eq!(log.warning_events, 1);
+        let page = log.events(None, 2);
+        assert_eq!(page[0].0, 5);
+        assert_eq!(page[0].1.timestamp, 5);
+        let next = log.events(Some(page[1].0), 1);
+        assert_eq!(next[0].0, 7);
+        assert!(log.events(Some(log.total_events), 10).is_empty());
+    }
+}
@@ -0,0 +1,379 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use num_traits::ToPrimitive;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::time::Duration;
+use crate::admin::metrics::{self, Alert, AlertLevel};
+use crate::admin::{is_admin, require_role, with_admin_config, AlertThresholds, Role};
+use crate::error::{AnimaError, Result};
+use crate::stable::{RegionKey, StableRegion, ALERTS_MEMORY_ID};
+
+/// Alerts kept in the log; older ones are dropped first.
+pub const MAX_ALERTS: usize = 1_000;
+/// The wasm heap cannot grow past 4 GiB.
+const MAX_HEAP_BYTES: f64 = 4.0 * 1024.0 * 1024.0 * 1024.0;
+/// Recovery margins, so a reading hovering at a threshold does not flap.
+const MEMORY_RECOVERY_MARGIN_PERCENT: f64 = 5.0;
+const CYCLES_RECOVERY_MARGIN_PERCENT: u128 = 10;
+const ERROR_RATE_RECOVERY_FACTOR: f64 = 0.5;
+/// Fewer LLM calls than this in an interval say nothing about the error rate.
+const MIN_ERROR_RATE_SAMPLE: u64 = 20;
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 1_024;
+const WEBHOOK_CYCLES: u128 = 2_000_000_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AlertSource {
+    Cycles,
+    Memory,
+    ErrorRate,
+}
+
+impl AlertSource {
+    fn as_str(self) -> &'static str {
+        match self {
+            AlertSource::Cycles => "cycles",
+            AlertSource::Memory => "memory",
+            AlertSource::ErrorRate => "error_rate",
+        }
+    }
+
+    fn level(self) -> AlertLevel {
+        match self {
+            AlertSource::Cycles => AlertLevel::Critical,
+            AlertSource::Memory | AlertSource::ErrorRate => AlertLevel::Warning,
+        }
+    }
+}
+
+/// Live values the thresholds are checked against.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Readings {
+    pub memory_usage_percent: f64,
+    pub cycles_balance: u128,
+    /// `None` when too few calls were made to judge.
+    pub error_rate: Option<f64>,
+}
+
+/// Whether a reading breaches, has recovered from, or sits between the two
+/// edges of a threshold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Check {
+    Breached,
+    Recovered,
+    Unchanged,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AlertLog {
+    alerts: Vec<Alert>,
+    /// Sources with an alert raised and not yet recovered.
+    active: BTreeSet<AlertSource>,
+    /// LLM (calls, errors) at the previous check, to rate each interval.
+    last_llm_counts: (u64, u64),
+    webhook_url: Option<String>,
+}
+
+impl AlertLog {
+    /// Compares `readings` with `thresholds` and returns the alerts raised or
+    /// cleared. A source alerts once when breached and stays quiet until it
+    /// recovers past its margin, which emits an `Info` alert.
+    pub fn evaluate(&mut self, readings: &Readings, thresholds: &AlertThresholds, now: u64) -> Vec<Alert> {
+        let cycles_recovery = thresholds.cycles_balance_min
+            .saturating_add(thresholds.cycles_balance_min * CYCLES_RECOVERY_MARGIN_PERCENT / 100);
+        let checks = [
+            (
+                AlertSource::Cycles,
+                if readings.cycles_balance < thresholds.cycles_balance_min {
+                    Check::Breached
+                } else if readings.cycles_balance >= cycles_recovery {
+                    Check::Recovered
+                } else {
+                    Check::Unchanged
+                },
+                format!(
+                    "Cycles balance {} is below the minimum of {}",
+                    readings.cycles_balance, thresholds.cycles_balance_min
+                ),
+            ),
+            (
+                AlertSource::Memory,
+                if readings.memory_usage_percent >= thresholds.memory_usage_percent {
+                    Check::Breached
+                } else if readings.memory_usage_percent < thresholds.memory_usage_percent - MEMORY_RECOVERY_MARGIN_PERCENT {
+                    Check::Recovered
+                } else {
+                    Check::Unchanged
+                },
+                format!(
+                    "Memory usage {:.1}% exceeds {:.1}%",
+                    readings.memory_usage_percent, thresholds.memory_usage_percent
+                ),
+            ),
+            (
+                AlertSource::ErrorRate,
+                match readings.error_rate {
+                    Some(rate) if rate > thresholds.error_rate_max => Check::Breached,
+                    Some(rate) if rate < thresholds.error_rate_max * ERROR_RATE_RECOVERY_FACTOR => Check::Recovered,
+                    _ => Check::Unchanged,
+                },
+                format!(
+                    "Error rate {:.2}% exceeds {:.2}%",
+                    readings.error_rate.unwrap_or_default() * 100.0,
+                    thresholds.error_rate_max * 100.0
+                ),
+            ),
+        ];
+
+        let mut raised = Vec::new();
+        for (source, check, message) in checks {
+            let alert = match check {
+                Check::Breached if self.active.insert(source) => Alert {
+                    level: source.level(),
+                    message,
+                    timestamp: now,
+                    source: source.as_str().to_string(),
+                },
+                Check::Recovered if self.active.remove(&source) => Alert {
+                    level: AlertLevel::Info,
+                    message: format!("{} back within threshold", source.as_str()),
+                    timestamp: now,
+                    source: source.as_str().to_string(),
+                },
+                _ => continue,
+            };
+            raised.push(alert);
+        }
+        for alert in &raised {
+            self.push(alert.clone());
+        }
+        raised
+    }
+
+    fn push(&mut self, alert: Alert) {
+        self.alerts.push(alert);
+        if self.alerts.len() > MAX_ALERTS {
+            let excess = self.alerts.len() - MAX_ALERTS;
+            self.alerts.drain(..excess);
+        }
+    }
+
+    /// Error rate over the calls made since the previous check.
+    pub fn interval_error_rate(&mut self, calls: u64, errors: u64) -> Option<f64> {
+        let (last_calls, last_errors) = std::mem::replace(&mut self.last_llm_counts, (calls, errors));
+        let calls = calls.saturating_sub(last_calls);
+        if calls < MIN_ERROR_RATE_SAMPLE {
+            return None;
+        }
+        Some(errors.saturating_sub(last_errors) as f64 / calls as f64)
+    }
+
+    /// Alerts after `since`, at `level` or more severe.
+    pub fn alerts(&self, since: u64, level: Option<AlertLevel>) -> Vec<Alert> {
+        let min_severity = level.map(AlertLevel::severity).unwrap_or(0);
+        self.alerts.iter()
+            .filter(|alert| alert.timestamp > since && alert.level.severity() >= min_severity)
+            .cloned()
+            .collect()
+    }
+}
+
+thread_local! {
+    static ALERTS: RefCell<AlertLog> = RefCell::new(AlertLog::default());
+}
+
+const ALERTS_REGION: StableRegion<AlertLog> = StableRegion::new(ALERTS_MEMORY_ID);
+const ALERTS_KEY: &str = "alerts";
+
+pub fn save_stable() -> Result<()> {
+    ALERTS.with(|alerts| {
+        ALERTS_REGION.save([(RegionKey::singleton(ALERTS_KEY), &*alerts.borrow())])
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    if let Some(restored) = ALERTS_REGION.load_singleton(ALERTS_KEY)? {
+        ALERTS.with(|alerts| *alerts.borrow_mut() = restored);
+    }
+    Ok(())
+}
+
+/// Checks the thresholds every `MetricsConfig::collection_interval`
+/// seconds. Timers do not survive upgrades, so this runs from both `init`
+/// and `post_upgrade`.
+pub fn start_alert_timer() {
+    let interval = with_admin_config(|config| config.metrics_config.collection_interval).max(1);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(interval), check_thresholds);
+}
+
+fn check_thresholds() {
+    let thresholds = with_admin_config(|config| config.metrics_config.alert_thresholds.clone());
+    let (calls, errors) = metrics::llm_counts();
+    let (raised, webhook_url) = ALERTS.with(|alerts| {
+        let mut alerts = alerts.borrow_mut();
+        let readings = Readings {
+            memory_usage_percent: metrics::heap_memory_bytes() as f64 / MAX_HEAP_BYTES * 100.0,
+            cycles_balance: ic_cdk::api::canister_balance128(),
+            error_rate: alerts.interval_error_rate(calls, errors),
+        };
+        (alerts.evaluate(&readings, &thresholds, time()), alerts.webhook_url.clone())
+    });
+    for alert in &raised {
+        ic_cdk::println!("[{:?}] {}: {}", alert.level, alert.source, alert.message);
+    }
+    if let Some(url) = webhook_url {
+        for alert in raised {
+            ic_cdk::spawn(push_to_webhook(url.clone(), alert));
+        }
+    }
+}
+
+/// Posts `alert` as JSON. Every replica makes the request, so receivers
+/// should dedupe on `(source, timestamp)`.
+async fn push_to_webhook(url: String, alert: Alert) {
+    let body = match serde_json::to_vec(&alert) {
+        Ok(body) => body,
+        Err(e) => {
+            ic_cdk::println!("Failed to encode alert for webhook: {}", e);
+            return;
+        }
+    };
+    let request = CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: Some(body),
+        transform: Some(TransformContext::from_name("transform_alert_webhook".to_string(), vec![])),
+    };
+    match http_request(request, WEBHOOK_CYCLES).await {
+        Ok((response,)) if matches!(response.status.0.to_u64(), Some(200..=299)) => {}
+        Ok((response,)) => ic_cdk::println!("Alert webhook answered {}", response.status),
+        Err((code, message)) => ic_cdk::println!("Alert webhook failed: {:?} {}", code, message),
+    }
+}
+
+/// Keeps only the status, so replicas agree on the webhook's response.
+#[query]
+fn transform_alert_webhook(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
+}
+
+/// Alerts raised after `since` (nanoseconds), optionally only those at
+/// `level` or more severe. Readable by any admin.
+#[query]
+fn get_alerts(since: u64, level: Option<AlertLevel>) -> Result<Vec<Alert>> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AnimaError::NotAuthorized);
+    }
+    Ok(ALERTS.with(|alerts| alerts.borrow().alerts(since, level)))
+}
+
+/// Sets or clears the HTTPS endpoint alerts are posted to.
+#[update]
+fn set_alert_webhook(url: Option<String>) -> Result<()> {
+    require_role(Role::SuperAdmin, "set_alert_webhook")?;
+    if let Some(url) = &url {
+        if !url.starts_with("https://") {
+            return Err(AnimaError::InvalidInput("Webhook must be an https:// URL".to_string()));
+        }
+    }
+    ALERTS.with(|alerts| alerts.borrow_mut().webhook_url = url);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn healthy() -> Readings {
+        Readings {
+            memory_usage_percent: 10.0,
+            cycles_balance: 10_000_000_000_000,
+            error_rate: Some(0.0),
+        }
+    }
+
+    #[test]
+    fn test_breach_alerts_once_until_recovered() {
+        let thresholds = AlertThresholds::default();
+        let mut log = AlertLog::default();
+        assert!(log.evaluate(&healthy(), &thresholds, 1).is_empty());
+
+        let low = Readings { cycles_balance: 500_000_000_000, ..healthy() };
+        let raised = log.evaluate(&low, &thresholds, 2);
+        assert_eq!(raised.len(), 1);
+        assert_eq!(raised[0].level, AlertLevel::Critical);
+        assert_eq!(raised[0].source, "cycles");
+        assert!(log.evaluate(&low, &thresholds, 3).is_empty());
+
+        // Just above the minimum is inside the recovery margin.
+        let marginal = Readings { cycles_balance: 1_050_000_000_000, ..healthy() };
+        assert!(log.evaluate(&marginal, &thresholds, 4).is_empty());
+        assert!(log.evaluate(&low, &thresholds, 5).is_empty());
+
+        let recovered = log.evaluate(&healthy(), &thresholds, 6);
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].level, AlertLevel::Info);
+        assert_eq!(log.evaluate(&low, &thresholds, 7).len(), 1);
+    }
+
+    #[test]
+    fn test_memory_and_error_rate_thresholds() {
+        let thresholds = AlertThresholds::default();
+        let mut log = AlertLog::default();
+        let readings = Readings { memory_usage_percent: 95.0, error_rate: Some(0.05), ..healthy() };
+        let raised = log.evaluate(&readings, &thresholds, 1);
+        let sources: Vec<_> = raised.iter().map(|a| a.source.as_str()).collect();
+        assert_eq!(sources, vec!["memory", "error_rate"]);
+
+        // Too few calls to judge leaves the error rate alert standing.
+        let quiet = Readings { memory_usage_percent: 87.0, error_rate: None, ..healthy() };
+        assert!(log.evaluate(&quiet, &thresholds, 2).is_empty());
+    }
+
+    #[test]
+    fn test_interval_error_rate() {
+        let mut log = AlertLog::default();
+        assert_eq!(log.interval_error_rate(10, 5), None);
+        assert_eq!(log.interval_error_rate(50, 7), Some(0.05));
+        assert_eq!(log.interval_error_rate(60, 7), None);
+    }
+
+    #[test]
+    fn test_alerts_filtered_by_time_and_level() {
+        let thresholds = AlertThresholds::default();
+        let mut log = AlertLog::default();
+        log.evaluate(&Readings { memory_usage_percent: 95.0, ..healthy() }, &thresholds, 1);
+        log.evaluate(&Readings { cycles_balance: 0, ..healthy() }, &thresholds, 2);
+        log.evaluate(&Readings { cycles_balance: 0, ..healthy() }, &thresholds, 3);
+
+        assert_eq!(log.alerts(0, None).len(), 3);
+        assert_eq!(log.alerts(1, None).len(), 2);
+        assert_eq!(log.alerts(0, Some(AlertLevel::Critical)).len(), 1);
+        assert_eq!(log.alerts(0, Some(AlertLevel::Warning)).len(), 2);
+    }
+
+    #[test]
+    fn test_log_is_bounded() {
+        let mut log = AlertLog::default();
+        for i in 0..MAX_ALERTS as u64 + 10 {
+            log.push(Alert { level: AlertLevel::Info, message: String::new(), timestamp: i, source: String::new() });
+        }
+        assert_eq!(log.alerts.len(), MAX_ALERTS);
+        assert_eq!(log.alerts[0].timestamp, 10);
+    }
+}
@@ -0,0 +1,506 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::CallResult;
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use num_traits::ToPrimitive;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+use crate::admin::{is_admin, require_role, with_admin_config, Role};
+use crate::error::{AnimaError, Result};
+use crate::icrc::account_id::AccountIdentifier;
+use crate::icrc::{ledger, Account, Subaccount};
+use crate::stable::{RegionKey, StableRegion, CYCLES_MEMORY_ID};
+
+pub const CMC_CANISTER_ID: &str = "rkp4c-7iaaa-aaaaa-aaaca-cai";
+/// Memo the CMC expects on a canister top-up transfer ("TPUP").
+pub const MEMO_TOP_UP_CANISTER: u64 = 0x5055_5054;
+/// Subaccount of this canister holding the ICP set aside for cycles.
+pub const CYCLES_TREASURY_SUBACCOUNT: Subaccount = *b"anima-cycles-treasury\0\0\0\0\0\0\0\0\0\0\0";
+const ICP_FEE_E8S: u64 = 10_000;
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const MAX_CONVERSIONS: usize = 1_000;
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Budget and kill switch for automatic top-ups.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CyclesConfig {
+    /// Off until the treasury enables it; turning it off stops new top-ups
+    /// but lets one already paid for be notified.
+    pub enabled: bool,
+    /// ICP converted per top-up.
+    pub top_up_e8s: u64,
+    /// Most ICP converted in any trailing 24 hours.
+    pub max_e8s_per_day: u64,
+    /// Most ICP ever converted; `None` for no lifetime cap.
+    pub max_e8s_total: Option<u64>,
+}
+
+impl Default for CyclesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            top_up_e8s: 200_000_000,        // 2 ICP
+            max_e8s_per_day: 1_000_000_000, // 10 ICP
+            max_e8s_total: None,
+        }
+    }
+}
+
+impl CyclesConfig {
+    fn validate(&self) -> Result<()> {
+        if self.top_up_e8s <= ICP_FEE_E8S {
+            return Err(AnimaError::InvalidAmount("Top-up must exceed the ledger fee".to_string()));
+        }
+        if self.top_up_e8s > self.max_e8s_per_day {
+            return Err(AnimaError::InvalidAmount("Top-up exceeds the daily budget".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// A top-up between paying the CMC and being credited the cycles. Kept
+/// across ticks and upgrades so a payment is never made or notified twice.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingTopUp {
+    pub amount_e8s: u64,
+    /// Fixed for the transfer so a retry deduplicates on the ledger.
+    pub created_at_time: u64,
+    pub block_index: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ConversionOutcome {
+    Credited { cycles: u128 },
+    /// The CMC returned the ICP, less fees, to the treasury subaccount.
+    Refunded { reason: String },
+    Failed { reason: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Conversion {
+    pub timestamp: u64,
+    pub amount_e8s: u64,
+    pub block_index: Option<u64>,
+    pub balance_before: u128,
+    pub outcome: ConversionOutcome,
+}
+
+// Subset of the cycles minting canister's interface.
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NotifyTopUpArg {
+    pub block_index: u64,
+    pub canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum NotifyError {
+    Refunded { reason: String, block_index: Option<u64> },
+    Processing,
+    TransactionTooOld(u64),
+    InvalidTransaction(String),
+    Other { error_code: u64, error_message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CyclesManager {
+    pub config: CyclesConfig,
+    pending: Option<PendingTopUp>,
+    /// Balance that triggered the pending top-up.
+    pending_balance: u128,
+    conversions: Vec<Conversion>,
+    pub spent_total_e8s: u64,
+}
+
+impl CyclesManager {
+    /// ICP paid to the CMC after `since`, including a pending top-up.
+    pub fn spent_since(&self, since: u64) -> u64 {
+        let settled: u64 = self.conversions.iter()
+            .filter(|c| c.timestamp > since && c.block_index.is_some())
+            .map(|c| c.amount_e8s)
+            .sum();
+        settled + self.pending.as_ref().map(|p| p.amount_e8s).unwrap_or(0)
+    }
+
+    /// Starts a top-up when the balance is under `threshold` and the budget
+    /// allows, or returns the one already in flight.
+    pub fn plan(&mut self, balance: u128, threshold: u128, now: u64) -> Option<PendingTopUp> {
+        if let Some(pending) = &self.pending {
+            return Some(pending.clone());
+        }
+        if !self.config.enabled || balance >= threshold {
+            return None;
+        }
+        let amount = self.config.top_up_e8s;
+        if self.spent_since(now.saturating_sub(DAY)) + amount > self.config.max_e8s_per_day {
+            return None;
+        }
+        if let Some(cap) = self.config.max_e8s_total {
+            if self.spent_total_e8s + amount > cap {
+                return None;
+            }
+        }
+        let pending = PendingTopUp { amount_e8s: amount, created_at_time: now, block_index: None };
+        self.pending = Some(pending.clone());
+        self.pending_balance = balance;
+        Some(pending)
+    }
+
+    pub fn transferred(&mut self, block_index: u64) {
+        if let Some(pending) = &mut self.pending {
+            pending.block_index = Some(block_index);
+        }
+    }
+
+    /// Closes the pending top-up with `outcome`. Anything already paid to the
+    /// CMC counts against the budget, whatever came of it.
+    pub fn finish(&mut self, outcome: ConversionOutcome, now: u64) -> Option<Conversion> {
+        let pending = self.pending.take()?;
+        if pending.block_index.is_some() {
+            self.spent_total_e8s += pending.amount_e8s;
+        }
+        let conversion = Conversion {
+            timestamp: now,
+            amount_e8s: pending.amount_e8s,
+            block_index: pending.block_index,
+            balance_before: self.pending_balance,
+            outcome,
+        };
+        self.conversions.push(conversion.clone());
+        if self.conversions.len() > MAX_CONVERSIONS {
+            let excess = self.conversions.len() - MAX_CONVERSIONS;
+            self.conversions.drain(..excess);
+        }
+        Some(conversion)
+    }
+
+    /// Maps a notify result onto the pending top-up. `None` leaves it
+    /// pending for the next tick.
+    pub fn notified(&mut self, result: std::result::Result<u128, NotifyError>, now: u64) -> Option<Conversion> {
+        let outcome = match result {
+            Ok(cycles) => ConversionOutcome::Credited { cycles },
+            Err(NotifyError::Refunded { reason, .. }) => ConversionOutcome::Refunded { reason },
+            // Another notify is in flight, or the CMC hit a transient error.
+            Err(NotifyError::Processing) | Err(NotifyError::Other { .. }) => return None,
+            Err(e) => ConversionOutcome::Failed { reason: format!("{:?}", e) },
+        };
+        self.finish(outcome, now)
+    }
+
+    pub fn pending(&self) -> Option<&PendingTopUp> {
+        self.pending.as_ref()
+    }
+
+    /// Newest first.
+    pub fn conversions(&self, limit: usize) -> Vec<Conversion> {
+        self.conversions.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+/// Account on the CMC that tops up `canister` when paid with the top-up memo.
+pub fn cmc_top_up_account(cmc: &Principal, canister: &Principal) -> AccountIdentifier {
+    let bytes = canister.as_slice();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..=bytes.len()].copy_from_slice(bytes);
+    AccountIdentifier::new(cmc, Some(&subaccount))
+}
+
+thread_local! {
+    static CYCLES: RefCell<CyclesManager> = RefCell::new(CyclesManager::default());
+    /// Set while a top-up is awaiting the ledger or CMC, so ticks never overlap.
+    static TOPPING_UP: Cell<bool> = const { Cell::new(false) };
+}
+
+const CYCLES_REGION: StableRegion<CyclesManager> = StableRegion::new(CYCLES_MEMORY_ID);
+const CYCLES_KEY: &str = "cycles_manager";
+
+pub fn save_stable() -> Result<()> {
+    CYCLES.with(|cycles| {
+        CYCLES_REGION.save([(RegionKey::singleton(CYCLES_KEY), &*cycles.borrow())])
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    if let Some(restored) = CYCLES_REGION.load_singleton(CYCLES_KEY)? {
+        CYCLES.with(|cycles| *cycles.borrow_mut() = restored);
+    }
+    Ok(())
+}
+
+/// Checks the cycles balance every `CHECK_INTERVAL`. Timers do not survive
+/// upgrades, so this runs from both `init` and `post_upgrade`; a top-up left
+/// pending by the upgrade resumes on the first tick.
+pub fn start_cycles_timer() {
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || ic_cdk::spawn(top_up_if_needed()));
+}
+
+async fn top_up_if_needed() {
+    if TOPPING_UP.with(|flag| flag.replace(true)) {
+        return;
+    }
+    if let Err(e) = run_top_up().await {
+        ic_cdk::println!("Cycles top-up failed: {:?}", e);
+    }
+    TOPPING_UP.with(|flag| flag.set(false));
+}
+
+async fn run_top_up() -> Result<()> {
+    let threshold = with_admin_config(|config| config.metrics_config.alert_thresholds.cycles_balance_min);
+    let balance = ic_cdk::api::canister_balance128();
+    let pending = match CYCLES.with(|cycles| cycles.borrow_mut().plan(balance, threshold, time())) {
+        Some(pending) => pending,
+        None => return Ok(()),
+    };
+    let cmc = Principal::from_text(CMC_CANISTER_ID).map_err(|_| AnimaError::InvalidCanister)?;
+
+    let block_index = match pending.block_index {
+        Some(block_index) => block_index,
+        None => {
+            let transfer = ledger::transfer_icp(
+                Some(CYCLES_TREASURY_SUBACCOUNT),
+                cmc_top_up_account(&cmc, &ic_cdk::id()),
+                pending.amount_e8s - ICP_FEE_E8S,
+                ICP_FEE_E8S,
+                MEMO_TOP_UP_CANISTER,
+                pending.created_at_time,
+            ).await;
+            match transfer {
+                Ok(block_index) => {
+                    CYCLES.with(|cycles| cycles.borrow_mut().transferred(block_index));
+                    block_index
+                }
+                // Retried with the same `created_at_time` on the next tick.
+                Err(e @ (AnimaError::NetworkError(_) | AnimaError::TimeoutError)) => return Err(e),
+                Err(e) => {
+                    CYCLES.with(|cycles| {
+                        cycles.borrow_mut().finish(ConversionOutcome::Failed { reason: format!("{:?}", e) }, time())
+                    });
+                    return Err(e);
+                }
+            }
+        }
+    };
+
+    let result: CallResult<(std::result::Result<Nat, NotifyError>,)> = ic_cdk::call(
+        cmc,
+        "notify_top_up",
+        (NotifyTopUpArg { block_index, canister_id: ic_cdk::id() },),
+    ).await;
+    let (result,) = result.map_err(AnimaError::from)?;
+    let result = result.map(|cycles| cycles.0.to_u128().unwrap_or(u128::MAX));
+    if let Some(conversion) = CYCLES.with(|cycles| cycles.borrow_mut().notified(result, time())) {
+        ic_cdk::println!("Cycles top-up from block {}: {:?}", block_index, conversion.outcome);
+    }
+    Ok(())
+}
+
+#[derive(CandidType, Serialize, Clone, Debug)]
+pub struct CyclesStatus {
+    pub balance: u128,
+    pub threshold: u128,
+    pub config: CyclesConfig,
+    pub spent_last_day_e8s: u64,
+    pub spent_total_e8s: u64,
+    pub pending: Option<PendingTopUp>,
+    pub treasury_account: Account,
+}
+
+/// Where to send ICP earmarked for cycles.
+#[query]
+fn get_cycles_treasury_account() -> (Account, String) {
+    let account = Account::new(ic_cdk::id(), Some(CYCLES_TREASURY_SUBACCOUNT));
+    let account_id = AccountIdentifier::new(&ic_cdk::id(), Some(&CYCLES_TREASURY_SUBACCOUNT));
+    (account, account_id.to_hex())
+}
+
+#[query]
+fn get_cycles_status() -> Result<CyclesStatus> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AnimaError::NotAuthorized);
+    }
+    let threshold = with_admin_config(|config| config.metrics_config.alert_thresholds.cycles_balance_min);
+    let now = time();
+    Ok(CYCLES.with(|cycles| {
+        let cycles = cycles.borrow();
+        CyclesStatus {
+            balance: ic_cdk::api::canister_balance128(),
+            threshold,
+            config: cycles.config.clone(),
+            spent_last_day_e8s: cycles.spent_since(now.saturating_sub(DAY)),
+            spent_total_e8s: cycles.spent_total_e8s,
+            pending: cycles.pending().cloned(),
+            treasury_account: Account::new(ic_cdk::id(), Some(CYCLES_TREASURY_SUBACCOUNT)),
+        }
+    }))
+}
+
+/// Recorded conversions, newest first.
+#[query]
+fn get_cycles_conversions(limit: Option<u64>) -> Result<Vec<Conversion>> {
+    if !is_admin(&ic_cdk::caller()) {
+        return Err(AnimaError::NotAuthorized);
+    }
+    let limit = limit.map(|l| l as usize).unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_CONVERSIONS);
+    Ok(CYCLES.with(|cycles| cycles.borrow().conversions(limit)))
+}
+
+#[update]
+fn set_cycles_config(config: CyclesConfig) -> Result<()> {
+    require_role(Role::Treasury, &format!("set_cycles_config {:?}", config))?;
+    config.validate()?;
+    CYCLES.with(|cycles| cycles.borrow_mut().config = config);
+    Ok(())
+}
+
+/// Kill switch for automatic top-ups.
+#[update]
+fn set_cycles_top_up_enabled(enabled: bool) -> Result<()> {
+    require_role(Role::Treasury, &format!("set_cycles_top_up_enabled {}", enabled))?;
+    CYCLES.with(|cycles| cycles.borrow_mut().config.enabled = enabled);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+    const THRESHOLD: u128 = 1_000_000_000_000;
+    /// Cycles the stand-in CMC mints per e8.
+    const CYCLES_PER_E8: u128 = 10_000;
+
+    /// Stand-in for the ICP ledger and CMC: the treasury subaccount's
+    /// balance, transfers deduplicated on `created_at_time`, and blocks the
+    /// CMC converts at most once.
+    #[derive(Default)]
+    struct LocalChain {
+        treasury_e8s: u64,
+        blocks: Vec<(u64, u64)>,
+        notified: Vec<u64>,
+        cycles: u128,
+    }
+
+    impl LocalChain {
+        fn transfer(&mut self, pending: &PendingTopUp) -> Option<u64> {
+            if let Some(block) = self.blocks.iter().position(|(at, _)| *at == pending.created_at_time) {
+                return Some(block as u64);
+            }
+            let amount = pending.amount_e8s - ICP_FEE_E8S;
+            if self.treasury_e8s < pending.amount_e8s {
+                return None;
+            }
+            self.treasury_e8s -= pending.amount_e8s;
+            self.blocks.push((pending.created_at_time, amount));
+            Some(self.blocks.len() as u64 - 1)
+        }
+
+        fn notify_top_up(&mut self, block_index: u64) -> std::result::Result<u128, NotifyError> {
+            if self.notified.contains(&block_index) {
+                return Err(NotifyError::InvalidTransaction("already notified".to_string()));
+            }
+            self.notified.push(block_index);
+            let cycles = self.blocks[block_index as usize].1 as u128 * CYCLES_PER_E8;
+            self.cycles += cycles;
+            Ok(cycles)
+        }
+
+        /// One timer tick, as `run_top_up` drives it.
+        fn tick(&mut self, manager: &mut CyclesManager, now: u64) -> Option<Conversion> {
+            let pending = manager.plan(THRESHOLD - 1, THRESHOLD, now)?;
+            let block = match pending.block_index {
+                Some(block) => block,
+                None => match self.transfer(&pending) {
+                    Some(block) => {
+                        manager.transferred(block);
+                        block
+                    }
+                    None => {
+                        return manager.finish(ConversionOutcome::Failed { reason: "insufficient funds".to_string() }, now);
+                    }
+                },
+            };
+            let result = self.notify_top_up(block);
+            manager.notified(result, now)
+        }
+    }
+
+    fn enabled() -> CyclesManager {
+        CyclesManager {
+            config: CyclesConfig { enabled: true, ..CyclesConfig::default() },
+            ..CyclesManager::default()
+        }
+    }
+
+    #[test]
+    fn test_tops_up_only_below_threshold_and_when_enabled() {
+        let mut manager = CyclesManager::default();
+        assert_eq!(manager.plan(0, THRESHOLD, 0), None);
+
+        let mut manager = enabled();
+        assert_eq!(manager.plan(THRESHOLD, THRESHOLD, 0), None);
+        let mut chain = LocalChain { treasury_e8s: 1_000_000_000, ..LocalChain::default() };
+        let conversion = chain.tick(&mut manager, 0).unwrap();
+        assert_eq!(conversion.outcome, ConversionOutcome::Credited { cycles: 199_990_000 * CYCLES_PER_E8 });
+        assert_eq!(chain.treasury_e8s, 800_000_000);
+        assert_eq!(manager.spent_total_e8s, 200_000_000);
+        assert!(manager.pending().is_none());
+    }
+
+    #[test]
+    fn test_daily_and_lifetime_budgets() {
+        let start = 10 * DAY;
+        let mut manager = enabled();
+        manager.config.max_e8s_per_day = 400_000_000;
+        let mut chain = LocalChain { treasury_e8s: 10_000_000_000, ..LocalChain::default() };
+        assert!(chain.tick(&mut manager, start).is_some());
+        assert!(chain.tick(&mut manager, start + HOUR).is_some());
+        assert!(chain.tick(&mut manager, start + 2 * HOUR).is_none());
+        // The window slides.
+        assert!(chain.tick(&mut manager, start + DAY + HOUR).is_some());
+
+        manager.config.max_e8s_total = Some(700_000_000);
+        assert!(chain.tick(&mut manager, start + 3 * DAY).is_none());
+        assert_eq!(chain.blocks.len(), 3);
+    }
+
+    #[test]
+    fn test_pending_top_up_resumes_without_paying_twice() {
+        let mut manager = enabled();
+        let mut chain = LocalChain { treasury_e8s: 1_000_000_000, ..LocalChain::default() };
+
+        // Paid, but the notify never landed (e.g. an upgrade in between).
+        let pending = manager.plan(0, THRESHOLD, 0).unwrap();
+        let block = chain.transfer(&pending).unwrap();
+        manager.transferred(block);
+        assert_eq!(manager.notified(Err(NotifyError::Processing), 1), None);
+
+        // Disabling stops new top-ups but the paid one still completes.
+        manager.config.enabled = false;
+        let conversion = chain.tick(&mut manager, 2).unwrap();
+        assert!(matches!(conversion.outcome, ConversionOutcome::Credited { .. }));
+        assert_eq!(chain.blocks.len(), 1);
+        assert_eq!(chain.treasury_e8s, 800_000_000);
+        assert_eq!(chain.tick(&mut manager, 3), None);
+    }
+
+    #[test]
+    fn test_failed_transfer_does_not_count_against_budget() {
+        let mut manager = enabled();
+        let mut chain = LocalChain::default();
+        let conversion = chain.tick(&mut manager, 0).unwrap();
+        assert!(matches!(conversion.outcome, ConversionOutcome::Failed { .. }));
+        assert_eq!(manager.spent_since(0), 0);
+        assert_eq!(manager.spent_total_e8s, 0);
+        assert_eq!(manager.conversions(10).len(), 1);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        assert!(CyclesConfig::default().validate().is_ok());
+        assert!(CyclesConfig { top_up_e8s: ICP_FEE_E8S, ..CyclesConfig::default() }.validate().is_err());
+        assert!(CyclesConfig { max_e8s_per_day: 1, ..CyclesConfig::default() }.validate().is_err());
+    }
+}
@@ -1,9 +1,20 @@
 use ic_cdk::api::{time, performance_counter};
+use ic_cdk_macros::*;
+use ic_metrics_encoder::MetricsEncoder;
+use num_traits::ToPrimitive;
 use serde::{Serialize, Deserialize};
 use candid::CandidType;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use crate::error::Result;
+use crate::icrc::{client, AcceptedToken};
+use crate::payments::pricing_config::PricingTier;
+use crate::stable::{RegionKey, StableRegion, METRICS_MEMORY_ID};
 
 const PERF_COUNTER_INSTRUCTIONS: u32 = 0;
 const MAX_HISTORY_SIZE: usize = 100;
+pub const WASM_PAGE_SIZE: u64 = 64 * 1024;
 
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
 pub struct SystemMetrics {
@@ -19,17 +30,26 @@ pub struct Alert {
     pub source: String,
 }
 
-#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, CandidType, Serialize, Deserialize, PartialEq, Eq)]
 pub enum AlertLevel {
     Critical,
     Warning,
     Info,
 }
 
+impl AlertLevel {
+    pub fn severity(self) -> u8 {
+        match self {
+            AlertLevel::Critical => 2,
+            AlertLevel::Warning => 1,
+            AlertLevel::Info => 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
 pub struct MetricsHistory {
     pub system_metrics: Vec<SystemMetrics>,
-    pub alerts: Vec<Alert>,
     pub last_update: u64,
 }
 
@@ -42,11 +62,16 @@ impl SystemMetrics {
     }
 }
 
+impl Default for SystemMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Default for MetricsHistory {
     fn default() -> Self {
         Self {
             system_metrics: Vec::new(),
-            alerts: Vec::new(),
             last_update: time(),
         }
     }
@@ -55,10 +80,299 @@ impl Default for MetricsHistory {
 pub fn record_metrics(history: &mut MetricsHistory) {
     let current_metrics = SystemMetrics::new();
     history.system_metrics.push(current_metrics);
-    
+
     if history.system_metrics.len() > MAX_HISTORY_SIZE {
         history.system_metrics.remove(0);
     }
-    
+
     history.last_update = time();
-}
\ No newline at end of file
+}
+
+/// Running totals behind the `/metrics` counters. Kept across upgrades so
+/// scrapes see monotonic counters.
+#[derive(Debug, Clone, Default, CandidType, Serialize, Deserialize)]
+pub struct Counters {
+    pub mints: BTreeMap<(PricingTier, AcceptedToken), u64>,
+    pub llm_calls: u64,
+    pub llm_errors: u64,
+    /// ANIMA ledger supply as of the last collection; queries cannot call
+    /// the ledger themselves.
+    pub anima_supply: Option<u128>,
+}
+
+/// Everything `/metrics` reports, gathered in one place so encoding stays
+/// independent of canister state.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub cycles_balance: u128,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub nft_supply: u64,
+    pub anima_supply: Option<u128>,
+    pub mints: Vec<((PricingTier, AcceptedToken), u64)>,
+    pub sales_volume: Vec<(AcceptedToken, u64)>,
+    pub sales_volume_24h: Vec<(AcceptedToken, u64)>,
+    pub total_staked: u128,
+    pub number_of_stakers: u64,
+    pub staking_rewards_distributed: u128,
+    pub llm_calls: u64,
+    pub llm_errors: u64,
+}
+
+impl MetricsSnapshot {
+    pub fn llm_error_rate(&self) -> f64 {
+        if self.llm_calls == 0 {
+            0.0
+        } else {
+            self.llm_errors as f64 / self.llm_calls as f64
+        }
+    }
+
+    /// Renders the snapshot in the Prometheus text exposition format.
+    pub fn encode(&self, now_millis: i64) -> std::io::Result<Vec<u8>> {
+        let mut w = MetricsEncoder::new(Vec::new(), now_millis);
+        w.encode_gauge("anima_cycles_balance", self.cycles_balance as f64, "Cycles held by the canister.")?;
+        w.encode_gauge("anima_heap_memory_bytes", self.heap_memory_bytes as f64, "Wasm heap size in bytes.")?;
+        w.encode_gauge("anima_stable_memory_bytes", self.stable_memory_bytes as f64, "Stable memory size in bytes.")?;
+        w.encode_gauge("anima_nft_supply", self.nft_supply as f64, "Animas minted and not burned.")?;
+        if let Some(supply) = self.anima_supply {
+            w.encode_gauge("anima_token_supply", supply as f64, "ANIMA ledger total supply, in base units.")?;
+        }
+
+        let mut mints = w.counter_vec("anima_mints_total", "Animas minted, by pricing tier and payment token.")?;
+        for ((tier, token), count) in &self.mints {
+            mints = mints.value(&[("tier", &format!("{:?}", tier)), ("token", &token_label(token))], *count as f64)?;
+        }
+        let mut volume = w.counter_vec("anima_marketplace_volume_total", "All-time marketplace sales volume, by payment token.")?;
+        for (token, amount) in &self.sales_volume {
+            volume = volume.value(&[("token", &token_label(token))], *amount as f64)?;
+        }
+        let mut volume_24h = w.gauge_vec("anima_marketplace_volume_24h", "Marketplace sales volume over the last day, by payment token.")?;
+        for (token, amount) in &self.sales_volume_24h {
+            volume_24h = volume_24h.value(&[("token", &token_label(token))], *amount as f64)?;
+        }
+
+        w.encode_gauge("anima_staked_total", self.total_staked as f64, "ANIMA staked in the pool, in base units.")?;
+        w.encode_gauge("anima_stakers", self.number_of_stakers as f64, "Principals with an open stake.")?;
+        w.encode_counter(
+            "anima_staking_rewards_distributed_total",
+            self.staking_rewards_distributed as f64,
+            "Staking rewards paid out, in base units.",
+        )?;
+
+        w.encode_counter("anima_llm_calls_total", self.llm_calls as f64, "LLM requests made.")?;
+        w.encode_counter("anima_llm_errors_total", self.llm_errors as f64, "LLM requests that failed.")?;
+        w.encode_gauge("anima_llm_error_rate", self.llm_error_rate(), "Share of LLM requests that failed.")?;
+        Ok(w.into_inner())
+    }
+}
+
+fn token_label(token: &AcceptedToken) -> String {
+    match token {
+        AcceptedToken::ICP => "ICP".to_string(),
+        AcceptedToken::ANIMA => "ANIMA".to_string(),
+        AcceptedToken::Icrc1(ledger) => ledger.to_text(),
+    }
+}
+
+/// Size of the wasm heap. Only meaningful inside the canister.
+pub fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+pub fn stable_memory_bytes() -> u64 {
+    ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE
+}
+
+thread_local! {
+    static COUNTERS: RefCell<Counters> = RefCell::new(Counters::default());
+    static HISTORY: RefCell<MetricsHistory> = RefCell::new(MetricsHistory::default());
+}
+
+const COUNTERS_REGION: StableRegion<Counters> = StableRegion::new(METRICS_MEMORY_ID);
+const COUNTERS_KEY: &str = "counters";
+
+pub fn with_history_mut<R>(f: impl FnOnce(&mut MetricsHistory) -> R) -> R {
+    HISTORY.with(|history| f(&mut history.borrow_mut()))
+}
+
+pub fn save_stable() -> Result<()> {
+    COUNTERS.with(|counters| {
+        COUNTERS_REGION.save([(RegionKey::singleton(COUNTERS_KEY), &*counters.borrow())])
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    if let Some(restored) = COUNTERS_REGION.load_singleton(COUNTERS_KEY)? {
+        COUNTERS.with(|counters| *counters.borrow_mut() = restored);
+    }
+    Ok(())
+}
+
+pub fn record_mint(tier: PricingTier, token: AcceptedToken) {
+    COUNTERS.with(|counters| *counters.borrow_mut().mints.entry((tier, token)).or_insert(0) += 1);
+}
+
+pub fn llm_counts() -> (u64, u64) {
+    COUNTERS.with(|counters| {
+        let counters = counters.borrow();
+        (counters.llm_calls, counters.llm_errors)
+    })
+}
+
+pub fn record_llm_call(succeeded: bool) {
+    COUNTERS.with(|counters| {
+        let mut counters = counters.borrow_mut();
+        counters.llm_calls += 1;
+        if !succeeded {
+            counters.llm_errors += 1;
+        }
+    });
+}
+
+/// Samples system metrics and refreshes the cached ledger supply every
+/// `MetricsConfig::collection_interval` seconds. Timers do not survive
+/// upgrades, so this runs from both `init` and `post_upgrade`; a changed
+/// interval takes effect on the next upgrade.
+pub fn start_collection_timer() {
+    let interval = super::with_admin_config(|config| config.metrics_config.collection_interval).max(1);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(interval), || {
+        with_history_mut(record_metrics);
+        ic_cdk::spawn(refresh_anima_supply());
+    });
+}
+
+async fn refresh_anima_supply() {
+    let Ok(ledger) = crate::payments::transaction_processor::token_canister(&AcceptedToken::ANIMA) else {
+        return;
+    };
+    match client::icrc1_total_supply(ledger).await {
+        Ok(supply) => {
+            let supply = supply.0.to_u128().unwrap_or(u128::MAX);
+            COUNTERS.with(|counters| counters.borrow_mut().anima_supply = Some(supply));
+        }
+        Err(e) => ic_cdk::println!("ANIMA supply refresh failed: {:?}", e),
+    }
+}
+
+pub fn snapshot() -> MetricsSnapshot {
+    let now = time();
+    let counters = COUNTERS.with(|counters| counters.borrow().clone());
+    let (sales_volume, sales_volume_24h) = crate::nft::marketplace::with_marketplace(|marketplace| {
+        let totals: Vec<_> = marketplace.sales.total_volume().collect();
+        let day = totals.iter()
+            .map(|(token, _)| (*token, marketplace.sales.volume(*token, crate::nft::market_stats::DAY, now)))
+            .collect();
+        (totals, day)
+    });
+    let (total_staked, number_of_stakers, staking_rewards_distributed) =
+        crate::anima_token::staking::pool::with_pool_metrics(|pool| {
+            (pool.total_staked, pool.number_of_stakers, pool.total_rewards_distributed)
+        });
+
+    MetricsSnapshot {
+        cycles_balance: ic_cdk::api::canister_balance128(),
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: stable_memory_bytes(),
+        nft_supply: crate::nft::registry::with_registry(|registry| registry.total_supply()),
+        anima_supply: counters.anima_supply,
+        mints: counters.mints.into_iter().collect(),
+        sales_volume,
+        sales_volume_24h,
+        total_staked,
+        number_of_stakers,
+        staking_rewards_distributed,
+        llm_calls: counters.llm_calls,
+        llm_errors: counters.llm_errors,
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Serialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status_code: u16, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status_code,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Cache-Control".to_string(), "no-store".to_string()),
+            ],
+            body: body.into(),
+        }
+    }
+}
+
+/// Serves `/metrics` in the Prometheus text format for scraping.
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let path = request.url.split('?').next().unwrap_or_default();
+    if path != "/metrics" {
+        return HttpResponse::new(404, "text/plain", "Not found");
+    }
+    let now_millis = (time() / 1_000_000) as i64;
+    match snapshot().encode(now_millis) {
+        Ok(body) => HttpResponse::new(200, "text/plain; version=0.0.4", body),
+        Err(e) => HttpResponse::new(500, "text/plain", format!("Failed to encode metrics: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+
+    #[test]
+    fn test_encodes_prometheus_text() {
+        let ckbtc = Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap();
+        let snapshot = MetricsSnapshot {
+            cycles_balance: 5_000_000_000_000,
+            nft_supply: 42,
+            mints: vec![
+                ((PricingTier::Genesis, AcceptedToken::ICP), 40),
+                ((PricingTier::Common, AcceptedToken::ICP), 3),
+                ((PricingTier::Common, AcceptedToken::Icrc1(ckbtc)), 2),
+            ],
+            sales_volume: vec![(AcceptedToken::ICP, 1_000)],
+            total_staked: 7,
+            llm_calls: 4,
+            llm_errors: 1,
+            ..MetricsSnapshot::default()
+        };
+        let text = String::from_utf8(snapshot.encode(1_000).unwrap()).unwrap();
+
+        assert!(text.contains("# TYPE anima_cycles_balance gauge"));
+        assert!(text.contains("anima_cycles_balance 5000000000000 1000"));
+        assert!(text.contains("anima_mints_total{tier=\"Genesis\",token=\"ICP\"} 40 1000"));
+        assert!(text.contains("anima_mints_total{tier=\"Common\",token=\"ICP\"} 3 1000"));
+        assert!(text.contains(&format!("anima_mints_total{{tier=\"Common\",token=\"{}\"}} 2 1000", ckbtc)));
+        assert!(text.contains("anima_marketplace_volume_total{token=\"ICP\"} 1000 1000"));
+        assert!(text.contains("anima_staked_total 7 1000"));
+        assert!(text.contains("anima_llm_error_rate 0.25 1000"));
+        assert!(!text.contains("anima_token_supply"));
+    }
+
+    #[test]
+    fn test_llm_error_rate_without_calls() {
+        assert_eq!(MetricsSnapshot::default().llm_error_rate(), 0.0);
+    }
+}
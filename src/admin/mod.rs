@@ -1 +1,10 @@
-// Reserved for future admin functionality
\ No newline at end of file
+#[allow(clippy::module_inception)]
+mod admin;
+pub mod alerts;
+pub mod cycles;
+pub mod metrics;
+
+pub use admin::{
+    is_admin, record_event, require_role, restore_stable, save_stable, with_admin_config, AdminProposal, AlertThresholds,
+    MetricsConfig, Role,
+};
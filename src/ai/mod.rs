@@ -13,7 +13,9 @@ pub async fn analyze_interaction(
     } else {
         None
     };
-    openai_client::get_response(text, personality, quantum_state, context).await
+    let response = openai_client::get_response(text, personality, quantum_state, context).await;
+    crate::admin::metrics::record_llm_call(response.is_ok());
+    response
 }
 
 pub mod openai_client;
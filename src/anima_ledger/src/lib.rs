@@ -0,0 +1,1436 @@
+use candid::{CandidType, Deserialize, Encode, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableCell, StableLog};
+use num_traits::ToPrimitive;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Constants
+const TOKEN_NAME: &str = "ANIMA Token";
+const TOKEN_SYMBOL: &str = "ANIMA";
+const DECIMALS: u8 = 8;
+const TRANSFER_FEE: u128 = 10_000;
+/// Burns below this are rejected so dust cannot be used to spam the log.
+const MIN_BURN_AMOUNT: u128 = TRANSFER_FEE;
+const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+
+/// Blocks kept on the heap; once exceeded, the oldest batch moves to the
+/// stable-memory archive.
+const MAX_HEAP_BLOCKS: usize = 2_000;
+const ARCHIVE_BATCH_SIZE: usize = 1_000;
+const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+
+pub type Subaccount = [u8; 32];
+pub const DEFAULT_SUBACCOUNT: Subaccount = [0; 32];
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const UPGRADES_MEMORY_ID: MemoryId = MemoryId::new(0);
+const ARCHIVE_INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
+const ARCHIVE_DATA_MEMORY_ID: MemoryId = MemoryId::new(2);
+
+thread_local! {
+    static STATE: RefCell<Ledger> = RefCell::new(Ledger::default());
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    /// Candid-encoded blocks `0..archived_blocks`, in order.
+    static ARCHIVE: RefCell<StableLog<Vec<u8>, Memory, Memory>> = RefCell::new(
+        StableLog::init(get_memory(ARCHIVE_INDEX_MEMORY_ID), get_memory(ARCHIVE_DATA_MEMORY_ID))
+            .expect("Failed to initialize block archive")
+    );
+}
+
+fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// ICRC-1 account. `None` and the all-zero subaccount are the same account.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+/// Normalised account used as the balance key.
+type AccountKey = (Principal, Subaccount);
+
+impl Account {
+    fn key(&self) -> AccountKey {
+        (self.owner, self.subaccount.unwrap_or(DEFAULT_SUBACCOUNT))
+    }
+}
+
+impl PartialEq for Account {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Account {}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Allowance {
+    pub allowance: u128,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Mint,
+    Burn,
+    Transfer,
+    TransferFrom,
+    Approve,
+}
+
+impl Operation {
+    fn btype(&self) -> &'static str {
+        match self {
+            Operation::Mint => "1mint",
+            Operation::Burn => "1burn",
+            Operation::Transfer => "1xfer",
+            Operation::TransferFrom => "2xfer",
+            Operation::Approve => "2approve",
+        }
+    }
+}
+
+/// ICRC-3 generic value. Blocks are maps of these, hashed with the
+/// representation-independent hash so the log is verifiable off-chain.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(candid::Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn nat(n: impl Into<candid::Nat>) -> Self {
+        Value::Nat(n.into())
+    }
+
+    fn account(account: &Account) -> Self {
+        let mut parts = vec![Value::Blob(account.owner.as_slice().to_vec())];
+        if let Some(subaccount) = account.subaccount.filter(|s| *s != DEFAULT_SUBACCOUNT) {
+            parts.push(Value::Blob(subaccount.to_vec()));
+        }
+        Value::Array(parts)
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        match self {
+            Value::Blob(bytes) => sha256(bytes),
+            Value::Text(text) => sha256(text.as_bytes()),
+            Value::Nat(n) => {
+                let mut leb = Vec::new();
+                n.encode(&mut leb).expect("writing to a Vec cannot fail");
+                sha256(&leb)
+            }
+            Value::Int(i) => {
+                let mut sleb = Vec::new();
+                i.encode(&mut sleb).expect("writing to a Vec cannot fail");
+                sha256(&sleb)
+            }
+            Value::Array(items) => {
+                let mut hasher = Sha256::new();
+                for item in items {
+                    hasher.update(item.hash());
+                }
+                hasher.finalize().into()
+            }
+            Value::Map(fields) => {
+                let mut entries: Vec<Vec<u8>> = fields.iter()
+                    .map(|(key, value)| [sha256(key.as_bytes()), value.hash()].concat())
+                    .collect();
+                entries.sort();
+                sha256(&entries.concat())
+            }
+        }
+    }
+}
+
+fn sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+type TransferResult = Result<u128, TransferError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferFromError {
+    BadFee { expected_fee: u128 },
+    BadBurn { min_burn_amount: u128 },
+    InsufficientFunds { balance: u128 },
+    InsufficientAllowance { allowance: u128 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum ApproveError {
+    BadFee { expected_fee: u128 },
+    InsufficientFunds { balance: u128 },
+    AllowanceChanged { current_allowance: u128 },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    TemporarilyUnavailable,
+    GenericError { error_code: u128, message: String },
+}
+
+// The transfer path reports the superset `TransferFromError`; these narrow it
+// for the other endpoints. Variants that cannot occur there become generic.
+impl From<TransferFromError> for TransferError {
+    fn from(error: TransferFromError) -> Self {
+        match error {
+            TransferFromError::BadFee { expected_fee } => TransferError::BadFee { expected_fee },
+            TransferFromError::BadBurn { min_burn_amount } => TransferError::BadBurn { min_burn_amount },
+            TransferFromError::InsufficientFunds { balance } => TransferError::InsufficientFunds { balance },
+            TransferFromError::TooOld => TransferError::TooOld,
+            TransferFromError::CreatedInFuture { ledger_time } => TransferError::CreatedInFuture { ledger_time },
+            TransferFromError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+            TransferFromError::TemporarilyUnavailable => TransferError::TemporarilyUnavailable,
+            TransferFromError::GenericError { error_code, message } => TransferError::GenericError { error_code, message },
+            TransferFromError::InsufficientAllowance { allowance } => TransferError::GenericError {
+                error_code: 0,
+                message: format!("Insufficient allowance: {}", allowance),
+            },
+        }
+    }
+}
+
+impl From<TransferFromError> for ApproveError {
+    fn from(error: TransferFromError) -> Self {
+        match error {
+            TransferFromError::BadFee { expected_fee } => ApproveError::BadFee { expected_fee },
+            TransferFromError::InsufficientFunds { balance } => ApproveError::InsufficientFunds { balance },
+            TransferFromError::TooOld => ApproveError::TooOld,
+            TransferFromError::CreatedInFuture { ledger_time } => ApproveError::CreatedInFuture { ledger_time },
+            TransferFromError::Duplicate { duplicate_of } => ApproveError::Duplicate { duplicate_of },
+            TransferFromError::TemporarilyUnavailable => ApproveError::TemporarilyUnavailable,
+            TransferFromError::GenericError { error_code, message } => ApproveError::GenericError { error_code, message },
+            other => ApproveError::GenericError { error_code: 0, message: format!("{:?}", other) },
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: u128,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+/// A transfer after the caller has been resolved into accounts. `spender` is
+/// set for ICRC-2 transfers.
+struct Transfer {
+    from: Account,
+    spender: Option<Account>,
+    to: Account,
+    amount: u128,
+    fee: Option<u128>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: u128,
+    pub expected_allowance: Option<u128>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<u128>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InitArgs {
+    pub minting_account: Account,
+    pub initial_balances: Vec<(Account, u128)>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum MetadataValue {
+    Nat(candid::Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+// ICRC-3 interface types
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: candid::Nat,
+    pub length: candid::Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: candid::Nat,
+    pub block: Value,
+}
+
+candid::define_function!(pub GetBlocksFn : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksFn,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: candid::Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DataCertificate {
+    pub certificate: Vec<u8>,
+    pub hash_tree: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: candid::Nat,
+    pub end: candid::Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Ledger {
+    balances: BTreeMap<AccountKey, u128>,
+    allowances: BTreeMap<(AccountKey, AccountKey), Allowance>,
+    total_supply: u128,
+    minting_account: Account,
+    /// Blocks `archived_blocks..` of the log; older ones live in `ARCHIVE`.
+    blocks: Vec<Value>,
+    archived_blocks: u64,
+    tip_hash: Option<[u8; 32]>,
+    /// Dedup key -> block index, for transfers that set `created_at_time`.
+    dedup: BTreeMap<[u8; 32], u64>,
+    dedup_expiry: BTreeSet<(u64, [u8; 32])>,
+}
+
+impl Default for Ledger {
+    fn default() -> Self {
+        Self::new(Account { owner: Principal::anonymous(), subaccount: None })
+    }
+}
+
+impl Ledger {
+    pub fn new(minting_account: Account) -> Self {
+        Self {
+            balances: BTreeMap::new(),
+            allowances: BTreeMap::new(),
+            total_supply: 0,
+            minting_account,
+            blocks: Vec::new(),
+            archived_blocks: 0,
+            tip_hash: None,
+            dedup: BTreeMap::new(),
+            dedup_expiry: BTreeSet::new(),
+        }
+    }
+
+    pub fn balance_of(&self, account: &Account) -> u128 {
+        self.balances.get(&account.key()).copied().unwrap_or(0)
+    }
+
+    pub fn total_supply(&self) -> u128 {
+        self.total_supply
+    }
+
+    pub fn minting_account(&self) -> Account {
+        self.minting_account
+    }
+
+    fn credit(&mut self, account: &Account, amount: u128) {
+        *self.balances.entry(account.key()).or_insert(0) += amount;
+    }
+
+    fn debit(&mut self, account: &Account, amount: u128) {
+        let key = account.key();
+        let balance = self.balances.get(&key).copied().unwrap_or(0) - amount;
+        if balance == 0 {
+            self.balances.remove(&key);
+        } else {
+            self.balances.insert(key, balance);
+        }
+    }
+
+    fn check_created_at_time(created_at_time: Option<u64>, now: u64) -> Result<(), TransferFromError> {
+        let Some(created_at_time) = created_at_time else {
+            return Ok(());
+        };
+        if created_at_time.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now {
+            return Err(TransferFromError::TooOld);
+        }
+        if created_at_time > now.saturating_add(PERMITTED_DRIFT) {
+            return Err(TransferFromError::CreatedInFuture { ledger_time: now });
+        }
+        Ok(())
+    }
+
+    /// Identifies a request for deduplication: the same caller submitting
+    /// the same arguments (including `created_at_time`) twice.
+    fn dedup_key<T: CandidType>(caller: &Principal, args: &T) -> [u8; 32] {
+        sha256(&Encode!(caller, args).expect("ledger args are encodable"))
+    }
+
+    /// Checks shared by every ledger update: memo size, the
+    /// `created_at_time` window and deduplication.
+    fn check_request(
+        &mut self,
+        memo: Option<&Vec<u8>>,
+        created_at_time: Option<u64>,
+        dedup_key: Option<[u8; 32]>,
+        now: u64,
+    ) -> Result<(), TransferFromError> {
+        if memo.map(|memo| memo.len() > 32).unwrap_or(false) {
+            return Err(TransferFromError::GenericError {
+                error_code: 2,
+                message: "Memo must be at most 32 bytes".to_string(),
+            });
+        }
+        Self::check_created_at_time(created_at_time, now)?;
+
+        self.prune_dedup(now);
+        if let Some(&duplicate_of) = dedup_key.and_then(|key| self.dedup.get(&key)) {
+            return Err(TransferFromError::Duplicate { duplicate_of: duplicate_of as u128 });
+        }
+        Ok(())
+    }
+
+    fn record_dedup(&mut self, dedup_key: Option<[u8; 32]>, created_at_time: Option<u64>, index: u64) {
+        if let (Some(key), Some(created_at_time)) = (dedup_key, created_at_time) {
+            self.dedup.insert(key, index);
+            self.dedup_expiry.insert((created_at_time, key));
+        }
+    }
+
+    /// Forgets dedup entries whose `created_at_time` has left the window;
+    /// such transfers are rejected as `TooOld` anyway.
+    fn prune_dedup(&mut self, now: u64) {
+        while let Some(&(created_at_time, key)) = self.dedup_expiry.first() {
+            if created_at_time.saturating_add(TX_WINDOW + PERMITTED_DRIFT) >= now {
+                break;
+            }
+            self.dedup_expiry.pop_first();
+            self.dedup.remove(&key);
+        }
+    }
+
+    pub fn log_length(&self) -> u64 {
+        self.archived_blocks + self.blocks.len() as u64
+    }
+
+    /// Index and hash of the last block, if any.
+    pub fn tip(&self) -> Option<(u64, [u8; 32])> {
+        self.tip_hash.map(|hash| (self.log_length() - 1, hash))
+    }
+
+    /// Returns a block still held on the heap.
+    pub fn heap_block(&self, index: u64) -> Option<&Value> {
+        index.checked_sub(self.archived_blocks)
+            .and_then(|offset| self.blocks.get(offset as usize))
+    }
+
+    pub fn archived_blocks(&self) -> u64 {
+        self.archived_blocks
+    }
+
+    fn append_block(&mut self, operation: Operation, tx: Vec<(String, Value)>, fee: u128, now: u64) -> u64 {
+        let mut fields = Vec::new();
+        if let Some(phash) = self.tip_hash {
+            fields.push(("phash".to_string(), Value::Blob(phash.to_vec())));
+        }
+        fields.push(("btype".to_string(), Value::Text(operation.btype().to_string())));
+        fields.push(("ts".to_string(), Value::nat(now)));
+        if fee > 0 {
+            fields.push(("fee".to_string(), Value::nat(fee)));
+        }
+        fields.push(("tx".to_string(), Value::Map(tx)));
+
+        let block = Value::Map(fields);
+        self.tip_hash = Some(block.hash());
+        self.blocks.push(block);
+        self.log_length() - 1
+    }
+
+    /// Detaches the oldest heap blocks once the heap holds more than
+    /// `MAX_HEAP_BLOCKS`. The caller must persist them to the archive.
+    pub fn take_archive_batch(&mut self) -> Vec<Value> {
+        if self.blocks.len() <= MAX_HEAP_BLOCKS {
+            return Vec::new();
+        }
+        let batch: Vec<Value> = self.blocks.drain(..ARCHIVE_BATCH_SIZE).collect();
+        self.archived_blocks += batch.len() as u64;
+        batch
+    }
+
+    /// Executes an ICRC-1 transfer on behalf of `caller`. The debited account
+    /// is always the caller's own (`from_subaccount` selects which one).
+    /// Transfers from the minting account mint, transfers to it burn; both
+    /// are fee-free.
+    pub fn transfer(&mut self, caller: Principal, args: TransferArg, now: u64) -> TransferResult {
+        let dedup_key = args.created_at_time.map(|_| Self::dedup_key(&caller, &args));
+        let transfer = Transfer {
+            from: Account { owner: caller, subaccount: args.from_subaccount },
+            spender: None,
+            to: args.to,
+            amount: args.amount,
+            fee: args.fee,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        };
+        self.execute_transfer(transfer, dedup_key, now)
+            .map(|index| index as u128)
+            .map_err(TransferError::from)
+    }
+
+    /// Executes an ICRC-2 transfer on behalf of `caller` as spender, drawing
+    /// `amount + fee` from the allowance `from` granted to it.
+    pub fn transfer_from(&mut self, caller: Principal, args: TransferFromArgs, now: u64) -> Result<u128, TransferFromError> {
+        let dedup_key = args.created_at_time.map(|_| Self::dedup_key(&caller, &args));
+        let transfer = Transfer {
+            from: args.from,
+            spender: Some(Account { owner: caller, subaccount: args.spender_subaccount }),
+            to: args.to,
+            amount: args.amount,
+            fee: args.fee,
+            memo: args.memo,
+            created_at_time: args.created_at_time,
+        };
+        self.execute_transfer(transfer, dedup_key, now).map(|index| index as u128)
+    }
+
+    fn execute_transfer(&mut self, t: Transfer, dedup_key: Option<[u8; 32]>, now: u64) -> Result<u64, TransferFromError> {
+        let is_mint = t.from == self.minting_account;
+        let is_burn = t.to == self.minting_account;
+
+        if is_mint && is_burn {
+            return Err(TransferFromError::GenericError {
+                error_code: 1,
+                message: "The minting account cannot transfer to itself".to_string(),
+            });
+        }
+        if is_mint && t.spender.is_some() {
+            return Err(TransferFromError::GenericError {
+                error_code: 3,
+                message: "The minting account cannot be spent from through an allowance".to_string(),
+            });
+        }
+
+        let expected_fee = if is_mint || is_burn { 0 } else { TRANSFER_FEE };
+        if t.fee.map(|fee| fee != expected_fee).unwrap_or(false) {
+            return Err(TransferFromError::BadFee { expected_fee });
+        }
+        if is_burn && t.amount < MIN_BURN_AMOUNT {
+            return Err(TransferFromError::BadBurn { min_burn_amount: MIN_BURN_AMOUNT });
+        }
+        self.check_request(t.memo.as_ref(), t.created_at_time, dedup_key, now)?;
+
+        // Spending from one's own account needs no allowance.
+        let allowance_spender = t.spender.filter(|spender| *spender != t.from);
+        let total_debit = t.amount.saturating_add(expected_fee);
+        if let Some(spender) = &allowance_spender {
+            let allowance = self.allowance(&t.from, spender, now).allowance;
+            if allowance < total_debit {
+                return Err(TransferFromError::InsufficientAllowance { allowance });
+            }
+        }
+
+        let operation = if is_mint {
+            self.credit(&t.to, t.amount);
+            self.total_supply += t.amount;
+            Operation::Mint
+        } else {
+            let balance = self.balance_of(&t.from);
+            if balance < total_debit {
+                return Err(TransferFromError::InsufficientFunds { balance });
+            }
+            self.debit(&t.from, total_debit);
+            if let Some(spender) = &allowance_spender {
+                self.spend_allowance(&t.from, spender, total_debit);
+            }
+            if is_burn {
+                self.total_supply -= t.amount;
+                Operation::Burn
+            } else {
+                self.credit(&t.to, t.amount);
+                // Fees are burned.
+                self.total_supply -= expected_fee;
+                if t.spender.is_some() { Operation::TransferFrom } else { Operation::Transfer }
+            }
+        };
+
+        let mut tx = vec![("amt".to_string(), Value::nat(t.amount))];
+        if operation != Operation::Mint {
+            tx.push(("from".to_string(), Value::account(&t.from)));
+        }
+        if operation != Operation::Burn {
+            tx.push(("to".to_string(), Value::account(&t.to)));
+        }
+        if let Some(spender) = &t.spender {
+            tx.push(("spender".to_string(), Value::account(spender)));
+        }
+        if let Some(memo) = t.memo {
+            tx.push(("memo".to_string(), Value::Blob(memo)));
+        }
+        if let Some(created_at_time) = t.created_at_time {
+            tx.push(("ts".to_string(), Value::nat(created_at_time)));
+        }
+        if let Some(fee) = t.fee {
+            tx.push(("fee".to_string(), Value::nat(fee)));
+        }
+        let index = self.append_block(operation, tx, expected_fee, now);
+        self.record_dedup(dedup_key, t.created_at_time, index);
+        Ok(index)
+    }
+
+    pub fn allowance(&self, account: &Account, spender: &Account, now: u64) -> Allowance {
+        self.allowances
+            .get(&(account.key(), spender.key()))
+            .filter(|a| a.expires_at.map(|exp| exp > now).unwrap_or(true))
+            .cloned()
+            .unwrap_or(Allowance { allowance: 0, expires_at: None })
+    }
+
+    fn spend_allowance(&mut self, account: &Account, spender: &Account, amount: u128) {
+        let key = (account.key(), spender.key());
+        if let Some(allowance) = self.allowances.get_mut(&key) {
+            allowance.allowance -= amount;
+            if allowance.allowance == 0 {
+                self.allowances.remove(&key);
+            }
+        }
+    }
+
+    /// Sets the allowance of `spender` over the caller's account, charging
+    /// the transfer fee. `expected_allowance` guards against racing approvals.
+    pub fn approve(&mut self, caller: Principal, args: ApproveArgs, now: u64) -> Result<u128, ApproveError> {
+        let from = Account { owner: caller, subaccount: args.from_subaccount };
+        if args.spender.owner == from.owner {
+            return Err(ApproveError::GenericError {
+                error_code: 4,
+                message: "Cannot approve an account of the caller as spender".to_string(),
+            });
+        }
+        if args.fee.map(|fee| fee != TRANSFER_FEE).unwrap_or(false) {
+            return Err(ApproveError::BadFee { expected_fee: TRANSFER_FEE });
+        }
+        if args.expires_at.map(|expires_at| expires_at <= now).unwrap_or(false) {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+        let dedup_key = args.created_at_time.map(|_| Self::dedup_key(&caller, &args));
+        self.check_request(args.memo.as_ref(), args.created_at_time, dedup_key, now)?;
+
+        let current_allowance = self.allowance(&from, &args.spender, now).allowance;
+        if args.expected_allowance.map(|expected| expected != current_allowance).unwrap_or(false) {
+            return Err(ApproveError::AllowanceChanged { current_allowance });
+        }
+        let balance = self.balance_of(&from);
+        if balance < TRANSFER_FEE {
+            return Err(ApproveError::InsufficientFunds { balance });
+        }
+
+        self.debit(&from, TRANSFER_FEE);
+        self.total_supply -= TRANSFER_FEE;
+        let key = (from.key(), args.spender.key());
+        if args.amount == 0 {
+            self.allowances.remove(&key);
+        } else {
+            self.allowances.insert(key, Allowance { allowance: args.amount, expires_at: args.expires_at });
+        }
+
+        let mut tx = vec![
+            ("amt".to_string(), Value::nat(args.amount)),
+            ("from".to_string(), Value::account(&from)),
+            ("spender".to_string(), Value::account(&args.spender)),
+        ];
+        if let Some(expected_allowance) = args.expected_allowance {
+            tx.push(("expected_allowance".to_string(), Value::nat(expected_allowance)));
+        }
+        if let Some(expires_at) = args.expires_at {
+            tx.push(("expires_at".to_string(), Value::nat(expires_at)));
+        }
+        if let Some(memo) = args.memo {
+            tx.push(("memo".to_string(), Value::Blob(memo)));
+        }
+        if let Some(created_at_time) = args.created_at_time {
+            tx.push(("ts".to_string(), Value::nat(created_at_time)));
+        }
+        if let Some(fee) = args.fee {
+            tx.push(("fee".to_string(), Value::nat(fee)));
+        }
+        let index = self.append_block(Operation::Approve, tx, TRANSFER_FEE, now);
+        self.record_dedup(dedup_key, args.created_at_time, index);
+        Ok(index as u128)
+    }
+}
+
+// Initialize the token
+#[init]
+fn init(args: Option<InitArgs>) {
+    let caller = ic_cdk::caller();
+    let args = args.unwrap_or(InitArgs {
+        minting_account: Account { owner: caller, subaccount: None },
+        initial_balances: Vec::new(),
+    });
+
+    STATE.with(|state| {
+        let mut ledger = Ledger::new(args.minting_account);
+        for (account, amount) in args.initial_balances {
+            let mint = TransferArg {
+                from_subaccount: ledger.minting_account.subaccount,
+                to: account,
+                amount,
+                fee: None,
+                memo: None,
+                created_at_time: None,
+            };
+            if let Err(e) = ledger.transfer(ledger.minting_account.owner, mint, time()) {
+                ic_cdk::trap(&format!("Invalid initial balance: {:?}", e));
+            }
+        }
+        *state.borrow_mut() = ledger;
+    });
+    archive_blocks();
+    certify_tip();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let bytes = STATE.with(|state| candid::encode_one(&*state.borrow()))
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to encode ledger: {}", e)));
+    let mut cell = StableCell::init(get_memory(UPGRADES_MEMORY_ID), Vec::new())
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to open upgrade memory: {:?}", e)));
+    if let Err(e) = cell.set(bytes) {
+        ic_cdk::trap(&format!("Failed to save ledger: {:?}", e));
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let cell = StableCell::init(get_memory(UPGRADES_MEMORY_ID), Vec::new())
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to open upgrade memory: {:?}", e)));
+    match candid::decode_one::<Ledger>(cell.get()) {
+        Ok(ledger) => STATE.with(|state| *state.borrow_mut() = ledger),
+        Err(e) => ic_cdk::trap(&format!("Failed to restore ledger: {}", e)),
+    }
+    certify_tip();
+}
+
+/// Moves surplus heap blocks into the stable-memory archive. Traps on
+/// failure so the triggering update is rolled back rather than losing blocks.
+fn archive_blocks() {
+    let batch = STATE.with(|state| state.borrow_mut().take_archive_batch());
+    if batch.is_empty() {
+        return;
+    }
+    ARCHIVE.with(|archive| {
+        let archive = archive.borrow();
+        for block in &batch {
+            let bytes = candid::encode_one(block)
+                .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to encode block: {}", e)));
+            if let Err(e) = archive.append(&bytes) {
+                ic_cdk::trap(&format!("Failed to archive block: {:?}", e));
+            }
+        }
+    });
+}
+
+fn archived_block(index: u64) -> Option<Value> {
+    ARCHIVE.with(|archive| archive.borrow().get(index))
+        .and_then(|bytes| candid::decode_one(&bytes).ok())
+}
+
+fn certify_tip() {
+    if let Some((index, hash)) = STATE.with(|state| state.borrow().tip()) {
+        ic_cdk::api::set_certified_data(&tip_tree_root(index, &hash));
+    }
+}
+
+// Certification: the tip is published as the hash tree
+// fork(labeled("last_block_hash", leaf(hash)), labeled("last_block_index", leaf(leb128(index)))).
+const LAST_BLOCK_HASH_LABEL: &[u8] = b"last_block_hash";
+const LAST_BLOCK_INDEX_LABEL: &[u8] = b"last_block_index";
+
+fn leb128(mut n: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn labeled_leaf_hash(label: &[u8], value: &[u8]) -> [u8; 32] {
+    let leaf = domain_hash("ic-hashtree-leaf", &[value]);
+    domain_hash("ic-hashtree-labeled", &[label, &leaf])
+}
+
+fn tip_tree_root(index: u64, hash: &[u8; 32]) -> [u8; 32] {
+    let left = labeled_leaf_hash(LAST_BLOCK_HASH_LABEL, hash);
+    let right = labeled_leaf_hash(LAST_BLOCK_INDEX_LABEL, &leb128(index));
+    domain_hash("ic-hashtree-fork", &[&left, &right])
+}
+
+fn cbor_head(out: &mut Vec<u8>, major: u8, len: usize) {
+    let major = major << 5;
+    match len {
+        0..=23 => out.push(major | len as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, len as u8]),
+        _ => {
+            out.push(major | 25);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    cbor_head(out, 2, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+fn cbor_labeled_leaf(out: &mut Vec<u8>, label: &[u8], value: &[u8]) {
+    cbor_head(out, 4, 3);
+    out.push(2);
+    cbor_bytes(out, label);
+    cbor_head(out, 4, 2);
+    out.push(3);
+    cbor_bytes(out, value);
+}
+
+/// CBOR encoding of the tip hash tree, matching `tip_tree_root`.
+fn tip_tree_cbor(index: u64, hash: &[u8; 32]) -> Vec<u8> {
+    let mut out = vec![0xd9, 0xd9, 0xf7];
+    cbor_head(&mut out, 4, 3);
+    out.push(1);
+    cbor_labeled_leaf(&mut out, LAST_BLOCK_HASH_LABEL, hash);
+    cbor_labeled_leaf(&mut out, LAST_BLOCK_INDEX_LABEL, &leb128(index));
+    out
+}
+
+// Token Metadata
+#[query]
+fn icrc1_name() -> String {
+    TOKEN_NAME.to_string()
+}
+
+#[query]
+fn icrc1_symbol() -> String {
+    TOKEN_SYMBOL.to_string()
+}
+
+#[query]
+fn icrc1_decimals() -> u8 {
+    DECIMALS
+}
+
+#[query]
+fn icrc1_fee() -> u128 {
+    TRANSFER_FEE
+}
+
+#[query]
+fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    vec![
+        ("icrc1:name".to_string(), MetadataValue::Text(TOKEN_NAME.to_string())),
+        ("icrc1:symbol".to_string(), MetadataValue::Text(TOKEN_SYMBOL.to_string())),
+        ("icrc1:decimals".to_string(), MetadataValue::Nat(DECIMALS.into())),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(TRANSFER_FEE.into())),
+    ]
+}
+
+#[query]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-3".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
+        },
+    ]
+}
+
+#[query]
+fn icrc1_total_supply() -> u128 {
+    STATE.with(|state| state.borrow().total_supply())
+}
+
+#[query]
+fn icrc1_minting_account() -> Option<Account> {
+    STATE.with(|state| Some(state.borrow().minting_account()))
+}
+
+// Balance operations
+#[query]
+fn icrc1_balance_of(account: Account) -> u128 {
+    STATE.with(|state| state.borrow().balance_of(&account))
+}
+
+// Transfer operation
+#[update]
+fn icrc1_transfer(args: TransferArg) -> TransferResult {
+    let caller = ic_cdk::caller();
+    let result = STATE.with(|state| state.borrow_mut().transfer(caller, args, time()));
+    if result.is_ok() {
+        archive_blocks();
+        certify_tip();
+    }
+    result
+}
+
+// Approvals
+#[query]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    STATE.with(|state| state.borrow().allowance(&args.account, &args.spender, time()))
+}
+
+#[update]
+fn icrc2_approve(args: ApproveArgs) -> Result<u128, ApproveError> {
+    let caller = ic_cdk::caller();
+    let result = STATE.with(|state| state.borrow_mut().approve(caller, args, time()));
+    if result.is_ok() {
+        archive_blocks();
+        certify_tip();
+    }
+    result
+}
+
+#[update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<u128, TransferFromError> {
+    let caller = ic_cdk::caller();
+    let result = STATE.with(|state| state.borrow_mut().transfer_from(caller, args, time()));
+    if result.is_ok() {
+        archive_blocks();
+        certify_tip();
+    }
+    result
+}
+
+// Block log (ICRC-3)
+#[query]
+fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    STATE.with(|state| {
+        let ledger = state.borrow();
+        let log_length = ledger.log_length();
+        let mut budget = MAX_BLOCKS_PER_RESPONSE;
+        let mut blocks = Vec::new();
+
+        for range in args {
+            let start = range.start.0.to_u64().unwrap_or(u64::MAX).min(log_length);
+            let length = range.length.0.to_u64().unwrap_or(u64::MAX).min(budget);
+            let end = start.saturating_add(length).min(log_length);
+            for id in start..end {
+                // Archived blocks live in this canister's stable memory, so
+                // they are served inline rather than through a callback.
+                let block = match ledger.heap_block(id) {
+                    Some(block) => Some(block.clone()),
+                    None => archived_block(id),
+                };
+                if let Some(block) = block {
+                    blocks.push(BlockWithId { id: id.into(), block });
+                }
+            }
+            budget -= end - start;
+        }
+
+        GetBlocksResult { log_length: log_length.into(), blocks, archived_blocks: Vec::new() }
+    })
+}
+
+#[query]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ArchiveInfo> {
+    // No separate archive canisters: old blocks stay in stable memory here.
+    Vec::new()
+}
+
+#[query]
+fn icrc3_get_tip_certificate() -> Option<DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let (index, hash) = STATE.with(|state| state.borrow().tip())?;
+    Some(DataCertificate { certificate, hash_tree: tip_tree_cbor(index, &hash) })
+}
+
+#[query]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    ["1mint", "1burn", "1xfer", "2approve", "2xfer"]
+        .iter()
+        .map(|block_type| SupportedBlockType {
+            block_type: block_type.to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-3".to_string(),
+        })
+        .collect()
+}
+
+// Generate Candid interface
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn account(id: u8, subaccount: Option<u8>) -> Account {
+        Account { owner: principal(id), subaccount: subaccount.map(|s| [s; 32]) }
+    }
+
+    fn transfer_arg(from_subaccount: Option<u8>, to: Account, amount: u128) -> TransferArg {
+        TransferArg {
+            from_subaccount: from_subaccount.map(|s| [s; 32]),
+            to,
+            amount,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    /// Ledger whose minting account is principal 0, with 1_000_000 minted to
+    /// principal 1's default account.
+    fn funded_ledger() -> Ledger {
+        let mut ledger = Ledger::new(account(0, None));
+        ledger.transfer(principal(0), transfer_arg(None, account(1, None), 1_000_000), NOW).unwrap();
+        ledger
+    }
+
+    #[test]
+    fn test_transfer_debits_caller_and_burns_fee() {
+        let mut ledger = funded_ledger();
+        ledger.transfer(principal(1), transfer_arg(None, account(2, None), 100_000), NOW).unwrap();
+
+        assert_eq!(ledger.balance_of(&account(1, None)), 1_000_000 - 100_000 - TRANSFER_FEE);
+        assert_eq!(ledger.balance_of(&account(2, None)), 100_000);
+        assert_eq!(ledger.total_supply(), 1_000_000 - TRANSFER_FEE);
+    }
+
+    #[test]
+    fn test_caller_cannot_spend_others_balance() {
+        let mut ledger = funded_ledger();
+        let result = ledger.transfer(principal(2), transfer_arg(None, account(2, Some(1)), 100), NOW);
+        assert_eq!(result, Err(TransferError::InsufficientFunds { balance: 0 }));
+        assert_eq!(ledger.balance_of(&account(1, None)), 1_000_000);
+    }
+
+    #[test]
+    fn test_subaccounts_are_separate() {
+        let mut ledger = funded_ledger();
+        ledger.transfer(principal(1), transfer_arg(None, account(1, Some(7)), 50_000), NOW).unwrap();
+
+        assert_eq!(ledger.balance_of(&account(1, Some(7))), 50_000);
+        assert_eq!(ledger.balance_of(&account(1, Some(0))), 1_000_000 - 50_000 - TRANSFER_FEE);
+        ledger.transfer(principal(1), transfer_arg(Some(7), account(2, None), 40_000), NOW).unwrap();
+        assert_eq!(ledger.balance_of(&account(1, Some(7))), 0);
+    }
+
+    #[test]
+    fn test_burn_to_minting_account() {
+        let mut ledger = funded_ledger();
+        let too_small = ledger.transfer(principal(1), transfer_arg(None, account(0, None), MIN_BURN_AMOUNT - 1), NOW);
+        assert_eq!(too_small, Err(TransferError::BadBurn { min_burn_amount: MIN_BURN_AMOUNT }));
+
+        ledger.transfer(principal(1), transfer_arg(None, account(0, None), 200_000), NOW).unwrap();
+        assert_eq!(ledger.balance_of(&account(1, None)), 800_000);
+        assert_eq!(ledger.total_supply(), 800_000);
+    }
+
+    #[test]
+    fn test_bad_fee_and_time_window() {
+        let mut ledger = funded_ledger();
+        let mut arg = transfer_arg(None, account(2, None), 1);
+        arg.fee = Some(1);
+        assert_eq!(ledger.transfer(principal(1), arg.clone(), NOW), Err(TransferError::BadFee { expected_fee: TRANSFER_FEE }));
+
+        arg.fee = None;
+        arg.created_at_time = Some(NOW - TX_WINDOW - PERMITTED_DRIFT - 1);
+        assert_eq!(ledger.transfer(principal(1), arg.clone(), NOW), Err(TransferError::TooOld));
+        arg.created_at_time = Some(NOW + PERMITTED_DRIFT + 1);
+        assert_eq!(ledger.transfer(principal(1), arg, NOW), Err(TransferError::CreatedInFuture { ledger_time: NOW }));
+    }
+
+    #[test]
+    fn test_mint_requires_no_fee() {
+        let mut ledger = funded_ledger();
+        let mut arg = transfer_arg(None, account(3, None), 10);
+        arg.fee = Some(TRANSFER_FEE);
+        assert_eq!(ledger.transfer(principal(0), arg, NOW), Err(TransferError::BadFee { expected_fee: 0 }));
+    }
+
+    #[test]
+    fn test_duplicate_transfer_rejected_within_window() {
+        let mut ledger = funded_ledger();
+        let mut arg = transfer_arg(None, account(2, None), 100);
+        arg.created_at_time = Some(NOW);
+        let first = ledger.transfer(principal(1), arg.clone(), NOW).unwrap();
+
+        assert_eq!(ledger.transfer(principal(1), arg.clone(), NOW + 1), Err(TransferError::Duplicate { duplicate_of: first }));
+        // A different memo is a different transaction.
+        arg.memo = Some(vec![1]);
+        assert!(ledger.transfer(principal(1), arg, NOW + 1).is_ok());
+        // Without created_at_time nothing is deduplicated.
+        ledger.transfer(principal(1), transfer_arg(None, account(2, None), 100), NOW).unwrap();
+        ledger.transfer(principal(1), transfer_arg(None, account(2, None), 100), NOW).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_entries_expire_with_window() {
+        let mut ledger = funded_ledger();
+        let mut arg = transfer_arg(None, account(2, None), 100);
+        arg.created_at_time = Some(NOW);
+        ledger.transfer(principal(1), arg, NOW).unwrap();
+        assert_eq!(ledger.dedup.len(), 1);
+
+        ledger.prune_dedup(NOW + TX_WINDOW + PERMITTED_DRIFT + 1);
+        assert!(ledger.dedup.is_empty());
+        assert!(ledger.dedup_expiry.is_empty());
+    }
+
+    #[test]
+    fn test_blocks_are_hash_chained() {
+        let mut ledger = funded_ledger();
+        ledger.transfer(principal(1), transfer_arg(None, account(2, None), 100), NOW).unwrap();
+
+        let genesis = ledger.heap_block(0).unwrap().clone();
+        let second = ledger.heap_block(1).unwrap();
+        let Value::Map(fields) = second else { panic!("block is not a map") };
+        let phash = fields.iter().find(|(key, _)| key == "phash").map(|(_, value)| value.clone());
+        assert_eq!(phash, Some(Value::Blob(genesis.hash().to_vec())));
+        assert_eq!(ledger.tip(), Some((1, second.hash())));
+        let btype = fields.iter().find(|(key, _)| key == "btype").map(|(_, value)| value.clone());
+        assert_eq!(btype, Some(Value::Text("1xfer".to_string())));
+    }
+
+    #[test]
+    fn test_old_blocks_move_to_archive_batch() {
+        let mut ledger = Ledger::new(account(0, None));
+        for _ in 0..=MAX_HEAP_BLOCKS {
+            ledger.transfer(principal(0), transfer_arg(None, account(1, None), 1), NOW).unwrap();
+        }
+        let tip = ledger.tip();
+
+        let batch = ledger.take_archive_batch();
+        assert_eq!(batch.len(), ARCHIVE_BATCH_SIZE);
+        assert_eq!(ledger.archived_blocks(), ARCHIVE_BATCH_SIZE as u64);
+        assert_eq!(ledger.log_length(), MAX_HEAP_BLOCKS as u64 + 1);
+        assert!(ledger.heap_block(0).is_none());
+        assert!(ledger.heap_block(ARCHIVE_BATCH_SIZE as u64).is_some());
+        assert_eq!(ledger.tip(), tip);
+        assert!(ledger.take_archive_batch().is_empty());
+    }
+
+    #[test]
+    fn test_value_hash_vectors() {
+        // Examples from the ICRC-3 specification.
+        let hex = |value: Value| hex::encode(value.hash());
+        assert_eq!(hex(Value::nat(42u64)), "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1");
+        assert_eq!(hex(Value::Text("Hello, World!".to_string())), "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f");
+        assert_eq!(hex(Value::Blob(vec![1, 2, 3, 4])), "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a");
+        assert_eq!(
+            hex(Value::Array(vec![Value::nat(3u64), Value::Text("foo".to_string()), Value::Blob(vec![5, 6])])),
+            "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6"
+        );
+        let map = Value::Map(vec![
+            ("from".to_string(), Value::Blob(hex::decode("00abcdef0012340056789a00bcdef000012345678900abcdef01").unwrap())),
+            ("to".to_string(), Value::Blob(hex::decode("00ab0def0012340056789a00bcdef000012345678900abcdef01").unwrap())),
+            ("amount".to_string(), Value::nat(42u64)),
+            ("created_at".to_string(), Value::nat(1_699_218_263u64)),
+            ("memo".to_string(), Value::nat(0u64)),
+        ]);
+        assert_eq!(hex(map), "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75");
+    }
+
+    fn approve_arg(spender: Account, amount: u128) -> ApproveArgs {
+        ApproveArgs {
+            from_subaccount: None,
+            spender,
+            amount,
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn transfer_from_arg(from: Account, to: Account, amount: u128) -> TransferFromArgs {
+        TransferFromArgs {
+            spender_subaccount: None,
+            from,
+            to,
+            amount,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    #[test]
+    fn test_approve_charges_fee_and_checks_expected_allowance() {
+        let mut ledger = funded_ledger();
+        ledger.approve(principal(1), approve_arg(account(2, None), 5_000), NOW).unwrap();
+        assert_eq!(ledger.balance_of(&account(1, None)), 1_000_000 - TRANSFER_FEE);
+        assert_eq!(ledger.allowance(&account(1, None), &account(2, None), NOW).allowance, 5_000);
+
+        let mut arg = approve_arg(account(2, None), 7_000);
+        arg.expected_allowance = Some(1);
+        assert_eq!(
+            ledger.approve(principal(1), arg.clone(), NOW),
+            Err(ApproveError::AllowanceChanged { current_allowance: 5_000 })
+        );
+        arg.expected_allowance = Some(5_000);
+        ledger.approve(principal(1), arg, NOW).unwrap();
+        assert_eq!(ledger.allowance(&account(1, None), &account(2, None), NOW).allowance, 7_000);
+
+        let mut expired = approve_arg(account(2, None), 1);
+        expired.expires_at = Some(NOW);
+        assert_eq!(ledger.approve(principal(1), expired, NOW), Err(ApproveError::Expired { ledger_time: NOW }));
+    }
+
+    #[test]
+    fn test_transfer_from_spends_allowance() {
+        let mut ledger = funded_ledger();
+        ledger.approve(principal(1), approve_arg(account(2, None), 100_000), NOW).unwrap();
+
+        ledger.transfer_from(principal(2), transfer_from_arg(account(1, None), account(3, None), 50_000), NOW).unwrap();
+        assert_eq!(ledger.balance_of(&account(3, None)), 50_000);
+        assert_eq!(ledger.allowance(&account(1, None), &account(2, None), NOW).allowance, 100_000 - 50_000 - TRANSFER_FEE);
+
+        let result = ledger.transfer_from(principal(2), transfer_from_arg(account(1, None), account(3, None), 50_000), NOW);
+        assert_eq!(result, Err(TransferFromError::InsufficientAllowance { allowance: 100_000 - 50_000 - TRANSFER_FEE }));
+        // A different spender has no allowance at all.
+        let result = ledger.transfer_from(principal(4), transfer_from_arg(account(1, None), account(4, None), 1), NOW);
+        assert_eq!(result, Err(TransferFromError::InsufficientAllowance { allowance: 0 }));
+    }
+
+    #[test]
+    fn test_transfer_from_honours_expiry() {
+        let mut ledger = funded_ledger();
+        let mut arg = approve_arg(account(2, None), 100_000);
+        arg.expires_at = Some(NOW + 10);
+        ledger.approve(principal(1), arg, NOW).unwrap();
+
+        let result = ledger.transfer_from(principal(2), transfer_from_arg(account(1, None), account(2, None), 1), NOW + 10);
+        assert_eq!(result, Err(TransferFromError::InsufficientAllowance { allowance: 0 }));
+    }
+
+    #[test]
+    fn test_transfer_from_own_account_needs_no_allowance() {
+        let mut ledger = funded_ledger();
+        ledger.transfer_from(principal(1), transfer_from_arg(account(1, None), account(2, None), 10), NOW).unwrap();
+        assert_eq!(ledger.balance_of(&account(2, None)), 10);
+    }
+
+    /// The scenarios of the ICRC-1 reference acceptance suite
+    /// (dfinity/ICRC-1, `test/suite`), run against the ledger state instead
+    /// of a deployed canister. Each test is named after the suite's.
+    mod icrc1_suite {
+        use super::*;
+
+        fn transfer_at(created_at_time: u64) -> TransferArg {
+            TransferArg { created_at_time: Some(created_at_time), ..transfer_arg(None, account(2, None), 1_000) }
+        }
+
+        #[test]
+        fn icrc1_transfer() {
+            let mut ledger = funded_ledger();
+            let supply = ledger.total_supply();
+            let index = ledger.transfer(principal(1), transfer_arg(None, account(2, Some(3)), 10_000), NOW).unwrap();
+
+            assert_eq!(index, 1);
+            assert_eq!(ledger.balance_of(&account(2, Some(3))), 10_000);
+            assert_eq!(ledger.balance_of(&account(1, None)), 1_000_000 - 10_000 - TRANSFER_FEE);
+            assert_eq!(ledger.total_supply(), supply - TRANSFER_FEE);
+        }
+
+        #[test]
+        fn icrc1_burn() {
+            let mut ledger = funded_ledger();
+            let minting_account = ledger.minting_account();
+            ledger.transfer(principal(1), transfer_arg(None, minting_account, MIN_BURN_AMOUNT), NOW).unwrap();
+
+            assert_eq!(ledger.balance_of(&account(1, None)), 1_000_000 - MIN_BURN_AMOUNT);
+            assert_eq!(ledger.total_supply(), 1_000_000 - MIN_BURN_AMOUNT);
+        }
+
+        #[test]
+        fn icrc1_metadata() {
+            let metadata = super::super::icrc1_metadata();
+            for (key, _) in &metadata {
+                let (namespace, name) = key.split_once(':').expect("metadata keys are namespaced");
+                assert!(!namespace.is_empty() && !name.is_empty());
+            }
+            let lookup = |key: &str| metadata.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone());
+            assert_eq!(lookup("icrc1:name"), Some(MetadataValue::Text(icrc1_name())));
+            assert_eq!(lookup("icrc1:symbol"), Some(MetadataValue::Text(icrc1_symbol())));
+            assert_eq!(lookup("icrc1:decimals"), Some(MetadataValue::Nat(icrc1_decimals().into())));
+            assert_eq!(lookup("icrc1:fee"), Some(MetadataValue::Nat(icrc1_fee().into())));
+        }
+
+        #[test]
+        fn icrc1_supported_standards() {
+            let standards = super::super::icrc1_supported_standards();
+            assert!(standards.iter().any(|standard| standard.name == "ICRC-1"
+                && standard.url == "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1"));
+        }
+
+        #[test]
+        fn icrc1_tx_deduplication() {
+            let mut ledger = funded_ledger();
+
+            // Without created_at_time identical transfers are all executed.
+            let plain = transfer_arg(None, account(2, None), 1_000);
+            let first = ledger.transfer(principal(1), plain.clone(), NOW).unwrap();
+            let second = ledger.transfer(principal(1), plain, NOW).unwrap();
+            assert_ne!(first, second);
+
+            let original = ledger.transfer(principal(1), transfer_at(NOW), NOW).unwrap();
+            assert_eq!(ledger.transfer(principal(1), transfer_at(NOW), NOW), Err(TransferError::Duplicate { duplicate_of: original }));
+
+            // Changing any argument, even to an equivalent value, makes it a new transaction.
+            let variants = [
+                TransferArg { created_at_time: Some(NOW + 1), ..transfer_at(NOW) },
+                TransferArg { fee: Some(TRANSFER_FEE), ..transfer_at(NOW) },
+                TransferArg { from_subaccount: Some(DEFAULT_SUBACCOUNT), ..transfer_at(NOW) },
+                TransferArg { memo: Some(vec![1, 2, 3]), ..transfer_at(NOW) },
+                TransferArg { to: Account { owner: principal(2), subaccount: Some(DEFAULT_SUBACCOUNT) }, ..transfer_at(NOW) },
+            ];
+            for arg in variants {
+                assert!(ledger.transfer(principal(1), arg, NOW).is_ok());
+            }
+            // Another caller submitting the same arguments is not a duplicate either.
+            ledger.transfer(principal(1), transfer_arg(None, account(3, None), 100_000), NOW).unwrap();
+            assert!(ledger.transfer(principal(3), transfer_at(NOW), NOW).is_ok());
+        }
+
+        #[test]
+        fn icrc1_bad_fee() {
+            let mut ledger = funded_ledger();
+            let arg = TransferArg { fee: Some(TRANSFER_FEE - 1), ..transfer_arg(None, account(2, None), 1_000) };
+            assert_eq!(ledger.transfer(principal(1), arg, NOW), Err(TransferError::BadFee { expected_fee: TRANSFER_FEE }));
+            assert_eq!(ledger.balance_of(&account(2, None)), 0);
+        }
+
+        #[test]
+        fn icrc1_future_transfer() {
+            let mut ledger = funded_ledger();
+            let arg = transfer_at(NOW + PERMITTED_DRIFT + 1);
+            assert_eq!(ledger.transfer(principal(1), arg, NOW), Err(TransferError::CreatedInFuture { ledger_time: NOW }));
+        }
+
+        #[test]
+        fn icrc1_memo_bytes_length() {
+            let mut ledger = funded_ledger();
+            let arg = TransferArg { memo: Some(vec![1; 32]), ..transfer_arg(None, account(2, None), 1_000) };
+            assert!(ledger.transfer(principal(1), arg, NOW).is_ok());
+
+            let arg = TransferArg { memo: Some(vec![1; 33]), ..transfer_arg(None, account(2, None), 1_000) };
+            assert!(matches!(ledger.transfer(principal(1), arg, NOW), Err(TransferError::GenericError { .. })));
+        }
+
+        #[test]
+        fn icrc1_insufficient_funds() {
+            let mut ledger = funded_ledger();
+            let arg = transfer_arg(None, account(2, None), 1_000_000);
+            assert_eq!(ledger.transfer(principal(1), arg, NOW), Err(TransferError::InsufficientFunds { balance: 1_000_000 }));
+        }
+    }
+
+    #[test]
+    fn test_candid_file_matches_interface() {
+        // Regenerate with `__export_service()` when an endpoint changes.
+        assert_eq!(__export_service().trim(), include_str!("../anima_ledger.did").trim());
+    }
+}
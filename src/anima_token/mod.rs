@@ -0,0 +1,3 @@
+pub mod rewards;
+pub mod staking;
+pub mod swap;
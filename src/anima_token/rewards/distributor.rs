@@ -1,156 +1,870 @@
-use candid::{CandidType, Decode, Encode, Principal};
-use ic_cdk::api::call::CallResult;
-use ic_cdk::api::{time, trap};
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::time;
 use ic_cdk_macros::*;
+use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use crate::admin::{require_role, Role};
+use crate::anima_token::staking::pool::{with_staking, StakePosition};
+use crate::icrc::{client, AcceptedToken, Account, Subaccount};
+use crate::nft::marketplace::{ledger_fee, marketplace_spender};
+use crate::nft::registry::{with_registry, NftOperation, NftTransaction, TokenRegistry};
+use crate::payments::transaction_processor::token_canister;
+use crate::stable::{
+    RegionKey, StableRegion, REWARDS_MEMORY_ID, REWARD_ALLOCATIONS_MEMORY_ID, REWARD_EPOCHS_MEMORY_ID,
+};
 
-#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const MONTH: u64 = 30 * DAY;
+
+/// Hard cap on ANIMA ever committed to reward epochs: 100M ANIMA (8 decimals).
+pub const MAX_TOTAL_REWARDS: u128 = 100_000_000 * 100_000_000;
+/// Staking counts towards an epoch weight for at most a year per position.
+const MAX_STAKING_MONTHS: f64 = 12.0;
+/// Weights are converted to integers at this precision before splitting.
+const WEIGHT_SCALE: f64 = 1_000_000.0;
+const EPOCH_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Holds the ANIMA escrowed for reward epochs. Claims are paid from it.
+pub const EPOCH_REWARDS_SUBACCOUNT: Subaccount = *b"anima-epoch-rewards\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+/// On-chain activity of one participant over an epoch.
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct RewardMetrics {
-    quantum_coherence: f64,
-    participation_score: f64,
-    nft_power: f64,
-    staking_duration: u64,
-    network_contribution: f64
+    /// Paid operations during the epoch: mints, marketplace purchases and
+    /// new stakes. Free transfers and approvals do not count, so passing a
+    /// token around a ring of accounts earns nothing.
+    pub interactions: u64,
+    /// Summed `coherence_level` of the ANIMA held at the snapshot.
+    pub nft_power: f64,
+    /// Summed age of their staking positions, each capped at a year.
+    pub staking_duration: u64,
 }
 
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
 pub struct RewardConfig {
-    base_rate: f64,
-    coherence_multiplier: f64,
-    participation_multiplier: f64,
-    nft_bonus_rate: f64,
-    staking_bonus_rate: f64,
-    network_bonus_rate: f64
+    pub participation_weight: f64,
+    pub nft_power_weight: f64,
+    pub staking_weight: f64,
+    /// Interactions beyond this per epoch earn nothing, to blunt spam.
+    pub max_counted_interactions: u64,
+}
+
+impl Default for RewardConfig {
+    fn default() -> Self {
+        Self {
+            participation_weight: 1.5,
+            nft_power_weight: 10.0,
+            staking_weight: 5.0,
+            max_counted_interactions: 100,
+        }
+    }
+}
+
+impl RewardConfig {
+    fn validate(&self) -> Result<(), String> {
+        let weights = [self.participation_weight, self.nft_power_weight, self.staking_weight];
+        if weights.iter().any(|weight| !weight.is_finite() || *weight < 0.0) {
+            return Err("Reward weights must be finite and non-negative".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn weight(&self, metrics: &RewardMetrics) -> f64 {
+        let interactions = metrics.interactions.min(self.max_counted_interactions) as f64;
+        let staking_months = metrics.staking_duration as f64 / MONTH as f64;
+        interactions * self.participation_weight
+            + metrics.nft_power * self.nft_power_weight
+            + staking_months * self.staking_weight
+    }
 }
 
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
 pub struct RewardPool {
-    total_rewards: u128,
-    distributed_rewards: u128,
-    last_distribution: u64,
-    distribution_interval: u64
+    /// ANIMA committed to closed epochs. Never exceeds `MAX_TOTAL_REWARDS`.
+    pub total_rewards: u128,
+    /// ANIMA actually paid out by claims.
+    pub distributed_rewards: u128,
+    pub last_distribution: u64,
+    pub distribution_interval: u64,
+    /// Budget split across participants when the next epoch closes, capped
+    /// by `escrowed`.
+    pub epoch_emission: u128,
+    pub next_epoch: u64,
+    /// ANIMA escrowed in `EPOCH_REWARDS_SUBACCOUNT` and not yet committed to
+    /// an epoch.
+    pub escrowed: u128,
+}
+
+impl Default for RewardPool {
+    fn default() -> Self {
+        Self {
+            total_rewards: 0,
+            distributed_rewards: 0,
+            last_distribution: 0,
+            distribution_interval: DAY,
+            epoch_emission: 0,
+            next_epoch: 0,
+            escrowed: 0,
+        }
+    }
+}
+
+/// Activity of the epoch in progress, tallied as it happens so neither the
+/// epoch timer nor `get_metrics` rescans the registry's history.
+#[derive(CandidType, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct EpochActivity {
+    /// Registry transactions before this index are already tallied.
+    pub tx_cursor: u64,
+    pub interactions: BTreeMap<Principal, u64>,
+    /// `nft_power` per holder as of the last sample.
+    pub nft_power: BTreeMap<Principal, f64>,
+}
+
+impl EpochActivity {
+    /// Tallies the transactions appended since the last call. `marketplace`
+    /// is the spender that settles sales.
+    pub fn ingest(&mut self, transactions: &[NftTransaction], marketplace: &Account) {
+        let start = (self.tx_cursor as usize).min(transactions.len());
+        for tx in &transactions[start..] {
+            if let Some(principal) = paying_party(tx, marketplace) {
+                self.record(principal);
+            }
+        }
+        self.tx_cursor = transactions.len() as u64;
+    }
+
+    pub fn record(&mut self, principal: Principal) {
+        *self.interactions.entry(principal).or_default() += 1;
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DistributorState {
+    pub config: RewardConfig,
+    pub pool: RewardPool,
+    pub activity: EpochActivity,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct Allocation {
+    pub principal: Principal,
+    pub metrics: RewardMetrics,
+    pub weight: f64,
+    pub amount: u128,
+    pub claimed: bool,
+}
+
+/// A closed epoch. Allocations are sorted by principal; their leaf hashes
+/// form the Merkle tree whose root is published here.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct EpochSnapshot {
+    pub epoch: u64,
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub budget: u128,
+    pub allocated: u128,
+    pub merkle_root: [u8; 32],
+    pub allocations: Vec<Allocation>,
+}
+
+/// An `EpochSnapshot` without its allocations, which are persisted one row
+/// each.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+struct EpochHeader {
+    epoch: u64,
+    started_at: u64,
+    ended_at: u64,
+    budget: u128,
+    allocated: u128,
+    merkle_root: [u8; 32],
+}
+
+impl EpochHeader {
+    fn of(snapshot: &EpochSnapshot) -> Self {
+        Self {
+            epoch: snapshot.epoch,
+            started_at: snapshot.started_at,
+            ended_at: snapshot.ended_at,
+            budget: snapshot.budget,
+            allocated: snapshot.allocated,
+            merkle_root: snapshot.merkle_root,
+        }
+    }
+
+    fn with_allocations(self, allocations: Vec<Allocation>) -> EpochSnapshot {
+        EpochSnapshot {
+            epoch: self.epoch,
+            started_at: self.started_at,
+            ended_at: self.ended_at,
+            budget: self.budget,
+            allocated: self.allocated,
+            merkle_root: self.merkle_root,
+            allocations,
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct EpochSummary {
+    pub epoch: u64,
+    pub started_at: u64,
+    pub ended_at: u64,
+    pub budget: u128,
+    pub allocated: u128,
+    pub merkle_root: [u8; 32],
+    pub participant_count: u64,
+    pub claimed: u128,
+}
+
+impl From<&EpochSnapshot> for EpochSummary {
+    fn from(snapshot: &EpochSnapshot) -> Self {
+        Self {
+            epoch: snapshot.epoch,
+            started_at: snapshot.started_at,
+            ended_at: snapshot.ended_at,
+            budget: snapshot.budget,
+            allocated: snapshot.allocated,
+            merkle_root: snapshot.merkle_root,
+            participant_count: snapshot.allocations.len() as u64,
+            claimed: snapshot.allocations.iter().filter(|a| a.claimed).map(|a| a.amount).sum(),
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct ClaimProof {
+    pub epoch: u64,
+    pub leaf_index: u64,
+    pub amount: u128,
+    /// Sibling hashes from the leaf up to the root.
+    pub proof: Vec<[u8; 32]>,
 }
 
 thread_local! {
-    static REWARD_METRICS: RefCell<HashMap<Principal, RewardMetrics>> = RefCell::new(HashMap::new());
-    static REWARD_CONFIG: RefCell<RewardConfig> = RefCell::new(RewardConfig {
-        base_rate: 0.01,
-        coherence_multiplier: 2.0,
-        participation_multiplier: 1.5,
-        nft_bonus_rate: 0.2,
-        staking_bonus_rate: 0.1,
-        network_bonus_rate: 0.3
-    });
-    static REWARD_POOL: RefCell<RewardPool> = RefCell::new(RewardPool {
-        total_rewards: 0,
-        distributed_rewards: 0,
-        last_distribution: 0,
-        distribution_interval: 24 * 60 * 60 * 1_000_000_000 // 24 hours
-    });
+    static DISTRIBUTOR: RefCell<DistributorState> = RefCell::new(DistributorState::default());
+    static EPOCHS: RefCell<BTreeMap<u64, EpochSnapshot>> = const { RefCell::new(BTreeMap::new()) };
 }
 
-#[update]
-async fn distribute_rewards(principal: Principal) -> Result<u128, String> {
-    let current_time = time();
-    let metrics = REWARD_METRICS.with(|metrics| {
-        metrics.borrow().get(&principal).cloned()
-    }).ok_or("No reward metrics found")?;
+const DISTRIBUTOR_REGION: StableRegion<DistributorState> = StableRegion::new(REWARDS_MEMORY_ID);
+const EPOCHS_REGION: StableRegion<EpochHeader> = StableRegion::new(REWARD_EPOCHS_MEMORY_ID);
+const ALLOCATIONS_REGION: StableRegion<Allocation> = StableRegion::new(REWARD_ALLOCATIONS_MEMORY_ID);
+const DISTRIBUTOR_KEY: &str = "reward_distributor";
+const EPOCH_KEY: &str = "epoch";
+
+pub fn save_stable() -> crate::Result<()> {
+    DISTRIBUTOR.with(|state| {
+        DISTRIBUTOR_REGION.save([(RegionKey::singleton(DISTRIBUTOR_KEY), &*state.borrow())])
+    })?;
+    EPOCHS.with(|epochs| {
+        let epochs = epochs.borrow();
+        let headers: Vec<(RegionKey, EpochHeader)> = epochs.iter()
+            .map(|(epoch, snapshot)| (RegionKey::new(EPOCH_KEY, *epoch), EpochHeader::of(snapshot)))
+            .collect();
+        EPOCHS_REGION.save(headers.iter().map(|(key, header)| (key.clone(), header)))?;
+        // One row per allocation, keyed by epoch and leaf index.
+        ALLOCATIONS_REGION.save(epochs.iter().flat_map(|(epoch, snapshot)| {
+            snapshot.allocations.iter()
+                .enumerate()
+                .map(move |(index, allocation)| (RegionKey::new(epoch.to_string(), index as u64), allocation))
+        }))
+    })
+}
+
+pub fn restore_stable() -> crate::Result<()> {
+    if let Some(restored) = DISTRIBUTOR_REGION.load_singleton(DISTRIBUTOR_KEY)? {
+        DISTRIBUTOR.with(|state| *state.borrow_mut() = restored);
+    }
+
+    let mut allocations: BTreeMap<u64, Vec<Allocation>> = BTreeMap::new();
+    for (key, allocation) in ALLOCATIONS_REGION.load()? {
+        let epoch = key.id.parse::<u64>()
+            .map_err(|_| crate::AnimaError::StorageError(format!("Invalid allocation key {:?}", key)))?;
+        // Rows load in key order, so leaf indexes arrive ascending.
+        allocations.entry(epoch).or_default().push(allocation);
+    }
+
+    EPOCHS.with(|epochs| {
+        *epochs.borrow_mut() = EPOCHS_REGION.load()?.into_iter()
+            .map(|(_, header)| {
+                let epoch_allocations = allocations.remove(&header.epoch).unwrap_or_default();
+                (header.epoch, header.with_allocations(epoch_allocations))
+            })
+            .collect();
+        Ok(())
+    })
+}
+
+// Merkle tree over allocations. Odd levels duplicate their last node, so
+// every level of a proof has exactly one sibling.
+pub fn leaf_hash(epoch: u64, principal: &Principal, amount: u128) -> [u8; 32] {
+    let principal = principal.as_slice();
+    let mut hasher = Sha256::new();
+    hasher.update(b"anima-reward-leaf");
+    hasher.update(epoch.to_be_bytes());
+    hasher.update([principal.len() as u8]);
+    hasher.update(principal);
+    hasher.update(amount.to_be_bytes());
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level.chunks(2)
+        .map(|pair| node_hash(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+pub fn merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    if leaves.is_empty() {
+        return [0; 32];
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+pub fn merkle_proof(leaves: &[[u8; 32]], mut index: usize) -> Vec<[u8; 32]> {
+    let mut proof = Vec::new();
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        let sibling = if index.is_multiple_of(2) { index + 1 } else { index - 1 };
+        proof.push(*level.get(sibling).unwrap_or(&level[index]));
+        level = next_level(&level);
+        index /= 2;
+    }
+    proof
+}
+
+pub fn verify_proof(root: &[u8; 32], leaf: [u8; 32], mut index: u64, proof: &[[u8; 32]]) -> bool {
+    let mut hash = leaf;
+    for sibling in proof {
+        hash = if index.is_multiple_of(2) { node_hash(&hash, sibling) } else { node_hash(sibling, &hash) };
+        index /= 2;
+    }
+    &hash == root
+}
+
+/// Splits `budget` pro rata by weight. Rounding dust stays unallocated.
+pub fn allocate(
+    budget: u128,
+    participants: BTreeMap<Principal, RewardMetrics>,
+    config: &RewardConfig,
+) -> Vec<Allocation> {
+    let weighted: Vec<(Principal, RewardMetrics, f64, u128)> = participants.into_iter()
+        .map(|(principal, metrics)| {
+            let weight = config.weight(&metrics);
+            let units = (weight * WEIGHT_SCALE) as u128;
+            (principal, metrics, weight, units)
+        })
+        .filter(|(_, _, _, units)| *units > 0)
+        .collect();
+    let total_units: u128 = weighted.iter().map(|(_, _, _, units)| units).sum();
+    if total_units == 0 {
+        return Vec::new();
+    }
+
+    weighted.into_iter()
+        .map(|(principal, metrics, weight, units)| Allocation {
+            principal,
+            metrics,
+            weight,
+            amount: budget * units / total_units,
+            claimed: false,
+        })
+        .collect()
+}
 
-    let config = REWARD_CONFIG.with(|config| config.borrow().clone());
-    let mut pool = REWARD_POOL.with(|pool| pool.borrow_mut());
+/// Principal who paid for a registry transaction: the recipient of a mint,
+/// or the buyer of a sale the marketplace settled. Other transactions are
+/// free and credit nobody.
+fn paying_party(tx: &NftTransaction, marketplace: &Account) -> Option<Principal> {
+    let paid = match tx.operation {
+        NftOperation::Mint => true,
+        NftOperation::Transfer => tx.spender.as_ref() == Some(marketplace),
+        _ => false,
+    };
+    tx.to.as_ref().filter(|_| paid).map(|account| account.owner)
+}
+
+/// Counts a paid operation that does not go through the registry, such as
+/// opening a stake, towards the epoch in progress.
+pub fn record_interaction(principal: Principal) {
+    DISTRIBUTOR.with(|state| state.borrow_mut().activity.record(principal));
+}
+
+/// Summed `coherence_level` of the ANIMA each holder owns.
+fn nft_power(registry: &TokenRegistry) -> BTreeMap<Principal, f64> {
+    let mut power: BTreeMap<Principal, f64> = BTreeMap::new();
+    for record in registry.records() {
+        *power.entry(record.owner.owner).or_default() += record.quantum_state.coherence_level;
+    }
+    power
+}
+
+/// Age of a position for epoch weighting, capped at a year.
+fn staking_age(position: &StakePosition, now: u64) -> u64 {
+    now.saturating_sub(position.start_time).min((MAX_STAKING_MONTHS * MONTH as f64) as u64)
+}
+
+/// Tallies registry transactions appended since the last sample and
+/// re-reads holders' `nft_power`. Runs once per epoch check, the shortest an
+/// epoch can last.
+pub fn sample_activity() {
+    with_registry(|registry| {
+        let power = nft_power(registry);
+        DISTRIBUTOR.with(|state| {
+            let activity = &mut state.borrow_mut().activity;
+            activity.ingest(registry.transactions(), &marketplace_spender());
+            activity.nft_power = power;
+        });
+    });
+}
 
-    if current_time - pool.last_distribution < pool.distribution_interval {
-        return Err("Distribution interval not met".into());
+/// Every participant's metrics for the epoch in progress, from the sampled
+/// activity and the staking pool.
+fn collect_metrics(activity: &EpochActivity, now: u64) -> BTreeMap<Principal, RewardMetrics> {
+    let mut metrics: BTreeMap<Principal, RewardMetrics> = BTreeMap::new();
+    for (principal, interactions) in &activity.interactions {
+        metrics.entry(*principal).or_default().interactions = *interactions;
+    }
+    for (principal, power) in &activity.nft_power {
+        metrics.entry(*principal).or_default().nft_power = *power;
     }
+    with_staking(|staking| {
+        for position in staking.positions() {
+            metrics.entry(position.owner).or_default().staking_duration += staking_age(position, now);
+        }
+    });
+
+    metrics.retain(|principal, _| *principal != Principal::anonymous());
+    metrics
+}
+
+/// Closes the current epoch if its interval has elapsed, publishing a
+/// snapshot of allocations over the activity sampled so far. The budget is
+/// capped by what is escrowed. Returns the closed epoch number.
+pub fn close_epoch(now: u64) -> Option<u64> {
+    let (epoch, started_at, budget, config, activity) = DISTRIBUTOR.with(|state| {
+        let mut state = state.borrow_mut();
+        let pool = &state.pool;
+        if now.saturating_sub(pool.last_distribution) < pool.distribution_interval {
+            return None;
+        }
+        let budget = pool.epoch_emission
+            .min(pool.escrowed)
+            .min(MAX_TOTAL_REWARDS - pool.total_rewards);
+        let (epoch, started_at, config) = (pool.next_epoch, pool.last_distribution, state.config.clone());
+        // Interactions count towards one epoch only; the cursor carries on.
+        let activity = EpochActivity {
+            tx_cursor: state.activity.tx_cursor,
+            interactions: std::mem::take(&mut state.activity.interactions),
+            nft_power: state.activity.nft_power.clone(),
+        };
+        Some((epoch, started_at, budget, config, activity))
+    })?;
 
-    // Calculate reward components
-    let coherence_bonus = metrics.quantum_coherence * config.coherence_multiplier;
-    let participation_bonus = metrics.participation_score * config.participation_multiplier;
-    let nft_bonus = metrics.nft_power * config.nft_bonus_rate;
-    let staking_bonus = (metrics.staking_duration as f64 / (30 * 24 * 60 * 60 * 1_000_000_000) as f64) * config.staking_bonus_rate;
-    let network_bonus = metrics.network_contribution * config.network_bonus_rate;
+    let allocations = allocate(budget, collect_metrics(&activity, now), &config);
+    let allocated = allocations.iter().map(|a| a.amount).sum();
+    let leaves: Vec<[u8; 32]> = allocations.iter()
+        .map(|a| leaf_hash(epoch, &a.principal, a.amount))
+        .collect();
 
-    // Calculate total reward
-    let total_multiplier = 1.0 + coherence_bonus + participation_bonus + nft_bonus + staking_bonus + network_bonus;
-    let base_reward = (pool.total_rewards as f64 * config.base_rate) as u128;
-    let reward = (base_reward as f64 * total_multiplier) as u128;
+    let snapshot = EpochSnapshot {
+        epoch,
+        started_at,
+        ended_at: now,
+        budget,
+        allocated,
+        merkle_root: merkle_root(&leaves),
+        allocations,
+    };
+    EPOCHS.with(|epochs| epochs.borrow_mut().insert(epoch, snapshot));
+    DISTRIBUTOR.with(|state| {
+        let pool = &mut state.borrow_mut().pool;
+        pool.total_rewards += allocated;
+        pool.escrowed -= allocated;
+        pool.last_distribution = now;
+        pool.next_epoch += 1;
+    });
+    Some(epoch)
+}
 
-    // Update pool
-    pool.distributed_rewards += reward;
-    pool.last_distribution = current_time;
+/// Starts the periodic epoch check. Timers do not survive upgrades, so this
+/// runs from both `init` and `post_upgrade`.
+pub fn start_epoch_timer() {
+    ic_cdk_timers::set_timer_interval(EPOCH_CHECK_INTERVAL, || {
+        sample_activity();
+        close_epoch(time());
+    });
+}
 
-    // Transfer rewards
-    match transfer_rewards(principal, reward).await {
-        Ok(_) => Ok(reward),
-        Err(e) => Err(format!("Reward transfer failed: {}", e))
+/// Escrows `amount` ANIMA from the caller, via an ICRC-2 allowance, into
+/// `EPOCH_REWARDS_SUBACCOUNT`. Returns the escrow not yet committed to an
+/// epoch.
+#[update]
+async fn fund_epoch_rewards(amount: u128) -> Result<u128, String> {
+    require_role(Role::Treasury, &format!("fund_epoch_rewards {}", amount)).map_err(|e| format!("{:?}", e))?;
+    if amount == 0 {
+        return Err("Funding amount must be greater than 0".to_string());
     }
+    let ledger = token_canister(&AcceptedToken::ANIMA).map_err(|e| format!("{:?}", e))?;
+    let to = Account { owner: ic_cdk::id(), subaccount: Some(EPOCH_REWARDS_SUBACCOUNT) };
+    client::icrc2_transfer_from(ledger, Account::from(ic_cdk::caller()), to, Nat::from(amount), None)
+        .await
+        .map_err(|e| format!("Funding transfer failed: {:?}", e))?;
+
+    Ok(DISTRIBUTOR.with(|state| {
+        let pool = &mut state.borrow_mut().pool;
+        pool.escrowed += amount;
+        pool.escrowed
+    }))
+}
+
+/// Budget of each future epoch. Epochs only ever allocate escrowed funds, so
+/// an emission above the escrow is cut down when the epoch closes.
+#[update]
+fn set_epoch_emission(amount: u128) -> Result<(), String> {
+    require_role(Role::Treasury, "set_epoch_emission").map_err(|e| format!("{:?}", e))?;
+    DISTRIBUTOR.with(|state| state.borrow_mut().pool.epoch_emission = amount);
+    Ok(())
 }
 
 #[update]
-async fn update_metrics(principal: Principal, metrics: RewardMetrics) -> Result<(), String> {
-    REWARD_METRICS.with(|reward_metrics| {
-        reward_metrics.borrow_mut().insert(principal, metrics);
+fn set_reward_config(config: RewardConfig, distribution_interval: u64) -> Result<(), String> {
+    require_role(Role::Treasury, "set_reward_config").map_err(|e| format!("{:?}", e))?;
+    config.validate()?;
+    if distribution_interval < 60 * 60 * 1_000_000_000 {
+        return Err("Epochs must last at least an hour".to_string());
+    }
+    DISTRIBUTOR.with(|state| {
+        let mut state = state.borrow_mut();
+        state.config = config;
+        state.pool.distribution_interval = distribution_interval;
     });
     Ok(())
 }
 
+/// Pays the caller's allocation for `epoch` and returns what arrived after
+/// the ledger fee. The proof must match the published root, so claims can be
+/// checked against the snapshot off-chain.
+#[update]
+async fn claim_epoch_rewards(claim: ClaimProof) -> Result<u128, String> {
+    let caller = ic_cdk::caller();
+    let fee = ledger_fee(AcceptedToken::ANIMA).await as u128;
+    let leaf = leaf_hash(claim.epoch, &caller, claim.amount);
+
+    EPOCHS.with(|epochs| {
+        let mut epochs = epochs.borrow_mut();
+        let snapshot = epochs.get_mut(&claim.epoch).ok_or("Unknown epoch")?;
+        if !verify_proof(&snapshot.merkle_root, leaf, claim.leaf_index, &claim.proof) {
+            return Err("Invalid claim proof".to_string());
+        }
+        let allocation = snapshot.allocations.get_mut(claim.leaf_index as usize)
+            .filter(|a| a.principal == caller && a.amount == claim.amount)
+            .ok_or("Invalid claim proof")?;
+        if allocation.claimed {
+            return Err("Rewards for this epoch were already claimed".to_string());
+        }
+        allocation.claimed = true;
+        Ok(())
+    })?;
+
+    match transfer_rewards(caller, claim.amount, fee).await {
+        Ok(received) => {
+            DISTRIBUTOR.with(|state| state.borrow_mut().pool.distributed_rewards += claim.amount);
+            Ok(received)
+        }
+        Err(e) => {
+            EPOCHS.with(|epochs| {
+                if let Some(allocation) = epochs.borrow_mut()
+                    .get_mut(&claim.epoch)
+                    .and_then(|snapshot| snapshot.allocations.get_mut(claim.leaf_index as usize))
+                {
+                    allocation.claimed = false;
+                }
+            });
+            Err(format!("Reward transfer failed: {}", e))
+        }
+    }
+}
+
 #[query]
-fn get_metrics(principal: Principal) -> Option<RewardMetrics> {
-    REWARD_METRICS.with(|metrics| {
-        metrics.borrow().get(&principal).cloned()
+fn get_claim_proof(epoch: u64, principal: Principal) -> Option<ClaimProof> {
+    EPOCHS.with(|epochs| {
+        let epochs = epochs.borrow();
+        let snapshot = epochs.get(&epoch)?;
+        let index = snapshot.allocations.iter().position(|a| a.principal == principal)?;
+        let leaves: Vec<[u8; 32]> = snapshot.allocations.iter()
+            .map(|a| leaf_hash(epoch, &a.principal, a.amount))
+            .collect();
+        Some(ClaimProof {
+            epoch,
+            leaf_index: index as u64,
+            amount: snapshot.allocations[index].amount,
+            proof: merkle_proof(&leaves, index),
+        })
     })
 }
 
+/// What the caller's metrics look like for the epoch in progress. Only
+/// transactions since the last sample are read.
 #[query]
-fn calculate_potential_rewards(metrics: RewardMetrics) -> u128 {
-    let config = REWARD_CONFIG.with(|config| config.borrow().clone());
-    let pool = REWARD_POOL.with(|pool| pool.borrow().clone());
-
-    let coherence_bonus = metrics.quantum_coherence * config.coherence_multiplier;
-    let participation_bonus = metrics.participation_score * config.participation_multiplier;
-    let nft_bonus = metrics.nft_power * config.nft_bonus_rate;
-    let staking_bonus = (metrics.staking_duration as f64 / (30 * 24 * 60 * 60 * 1_000_000_000) as f64) * config.staking_bonus_rate;
-    let network_bonus = metrics.network_contribution * config.network_bonus_rate;
-
-    let total_multiplier = 1.0 + coherence_bonus + participation_bonus + nft_bonus + staking_bonus + network_bonus;
-    let base_reward = (pool.total_rewards as f64 * config.base_rate) as u128;
-    (base_reward as f64 * total_multiplier) as u128
-}
-
-async fn transfer_rewards(to: Principal, amount: u128) -> Result<(), String> {
-    let token_canister = Principal::from_text("anima_token_canister_id").unwrap();
-    let args = TransferArgs {
-        from: Account { owner: ic_cdk::id(), subaccount: None },
-        to: Account { owner: to, subaccount: None },
-        amount,
-        fee: None,
-        memo: None,
-        created_at_time: None,
-    };
+fn get_metrics(principal: Principal) -> RewardMetrics {
+    let now = time();
+    let (tallied, nft_power, tx_cursor) = DISTRIBUTOR.with(|state| {
+        let activity = &state.borrow().activity;
+        (
+            activity.interactions.get(&principal).copied().unwrap_or(0),
+            activity.nft_power.get(&principal).copied().unwrap_or(0.0),
+            activity.tx_cursor as usize,
+        )
+    });
+    let marketplace = marketplace_spender();
+    let recent = with_registry(|registry| {
+        registry.transactions()
+            .get(tx_cursor..)
+            .unwrap_or_default()
+            .iter()
+            .filter(|tx| paying_party(tx, &marketplace) == Some(principal))
+            .count() as u64
+    });
+    let staking_duration = with_staking(|staking| {
+        staking.positions_of(&principal).into_iter().map(|position| staking_age(position, now)).sum()
+    });
+    RewardMetrics { interactions: tallied + recent, nft_power, staking_duration }
+}
 
-    match ic_cdk::call(token_canister, "icrc1_transfer", (args,)).await {
-        Ok(Ok(_)) => Ok(()),
-        Ok(Err(e)) => Err(format!("Transfer error: {:?}", e)),
-        Err((code, msg)) => Err(format!("RPC error: {} - {}", code, msg))
-    }
+#[query]
+fn get_reward_pool() -> RewardPool {
+    DISTRIBUTOR.with(|state| state.borrow().pool.clone())
+}
+
+#[query]
+fn get_reward_config() -> RewardConfig {
+    DISTRIBUTOR.with(|state| state.borrow().config.clone())
 }
 
-#[derive(CandidType, Clone, Debug)]
-struct Account {
-    owner: Principal,
-    subaccount: Option<Vec<u8>>,
+#[query]
+fn get_epoch(epoch: u64) -> Option<EpochSummary> {
+    EPOCHS.with(|epochs| epochs.borrow().get(&epoch).map(EpochSummary::from))
+}
+
+/// Audit view of an epoch's allocations, in principal order. Pass the last
+/// principal seen as `start_after` to page.
+#[query]
+fn get_epoch_allocations(epoch: u64, start_after: Option<Principal>, limit: Option<u64>) -> Vec<Allocation> {
+    let limit = limit.map(|l| l as usize).unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    EPOCHS.with(|epochs| {
+        epochs.borrow()
+            .get(&epoch)
+            .map(|snapshot| {
+                snapshot.allocations.iter()
+                    .filter(|a| start_after.map(|after| a.principal > after).unwrap_or(true))
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
 }
 
-#[derive(CandidType)]
-struct TransferArgs {
-    from: Account,
-    to: Account,
-    amount: u128,
-    fee: Option<u128>,
-    memo: Option<Vec<u8>>,
-    created_at_time: Option<u64>,
+/// Pays a claim out of the epoch escrow, net of the ledger `fee`, refusing
+/// to pay more than it holds. The escrow gives up exactly `amount`, which
+/// is what `close_epoch` took out of `escrowed` for it. Returns what `to`
+/// received.
+async fn transfer_rewards(to: Principal, amount: u128, fee: u128) -> Result<u128, String> {
+    if amount <= fee {
+        return Err(format!("{} ANIMA does not cover the ledger fee of {}", amount, fee));
+    }
+    let ledger = token_canister(&AcceptedToken::ANIMA).map_err(|e| format!("{:?}", e))?;
+    let escrow = Account { owner: ic_cdk::id(), subaccount: Some(EPOCH_REWARDS_SUBACCOUNT) };
+    let balance = client::icrc1_balance_of(ledger, escrow)
+        .await
+        .map_err(|e| format!("{:?}", e))?
+        .0
+        .to_u128()
+        .unwrap_or(u128::MAX);
+    if balance < amount {
+        return Err(format!("Reward escrow holds {} ANIMA, cannot pay {}", balance, amount));
+    }
+    client::icrc1_transfer(ledger, Some(EPOCH_REWARDS_SUBACCOUNT), Account::from(to), Nat::from(amount - fee), None)
+        .await
+        .map(|_| amount - fee)
+        .map_err(|e| format!("{:?}", e))
 }
 
-ic_cdk::export_candid!();
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn test_allocation_is_pro_rata_within_budget() {
+        let config = RewardConfig::default();
+        let mut participants = BTreeMap::new();
+        participants.insert(principal(1), RewardMetrics { interactions: 10, nft_power: 0.0, staking_duration: 0 });
+        participants.insert(principal(2), RewardMetrics { interactions: 30, nft_power: 0.0, staking_duration: 0 });
+        participants.insert(principal(3), RewardMetrics::default());
+
+        let allocations = allocate(1_000_000, participants, &config);
+        assert_eq!(allocations.len(), 2);
+        assert_eq!(allocations[0].amount, 250_000);
+        assert_eq!(allocations[1].amount, 750_000);
+        assert!(allocations.iter().map(|a| a.amount).sum::<u128>() <= 1_000_000);
+    }
+
+    #[test]
+    fn test_interactions_are_capped() {
+        let config = RewardConfig { max_counted_interactions: 5, ..RewardConfig::default() };
+        let capped = RewardMetrics { interactions: 500, nft_power: 0.0, staking_duration: 0 };
+        let at_cap = RewardMetrics { interactions: 5, nft_power: 0.0, staking_duration: 0 };
+        assert_eq!(config.weight(&capped), config.weight(&at_cap));
+    }
+
+    #[test]
+    fn test_merkle_proofs_verify_every_leaf() {
+        for count in 1..=7u8 {
+            let leaves: Vec<[u8; 32]> = (0..count).map(|i| leaf_hash(3, &principal(i), i as u128 * 10)).collect();
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, index);
+                assert!(verify_proof(&root, *leaf, index as u64, &proof));
+            }
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_rejects_wrong_amount() {
+        let leaves: Vec<[u8; 32]> = (0..4u8).map(|i| leaf_hash(1, &principal(i), 100)).collect();
+        let root = merkle_root(&leaves);
+        let proof = merkle_proof(&leaves, 2);
+        assert!(!verify_proof(&root, leaf_hash(1, &principal(2), 101), 2, &proof));
+        assert!(!verify_proof(&root, leaf_hash(2, &principal(2), 100), 2, &proof));
+    }
+
+    fn transaction(operation: NftOperation, from: u8, to: u8, spender: Option<u8>) -> NftTransaction {
+        NftTransaction {
+            operation,
+            token_id: Some(1),
+            from: Some(Account::from(principal(from))),
+            to: Some(Account::from(principal(to))),
+            spender: spender.map(|id| Account::from(principal(id))),
+            memo: None,
+            created_at_time: None,
+            timestamp: 0,
+        }
+    }
+
+    const MARKETPLACE: u8 = 200;
+
+    #[test]
+    fn test_activity_only_tallies_new_transactions() {
+        let marketplace = Account::from(principal(MARKETPLACE));
+        let mut transactions = vec![
+            transaction(NftOperation::Mint, 9, 1, None),
+            transaction(NftOperation::Transfer, 1, 2, Some(MARKETPLACE)),
+        ];
+        let mut activity = EpochActivity::default();
+        activity.ingest(&transactions, &marketplace);
+        assert_eq!(activity.interactions.get(&principal(1)), Some(&1));
+        assert_eq!(activity.interactions.get(&principal(2)), Some(&1));
+        assert_eq!(activity.interactions.get(&principal(9)), None);
+
+        activity.ingest(&transactions, &marketplace);
+        assert_eq!(activity.interactions.get(&principal(1)), Some(&1));
+
+        transactions.push(transaction(NftOperation::Mint, 9, 2, None));
+        activity.ingest(&transactions, &marketplace);
+        assert_eq!(activity.interactions.get(&principal(2)), Some(&2));
+        assert_eq!(activity.tx_cursor, 3);
+    }
+
+    #[test]
+    fn test_free_transfers_earn_no_interactions() {
+        let marketplace = Account::from(principal(MARKETPLACE));
+        // A ring of accounts passing one token around, directly and through
+        // an ICRC-37 spender of their own.
+        let transactions: Vec<NftTransaction> = (1..=20u8)
+            .map(|i| {
+                let spender = (i % 2 == 0).then_some(i % 3 + 1);
+                transaction(NftOperation::Transfer, i % 3 + 1, (i + 1) % 3 + 1, spender)
+            })
+            .chain([transaction(NftOperation::Approve, 1, 2, Some(2))])
+            .collect();
+        let mut activity = EpochActivity::default();
+        activity.ingest(&transactions, &marketplace);
+        assert!(activity.interactions.is_empty());
+    }
+
+    #[test]
+    fn test_epoch_budget_is_capped_by_escrow() {
+        DISTRIBUTOR.with(|state| {
+            let mut state = state.borrow_mut();
+            *state = DistributorState::default();
+            state.pool.epoch_emission = 1_000_000;
+            state.pool.escrowed = 4_000;
+            state.activity.interactions.insert(principal(1), 1);
+            state.activity.interactions.insert(principal(2), 3);
+        });
+
+        let epoch = close_epoch(DAY).unwrap();
+        let snapshot = EPOCHS.with(|epochs| epochs.borrow().get(&epoch).cloned()).unwrap();
+        assert_eq!(snapshot.budget, 4_000);
+        assert_eq!(snapshot.allocated, 4_000);
+        DISTRIBUTOR.with(|state| {
+            let state = state.borrow();
+            assert_eq!(state.pool.escrowed, 0);
+            assert!(state.activity.interactions.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_allocations_persist_as_rows() {
+        let allocations: Vec<Allocation> = (1..=3u8)
+            .map(|i| Allocation {
+                principal: principal(i),
+                metrics: RewardMetrics::default(),
+                weight: i as f64,
+                amount: i as u128 * 100,
+                claimed: i == 2,
+            })
+            .collect();
+        let header = EpochHeader { epoch: 12, started_at: 0, ended_at: DAY, budget: 600, allocated: 600, merkle_root: [7; 32] };
+        EPOCHS.with(|epochs| {
+            let mut epochs = epochs.borrow_mut();
+            epochs.clear();
+            epochs.insert(12, header.with_allocations(allocations));
+        });
+        DISTRIBUTOR.with(|state| state.borrow_mut().activity.tx_cursor = 5);
+
+        save_stable().unwrap();
+        assert_eq!(ALLOCATIONS_REGION.load().unwrap().len(), 3);
+        EPOCHS.with(|epochs| epochs.borrow_mut().clear());
+        DISTRIBUTOR.with(|state| *state.borrow_mut() = DistributorState::default());
+
+        restore_stable().unwrap();
+        let restored = EPOCHS.with(|epochs| epochs.borrow().get(&12).cloned()).unwrap();
+        assert_eq!(restored.merkle_root, [7; 32]);
+        let amounts: Vec<u128> = restored.allocations.iter().map(|a| a.amount).collect();
+        assert_eq!(amounts, vec![100, 200, 300]);
+        assert!(restored.allocations[1].claimed);
+        assert_eq!(DISTRIBUTOR.with(|state| state.borrow().activity.tx_cursor), 5);
+    }
+}
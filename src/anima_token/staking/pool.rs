@@ -1,21 +1,137 @@
-use candid::{CandidType, Decode, Encode, Principal};
-use ic_cdk::api::call::CallResult;
-use ic_cdk::api::{time, trap};
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::time;
 use ic_cdk_macros::*;
+use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use crate::admin::{record_event, require_role, Role};
+use crate::anima_token::rewards::distributor::record_interaction;
+use crate::icrc::{client, AcceptedToken, Account, Subaccount};
+use crate::nft::marketplace::ledger_fee;
+use crate::nft::registry::{with_registry, TokenId};
+use crate::payments::transaction_processor::token_canister;
+use crate::stable::{RegionKey, StableRegion, STAKES_MEMORY_ID, POOL_METRICS_MEMORY_ID};
+use crate::types::security::SecurityEventType;
 
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const YEAR_NANOS: f64 = 365.0 * 24.0 * 60.0 * 60.0 * 1_000_000_000.0;
+
+const BASE_APR: f64 = 0.15; // 15% base APR
+const COHERENCE_MULTIPLIER: f64 = 2.0; // Up to 2x rewards for perfect coherence
+const BASIS_POINTS: u128 = 10_000;
+const MIN_STAKING_COHERENCE: f64 = 0.5;
+/// How often boosters are re-read from the registry. A booster that changed
+/// hands stops earning its boost at the next sample.
+const COHERENCE_RESAMPLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Holds staked principal, including rewards once they are compounded.
+pub const STAKE_SUBACCOUNT: Subaccount = *b"anima-staking-principal\0\0\0\0\0\0\0\0\0";
+/// Holds the ANIMA that rewards are paid from. Treasury funds it with
+/// `fund_staking_rewards`; early-unstake penalties are swept into it.
+pub const STAKING_REWARDS_SUBACCOUNT: Subaccount = *b"anima-staking-rewards\0\0\0\0\0\0\0\0\0\0\0";
+
+/// Lock length chosen when opening a position. Longer locks earn a higher
+/// reward multiplier and forfeit more when unstaked early.
+#[derive(CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LockTier {
+    Days7,
+    Days30,
+    Days90,
+    Days365,
+}
+
+impl LockTier {
+    pub fn duration(&self) -> u64 {
+        match self {
+            LockTier::Days7 => 7 * DAY,
+            LockTier::Days30 => 30 * DAY,
+            LockTier::Days90 => 90 * DAY,
+            LockTier::Days365 => 365 * DAY,
+        }
+    }
+
+    pub fn reward_multiplier(&self) -> f64 {
+        match self {
+            LockTier::Days7 => 1.0,
+            LockTier::Days30 => 1.25,
+            LockTier::Days90 => 1.5,
+            LockTier::Days365 => 2.0,
+        }
+    }
+
+    /// Share of the withdrawn amount forfeited to the reward pool when
+    /// unstaking before the position unlocks.
+    pub fn early_unstake_penalty_bps(&self) -> u128 {
+        match self {
+            LockTier::Days7 => 500,
+            LockTier::Days30 => 1_000,
+            LockTier::Days90 => 1_500,
+            LockTier::Days365 => 2_500,
+        }
+    }
+}
+
+/// One stake. A principal may hold any number of these, each with its own
+/// lock and reward accounting.
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
-pub struct StakeInfo {
+pub struct StakePosition {
+    pub id: u64,
+    pub owner: Principal,
     pub amount: u128,
-    pub start_time: u64,
+    /// ANIMA NFT whose coherence boosts this position, while `owner` holds it.
+    #[serde(default)]
+    pub booster_token: Option<TokenId>,
+    /// Last sampled `coherence_level` of the booster; zero without one.
     pub quantum_coherence: f64,
-    pub lock_period: u64,
+    pub tier: LockTier,
+    pub auto_compound: bool,
+    pub start_time: u64,
+    pub unlock_time: u64,
     pub accumulated_rewards: u128,
     pub last_reward_calculation: u64,
 }
 
+impl StakePosition {
+    pub fn is_locked(&self, now: u64) -> bool {
+        now < self.unlock_time
+    }
+
+    /// Folds rewards earned since the last settlement into the position:
+    /// into the principal when auto-compounding, otherwise into
+    /// `accumulated_rewards`. Earnings are capped by what `pool` still has
+    /// available, so positions are never owed more than was funded.
+    fn settle(&mut self, now: u64, pool: &mut StakingRewardPool) {
+        let earned = calculate_rewards(self, now).min(pool.available);
+        pool.available -= earned;
+        if self.auto_compound {
+            self.amount += earned;
+            pool.pending_compound += earned;
+        } else {
+            self.accumulated_rewards += earned;
+        }
+        self.last_reward_calculation = self.last_reward_calculation.max(now);
+    }
+}
+
+/// Bookkeeping for the ANIMA in `STAKING_REWARDS_SUBACCOUNT`.
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StakingRewardPool {
+    /// Funded rewards not yet earned by any position.
+    pub available: u128,
+    /// Rewards compounded into principal that still sit in the rewards
+    /// subaccount, waiting to be moved to the stake subaccount.
+    pub pending_compound: u128,
+    /// Forfeited penalties that still sit in the stake subaccount, waiting to
+    /// be moved to the rewards subaccount.
+    pub pending_penalties: u128,
+    pub total_distributed: u128,
+    /// Rewards of positions that closed while their payout was failing.
+    /// They have left the pool but were never received by the staker.
+    pub unpaid: u128,
+}
+
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
 pub struct PoolMetrics {
     pub total_staked: u128,
@@ -23,39 +139,374 @@ pub struct PoolMetrics {
     pub number_of_stakers: u64,
     pub average_coherence: f64,
     pub network_stability: f64,
+    /// Funded rewards not yet earned by any position.
+    pub reward_pool: u128,
+}
+
+/// Principal leaving a position, and what the staker receives for it
+/// before ledger fees.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Withdrawal {
+    pub amount: u128,
+    pub penalty: u128,
+    pub rewards: u128,
+}
+
+/// A transfer between the stake and rewards subaccounts, taken from the
+/// pool's pending moves.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferDue {
+    pub from: Subaccount,
+    pub to: Subaccount,
+    pub amount: u128,
+    compounded: u128,
+    forfeited: u128,
+    fee: u128,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StakingState {
+    positions: BTreeMap<u64, StakePosition>,
+    by_owner: BTreeMap<Principal, BTreeSet<u64>>,
+    /// Each booster NFT backs at most one position.
+    by_booster: BTreeMap<TokenId, u64>,
+    next_position_id: u64,
+    rewards: StakingRewardPool,
+}
+
+impl StakingState {
+    fn insert(&mut self, position: StakePosition) {
+        self.next_position_id = self.next_position_id.max(position.id + 1);
+        self.by_owner.entry(position.owner).or_default().insert(position.id);
+        if let Some(token_id) = position.booster_token {
+            self.by_booster.insert(token_id, position.id);
+        }
+        self.positions.insert(position.id, position);
+    }
+
+    fn remove(&mut self, id: u64) -> Option<StakePosition> {
+        let position = self.positions.remove(&id)?;
+        if let Some(token_id) = position.booster_token {
+            self.by_booster.remove(&token_id);
+        }
+        if let Some(ids) = self.by_owner.get_mut(&position.owner) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.by_owner.remove(&position.owner);
+            }
+        }
+        Some(position)
+    }
+
+    pub fn is_booster_bound(&self, token_id: TokenId) -> bool {
+        self.by_booster.contains_key(&token_id)
+    }
+
+    /// Opens a position boosted by `booster_token`, whose current coherence
+    /// the caller has read from the registry.
+    #[allow(clippy::too_many_arguments)]
+    pub fn open(
+        &mut self,
+        owner: Principal,
+        amount: u128,
+        tier: LockTier,
+        booster_token: TokenId,
+        quantum_coherence: f64,
+        auto_compound: bool,
+        now: u64,
+    ) -> Result<u64, String> {
+        if self.is_booster_bound(booster_token) {
+            return Err("This ANIMA already boosts another stake".to_string());
+        }
+        let id = self.next_position_id;
+        self.insert(StakePosition {
+            id,
+            owner,
+            amount,
+            booster_token: Some(booster_token),
+            quantum_coherence,
+            tier,
+            auto_compound,
+            start_time: now,
+            unlock_time: now + tier.duration(),
+            accumulated_rewards: 0,
+            last_reward_calculation: now,
+        });
+        Ok(id)
+    }
+
+    pub fn reward_pool(&self) -> &StakingRewardPool {
+        &self.rewards
+    }
+
+    /// Credits ANIMA that arrived in the rewards subaccount.
+    pub fn fund_rewards(&mut self, amount: u128) {
+        self.rewards.available += amount;
+    }
+
+    /// Forfeits an early-unstake penalty to the reward pool. The tokens stay
+    /// in the stake subaccount until the next `take_transfers_due`.
+    pub fn forfeit(&mut self, penalty: u128) {
+        self.rewards.available += penalty;
+        self.rewards.pending_penalties += penalty;
+    }
+
+    pub fn record_distributed(&mut self, amount: u128) {
+        self.rewards.total_distributed += amount;
+    }
+
+    /// Rewards that left the pool without reaching their staker.
+    pub fn record_unpaid(&mut self, amount: u128) {
+        self.rewards.unpaid += amount;
+    }
+
+    /// Takes the pending moves between the stake and rewards subaccounts,
+    /// netted into a single transfer. The ledger `fee` for it is charged to
+    /// the reward pool. Returns `None` when nothing needs to move, or when
+    /// forfeited penalties do not yet cover the fee. Put the move back with
+    /// `restore_transfers_due` if the transfer fails.
+    pub fn take_transfers_due(&mut self, fee: u128) -> Result<Option<TransferDue>, String> {
+        let (compounded, forfeited) = (self.rewards.pending_compound, self.rewards.pending_penalties);
+        let (from, to, amount) = if compounded >= forfeited {
+            (STAKING_REWARDS_SUBACCOUNT, STAKE_SUBACCOUNT, compounded - forfeited)
+        } else if forfeited - compounded > fee {
+            // The fee comes out of the penalties, so the stake subaccount
+            // gives up exactly what was forfeited.
+            (STAKE_SUBACCOUNT, STAKING_REWARDS_SUBACCOUNT, forfeited - compounded - fee)
+        } else {
+            return Ok(None);
+        };
+        if amount == 0 {
+            self.rewards.pending_compound = 0;
+            self.rewards.pending_penalties = 0;
+            return Ok(None);
+        }
+        if self.rewards.available < fee {
+            return Err(format!("Reward pool cannot cover the ledger fee of {}", fee));
+        }
+        self.rewards.available -= fee;
+        self.rewards.pending_compound = 0;
+        self.rewards.pending_penalties = 0;
+        Ok(Some(TransferDue { from, to, amount, compounded, forfeited, fee }))
+    }
+
+    pub fn restore_transfers_due(&mut self, due: TransferDue) {
+        self.rewards.available += due.fee;
+        self.rewards.pending_compound += due.compounded;
+        self.rewards.pending_penalties += due.forfeited;
+    }
+
+    /// Re-reads every booster. Rewards up to `now` are settled at the old
+    /// coherence first. A position whose booster is gone or no longer held
+    /// by its owner loses the binding and earns no coherence bonus.
+    /// `lookup` returns a token's current holder and coherence level.
+    pub fn resample_coherence(&mut self, now: u64, lookup: impl Fn(TokenId) -> Option<(Principal, f64)>) {
+        for position in self.positions.values_mut() {
+            position.settle(now, &mut self.rewards);
+            let sampled = position.booster_token
+                .and_then(&lookup)
+                .filter(|(holder, _)| *holder == position.owner);
+            match sampled {
+                Some((_, coherence)) => position.quantum_coherence = coherence.clamp(0.0, 1.0),
+                None => {
+                    if let Some(token_id) = position.booster_token.take() {
+                        self.by_booster.remove(&token_id);
+                    }
+                    position.quantum_coherence = 0.0;
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&StakePosition> {
+        self.positions.get(&id)
+    }
+
+    pub fn positions_of(&self, owner: &Principal) -> Vec<&StakePosition> {
+        self.by_owner.get(owner)
+            .map(|ids| ids.iter().filter_map(|id| self.positions.get(id)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = &StakePosition> {
+        self.positions.values()
+    }
+
+    pub fn staker_count(&self) -> usize {
+        self.by_owner.len()
+    }
+
+    /// Positions with rewards settled up to `now`, without touching the
+    /// pool.
+    pub fn settled_positions_of(&self, owner: &Principal, now: u64) -> Vec<StakePosition> {
+        let mut pool = self.rewards.clone();
+        self.positions_of(owner)
+            .into_iter()
+            .map(|position| {
+                let mut position = position.clone();
+                position.settle(now, &mut pool);
+                position
+            })
+            .collect()
+    }
+
+    /// `owner`'s position `id`, with rewards settled up to `now`.
+    fn settled_mut(&mut self, owner: Principal, id: u64, now: u64) -> Result<&mut StakePosition, String> {
+        match self.positions.get_mut(&id) {
+            Some(position) if position.owner == owner => {
+                position.settle(now, &mut self.rewards);
+                Ok(position)
+            }
+            _ => Err("No such stake position".to_string()),
+        }
+    }
+
+    /// Takes `amount` (or everything) out of a position. Locked positions
+    /// forfeit the tier penalty on the withdrawn amount; pass it to `forfeit`
+    /// once the rest is paid. Closing a position also releases its unclaimed
+    /// rewards.
+    pub fn withdraw(&mut self, owner: Principal, id: u64, amount: Option<u128>, now: u64) -> Result<Withdrawal, String> {
+        let position = self.settled_mut(owner, id, now)?;
+
+        let amount = amount.unwrap_or(position.amount);
+        if amount == 0 || amount > position.amount {
+            return Err("Unstake amount must be between 1 and the staked amount".to_string());
+        }
+        let penalty = if position.is_locked(now) {
+            amount * position.tier.early_unstake_penalty_bps() / BASIS_POINTS
+        } else {
+            0
+        };
+
+        position.amount -= amount;
+        let rewards = if position.amount == 0 {
+            let rewards = std::mem::take(&mut position.accumulated_rewards);
+            self.remove(id);
+            rewards
+        } else {
+            0
+        };
+        Ok(Withdrawal { amount, penalty, rewards })
+    }
+
+    /// Puts a withdrawal made at `now` back after its payout failed.
+    /// `snapshot` is the position as it was before, used if the withdrawal
+    /// closed it. A reopened position only gets its booster back if no other
+    /// position bound it in the meantime and it still holds principal.
+    pub fn restore_withdrawal(&mut self, snapshot: StakePosition, withdrawal: &Withdrawal, now: u64) {
+        if let Some(position) = self.positions.get_mut(&snapshot.id) {
+            position.amount += withdrawal.amount;
+            position.accumulated_rewards += withdrawal.rewards;
+        } else {
+            let booster_token = snapshot.booster_token
+                .filter(|token_id| withdrawal.amount > 0 && !self.is_booster_bound(*token_id));
+            self.insert(StakePosition {
+                amount: withdrawal.amount,
+                booster_token,
+                quantum_coherence: if booster_token.is_some() { snapshot.quantum_coherence } else { 0.0 },
+                accumulated_rewards: withdrawal.rewards,
+                // Rewards up to the withdrawal are already in `withdrawal`.
+                last_reward_calculation: snapshot.last_reward_calculation.max(now),
+                ..snapshot
+            });
+        }
+    }
+
+    /// Settles and removes the claimable rewards of a position.
+    pub fn take_rewards(&mut self, owner: Principal, id: u64, now: u64) -> Result<u128, String> {
+        let position = self.settled_mut(owner, id, now)?;
+        if position.auto_compound {
+            return Err("Rewards on this position are auto-compounded".to_string());
+        }
+        if position.accumulated_rewards == 0 {
+            return Err("No rewards available".to_string());
+        }
+        Ok(std::mem::take(&mut position.accumulated_rewards))
+    }
+
+    pub fn restore_rewards(&mut self, id: u64, rewards: u128) -> bool {
+        match self.positions.get_mut(&id) {
+            Some(position) => {
+                position.accumulated_rewards += rewards;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops a position left with neither principal nor rewards, such as one
+    /// reopened only to hold rewards whose payout failed.
+    pub fn close_if_empty(&mut self, id: u64) {
+        if self.get(id).is_some_and(|position| position.amount == 0 && position.accumulated_rewards == 0) {
+            self.remove(id);
+        }
+    }
+
+    pub fn set_auto_compound(&mut self, owner: Principal, id: u64, enabled: bool, now: u64) -> Result<(), String> {
+        let position = self.settled_mut(owner, id, now)?;
+        position.auto_compound = enabled;
+        Ok(())
+    }
 }
 
 thread_local! {
-    static STAKES: RefCell<HashMap<Principal, StakeInfo>> = RefCell::new(HashMap::new());
-    static POOL_METRICS: RefCell<PoolMetrics> = RefCell::new(PoolMetrics {
+    static STAKING: RefCell<StakingState> = RefCell::new(StakingState::default());
+    static POOL_METRICS: RefCell<PoolMetrics> = const { RefCell::new(PoolMetrics {
         total_staked: 0,
         total_rewards_distributed: 0,
         number_of_stakers: 0,
         average_coherence: 0.0,
         network_stability: 1.0,
-    });
+        reward_pool: 0,
+    }) };
 }
 
-const BASE_APR: f64 = 0.15; // 15% base APR
-const COHERENCE_MULTIPLIER: f64 = 2.0; // Up to 2x rewards for perfect coherence
-const MIN_STAKE_DURATION: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // 7 days in nanoseconds
-const REWARD_CALCULATION_PERIOD: u64 = 24 * 60 * 60 * 1_000_000_000; // 24 hours in nanoseconds
+pub fn with_staking<R>(f: impl FnOnce(&StakingState) -> R) -> R {
+    STAKING.with(|staking| f(&staking.borrow()))
+}
+
+pub fn with_pool_metrics<R>(f: impl FnOnce(&PoolMetrics) -> R) -> R {
+    POOL_METRICS.with(|metrics| f(&metrics.borrow()))
+}
+
+const STAKES_REGION: StableRegion<StakePosition> = StableRegion::new(STAKES_MEMORY_ID);
+const REWARD_POOL_REGION: StableRegion<StakingRewardPool> = StableRegion::new(POOL_METRICS_MEMORY_ID);
+const REWARD_POOL_KEY: &str = "staking_reward_pool";
+
+pub fn save_stable() -> crate::Result<()> {
+    STAKING.with(|staking| {
+        let staking = staking.borrow();
+        STAKES_REGION.save(staking.positions().map(|position| {
+            (RegionKey::new(position.owner.to_text(), position.id), position)
+        }))?;
+        REWARD_POOL_REGION.save([(RegionKey::singleton(REWARD_POOL_KEY), staking.reward_pool())])
+    })
+}
+
+pub fn restore_stable() -> crate::Result<()> {
+    let positions = STAKES_REGION.load()?;
+    let rewards = REWARD_POOL_REGION.load_singleton(REWARD_POOL_KEY)?.unwrap_or_default();
+    STAKING.with(|staking| {
+        let mut staking = staking.borrow_mut();
+        *staking = StakingState { rewards, ..StakingState::default() };
+        for (_, position) in positions {
+            staking.insert(position);
+        }
+    });
+    // Pool metrics are derived from the positions and the reward pool.
+    update_pool_metrics();
+    Ok(())
+}
 
 #[update]
-async fn stake(amount: u128, lock_period: u64, quantum_coherence: f64) -> Result<(), String> {
+async fn stake(amount: u128, tier: LockTier, booster_token: TokenId, auto_compound: bool) -> Result<u64, String> {
     let caller = ic_cdk::caller();
     
     if amount == 0 {
         return Err("Stake amount must be greater than 0".to_string());
     }
 
-    if lock_period < MIN_STAKE_DURATION {
-        return Err("Lock period must be at least 7 days".to_string());
-    }
-
-    if quantum_coherence < 0.5 {
-        return Err("Quantum coherence too low for staking".to_string());
-    }
+    check_booster(caller, booster_token)?;
 
     // Transfer ANIMA tokens to staking contract
     match transfer_tokens_to_contract(caller, amount).await {
@@ -63,70 +514,137 @@ async fn stake(amount: u128, lock_period: u64, quantum_coherence: f64) -> Result
         Err(e) => return Err(format!("Token transfer failed: {}", e)),
     }
 
-    STAKES.with(|stakes| {
-        let mut stakes = stakes.borrow_mut();
-        let current_time = time();
-        
-        // Update or create stake
-        if let Some(existing_stake) = stakes.get_mut(&caller) {
-            existing_stake.amount += amount;
-            existing_stake.quantum_coherence = (existing_stake.quantum_coherence + quantum_coherence) / 2.0;
-            existing_stake.lock_period = lock_period;
-            existing_stake.last_reward_calculation = current_time;
-        } else {
-            stakes.insert(caller, StakeInfo {
-                amount,
-                start_time: current_time,
-                quantum_coherence,
-                lock_period,
-                accumulated_rewards: 0,
-                last_reward_calculation: current_time,
-            });
-        }
+    // The booster may have moved while the transfer was in flight.
+    let opened = check_booster(caller, booster_token).and_then(|quantum_coherence| {
+        // Every deposit is its own position, so topping up never extends the
+        // lock on funds already staked.
+        STAKING.with(|staking| {
+            staking.borrow_mut().open(caller, amount, tier, booster_token, quantum_coherence, auto_compound, time())
+        })
     });
 
+    match opened {
+        Ok(position_id) => {
+            update_pool_metrics();
+            record_interaction(caller);
+            Ok(position_id)
+        }
+        Err(e) => match transfer_from_stake(caller, amount, ledger_fee(AcceptedToken::ANIMA).await as u128).await {
+            Ok(_) => Err(e),
+            Err(refund_error) => Err(format!("{}; refund failed: {}", e, refund_error)),
+        },
+    }
+}
+
+/// Coherence of `token_id` if `owner` holds it, it is free to bind and it is
+/// coherent enough to stake with.
+fn check_booster(owner: Principal, token_id: TokenId) -> Result<f64, String> {
+    let (holder, coherence) = booster_state(token_id)
+        .ok_or_else(|| format!("ANIMA {} does not exist", token_id))?;
+    if holder != owner {
+        return Err("Stakes can only be boosted by an ANIMA you own".to_string());
+    }
+    if STAKING.with(|staking| staking.borrow().is_booster_bound(token_id)) {
+        return Err("This ANIMA already boosts another stake".to_string());
+    }
+    if coherence < MIN_STAKING_COHERENCE {
+        return Err("Quantum coherence too low for staking".to_string());
+    }
+    Ok(coherence)
+}
+
+fn booster_state(token_id: TokenId) -> Option<(Principal, f64)> {
+    with_registry(|registry| {
+        registry.get(token_id).ok()
+            .map(|record| (record.owner.owner, record.quantum_state.coherence_level))
+    })
+}
+
+/// Starts the periodic coherence resample. Timers do not survive upgrades,
+/// so this runs from both `init` and `post_upgrade`.
+pub fn start_coherence_timer() {
+    ic_cdk_timers::set_timer_interval(COHERENCE_RESAMPLE_INTERVAL, resample_coherence);
+}
+
+pub fn resample_coherence() {
+    let now = time();
+    STAKING.with(|staking| staking.borrow_mut().resample_coherence(now, booster_state));
     update_pool_metrics();
-    Ok(())
 }
 
+/// Withdraws `amount` from a position, or all of it when `None`. Returns
+/// what was paid out after any early-unstake penalty and ledger fees.
+/// Principal is paid from the stake subaccount and the rewards of a closed
+/// position from the rewards subaccount. Rewards too small to cover the
+/// ledger fee go back to the pool.
 #[update]
-async fn unstake() -> Result<u128, String> {
+async fn unstake(position_id: u64, amount: Option<u128>) -> Result<u128, String> {
     let caller = ic_cdk::caller();
+    let fee = ledger_fee(AcceptedToken::ANIMA).await as u128;
     let current_time = time();
-    
-    let (stake_info, rewards) = STAKES.with(|stakes| {
-        let mut stakes = stakes.borrow_mut();
-        if let Some(stake) = stakes.get(&caller) {
-            if current_time < stake.start_time + stake.lock_period {
-                return Err("Stake is still locked".to_string());
-            }
 
-            // Calculate final rewards
-            let final_rewards = calculate_rewards(stake, current_time);
-            let total_return = stake.amount + final_rewards;
-            
-            stakes.remove(&caller);
-            Ok((stake.clone(), total_return))
-        } else {
-            Err("No active stake found".to_string())
-        }
+    let (snapshot, withdrawal) = STAKING.with(|staking| {
+        let mut staking = staking.borrow_mut();
+        let snapshot = staking.get(position_id).cloned();
+        staking.withdraw(caller, position_id, amount, current_time)
+            .map(|withdrawal| (snapshot, withdrawal))
     })?;
+    // `withdraw` only succeeds on an existing position.
+    let snapshot = snapshot.expect("withdrawn position exists");
+    update_pool_metrics();
 
-    // Transfer tokens back to user
-    match transfer_tokens_to_user(caller, stake_info.amount + rewards).await {
-        Ok(_) => {
+    // Compounded principal must reach the stake subaccount before it is paid.
+    let principal = withdrawal.amount - withdrawal.penalty;
+    let paid = match settle_subaccounts().await {
+        Ok(()) => transfer_from_stake(caller, principal, fee).await,
+        Err(e) => Err(e),
+    };
+    let mut received = match paid {
+        Ok(received) => received,
+        Err(e) => {
+            STAKING.with(|staking| staking.borrow_mut().restore_withdrawal(snapshot, &withdrawal, current_time));
             update_pool_metrics();
-            Ok(stake_info.amount + rewards)
-        },
-        Err(e) => Err(format!("Token transfer failed: {}", e)),
+            return Err(format!("Token transfer failed: {}", e));
+        }
+    };
+    STAKING.with(|staking| staking.borrow_mut().forfeit(withdrawal.penalty));
+
+    if withdrawal.rewards > fee {
+        match transfer_from_rewards(caller, withdrawal.rewards, fee).await {
+            Ok(rewards) => {
+                STAKING.with(|staking| staking.borrow_mut().record_distributed(withdrawal.rewards));
+                received += rewards;
+            }
+            Err(e) => {
+                // The principal is out; keep the rewards claimable on the position.
+                let unpaid = Withdrawal { amount: 0, penalty: 0, rewards: withdrawal.rewards };
+                STAKING.with(|staking| staking.borrow_mut().restore_withdrawal(snapshot, &unpaid, current_time));
+                update_pool_metrics();
+                return Err(format!(
+                    "Returned {} staked ANIMA, but the reward transfer failed: {}. Claim the rewards of position {} later",
+                    received, e, position_id
+                ));
+            }
+        }
+    } else if withdrawal.rewards > 0 {
+        STAKING.with(|staking| staking.borrow_mut().fund_rewards(withdrawal.rewards));
     }
+    update_pool_metrics();
+    Ok(received)
 }
 
+#[update]
+fn set_auto_compound(position_id: u64, enabled: bool) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    STAKING.with(|staking| staking.borrow_mut().set_auto_compound(caller, position_id, enabled, time()))?;
+    update_pool_metrics();
+    Ok(())
+}
+
+/// Positions held by `principal`, with rewards settled up to now.
 #[query]
-fn get_stake_info(principal: Principal) -> Option<StakeInfo> {
-    STAKES.with(|stakes| {
-        stakes.borrow().get(&principal).cloned()
-    })
+fn get_positions(principal: Principal) -> Vec<StakePosition> {
+    STAKING.with(|staking| staking.borrow().settled_positions_of(&principal, time()))
 }
 
 #[query]
@@ -136,41 +654,76 @@ fn get_pool_metrics() -> PoolMetrics {
     })
 }
 
+#[query]
+fn get_staking_reward_pool() -> StakingRewardPool {
+    STAKING.with(|staking| staking.borrow().reward_pool().clone())
+}
+
+/// Pulls `amount` ANIMA from the caller into the rewards subaccount, using
+/// an ICRC-2 allowance, and makes it available to stakers. Returns what the
+/// pool has available afterwards.
 #[update]
-async fn claim_rewards() -> Result<u128, String> {
+async fn fund_staking_rewards(amount: u128) -> Result<u128, String> {
+    require_role(Role::Treasury, &format!("fund_staking_rewards {}", amount)).map_err(|e| format!("{:?}", e))?;
+    if amount == 0 {
+        return Err("Funding amount must be greater than 0".to_string());
+    }
+    let ledger = token_canister(&AcceptedToken::ANIMA).map_err(|e| format!("{:?}", e))?;
+    let from = Account { owner: ic_cdk::caller(), subaccount: None };
+    let to = Account { owner: ic_cdk::id(), subaccount: Some(STAKING_REWARDS_SUBACCOUNT) };
+    client::icrc2_transfer_from(ledger, from, to, Nat::from(amount), None)
+        .await
+        .map_err(|e| format!("Funding transfer failed: {:?}", e))?;
+
+    let available = STAKING.with(|staking| {
+        let mut staking = staking.borrow_mut();
+        staking.fund_rewards(amount);
+        staking.reward_pool().available
+    });
+    update_pool_metrics();
+    Ok(available)
+}
+
+/// Pays out a position's accumulated rewards and returns what arrived after
+/// the ledger fee.
+#[update]
+async fn claim_rewards(position_id: u64) -> Result<u128, String> {
     let caller = ic_cdk::caller();
+    let fee = ledger_fee(AcceptedToken::ANIMA).await as u128;
     let current_time = time();
     
-    let rewards = STAKES.with(|stakes| {
-        let mut stakes = stakes.borrow_mut();
-        if let Some(stake) = stakes.get_mut(&caller) {
-            let rewards = calculate_rewards(stake, current_time);
-            if rewards == 0 {
-                return Err("No rewards available".to_string());
-            }
-
-            stake.accumulated_rewards = 0;
-            stake.last_reward_calculation = current_time;
-            Ok(rewards)
-        } else {
-            Err("No active stake found".to_string())
-        }
-    })?;
+    let rewards = STAKING.with(|staking| staking.borrow_mut().take_rewards(caller, position_id, current_time))?;
 
-    // Transfer rewards to user
-    match transfer_tokens_to_user(caller, rewards).await {
-        Ok(_) => {
-            POOL_METRICS.with(|metrics| {
-                let mut metrics = metrics.borrow_mut();
-                metrics.total_rewards_distributed += rewards;
+    match transfer_from_rewards(caller, rewards, fee).await {
+        Ok(received) => {
+            STAKING.with(|staking| {
+                let mut staking = staking.borrow_mut();
+                staking.record_distributed(rewards);
+                staking.close_if_empty(position_id);
             });
-            Ok(rewards)
+            update_pool_metrics();
+            Ok(received)
         },
-        Err(e) => Err(format!("Reward transfer failed: {}", e)),
+        Err(e) => {
+            let restored = STAKING.with(|staking| staking.borrow_mut().restore_rewards(position_id, rewards));
+            if !restored {
+                // The position was closed while the transfer was in flight.
+                STAKING.with(|staking| staking.borrow_mut().record_unpaid(rewards));
+                record_event(
+                    SecurityEventType::TokenTransfer,
+                    format!("{} staking rewards of closed position {} left unpaid: {}", rewards, position_id, e),
+                    Some(caller),
+                    true,
+                );
+            }
+            Err(format!("Reward transfer failed: {}", e))
+        }
     }
 }
 
-fn calculate_rewards(stake: &StakeInfo, current_time: u64) -> u128 {
+/// Rewards earned by `stake` since its last settlement: base APR, boosted by
+/// coherence and scaled by the lock tier's multiplier.
+fn calculate_rewards(stake: &StakePosition, current_time: u64) -> u128 {
     if current_time <= stake.last_reward_calculation {
         return 0;
     }
@@ -179,104 +732,392 @@ fn calculate_rewards(stake: &StakeInfo, current_time: u64) -> u128 {
     let coherence_bonus = 1.0 + (stake.quantum_coherence * COHERENCE_MULTIPLIER);
     
     // Calculate APR with coherence bonus
-    let effective_apr = BASE_APR * coherence_bonus;
+    let effective_apr = BASE_APR * coherence_bonus * stake.tier.reward_multiplier();
     
     // Calculate rewards for the period
-    let rewards = (stake.amount as f64 * effective_apr * (time_staked as f64 / (365.0 * 24.0 * 60.0 * 60.0 * 1_000_000_000.0))) as u128;
-    
-    rewards + stake.accumulated_rewards
+    (stake.amount as f64 * effective_apr * (time_staked as f64 / YEAR_NANOS)) as u128
 }
 
 fn update_pool_metrics() {
-    STAKES.with(|stakes| {
-        let stakes = stakes.borrow();
+    STAKING.with(|staking| {
+        let staking = staking.borrow();
         let mut total_staked = 0u128;
         let mut total_coherence = 0.0;
-        let stakers_count = stakes.len() as u64;
+        let mut position_count = 0usize;
 
-        for stake in stakes.values() {
-            total_staked += stake.amount;
-            total_coherence += stake.quantum_coherence;
+        for position in staking.positions() {
+            total_staked += position.amount;
+            total_coherence += position.quantum_coherence;
+            position_count += 1;
         }
 
-        let average_coherence = if stakers_count > 0 {
-            total_coherence / stakers_count as f64
+        let average_coherence = if position_count > 0 {
+            total_coherence / position_count as f64
         } else {
             0.0
         };
+        let stakers_count = staking.staker_count();
 
         POOL_METRICS.with(|metrics| {
             let mut metrics = metrics.borrow_mut();
             metrics.total_staked = total_staked;
-            metrics.number_of_stakers = stakers_count;
+            metrics.total_rewards_distributed = staking.reward_pool().total_distributed;
+            metrics.reward_pool = staking.reward_pool().available;
+            metrics.number_of_stakers = stakers_count as u64;
             metrics.average_coherence = average_coherence;
-            metrics.network_stability = calculate_network_stability(average_coherence);
+            metrics.network_stability = calculate_network_stability(average_coherence, stakers_count);
         });
     });
 }
 
-fn calculate_network_stability(average_coherence: f64) -> f64 {
+fn calculate_network_stability(average_coherence: f64, stakers_count: usize) -> f64 {
     // Network stability increases with higher average coherence
     // and higher number of stakers
-    STAKES.with(|stakes| {
-        let stakes = stakes.borrow();
-        let staker_factor = (stakes.len() as f64).sqrt() / 10.0; // Square root scaling
-        let stability = average_coherence * (1.0 + staker_factor);
-        stability.min(1.0)
-    })
+    let staker_factor = (stakers_count as f64).sqrt() / 10.0; // Square root scaling
+    let stability = average_coherence * (1.0 + staker_factor);
+    stability.min(1.0)
 }
 
+/// Pulls the stake from `from` into the stake subaccount, using the ICRC-2
+/// allowance they granted to this canister on the ANIMA ledger.
 async fn transfer_tokens_to_contract(from: Principal, amount: u128) -> Result<(), String> {
-    let token_canister = Principal::from_text("anima_token_canister_id").unwrap();
-    let args = TransferArgs {
-        from: Account { owner: from, subaccount: None },
-        to: Account { owner: ic_cdk::id(), subaccount: None },
-        amount,
-        fee: None,
-        memo: None,
-        created_at_time: None,
-    };
+    let ledger = token_canister(&AcceptedToken::ANIMA).map_err(|e| format!("{:?}", e))?;
+    let from = Account { owner: from, subaccount: None };
+    let to = Account { owner: ic_cdk::id(), subaccount: Some(STAKE_SUBACCOUNT) };
+    client::icrc2_transfer_from(ledger, from, to, Nat::from(amount), None)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
 
-    match ic_cdk::call(token_canister, "icrc1_transfer", (args,)).await {
-        Ok(Ok(_)) => Ok(()),
-        Ok(Err(e)) => Err(format!("Transfer error: {:?}", e)),
-        Err((code, msg)) => Err(format!("RPC error: {} - {}", code, msg)),
+/// Pays `amount` out of the stake subaccount, net of the ledger `fee`, and
+/// returns what `to` received. The subaccount gives up exactly `amount`.
+async fn transfer_from_stake(to: Principal, amount: u128, fee: u128) -> Result<u128, String> {
+    if amount <= fee {
+        return Err(format!("{} ANIMA does not cover the ledger fee of {}", amount, fee));
     }
+    let ledger = token_canister(&AcceptedToken::ANIMA).map_err(|e| format!("{:?}", e))?;
+    let to = Account { owner: to, subaccount: None };
+    client::icrc1_transfer(ledger, Some(STAKE_SUBACCOUNT), to, Nat::from(amount - fee), None)
+        .await
+        .map(|_| amount - fee)
+        .map_err(|e| format!("{:?}", e))
 }
 
-async fn transfer_tokens_to_user(to: Principal, amount: u128) -> Result<(), String> {
-    let token_canister = Principal::from_text("anima_token_canister_id").unwrap();
-    let args = TransferArgs {
-        from: Account { owner: ic_cdk::id(), subaccount: None },
-        to: Account { owner: to, subaccount: None },
-        amount,
-        fee: None,
-        memo: None,
-        created_at_time: None,
-    };
-
-    match ic_cdk::call(token_canister, "icrc1_transfer", (args,)).await {
-        Ok(Ok(_)) => Ok(()),
-        Ok(Err(e)) => Err(format!("Transfer error: {:?}", e)),
-        Err((code, msg)) => Err(format!("RPC error: {} - {}", code, msg)),
+/// Pays rewards out of the rewards subaccount like `transfer_from_stake`,
+/// refusing to pay more than it holds.
+async fn transfer_from_rewards(to: Principal, amount: u128, fee: u128) -> Result<u128, String> {
+    if amount <= fee {
+        return Err(format!("{} ANIMA does not cover the ledger fee of {}", amount, fee));
+    }
+    let ledger = token_canister(&AcceptedToken::ANIMA).map_err(|e| format!("{:?}", e))?;
+    let pool = Account { owner: ic_cdk::id(), subaccount: Some(STAKING_REWARDS_SUBACCOUNT) };
+    let balance = client::icrc1_balance_of(ledger, pool)
+        .await
+        .map_err(|e| format!("{:?}", e))?
+        .0
+        .to_u128()
+        .unwrap_or(u128::MAX);
+    if balance < amount {
+        return Err(format!("Reward pool holds {} ANIMA, cannot pay {}", balance, amount));
     }
+    let to = Account { owner: to, subaccount: None };
+    client::icrc1_transfer(ledger, Some(STAKING_REWARDS_SUBACCOUNT), to, Nat::from(amount - fee), None)
+        .await
+        .map(|_| amount - fee)
+        .map_err(|e| format!("{:?}", e))
 }
 
-#[derive(CandidType, Clone, Debug)]
-struct Account {
-    owner: Principal,
-    subaccount: Option<Vec<u8>>,
-}
+/// Moves compounded rewards to the stake subaccount and forfeited penalties
+/// to the rewards subaccount, netted into a single transfer.
+async fn settle_subaccounts() -> Result<(), String> {
+    let fee = ledger_fee(AcceptedToken::ANIMA).await as u128;
+    let due = match STAKING.with(|staking| staking.borrow_mut().take_transfers_due(fee))? {
+        Some(due) => due,
+        None => return Ok(()),
+    };
 
-#[derive(CandidType)]
-struct TransferArgs {
-    from: Account,
-    to: Account,
-    amount: u128,
-    fee: Option<u128>,
-    memo: Option<Vec<u8>>,
-    created_at_time: Option<u64>,
+    let moved = match token_canister(&AcceptedToken::ANIMA) {
+        Ok(ledger) => {
+            let to = Account { owner: ic_cdk::id(), subaccount: Some(due.to) };
+            client::icrc1_transfer(ledger, Some(due.from), to, Nat::from(due.amount), None).await.map(|_| ())
+        }
+        Err(e) => Err(e),
+    };
+    moved.map_err(|e| {
+        STAKING.with(|staking| staking.borrow_mut().restore_transfers_due(due));
+        format!("Moving funds between staking subaccounts failed: {:?}", e)
+    })
 }
 
-// Candid interface generation
-ic_cdk::export_candid!();
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn funded_state() -> StakingState {
+        let mut state = StakingState::default();
+        state.fund_rewards(1_000_000_000_000);
+        state
+    }
+
+    #[test]
+    fn test_top_up_opens_separate_position() {
+        let mut state = funded_state();
+        let first = state.open(owner(1), 1_000, LockTier::Days365, 1, 0.8, false, 0).unwrap();
+        let second = state.open(owner(1), 500, LockTier::Days7, 2, 0.6, false, 10 * DAY).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(state.get(first).unwrap().unlock_time, 365 * DAY);
+        assert_eq!(state.get(second).unwrap().unlock_time, 17 * DAY);
+        assert_eq!(state.positions_of(&owner(1)).len(), 2);
+        assert_eq!(state.staker_count(), 1);
+    }
+
+    #[test]
+    fn test_early_partial_unstake_pays_penalty() {
+        let mut state = funded_state();
+        let id = state.open(owner(1), 10_000, LockTier::Days30, 3, 0.5, false, 0).unwrap();
+
+        let withdrawal = state.withdraw(owner(1), id, Some(4_000), DAY).unwrap();
+        assert_eq!(withdrawal.penalty, 400);
+        assert_eq!(withdrawal.rewards, 0);
+        assert_eq!(withdrawal.amount - withdrawal.penalty, 3_600);
+        assert_eq!(state.get(id).unwrap().amount, 6_000);
+
+        assert!(state.withdraw(owner(2), id, None, DAY).is_err());
+        assert!(state.withdraw(owner(1), id, Some(6_001), DAY).is_err());
+    }
+
+    #[test]
+    fn test_full_unstake_after_unlock_releases_rewards() {
+        let mut state = funded_state();
+        let id = state.open(owner(1), 1_000_000_000, LockTier::Days7, 4, 0.5, false, 0).unwrap();
+
+        let withdrawal = state.withdraw(owner(1), id, None, 8 * DAY).unwrap();
+        assert_eq!(withdrawal.penalty, 0);
+        assert!(withdrawal.rewards > 0);
+        assert!(state.get(id).is_none());
+        assert!(state.positions_of(&owner(1)).is_empty());
+
+        let snapshot = StakePosition {
+            id,
+            owner: owner(1),
+            amount: 0,
+            booster_token: Some(4),
+            quantum_coherence: 0.5,
+            tier: LockTier::Days7,
+            auto_compound: false,
+            start_time: 0,
+            unlock_time: 7 * DAY,
+            accumulated_rewards: 0,
+            last_reward_calculation: 8 * DAY,
+        };
+        state.restore_withdrawal(snapshot, &withdrawal, 8 * DAY);
+        assert_eq!(state.get(id).unwrap().amount, 1_000_000_000);
+        assert_eq!(state.get(id).unwrap().accumulated_rewards, withdrawal.rewards);
+    }
+
+    #[test]
+    fn test_longer_tiers_earn_more() {
+        let mut state = funded_state();
+        let short = state.open(owner(1), 1_000_000_000, LockTier::Days7, 5, 0.5, false, 0).unwrap();
+        let long = state.open(owner(1), 1_000_000_000, LockTier::Days365, 6, 0.5, false, 0).unwrap();
+
+        let short_rewards = calculate_rewards(state.get(short).unwrap(), 30 * DAY);
+        let long_rewards = calculate_rewards(state.get(long).unwrap(), 30 * DAY);
+        assert!(long_rewards > short_rewards);
+    }
+
+    #[test]
+    fn test_auto_compound_grows_principal() {
+        let mut state = funded_state();
+        let id = state.open(owner(1), 1_000_000_000, LockTier::Days30, 7, 0.5, true, 0).unwrap();
+
+        assert!(state.take_rewards(owner(1), id, 10 * DAY).is_err());
+        let position = state.get(id).unwrap();
+        assert!(position.amount > 1_000_000_000);
+        assert_eq!(position.accumulated_rewards, 0);
+    }
+
+    #[test]
+    fn test_booster_binds_one_position() {
+        let mut state = funded_state();
+        state.open(owner(1), 1_000, LockTier::Days7, 42, 0.9, false, 0).unwrap();
+        assert!(state.is_booster_bound(42));
+        assert!(state.open(owner(1), 1_000, LockTier::Days7, 42, 0.9, false, 0).is_err());
+    }
+
+    #[test]
+    fn test_resample_tracks_booster_and_drops_on_transfer() {
+        let mut state = funded_state();
+        let id = state.open(owner(1), 1_000_000_000, LockTier::Days30, 42, 0.6, false, 0).unwrap();
+
+        state.resample_coherence(DAY, |_| Some((owner(1), 0.9)));
+        assert_eq!(state.get(id).unwrap().quantum_coherence, 0.9);
+        let accrued = state.get(id).unwrap().accumulated_rewards;
+        assert!(accrued > 0);
+
+        // The NFT changed hands: the boost stops and the token is free again.
+        state.resample_coherence(2 * DAY, |_| Some((owner(2), 0.9)));
+        let position = state.get(id).unwrap();
+        assert_eq!(position.quantum_coherence, 0.0);
+        assert_eq!(position.booster_token, None);
+        assert!(position.accumulated_rewards > accrued);
+        assert!(!state.is_booster_bound(42));
+    }
+
+    #[test]
+    fn test_rewards_are_capped_by_funded_pool() {
+        let mut state = StakingState::default();
+        state.fund_rewards(100);
+        let id = state.open(owner(1), 1_000_000_000, LockTier::Days365, 8, 1.0, false, 0).unwrap();
+
+        assert_eq!(state.take_rewards(owner(1), id, 365 * DAY), Ok(100));
+        assert_eq!(state.reward_pool().available, 0);
+        assert!(state.take_rewards(owner(1), id, 400 * DAY).is_err());
+    }
+
+    #[test]
+    fn test_compounding_and_penalties_are_moved_between_subaccounts() {
+        let mut state = funded_state();
+        let id = state.open(owner(1), 1_000_000_000, LockTier::Days30, 9, 0.5, true, 0).unwrap();
+
+        let withdrawal = state.withdraw(owner(1), id, Some(1_000), DAY).unwrap();
+        state.forfeit(withdrawal.penalty);
+        let compounded = state.get(id).unwrap().amount + 1_000 - 1_000_000_000;
+        let available = state.reward_pool().available;
+        let due = state.take_transfers_due(10).unwrap().unwrap();
+        assert_eq!(due.from, STAKING_REWARDS_SUBACCOUNT);
+        assert_eq!(due.amount, compounded - withdrawal.penalty);
+        assert_eq!(state.reward_pool().available, available - 10);
+        assert_eq!(state.take_transfers_due(10), Ok(None));
+
+        state.restore_transfers_due(due.clone());
+        assert_eq!(state.reward_pool().available, available);
+        assert_eq!(state.take_transfers_due(10), Ok(Some(due)));
+    }
+
+    /// Ledger balances of the two staking subaccounts, moved the way the
+    /// transfer helpers move them.
+    #[derive(Default)]
+    struct Subaccounts {
+        stake: u128,
+        rewards: u128,
+    }
+
+    impl Subaccounts {
+        fn balance(&mut self, subaccount: Subaccount) -> &mut u128 {
+            if subaccount == STAKE_SUBACCOUNT { &mut self.stake } else { &mut self.rewards }
+        }
+
+        fn settle(&mut self, state: &mut StakingState, fee: u128) {
+            if let Some(due) = state.take_transfers_due(fee).unwrap() {
+                *self.balance(due.from) -= due.amount + fee;
+                *self.balance(due.to) += due.amount;
+            }
+        }
+
+        /// Asserts that the subaccounts hold what the books say they hold.
+        fn assert_backs(&self, state: &StakingState) {
+            let pool = state.reward_pool();
+            let staked: u128 = state.positions().map(|position| position.amount).sum();
+            let accumulated: u128 = state.positions().map(|position| position.accumulated_rewards).sum();
+            assert_eq!(self.stake + pool.pending_compound, staked + pool.pending_penalties);
+            assert_eq!(
+                self.rewards + pool.pending_penalties,
+                pool.available + accumulated + pool.pending_compound,
+            );
+        }
+    }
+
+    #[test]
+    fn test_books_match_subaccounts_with_ledger_fee() {
+        const FEE: u128 = 10_000;
+        let mut ledger = Subaccounts::default();
+        let mut state = StakingState::default();
+        ledger.rewards += 1_000_000_000;
+        state.fund_rewards(1_000_000_000);
+
+        let compounding = state.open(owner(1), 5_000_000_000, LockTier::Days30, 1, 0.8, true, 0).unwrap();
+        let claiming = state.open(owner(2), 5_000_000_000, LockTier::Days30, 2, 0.8, false, 0).unwrap();
+        ledger.stake += 10_000_000_000;
+        ledger.assert_backs(&state);
+
+        // Early partial unstake: the penalty is forfeited, the rest is paid
+        // out of the stake subaccount with the fee on top of the payout.
+        let withdrawal = state.withdraw(owner(1), compounding, Some(1_000_000_000), 10 * DAY).unwrap();
+        ledger.settle(&mut state, FEE);
+        ledger.stake -= withdrawal.amount - withdrawal.penalty;
+        state.forfeit(withdrawal.penalty);
+        ledger.assert_backs(&state);
+
+        let rewards = state.take_rewards(owner(2), claiming, 20 * DAY).unwrap();
+        ledger.rewards -= rewards;
+        state.record_distributed(rewards);
+        ledger.assert_backs(&state);
+
+        // Penalties now outweigh compounding, so the move runs the other way.
+        let withdrawal = state.withdraw(owner(2), claiming, Some(4_000_000_000), 20 * DAY).unwrap();
+        ledger.stake -= withdrawal.amount - withdrawal.penalty;
+        state.forfeit(withdrawal.penalty);
+        ledger.settle(&mut state, FEE);
+        ledger.assert_backs(&state);
+
+        let withdrawal = state.withdraw(owner(1), compounding, None, 40 * DAY).unwrap();
+        ledger.settle(&mut state, FEE);
+        ledger.stake -= withdrawal.amount - withdrawal.penalty;
+        state.forfeit(withdrawal.penalty);
+        ledger.settle(&mut state, FEE);
+        ledger.assert_backs(&state);
+        assert_eq!(ledger.stake, 1_000_000_000);
+    }
+
+    #[test]
+    fn test_restore_keeps_a_rebound_booster() {
+        let mut state = funded_state();
+        let id = state.open(owner(1), 1_000, LockTier::Days7, 42, 0.9, false, 0).unwrap();
+        let snapshot = state.get(id).unwrap().clone();
+        let withdrawal = state.withdraw(owner(1), id, None, 8 * DAY).unwrap();
+
+        // Another position bound the booster while the payout was in flight.
+        let other = state.open(owner(1), 1_000, LockTier::Days7, 42, 0.9, false, 8 * DAY).unwrap();
+        state.restore_withdrawal(snapshot, &withdrawal, 8 * DAY);
+
+        let restored = state.get(id).unwrap();
+        assert_eq!(restored.amount, 1_000);
+        assert_eq!(restored.booster_token, None);
+        assert_eq!(restored.quantum_coherence, 0.0);
+        assert_eq!(restored.last_reward_calculation, 8 * DAY);
+        assert!(state.is_booster_bound(42));
+        state.withdraw(owner(1), id, None, 8 * DAY).unwrap();
+        assert!(state.is_booster_bound(42));
+        assert!(state.get(other).is_some());
+    }
+
+    #[test]
+    fn test_reward_pool_persists() {
+        STAKING.with(|staking| {
+            let mut staking = staking.borrow_mut();
+            *staking = funded_state();
+            staking.open(owner(1), 1_000, LockTier::Days30, 10, 0.7, false, 0).unwrap();
+            staking.forfeit(50);
+            staking.record_unpaid(7);
+        });
+        let before = STAKING.with(|staking| staking.borrow().reward_pool().clone());
+        save_stable().unwrap();
+        STAKING.with(|staking| *staking.borrow_mut() = StakingState::default());
+
+        restore_stable().unwrap();
+        STAKING.with(|staking| {
+            let staking = staking.borrow();
+            assert_eq!(staking.reward_pool(), &before);
+            assert_eq!(staking.positions().count(), 1);
+            assert!(staking.is_booster_bound(10));
+        });
+        assert_eq!(with_pool_metrics(|metrics| metrics.reward_pool), before.available);
+    }
+}
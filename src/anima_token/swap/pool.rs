@@ -0,0 +1,719 @@
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use crate::admin::{require_role, Role};
+use crate::icrc::{client, AcceptedToken, Account, Subaccount};
+use crate::nft::marketplace::ledger_fee;
+use crate::payments::transaction_processor::token_canister;
+use crate::stable::{RegionKey, StableRegion, SWAP_POOL_MEMORY_ID};
+
+const BASIS_POINTS: u128 = 10_000;
+/// LP shares burned on the first deposit so the pool can never be fully
+/// drained and the share price cannot be inflated from zero.
+pub const MINIMUM_LIQUIDITY: u128 = 1_000;
+/// Upper bound on the combined swap fee, in basis points.
+pub const MAX_SWAP_FEE_BPS: u16 = 1_000;
+
+/// Subaccount of this canister that holds the pool's reserves, accrued
+/// protocol fees and unpaid credits.
+pub const SWAP_POOL_SUBACCOUNT: Subaccount = *b"anima-swap-pool\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SwapConfig {
+    /// Fee kept in the reserves for liquidity providers.
+    pub lp_fee_bps: u16,
+    /// Fee set aside for the treasury, charged on top of the LP fee.
+    pub protocol_fee_bps: u16,
+    /// Receives swept protocol fees; the canister's main account when unset.
+    pub treasury: Option<Principal>,
+}
+
+impl Default for SwapConfig {
+    fn default() -> Self {
+        Self {
+            lp_fee_bps: 25,
+            protocol_fee_bps: 5,
+            treasury: None,
+        }
+    }
+}
+
+impl SwapConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.lp_fee_bps as u32 + self.protocol_fee_bps as u32 > MAX_SWAP_FEE_BPS as u32 {
+            return Err(format!("Swap fees cannot exceed {} basis points", MAX_SWAP_FEE_BPS));
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SwapQuote {
+    pub token_in: AcceptedToken,
+    pub amount_in: u128,
+    pub token_out: AcceptedToken,
+    /// Paid out of the reserves, before the ledger fee on the payout.
+    pub amount_out: u128,
+    pub lp_fee: u128,
+    pub protocol_fee: u128,
+    /// Shortfall against the pre-trade spot price, in basis points.
+    pub price_impact_bps: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct SwapReceipt {
+    pub quote: SwapQuote,
+    /// Amount received after the ledger fee, or `None` when the payout failed
+    /// and was left as a credit for `claim_swap_credit`.
+    pub received: Option<u128>,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LiquidityReceipt {
+    pub shares: u128,
+    pub icp_used: u128,
+    pub anima_used: u128,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct SwapPoolInfo {
+    pub reserve_icp: u128,
+    pub reserve_anima: u128,
+    pub lp_supply: u128,
+    pub config: SwapConfig,
+    pub protocol_fees: Vec<(AcceptedToken, u128)>,
+}
+
+/// Constant-product (x * y = k) pool between ICP and ANIMA. Pure state: the
+/// endpoints below move the tokens and call into it once funds have landed.
+#[derive(CandidType, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SwapPool {
+    pub reserve_icp: u128,
+    pub reserve_anima: u128,
+    pub lp_supply: u128,
+    pub lp_balances: BTreeMap<Principal, u128>,
+    pub protocol_fees: BTreeMap<AcceptedToken, u128>,
+    /// Funds owed to users whose payout has not gone through yet.
+    pub credits: BTreeMap<(Principal, AcceptedToken), u128>,
+    pub config: SwapConfig,
+}
+
+/// The other side of the pool, for a token the pool trades.
+fn other(token: AcceptedToken) -> Result<AcceptedToken, String> {
+    match token {
+        AcceptedToken::ICP => Ok(AcceptedToken::ANIMA),
+        AcceptedToken::ANIMA => Ok(AcceptedToken::ICP),
+        AcceptedToken::Icrc1(_) => Err("The pool only trades ICP and ANIMA".to_string()),
+    }
+}
+
+/// `a * b / c`, rounding down, without silently overflowing.
+fn mul_div(a: u128, b: u128, c: u128) -> Result<u128, String> {
+    if c == 0 {
+        return Err("Pool has no liquidity".to_string());
+    }
+    a.checked_mul(b)
+        .map(|product| product / c)
+        .ok_or_else(|| "Amount too large".to_string())
+}
+
+fn isqrt(value: u128) -> u128 {
+    if value < 2 {
+        return value;
+    }
+    let mut x = value;
+    let mut y = x / 2 + 1;
+    while y < x {
+        x = y;
+        y = (x + value / x) / 2;
+    }
+    x
+}
+
+impl SwapPool {
+    pub fn reserve(&self, token: AcceptedToken) -> u128 {
+        match token {
+            AcceptedToken::ICP => self.reserve_icp,
+            AcceptedToken::ANIMA => self.reserve_anima,
+            AcceptedToken::Icrc1(_) => 0,
+        }
+    }
+
+    fn reserve_mut(&mut self, token: AcceptedToken) -> &mut u128 {
+        match token {
+            AcceptedToken::ICP => &mut self.reserve_icp,
+            AcceptedToken::ANIMA => &mut self.reserve_anima,
+            AcceptedToken::Icrc1(_) => unreachable!("quote rejects tokens the pool does not trade"),
+        }
+    }
+
+    pub fn quote(&self, token_in: AcceptedToken, amount_in: u128) -> Result<SwapQuote, String> {
+        if amount_in == 0 {
+            return Err("Swap amount must be greater than 0".to_string());
+        }
+        let token_out = other(token_in)?;
+        let reserve_in = self.reserve(token_in);
+        let reserve_out = self.reserve(token_out);
+        if reserve_in == 0 || reserve_out == 0 {
+            return Err("Pool has no liquidity".to_string());
+        }
+
+        let lp_fee = mul_div(amount_in, self.config.lp_fee_bps as u128, BASIS_POINTS)?;
+        let protocol_fee = mul_div(amount_in, self.config.protocol_fee_bps as u128, BASIS_POINTS)?;
+        let net_in = amount_in - lp_fee - protocol_fee;
+        let new_reserve_in = reserve_in.checked_add(net_in).ok_or("Amount too large")?;
+        let amount_out = mul_div(reserve_out, net_in, new_reserve_in)?;
+        if amount_out == 0 {
+            return Err("Swap amount too small".to_string());
+        }
+
+        let spot_out = mul_div(net_in, reserve_out, reserve_in)?;
+        let price_impact_bps = ((spot_out - amount_out) * BASIS_POINTS).checked_div(spot_out).unwrap_or(0) as u64;
+
+        Ok(SwapQuote {
+            token_in,
+            amount_in,
+            token_out,
+            amount_out,
+            lp_fee,
+            protocol_fee,
+            price_impact_bps,
+        })
+    }
+
+    /// Executes a swap whose input has already been deposited. Fails, leaving
+    /// the pool untouched, if it would pay out less than `min_amount_out`.
+    pub fn swap(&mut self, token_in: AcceptedToken, amount_in: u128, min_amount_out: u128) -> Result<SwapQuote, String> {
+        let quote = self.quote(token_in, amount_in)?;
+        if quote.amount_out < min_amount_out {
+            return Err(format!(
+                "Slippage exceeded: would receive {}, minimum is {}",
+                quote.amount_out, min_amount_out
+            ));
+        }
+        // The LP fee stays in the reserves, growing k for liquidity providers.
+        *self.reserve_mut(token_in) += amount_in - quote.protocol_fee;
+        *self.reserve_mut(quote.token_out) -= quote.amount_out;
+        *self.protocol_fees.entry(token_in).or_default() += quote.protocol_fee;
+        Ok(quote)
+    }
+
+    /// Amounts of each side a deposit would actually use at the current
+    /// ratio, with the shares it would mint.
+    pub fn liquidity_quote(&self, icp_amount: u128, anima_amount: u128) -> Result<LiquidityReceipt, String> {
+        if icp_amount == 0 || anima_amount == 0 {
+            return Err("Both sides of a deposit must be greater than 0".to_string());
+        }
+        if self.lp_supply == 0 {
+            let shares = isqrt(icp_amount.checked_mul(anima_amount).ok_or("Amount too large")?);
+            if shares <= MINIMUM_LIQUIDITY {
+                return Err("Initial deposit too small".to_string());
+            }
+            return Ok(LiquidityReceipt {
+                shares: shares - MINIMUM_LIQUIDITY,
+                icp_used: icp_amount,
+                anima_used: anima_amount,
+            });
+        }
+
+        let icp_for_anima = mul_div(anima_amount, self.reserve_icp, self.reserve_anima)?;
+        let (icp_used, anima_used) = if icp_for_anima <= icp_amount {
+            (icp_for_anima, anima_amount)
+        } else {
+            (icp_amount, mul_div(icp_amount, self.reserve_anima, self.reserve_icp)?)
+        };
+        let shares = mul_div(icp_used, self.lp_supply, self.reserve_icp)?
+            .min(mul_div(anima_used, self.lp_supply, self.reserve_anima)?);
+        if shares == 0 {
+            return Err("Deposit too small".to_string());
+        }
+        Ok(LiquidityReceipt { shares, icp_used, anima_used })
+    }
+
+    /// Adds deposited funds as liquidity. Whatever does not fit the current
+    /// ratio is credited back to the provider.
+    pub fn add_liquidity(
+        &mut self,
+        provider: Principal,
+        icp_amount: u128,
+        anima_amount: u128,
+        min_shares: u128,
+    ) -> Result<LiquidityReceipt, String> {
+        let receipt = self.liquidity_quote(icp_amount, anima_amount)?;
+        if receipt.shares < min_shares {
+            return Err(format!(
+                "Slippage exceeded: would mint {} shares, minimum is {}",
+                receipt.shares, min_shares
+            ));
+        }
+        if self.lp_supply == 0 {
+            self.lp_supply = MINIMUM_LIQUIDITY;
+        }
+        self.reserve_icp += receipt.icp_used;
+        self.reserve_anima += receipt.anima_used;
+        self.lp_supply += receipt.shares;
+        *self.lp_balances.entry(provider).or_default() += receipt.shares;
+        self.credit(provider, AcceptedToken::ICP, icp_amount - receipt.icp_used);
+        self.credit(provider, AcceptedToken::ANIMA, anima_amount - receipt.anima_used);
+        Ok(receipt)
+    }
+
+    /// Burns `shares` and credits the provider their part of both reserves.
+    pub fn remove_liquidity(
+        &mut self,
+        provider: Principal,
+        shares: u128,
+        min_icp: u128,
+        min_anima: u128,
+    ) -> Result<(u128, u128), String> {
+        let balance = self.lp_balance(&provider);
+        if shares == 0 || shares > balance {
+            return Err(format!("Insufficient LP shares: have {}, requested {}", balance, shares));
+        }
+        let icp_out = mul_div(shares, self.reserve_icp, self.lp_supply)?;
+        let anima_out = mul_div(shares, self.reserve_anima, self.lp_supply)?;
+        if icp_out < min_icp || anima_out < min_anima {
+            return Err(format!(
+                "Slippage exceeded: would receive {} ICP and {} ANIMA",
+                icp_out, anima_out
+            ));
+        }
+
+        self.reserve_icp -= icp_out;
+        self.reserve_anima -= anima_out;
+        self.lp_supply -= shares;
+        if shares == balance {
+            self.lp_balances.remove(&provider);
+        } else {
+            self.lp_balances.insert(provider, balance - shares);
+        }
+        self.credit(provider, AcceptedToken::ICP, icp_out);
+        self.credit(provider, AcceptedToken::ANIMA, anima_out);
+        Ok((icp_out, anima_out))
+    }
+
+    pub fn lp_balance(&self, provider: &Principal) -> u128 {
+        self.lp_balances.get(provider).copied().unwrap_or(0)
+    }
+
+    pub fn credit(&mut self, recipient: Principal, token: AcceptedToken, amount: u128) {
+        if amount > 0 {
+            *self.credits.entry((recipient, token)).or_default() += amount;
+        }
+    }
+
+    pub fn take_credit(&mut self, recipient: Principal, token: AcceptedToken) -> u128 {
+        self.credits.remove(&(recipient, token)).unwrap_or(0)
+    }
+
+    pub fn take_protocol_fees(&mut self, token: AcceptedToken) -> u128 {
+        self.protocol_fees.remove(&token).unwrap_or(0)
+    }
+
+    pub fn info(&self) -> SwapPoolInfo {
+        SwapPoolInfo {
+            reserve_icp: self.reserve_icp,
+            reserve_anima: self.reserve_anima,
+            lp_supply: self.lp_supply,
+            config: self.config.clone(),
+            protocol_fees: self.protocol_fees.iter().map(|(token, amount)| (*token, *amount)).collect(),
+        }
+    }
+}
+
+thread_local! {
+    static SWAP_POOL: RefCell<SwapPool> = RefCell::new(SwapPool::default());
+}
+
+const SWAP_POOL_REGION: StableRegion<SwapPool> = StableRegion::new(SWAP_POOL_MEMORY_ID);
+const SWAP_POOL_KEY: &str = "swap_pool";
+
+pub fn with_swap_pool<R>(f: impl FnOnce(&SwapPool) -> R) -> R {
+    SWAP_POOL.with(|pool| f(&pool.borrow()))
+}
+
+pub fn with_swap_pool_mut<R>(f: impl FnOnce(&mut SwapPool) -> R) -> R {
+    SWAP_POOL.with(|pool| f(&mut pool.borrow_mut()))
+}
+
+pub fn save_stable() -> crate::Result<()> {
+    SWAP_POOL.with(|pool| SWAP_POOL_REGION.save([(RegionKey::singleton(SWAP_POOL_KEY), &*pool.borrow())]))
+}
+
+pub fn restore_stable() -> crate::Result<()> {
+    if let Some(restored) = SWAP_POOL_REGION.load_singleton(SWAP_POOL_KEY)? {
+        SWAP_POOL.with(|pool| *pool.borrow_mut() = restored);
+    }
+    Ok(())
+}
+
+pub fn pool_account() -> Account {
+    Account::new(ic_cdk::id(), Some(SWAP_POOL_SUBACCOUNT))
+}
+
+async fn pull_into_pool(from: Principal, token: AcceptedToken, amount: u128) -> Result<(), String> {
+    let ledger = token_canister(&token).map_err(|e| format!("{:?}", e))?;
+    client::icrc2_transfer_from(ledger, Account::from(from), pool_account(), Nat::from(amount), None)
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("{:?}", e))
+}
+
+/// Pays out `recipient`'s credit in `token`, net of the ledger fee. A failed
+/// transfer puts the credit back for a later retry.
+async fn pay_credit(recipient: Principal, to: Account, token: AcceptedToken) -> Result<u128, String> {
+    let amount = with_swap_pool_mut(|pool| pool.take_credit(recipient, token));
+    if amount == 0 {
+        return Ok(0);
+    }
+    let fee = ledger_fee(token).await as u128;
+    if amount <= fee {
+        with_swap_pool_mut(|pool| pool.credit(recipient, token, amount));
+        return Err(format!("Credit of {} does not cover the ledger fee of {}", amount, fee));
+    }
+
+    let ledger = token_canister(&token).map_err(|e| format!("{:?}", e));
+    let result = match ledger {
+        Ok(ledger) => client::icrc1_transfer(ledger, Some(SWAP_POOL_SUBACCOUNT), to, Nat::from(amount - fee), None)
+            .await
+            .map_err(|e| format!("{:?}", e)),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(_) => Ok(amount - fee),
+        Err(e) => {
+            with_swap_pool_mut(|pool| pool.credit(recipient, token, amount));
+            Err(e)
+        }
+    }
+}
+
+/// Hands a deposit back to `owner` and returns `reason`, noting a failed
+/// refund so the caller knows to use `claim_swap_credit`.
+async fn refund(owner: Principal, to: Account, token: AcceptedToken, amount: u128, reason: String) -> String {
+    with_swap_pool_mut(|pool| pool.credit(owner, token, amount));
+    match pay_credit(owner, to, token).await {
+        Ok(_) => reason,
+        Err(refund_error) => format!("{}; refund failed: {}", reason, refund_error),
+    }
+}
+
+/// Completes a swap whose input already sits in the pool account, paying the
+/// output to `to`. Used by `swap_tokens` and by the custodial wallet.
+pub async fn settle_deposited_swap(
+    owner: Principal,
+    to: Account,
+    token_in: AcceptedToken,
+    amount_in: u128,
+    min_amount_out: u128,
+) -> Result<SwapReceipt, String> {
+    let executed = with_swap_pool_mut(|pool| {
+        let quote = pool.swap(token_in, amount_in, min_amount_out)?;
+        pool.credit(owner, quote.token_out, quote.amount_out);
+        Ok(quote)
+    });
+
+    match executed {
+        Ok(quote) => {
+            let received = pay_credit(owner, to, quote.token_out).await.ok();
+            Ok(SwapReceipt { quote, received })
+        }
+        Err(e) => Err(refund(owner, to, token_in, amount_in, e).await),
+    }
+}
+
+#[query]
+fn get_swap_quote(token_in: AcceptedToken, amount_in: u128) -> Result<SwapQuote, String> {
+    with_swap_pool(|pool| pool.quote(token_in, amount_in))
+}
+
+#[query]
+fn get_liquidity_quote(icp_amount: u128, anima_amount: u128) -> Result<LiquidityReceipt, String> {
+    with_swap_pool(|pool| pool.liquidity_quote(icp_amount, anima_amount))
+}
+
+#[query]
+fn get_swap_pool() -> SwapPoolInfo {
+    with_swap_pool(|pool| pool.info())
+}
+
+#[query]
+fn get_lp_balance(provider: Principal) -> u128 {
+    with_swap_pool(|pool| pool.lp_balance(&provider))
+}
+
+#[query]
+fn get_swap_credits(recipient: Principal) -> Vec<(AcceptedToken, u128)> {
+    with_swap_pool(|pool| {
+        pool.credits.iter()
+            .filter(|((owner, _), _)| *owner == recipient)
+            .map(|((_, token), amount)| (*token, *amount))
+            .collect()
+    })
+}
+
+/// Swaps `amount_in` of `token_in` for the other token. The input is pulled
+/// with an ICRC-2 allowance; the swap is re-priced once it lands and refunded
+/// if the output would fall below `min_amount_out` or `deadline` has passed.
+#[update]
+async fn swap_tokens(
+    token_in: AcceptedToken,
+    amount_in: u128,
+    min_amount_out: u128,
+    deadline: Option<u64>,
+) -> Result<SwapReceipt, String> {
+    let caller = ic_cdk::caller();
+    if deadline.map(|d| time() > d).unwrap_or(false) {
+        return Err("Swap deadline has passed".to_string());
+    }
+    // Reject early rather than pulling funds we would refund straight away.
+    let quote = with_swap_pool(|pool| pool.quote(token_in, amount_in))?;
+    if quote.amount_out < min_amount_out {
+        return Err(format!(
+            "Slippage exceeded: would receive {}, minimum is {}",
+            quote.amount_out, min_amount_out
+        ));
+    }
+
+    pull_into_pool(caller, token_in, amount_in)
+        .await
+        .map_err(|e| format!("Token transfer failed: {}", e))?;
+
+    if deadline.map(|d| time() > d).unwrap_or(false) {
+        let reason = "Swap deadline has passed".to_string();
+        return Err(refund(caller, Account::from(caller), token_in, amount_in, reason).await);
+    }
+    settle_deposited_swap(caller, Account::from(caller), token_in, amount_in, min_amount_out).await
+}
+
+/// Deposits both sides of the pool. Anything beyond the current ratio is
+/// refunded, as is everything if fewer than `min_shares` would be minted.
+#[update]
+async fn add_liquidity(icp_amount: u128, anima_amount: u128, min_shares: u128) -> Result<LiquidityReceipt, String> {
+    let caller = ic_cdk::caller();
+    with_swap_pool(|pool| pool.liquidity_quote(icp_amount, anima_amount))?;
+
+    pull_into_pool(caller, AcceptedToken::ICP, icp_amount)
+        .await
+        .map_err(|e| format!("ICP transfer failed: {}", e))?;
+    if let Err(e) = pull_into_pool(caller, AcceptedToken::ANIMA, anima_amount).await {
+        let reason = format!("ANIMA transfer failed: {}", e);
+        return Err(refund(caller, Account::from(caller), AcceptedToken::ICP, icp_amount, reason).await);
+    }
+
+    let added = with_swap_pool_mut(|pool| {
+        let added = pool.add_liquidity(caller, icp_amount, anima_amount, min_shares);
+        if added.is_err() {
+            pool.credit(caller, AcceptedToken::ICP, icp_amount);
+            pool.credit(caller, AcceptedToken::ANIMA, anima_amount);
+        }
+        added
+    });
+    // Leftovers stay claimable through `claim_swap_credit` if these fail.
+    let _ = pay_credit(caller, Account::from(caller), AcceptedToken::ICP).await;
+    let _ = pay_credit(caller, Account::from(caller), AcceptedToken::ANIMA).await;
+    added
+}
+
+#[update]
+async fn remove_liquidity(shares: u128, min_icp: u128, min_anima: u128) -> Result<(u128, u128), String> {
+    let caller = ic_cdk::caller();
+    let removed = with_swap_pool_mut(|pool| pool.remove_liquidity(caller, shares, min_icp, min_anima))?;
+    let _ = pay_credit(caller, Account::from(caller), AcceptedToken::ICP).await;
+    let _ = pay_credit(caller, Account::from(caller), AcceptedToken::ANIMA).await;
+    Ok(removed)
+}
+
+/// Retries a payout that failed during a swap or liquidity operation.
+#[update]
+async fn claim_swap_credit(token: AcceptedToken) -> Result<u128, String> {
+    let caller = ic_cdk::caller();
+    pay_credit(caller, Account::from(caller), token).await
+}
+
+#[update]
+fn set_swap_config(config: SwapConfig) -> Result<(), String> {
+    require_role(Role::PricingManager, "set_swap_config").map_err(|e| format!("{:?}", e))?;
+    config.validate()?;
+    with_swap_pool_mut(|pool| pool.config = config);
+    Ok(())
+}
+
+/// Sends the accrued protocol fees in `token` to the treasury.
+#[update]
+async fn sweep_protocol_fees(token: AcceptedToken) -> Result<u128, String> {
+    require_role(Role::Treasury, "sweep_protocol_fees").map_err(|e| format!("{:?}", e))?;
+    let treasury = with_swap_pool_mut(|pool| {
+        let fees = pool.take_protocol_fees(token);
+        let treasury = pool.config.treasury.unwrap_or_else(ic_cdk::id);
+        pool.credit(treasury, token, fees);
+        treasury
+    });
+    pay_credit(treasury, Account::from(treasury), token).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stand-in for the two ICRC ledgers: balances per (owner, token), with
+    /// the pool's subaccount modelled as its own owner.
+    #[derive(Default)]
+    struct LocalLedger {
+        balances: BTreeMap<(Principal, AcceptedToken), u128>,
+    }
+
+    impl LocalLedger {
+        fn mint(&mut self, owner: Principal, token: AcceptedToken, amount: u128) {
+            *self.balances.entry((owner, token)).or_default() += amount;
+        }
+
+        fn balance(&self, owner: Principal, token: AcceptedToken) -> u128 {
+            self.balances.get(&(owner, token)).copied().unwrap_or(0)
+        }
+
+        fn transfer(&mut self, from: Principal, to: Principal, token: AcceptedToken, amount: u128) {
+            let balance = self.balances.entry((from, token)).or_default();
+            assert!(*balance >= amount, "insufficient funds");
+            *balance -= amount;
+            self.mint(to, token, amount);
+        }
+
+        /// Pays every credit and protocol fee out of the pool account.
+        fn settle(&mut self, pool: &mut SwapPool) {
+            for ((owner, token), amount) in std::mem::take(&mut pool.credits) {
+                self.transfer(pool_owner(), owner, token, amount);
+            }
+        }
+    }
+
+    fn pool_owner() -> Principal {
+        Principal::from_slice(&[0xff])
+    }
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn assert_solvent(ledger: &LocalLedger, pool: &SwapPool) {
+        for token in [AcceptedToken::ICP, AcceptedToken::ANIMA] {
+            let owed = pool.reserve(token)
+                + pool.protocol_fees.get(&token).copied().unwrap_or(0)
+                + pool.credits.iter().filter(|((_, t), _)| *t == token).map(|(_, a)| a).sum::<u128>();
+            assert!(ledger.balance(pool_owner(), token) >= owed);
+        }
+    }
+
+    fn seeded() -> (LocalLedger, SwapPool) {
+        let mut ledger = LocalLedger::default();
+        let mut pool = SwapPool::default();
+        ledger.mint(user(1), AcceptedToken::ICP, 1_000_000_000);
+        ledger.mint(user(1), AcceptedToken::ANIMA, 100_000_000_000);
+        ledger.transfer(user(1), pool_owner(), AcceptedToken::ICP, 1_000_000_000);
+        ledger.transfer(user(1), pool_owner(), AcceptedToken::ANIMA, 100_000_000_000);
+        pool.add_liquidity(user(1), 1_000_000_000, 100_000_000_000, 0).unwrap();
+        (ledger, pool)
+    }
+
+    #[test]
+    fn test_first_deposit_locks_minimum_liquidity() {
+        let (_, pool) = seeded();
+        assert_eq!(pool.lp_supply, isqrt(1_000_000_000 * 100_000_000_000));
+        assert_eq!(pool.lp_balance(&user(1)), pool.lp_supply - MINIMUM_LIQUIDITY);
+    }
+
+    #[test]
+    fn test_swaps_in_both_directions_keep_k_and_solvency() {
+        let (mut ledger, mut pool) = seeded();
+        ledger.mint(user(2), AcceptedToken::ICP, 50_000_000);
+
+        let k = pool.reserve_icp * pool.reserve_anima;
+        ledger.transfer(user(2), pool_owner(), AcceptedToken::ICP, 50_000_000);
+        let quote = pool.swap(AcceptedToken::ICP, 50_000_000, 0).unwrap();
+        pool.credit(user(2), quote.token_out, quote.amount_out);
+        ledger.settle(&mut pool);
+        assert!(pool.reserve_icp * pool.reserve_anima >= k);
+        assert_solvent(&ledger, &pool);
+
+        let anima = ledger.balance(user(2), AcceptedToken::ANIMA);
+        let k = pool.reserve_icp * pool.reserve_anima;
+        ledger.transfer(user(2), pool_owner(), AcceptedToken::ANIMA, anima);
+        let quote = pool.swap(AcceptedToken::ANIMA, anima, 0).unwrap();
+        pool.credit(user(2), quote.token_out, quote.amount_out);
+        ledger.settle(&mut pool);
+        assert!(pool.reserve_icp * pool.reserve_anima >= k);
+        assert_solvent(&ledger, &pool);
+
+        // A round trip loses to fees, never gains.
+        assert!(ledger.balance(user(2), AcceptedToken::ICP) < 50_000_000);
+        assert_eq!(pool.protocol_fees.len(), 2);
+    }
+
+    #[test]
+    fn test_quote_matches_constant_product() {
+        let (_, pool) = seeded();
+        let quote = pool.quote(AcceptedToken::ICP, 10_000_000).unwrap();
+        assert_eq!(quote.lp_fee, 25_000);
+        assert_eq!(quote.protocol_fee, 5_000);
+        let net_in = 10_000_000 - 30_000;
+        assert_eq!(quote.amount_out, 100_000_000_000 * net_in / (1_000_000_000 + net_in));
+        assert!(quote.price_impact_bps > 0 && quote.price_impact_bps < 100);
+    }
+
+    #[test]
+    fn test_slippage_bound_leaves_pool_untouched() {
+        let (_, mut pool) = seeded();
+        let quote = pool.quote(AcceptedToken::ICP, 10_000_000).unwrap();
+        let before = (pool.reserve_icp, pool.reserve_anima);
+        assert!(pool.swap(AcceptedToken::ICP, 10_000_000, quote.amount_out + 1).is_err());
+        assert_eq!((pool.reserve_icp, pool.reserve_anima), before);
+        assert!(pool.protocol_fees.is_empty());
+    }
+
+    #[test]
+    fn test_unbalanced_deposit_credits_excess() {
+        let (mut ledger, mut pool) = seeded();
+        ledger.mint(user(3), AcceptedToken::ICP, 20_000_000);
+        ledger.mint(user(3), AcceptedToken::ANIMA, 1_000_000_000);
+        ledger.transfer(user(3), pool_owner(), AcceptedToken::ICP, 20_000_000);
+        ledger.transfer(user(3), pool_owner(), AcceptedToken::ANIMA, 1_000_000_000);
+
+        let receipt = pool.add_liquidity(user(3), 20_000_000, 1_000_000_000, 0).unwrap();
+        assert_eq!(receipt.icp_used, 10_000_000);
+        assert_eq!(receipt.anima_used, 1_000_000_000);
+        assert_eq!(pool.credits.get(&(user(3), AcceptedToken::ICP)), Some(&10_000_000));
+        ledger.settle(&mut pool);
+        assert_solvent(&ledger, &pool);
+        assert_eq!(ledger.balance(user(3), AcceptedToken::ICP), 10_000_000);
+    }
+
+    #[test]
+    fn test_remove_liquidity_returns_share_of_reserves() {
+        let (mut ledger, mut pool) = seeded();
+        let shares = pool.lp_balance(&user(1));
+        let (icp, anima) = pool.remove_liquidity(user(1), shares, 0, 0).unwrap();
+        ledger.settle(&mut pool);
+        assert_eq!(pool.lp_supply, MINIMUM_LIQUIDITY);
+        assert_eq!(pool.lp_balance(&user(1)), 0);
+        assert_eq!(ledger.balance(user(1), AcceptedToken::ICP), icp);
+        assert_eq!(ledger.balance(user(1), AcceptedToken::ANIMA), anima);
+        assert!(pool.reserve_icp > 0 && pool.reserve_anima > 0);
+        assert_solvent(&ledger, &pool);
+        assert!(pool.remove_liquidity(user(1), 1, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_empty_pool_cannot_quote() {
+        assert!(SwapPool::default().quote(AcceptedToken::ICP, 1_000).is_err());
+    }
+
+    #[test]
+    fn test_fee_cap() {
+        let config = SwapConfig { lp_fee_bps: 900, protocol_fee_bps: 200, treasury: None };
+        assert!(config.validate().is_err());
+        assert!(SwapConfig::default().validate().is_ok());
+    }
+}
@@ -1,9 +1,29 @@
-use candid::{CandidType, Decode, Encode};
+use candid::CandidType;
 use serde::{Deserialize, Serialize};
 use ic_cdk::api::{time, canister_balance128, performance_counter};
 use std::collections::HashMap;
-
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+use crate::admin::metrics::{heap_memory_bytes, WASM_PAGE_SIZE};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const HOUR: u64 = 60 * 60 * NANOS_PER_SECOND;
+const DAY: u64 = 24 * HOUR;
+/// The wasm heap cannot grow past 4 GiB.
+const MAX_HEAP_PAGES: u64 = 65_536;
+/// Instruction limit of a single update message.
+const MAX_INSTRUCTIONS_PER_MESSAGE: u64 = 20_000_000_000;
+/// Balance samples older than this drop out of the burn rate.
+const BURN_RATE_WINDOW: u64 = HOUR;
+const MAX_BALANCE_SAMPLES: usize = 120;
+/// Latencies kept per call target.
+const MAX_LATENCY_SAMPLES: usize = 50;
+const MAX_ANOMALIES: usize = 1_000;
+/// An anomaly of the same type is not recorded again within this span.
+const ANOMALY_COOLDOWN: u64 = 10 * 60 * NANOS_PER_SECOND;
+/// A reading this many times its baseline counts as a spike.
+const SPIKE_FACTOR: f64 = 3.0;
+const MIN_RUNWAY: u64 = 7 * DAY;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
 pub struct EnvironmentalState {
     pub network_metrics: NetworkMetrics,
     pub resource_metrics: ResourceMetrics,
@@ -11,29 +31,54 @@ pub struct EnvironmentalState {
     pub anomaly_log: Vec<EnvironmentalAnomaly>,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
 pub struct NetworkMetrics {
     pub cycles_balance: u128,
-    pub performance_index: u64,
-    pub network_load: f32,
-    pub active_canisters: u32,
-    pub response_times: HashMap<String, u64>,
+    /// Cycles spent per second over `BURN_RATE_WINDOW`; top-ups are ignored.
+    pub cycles_burn_rate: f64,
+    /// Recent inter-canister call latencies in nanoseconds, oldest first,
+    /// keyed by `canister.method`.
+    pub response_times: HashMap<String, Vec<u64>>,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ResourceMetrics {
+    /// Share of the 4 GiB wasm heap in use.
     pub memory_usage: f32,
-    pub cpu_usage: f32,
+    /// Mean instructions per message as a share of the per-message limit.
+    pub instruction_usage: f32,
+    /// Stable memory in use, in bytes.
     pub storage_used: u64,
-    pub bandwidth_usage: f32,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
 pub struct CanisterMetrics {
+    pub heap_pages: u64,
+    pub stable_pages: u64,
     pub heap_memory: u64,
     pub stable_memory: u64,
-    pub certified_data: Vec<u8>,
-    pub module_hash: Option<Vec<u8>>,
+    /// Instructions used per message, by method.
+    pub message_instructions: HashMap<String, InstructionStats>,
+    /// (timestamp, cycles balance) samples inside the burn rate window.
+    pub balance_samples: Vec<(u64, u128)>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct InstructionStats {
+    pub messages: u64,
+    pub total: u64,
+    pub max: u64,
+    pub last: u64,
+}
+
+impl InstructionStats {
+    pub fn mean(&self) -> f64 {
+        if self.messages == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.messages as f64
+        }
+    }
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -44,7 +89,7 @@ pub struct EnvironmentalAnomaly {
     pub description: String,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum AnomalyType {
     NetworkSpike,
     ResourceDepletion,
@@ -52,37 +97,102 @@ pub enum AnomalyType {
     UnusualActivity,
 }
 
-impl EnvironmentalState {
-    pub fn new() -> Self {
+/// One reading of the canister's resources.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResourceSample {
+    pub timestamp: u64,
+    pub cycles_balance: u128,
+    pub heap_pages: u64,
+    pub stable_pages: u64,
+}
+
+impl ResourceSample {
+    pub fn now() -> Self {
         Self {
-            network_metrics: NetworkMetrics {
-                cycles_balance: canister_balance128(),
-                performance_index: performance_counter(),
-                network_load: 0.0,
-                active_canisters: 0,
-                response_times: HashMap::new(),
-            },
-            resource_metrics: ResourceMetrics {
-                memory_usage: 0.0,
-                cpu_usage: 0.0,
-                storage_used: 0,
-                bandwidth_usage: 0.0,
-            },
-            canister_metrics: CanisterMetrics {
-                heap_memory: 0,
-                stable_memory: 0,
-                certified_data: Vec::new(),
-                module_hash: None,
-            },
-            anomaly_log: Vec::new(),
+            timestamp: time(),
+            cycles_balance: canister_balance128(),
+            heap_pages: heap_memory_bytes() / WASM_PAGE_SIZE,
+            stable_pages: ic_cdk::api::stable::stable64_size(),
         }
     }
+}
 
+impl EnvironmentalState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Samples the canister's resources and checks them for anomalies.
     pub fn update(&mut self) {
-        self.update_network_metrics();
-        self.update_resource_metrics();
-        self.update_canister_metrics();
-        self.detect_anomalies();
+        self.apply_sample(ResourceSample::now());
+    }
+
+    pub fn apply_sample(&mut self, sample: ResourceSample) {
+        let canister = &mut self.canister_metrics;
+        canister.heap_pages = sample.heap_pages;
+        canister.stable_pages = sample.stable_pages;
+        canister.heap_memory = sample.heap_pages * WASM_PAGE_SIZE;
+        canister.stable_memory = sample.stable_pages * WASM_PAGE_SIZE;
+        canister.balance_samples.push((sample.timestamp, sample.cycles_balance));
+        let oldest = sample.timestamp.saturating_sub(BURN_RATE_WINDOW);
+        canister.balance_samples.retain(|(at, _)| *at >= oldest);
+        if canister.balance_samples.len() > MAX_BALANCE_SAMPLES {
+            let excess = canister.balance_samples.len() - MAX_BALANCE_SAMPLES;
+            canister.balance_samples.drain(..excess);
+        }
+
+        self.network_metrics.cycles_balance = sample.cycles_balance;
+        self.network_metrics.cycles_burn_rate = burn_rate(&self.canister_metrics.balance_samples);
+        self.resource_metrics.memory_usage = sample.heap_pages as f32 / MAX_HEAP_PAGES as f32;
+        self.resource_metrics.storage_used = self.canister_metrics.stable_memory;
+        self.resource_metrics.instruction_usage = self.mean_instructions() as f32 / MAX_INSTRUCTIONS_PER_MESSAGE as f32;
+
+        self.detect_anomalies(sample.timestamp);
+    }
+
+    /// Records the instructions the current message has used so far; call at
+    /// the end of the message being measured.
+    pub fn record_message(&mut self, method: &str) {
+        self.record_instructions(method, performance_counter(0), time());
+    }
+
+    pub fn record_instructions(&mut self, method: &str, instructions: u64, now: u64) {
+        let stats = self.canister_metrics.message_instructions.entry(method.to_string()).or_default();
+        let baseline = stats.mean();
+        let spike = stats.messages > 0 && instructions as f64 > baseline * SPIKE_FACTOR;
+        stats.messages += 1;
+        stats.total = stats.total.saturating_add(instructions);
+        stats.max = stats.max.max(instructions);
+        stats.last = instructions;
+        if spike {
+            self.record_anomaly(
+                AnomalyType::PerformanceDrop,
+                0.6,
+                format!("{} used {} instructions against a mean of {:.0}", method, instructions, baseline),
+                now,
+            );
+        }
+    }
+
+    /// Records how long a call to `target` took.
+    pub fn record_call_latency(&mut self, target: &str, started_at: u64, now: u64) {
+        let latency = now.saturating_sub(started_at);
+        let samples = self.network_metrics.response_times.entry(target.to_string()).or_default();
+        let baseline = median(samples);
+        samples.push(latency);
+        if samples.len() > MAX_LATENCY_SAMPLES {
+            samples.remove(0);
+        }
+        if let Some(baseline) = baseline {
+            if baseline > 0 && latency as f64 > baseline as f64 * SPIKE_FACTOR {
+                self.record_anomaly(
+                    AnomalyType::NetworkSpike,
+                    0.5,
+                    format!("{} took {}ms against a median of {}ms", target, latency / 1_000_000, baseline / 1_000_000),
+                    now,
+                );
+            }
+        }
     }
 
     pub fn get_environmental_context(&self) -> EnvironmentalContext {
@@ -90,157 +200,135 @@ impl EnvironmentalState {
             timestamp: time(),
             network_health: self.calculate_network_health(),
             resource_availability: self.calculate_resource_availability(),
-            recent_anomalies: self.get_recent_anomalies(),
+            recent_anomalies: self.get_recent_anomalies().into_iter().cloned().collect(),
             performance_status: self.get_performance_status(),
         }
     }
 
-    fn update_network_metrics(&mut self) {
-        self.network_metrics.cycles_balance = canister_balance128();
-        self.network_metrics.performance_index = performance_counter();
-        // Update other network metrics
-        self.update_response_times();
-    }
-
-    fn update_resource_metrics(&mut self) {
-        // Get current heap memory usage
-        let heap_size = ic_cdk::api::stable::stable64_size() as f32;
-        let max_heap = 2u64.pow(32) as f32; // 4GB max
-        self.resource_metrics.memory_usage = heap_size / max_heap;
-
-        // Estimate CPU usage based on performance counter
-        let perf_counter = performance_counter() as f32;
-        self.resource_metrics.cpu_usage = (perf_counter % 100.0) / 100.0;
+    /// Seconds of cycles left at the current burn rate.
+    pub fn cycles_runway(&self) -> Option<u64> {
+        let rate = self.network_metrics.cycles_burn_rate;
+        if rate <= 0.0 {
+            None
+        } else {
+            Some((self.network_metrics.cycles_balance as f64 / rate) as u64)
+        }
     }
 
-    fn update_canister_metrics(&mut self) {
-        self.canister_metrics.heap_memory = ic_cdk::api::stable::stable64_size();
-        self.canister_metrics.stable_memory = ic_cdk::api::stable::stable64_size();
+    fn mean_instructions(&self) -> f64 {
+        let (messages, total) = self.canister_metrics.message_instructions.values()
+            .fold((0u64, 0u64), |(m, t), stats| (m + stats.messages, t.saturating_add(stats.total)));
+        if messages == 0 {
+            0.0
+        } else {
+            total as f64 / messages as f64
+        }
     }
 
-    fn detect_anomalies(&mut self) {
-        // Check for network anomalies
-        if self.network_metrics.network_load > 0.9 {
-            self.record_anomaly(AnomalyType::NetworkSpike, 0.8, 
-                "High network load detected");
+    fn detect_anomalies(&mut self, now: u64) {
+        if self.resource_metrics.memory_usage > 0.85 {
+            self.record_anomaly(
+                AnomalyType::ResourceDepletion,
+                0.7,
+                format!("Heap at {:.0}% of capacity", self.resource_metrics.memory_usage * 100.0),
+                now,
+            );
         }
 
-        // Check for resource issues
-        if self.resource_metrics.memory_usage > 0.85 {
-            self.record_anomaly(AnomalyType::ResourceDepletion, 0.7,
-                "Memory usage nearing capacity");
+        if let Some(runway) = self.cycles_runway() {
+            if runway.saturating_mul(NANOS_PER_SECOND) < MIN_RUNWAY {
+                self.record_anomaly(
+                    AnomalyType::ResourceDepletion,
+                    0.9,
+                    format!("Cycles run out in {}h at the current burn rate", runway / 3600),
+                    now,
+                );
+            }
         }
 
-        // Check performance
-        if self.calculate_performance_drop() > 0.3 {
-            self.record_anomaly(AnomalyType::PerformanceDrop, 0.6,
-                "Significant performance degradation");
+        // Burn over the latest interval against the window's average.
+        let samples = &self.canister_metrics.balance_samples;
+        if samples.len() >= 3 {
+            let latest = burn_rate(&samples[samples.len() - 2..]);
+            let average = self.network_metrics.cycles_burn_rate;
+            if average > 0.0 && latest > average * SPIKE_FACTOR {
+                self.record_anomaly(
+                    AnomalyType::UnusualActivity,
+                    0.6,
+                    format!("Burning {:.0} cycles/s against an average of {:.0}", latest, average),
+                    now,
+                );
+            }
         }
     }
 
-    fn record_anomaly(&mut self, anomaly_type: AnomalyType, severity: f32, description: &str) {
+    fn record_anomaly(&mut self, anomaly_type: AnomalyType, severity: f32, description: String, now: u64) {
+        let recent = self.anomaly_log.iter().rev()
+            .take_while(|anomaly| now.saturating_sub(anomaly.timestamp) < ANOMALY_COOLDOWN)
+            .any(|anomaly| anomaly.anomaly_type == anomaly_type);
+        if recent {
+            return;
+        }
         self.anomaly_log.push(EnvironmentalAnomaly {
-            timestamp: time(),
+            timestamp: now,
             anomaly_type,
             severity,
-            description: description.to_string(),
+            description,
         });
 
-        // Keep log size manageable
-        if self.anomaly_log.len() > 1000 {
+        if self.anomaly_log.len() > MAX_ANOMALIES {
             self.anomaly_log.remove(0);
         }
     }
 
     fn calculate_network_health(&self) -> f32 {
-        let cycle_health = if self.network_metrics.cycles_balance > 1_000_000 { 1.0 } else { 0.5 };
-        let load_health = 1.0 - self.network_metrics.network_load;
-        let response_health = self.calculate_response_health();
-        
-        (cycle_health + load_health + response_health) / 3.0
+        let cycle_health = match self.get_cycles_status() {
+            CyclesStatus::Healthy => 1.0,
+            CyclesStatus::Warning => 0.5,
+            CyclesStatus::Critical => 0.0,
+        };
+        (cycle_health + self.calculate_response_health()) / 2.0
     }
 
     fn calculate_resource_availability(&self) -> f32 {
         let memory_avail = 1.0 - self.resource_metrics.memory_usage;
-        let cpu_avail = 1.0 - self.resource_metrics.cpu_usage;
-        let storage_health = self.calculate_storage_health();
-        
-        (memory_avail + cpu_avail + storage_health) / 3.0
-    }
-
-    fn calculate_performance_drop(&self) -> f32 {
-        let current_perf = self.network_metrics.performance_index as f32;
-        let baseline_perf = self.get_baseline_performance() as f32;
-        
-        if baseline_perf == 0.0 {
-            0.0
-        } else {
-            (baseline_perf - current_perf) / baseline_perf
-        }
+        let instruction_avail = 1.0 - self.resource_metrics.instruction_usage.min(1.0);
+        (memory_avail + instruction_avail) / 2.0
     }
 
-    fn get_recent_anomalies(&self) -> Vec<&EnvironmentalAnomaly> {
-        let current_time = time();
-        let threshold = 3600 * 24; // Last 24 hours
-        
+    pub fn get_recent_anomalies(&self) -> Vec<&EnvironmentalAnomaly> {
+        let since = time().saturating_sub(DAY);
         self.anomaly_log
             .iter()
-            .filter(|anomaly| current_time - anomaly.timestamp < threshold)
+            .filter(|anomaly| anomaly.timestamp >= since)
             .collect()
     }
 
     fn get_performance_status(&self) -> PerformanceStatus {
         PerformanceStatus {
-            current_load: self.network_metrics.network_load,
+            current_load: self.resource_metrics.instruction_usage,
             cycles_status: self.get_cycles_status(),
             memory_status: self.get_memory_status(),
             performance_trend: self.calculate_performance_trend(),
         }
     }
 
-    fn update_response_times(&mut self) {
-        // Update response times based on recent interactions
-        let current_time = time();
-        self.network_metrics.response_times.retain(|_, &mut last_time| {
-            current_time - last_time < 3600 // Keep last hour of data
-        });
-    }
-
+    /// Mean latency across targets mapped onto 0-1, a second scoring 0.5.
     fn calculate_response_health(&self) -> f32 {
-        if self.network_metrics.response_times.is_empty() {
+        let latencies: Vec<u64> = self.network_metrics.response_times.values().flatten().copied().collect();
+        if latencies.is_empty() {
             return 1.0;
         }
-
-        let avg_response = self.network_metrics.response_times.values()
-            .sum::<u64>() as f32 / self.network_metrics.response_times.len() as f32;
-        
-        1.0 / (1.0 + avg_response / 1_000.0) // Normalize to 0-1
-    }
-
-    fn calculate_storage_health(&self) -> f32 {
-        let max_storage = 2u64.pow(32); // 4GB max
-        1.0 - (self.resource_metrics.storage_used as f32 / max_storage as f32)
-    }
-
-    fn get_baseline_performance(&self) -> u64 {
-        // Use 95th percentile of recent performance as baseline
-        let mut recent_perfs = vec![self.network_metrics.performance_index];
-        let index = (recent_perfs.len() as f32 * 0.95) as usize;
-        if let Some(&baseline) = recent_perfs.get(index) {
-            baseline
-        } else {
-            self.network_metrics.performance_index
-        }
+        let mean_secs = latencies.iter().sum::<u64>() as f32 / latencies.len() as f32 / NANOS_PER_SECOND as f32;
+        1.0 / (1.0 + mean_secs)
     }
 
     fn get_cycles_status(&self) -> CyclesStatus {
-        let balance = self.network_metrics.cycles_balance;
-        if balance > 10_000_000 {
-            CyclesStatus::Healthy
-        } else if balance > 1_000_000 {
-            CyclesStatus::Warning
-        } else {
-            CyclesStatus::Critical
+        match self.cycles_runway() {
+            None => CyclesStatus::Healthy,
+            Some(runway) if runway.saturating_mul(NANOS_PER_SECOND) > 30 * DAY => CyclesStatus::Healthy,
+            Some(runway) if runway.saturating_mul(NANOS_PER_SECOND) > MIN_RUNWAY => CyclesStatus::Warning,
+            Some(_) => CyclesStatus::Critical,
         }
     }
 
@@ -255,11 +343,15 @@ impl EnvironmentalState {
         }
     }
 
+    /// Compares each method's latest message with its mean.
     fn calculate_performance_trend(&self) -> PerformanceTrend {
-        let drop = self.calculate_performance_drop();
-        if drop < 0.1 {
+        let worst = self.canister_metrics.message_instructions.values()
+            .filter(|stats| stats.messages > 1 && stats.mean() > 0.0)
+            .map(|stats| stats.last as f64 / stats.mean())
+            .fold(1.0, f64::max);
+        if worst < 1.5 {
             PerformanceTrend::Stable
-        } else if drop < 0.3 {
+        } else if worst < SPIKE_FACTOR {
             PerformanceTrend::Declining
         } else {
             PerformanceTrend::Critical
@@ -267,12 +359,38 @@ impl EnvironmentalState {
     }
 }
 
+/// Cycles spent per second across `samples`, counting only decreases so a
+/// top-up does not read as negative burn.
+fn burn_rate(samples: &[(u64, u128)]) -> f64 {
+    let (Some((first_at, _)), Some((last_at, _))) = (samples.first(), samples.last()) else {
+        return 0.0;
+    };
+    let elapsed = last_at.saturating_sub(*first_at);
+    if elapsed == 0 {
+        return 0.0;
+    }
+    let spent: u128 = samples.iter()
+        .zip(samples.iter().skip(1))
+        .map(|((_, before), (_, after))| before.saturating_sub(*after))
+        .sum();
+    spent as f64 / (elapsed as f64 / NANOS_PER_SECOND as f64)
+}
+
+fn median(samples: &[u64]) -> Option<u64> {
+    if samples.is_empty() {
+        return None;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_unstable();
+    Some(sorted[sorted.len() / 2])
+}
+
 #[derive(Clone, Debug)]
 pub struct EnvironmentalContext {
     pub timestamp: u64,
     pub network_health: f32,
     pub resource_availability: f32,
-    pub recent_anomalies: Vec<&'static EnvironmentalAnomaly>,
+    pub recent_anomalies: Vec<EnvironmentalAnomaly>,
     pub performance_status: PerformanceStatus,
 }
 
@@ -284,23 +402,98 @@ pub struct PerformanceStatus {
     pub performance_trend: PerformanceTrend,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum CyclesStatus {
     Healthy,
     Warning,
     Critical,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum MemoryStatus {
     Healthy,
     Warning,
     Critical,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PerformanceTrend {
     Stable,
     Declining,
     Critical,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60 * NANOS_PER_SECOND;
+
+    fn sample(minute: u64, cycles_balance: u128) -> ResourceSample {
+        ResourceSample {
+            timestamp: minute * MINUTE,
+            cycles_balance,
+            heap_pages: 1_024,
+            stable_pages: 16,
+        }
+    }
+
+    #[test]
+    fn test_memory_reported_from_pages() {
+        let mut state = EnvironmentalState::new();
+        state.apply_sample(sample(0, 1_000_000));
+        assert_eq!(state.canister_metrics.heap_memory, 64 * 1024 * 1024);
+        assert_eq!(state.canister_metrics.stable_memory, 1024 * 1024);
+        assert_eq!(state.resource_metrics.memory_usage, 1_024.0 / 65_536.0);
+    }
+
+    #[test]
+    fn test_burn_rate_ignores_top_ups() {
+        let mut state = EnvironmentalState::new();
+        state.apply_sample(sample(0, 1_000_000));
+        state.apply_sample(sample(1, 940_000));
+        state.apply_sample(sample(2, 5_000_000));
+        state.apply_sample(sample(3, 4_940_000));
+        // 120k spent over three minutes.
+        assert!((state.network_metrics.cycles_burn_rate - 120_000.0 / 180.0).abs() < 1e-9);
+
+        // Samples older than the window drop out.
+        state.apply_sample(sample(90, 4_940_000));
+        assert_eq!(state.canister_metrics.balance_samples.len(), 1);
+        assert_eq!(state.network_metrics.cycles_burn_rate, 0.0);
+    }
+
+    #[test]
+    fn test_short_runway_is_an_anomaly() {
+        let mut state = EnvironmentalState::new();
+        state.apply_sample(sample(0, 1_000_000_000));
+        state.apply_sample(sample(60, 1_000_000_000 - 360_000));
+        assert_eq!(state.cycles_runway(), Some(9_996_400));
+        assert_eq!(state.get_cycles_status(), CyclesStatus::Healthy);
+
+        let mut draining = EnvironmentalState::new();
+        draining.apply_sample(sample(0, 1_000_000_000));
+        draining.apply_sample(sample(60, 500_000_000));
+        assert_eq!(draining.get_cycles_status(), CyclesStatus::Critical);
+        assert_eq!(draining.anomaly_log.len(), 1);
+        assert_eq!(draining.anomaly_log[0].anomaly_type, AnomalyType::ResourceDepletion);
+    }
+
+    #[test]
+    fn test_latency_and_instruction_spikes() {
+        let mut state = EnvironmentalState::new();
+        for i in 0..5 {
+            state.record_call_latency("ledger.icrc1_transfer", 0, 2 * NANOS_PER_SECOND);
+            state.record_instructions("mint_anima", 1_000_000, i);
+        }
+        assert!(state.anomaly_log.is_empty());
+        assert_eq!(state.calculate_performance_trend(), PerformanceTrend::Stable);
+
+        state.record_call_latency("ledger.icrc1_transfer", 0, 10 * NANOS_PER_SECOND);
+        state.record_instructions("mint_anima", 5_000_000, 10);
+        let types: Vec<_> = state.anomaly_log.iter().map(|a| a.anomaly_type).collect();
+        assert_eq!(types, vec![AnomalyType::NetworkSpike, AnomalyType::PerformanceDrop]);
+        assert_eq!(state.calculate_performance_trend(), PerformanceTrend::Critical);
+        assert_eq!(state.network_metrics.response_times["ledger.icrc1_transfer"].len(), 6);
+    }
+}
@@ -7,7 +7,10 @@ use crate::consciousness::types::{
 use std::collections::{HashMap, HashSet};
 use crate::quantum::QuantumState;
 use crate::error::Result;
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
 
+#[derive(Debug, Clone, CandidType, Deserialize, Serialize)]
 pub struct ConsciousnessEvolution {
     pub current_stage: EvolutionStage,
     pub metrics: EnhancedEvolutionMetrics,
@@ -3,6 +3,7 @@ pub mod types;
 
 use crate::error::Result;
 use crate::quantum::QuantumState;
+use crate::stable::{RegionKey, StableRegion, EVOLUTIONS_MEMORY_ID};
 
 pub use types::{
     ConsciousnessState,
@@ -20,6 +21,27 @@ thread_local! {
         std::cell::RefCell::new(std::collections::HashMap::new());
 }
 
+const EVOLUTIONS_REGION: StableRegion<evolution::ConsciousnessEvolution> = StableRegion::new(EVOLUTIONS_MEMORY_ID);
+
+pub fn save_stable() -> Result<()> {
+    EVOLUTIONS.with(|evolutions| {
+        let evolutions = evolutions.borrow();
+        EVOLUTIONS_REGION.save(evolutions.iter().map(|(anima_id, evolution)| {
+            (RegionKey::singleton(anima_id), evolution)
+        }))
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    let entries = EVOLUTIONS_REGION.load()?;
+    EVOLUTIONS.with(|evolutions| {
+        let mut evolutions = evolutions.borrow_mut();
+        evolutions.clear();
+        evolutions.extend(entries.into_iter().map(|(key, evolution)| (key.id, evolution)));
+        Ok(())
+    })
+}
+
 pub fn get_consciousness_state(anima_id: &str) -> Result<ConsciousnessState> {
     EVOLUTIONS.with(|evolutions| {
         let evolutions = evolutions.borrow();
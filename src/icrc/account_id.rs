@@ -0,0 +1,79 @@
+use candid::Principal;
+use sha2::{Digest, Sha224};
+use crate::icrc::types::{Subaccount, DEFAULT_SUBACCOUNT};
+
+const ACCOUNT_DOMAIN_SEPARATOR: &[u8] = b"\x0Aaccount-id";
+
+/// Legacy ICP ledger account identifier: a CRC32 checksum followed by the
+/// SHA-224 of the owner and subaccount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AccountIdentifier([u8; 32]);
+
+impl AccountIdentifier {
+    pub fn new(owner: &Principal, subaccount: Option<&Subaccount>) -> Self {
+        let mut hasher = Sha224::new();
+        hasher.update(ACCOUNT_DOMAIN_SEPARATOR);
+        hasher.update(owner.as_slice());
+        hasher.update(subaccount.unwrap_or(&DEFAULT_SUBACCOUNT));
+        let hash = hasher.finalize();
+
+        let mut bytes = [0u8; 32];
+        bytes[..4].copy_from_slice(&crc32(&hash).to_be_bytes());
+        bytes[4..].copy_from_slice(&hash);
+        Self(bytes)
+    }
+
+    /// Parses the 32-byte form used on the wire, rejecting a bad checksum.
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        let bytes: [u8; 32] = bytes.try_into().ok()?;
+        if bytes[..4] != crc32(&bytes[4..]).to_be_bytes() {
+            return None;
+        }
+        Some(Self(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.0)
+    }
+}
+
+/// CRC-32 (IEEE 802.3), as used by the ICP ledger for account checksums.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_anonymous_default_account() {
+        let account = AccountIdentifier::new(&Principal::anonymous(), None);
+        assert_eq!(account.to_hex(), "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79");
+        assert_eq!(AccountIdentifier::from_slice(account.as_bytes()), Some(account));
+    }
+
+    #[test]
+    fn test_bad_checksum_rejected() {
+        let mut bytes = *AccountIdentifier::new(&Principal::anonymous(), None).as_bytes();
+        bytes[0] ^= 1;
+        assert!(AccountIdentifier::from_slice(&bytes).is_none());
+    }
+}
@@ -35,7 +35,6 @@ pub enum TokenOperation {
     Mint,
     Burn,
     Transfer,
-    SwapMint,
 }
 
 impl TokenState {
@@ -74,26 +73,6 @@ impl TokenState {
         Ok(())
     }
 
-    pub fn mint_for_swap(&mut self, to: Principal, amount: Nat, from_icp: Nat) -> Result<(), String> {
-        if caller() != self.metadata.minter {
-            return Err("Only minter can mint tokens".to_string());
-        }
-
-        let balance = self.balances.entry(to).or_insert(Nat::from(0));
-        *balance += &amount;
-        self.metadata.total_supply += &amount;
-
-        self.transactions.push(Transaction {
-            from: self.metadata.minter,
-            to,
-            amount: amount.clone(),
-            timestamp: time(),
-            operation: TokenOperation::SwapMint,
-        });
-
-        Ok(())
-    }
-
     pub fn burn(&mut self, from: Principal, amount: Nat) -> Result<(), String> {
         let balance = self.balances.get_mut(&from)
             .ok_or("No balance for account".to_string())?;
@@ -177,63 +156,15 @@ impl TokenState {
     }
 }
 
-#[derive(Clone, Debug, CandidType, Serialize, Deserialize)]
-pub struct SwapConfig {
-    pub min_icp_amount: Nat,
-    pub max_icp_amount: Nat,
-    pub rate: Nat, // ICP to ANIMA conversion rate (e.g., 1 ICP = 100 ANIMA)
-    pub fee_percentage: u32, // In basis points (e.g., 50 = 0.5%)
-}
-
-impl Default for SwapConfig {
-    fn default() -> Self {
-        Self {
-            min_icp_amount: Nat::from(100_000), // 0.001 ICP
-            max_icp_amount: Nat::from(1_000_000_000), // 10 ICP
-            rate: Nat::from(100), // 1 ICP = 100 ANIMA
-            fee_percentage: 50, // 0.5%
-        }
-    }
-}
-
+/// ICP/ANIMA swaps are priced and settled by `anima_token::swap::pool`.
 pub struct ANIMATokenService {
     pub state: TokenState,
-    pub swap_config: SwapConfig,
 }
 
 impl ANIMATokenService {
     pub fn new(minter: Principal) -> Self {
         Self {
             state: TokenState::new(minter),
-            swap_config: SwapConfig::default(),
         }
     }
-
-    pub async fn process_swap(&mut self, from: Principal, icp_amount: Nat) -> Result<Nat, String> {
-        // Validate swap amount
-        if icp_amount < self.swap_config.min_icp_amount {
-            return Err("Amount below minimum".to_string());
-        }
-        if icp_amount > self.swap_config.max_icp_amount {
-            return Err("Amount above maximum".to_string());
-        }
-
-        // Calculate ANIMA amount
-        let fee = (&icp_amount * Nat::from(self.swap_config.fee_percentage)) / Nat::from(10000);
-        let net_icp = icp_amount - fee;
-        let anima_amount = &net_icp * &self.swap_config.rate;
-
-        // Mint ANIMA tokens
-        self.state.mint_for_swap(from, anima_amount.clone(), icp_amount)?;
-
-        Ok(anima_amount)
-    }
-
-    pub fn update_swap_config(&mut self, config: SwapConfig) -> Result<(), String> {
-        if caller() != self.state.metadata.minter {
-            return Err("Only minter can update swap config".to_string());
-        }
-        self.swap_config = config;
-        Ok(())
-    }
-}
\ No newline at end of file
+}
@@ -0,0 +1,151 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::CallResult;
+use serde::Serialize;
+use crate::error::{AnimaError, Result};
+use crate::icrc::types::{Account, Subaccount};
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+pub async fn icrc1_fee(ledger: Principal) -> Result<Nat> {
+    let result: CallResult<(Nat,)> = ic_cdk::call(ledger, "icrc1_fee", ()).await;
+    result.map(|(fee,)| fee).map_err(AnimaError::from)
+}
+
+pub async fn icrc1_total_supply(ledger: Principal) -> Result<Nat> {
+    let result: CallResult<(Nat,)> = ic_cdk::call(ledger, "icrc1_total_supply", ()).await;
+    result.map(|(supply,)| supply).map_err(AnimaError::from)
+}
+
+pub async fn icrc1_balance_of(ledger: Principal, account: Account) -> Result<Nat> {
+    let result: CallResult<(Nat,)> = ic_cdk::call(ledger, "icrc1_balance_of", (account,)).await;
+    result.map(|(balance,)| balance).map_err(AnimaError::from)
+}
+
+/// Sends `amount` from one of this canister's subaccounts. Returns the block index.
+pub async fn icrc1_transfer(
+    ledger: Principal,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: Nat,
+    memo: Option<Vec<u8>>,
+) -> Result<Nat> {
+    icrc1_transfer_at(ledger, from_subaccount, to, amount, memo, ic_cdk::api::time()).await
+}
+
+/// `icrc1_transfer` with a fixed `created_at_time`, so a retry of the same
+/// transfer is deduplicated by the ledger instead of paying twice. A
+/// duplicate resolves to the block of the original transfer.
+pub async fn icrc1_transfer_at(
+    ledger: Principal,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: u64,
+) -> Result<Nat> {
+    let args = TransferArg {
+        from_subaccount,
+        to,
+        amount,
+        fee: None,
+        memo,
+        created_at_time: Some(created_at_time),
+    };
+    let result: CallResult<(std::result::Result<Nat, TransferError>,)> =
+        ic_cdk::call(ledger, "icrc1_transfer", (args,)).await;
+
+    match result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(TransferError::Duplicate { duplicate_of }),)) => Ok(duplicate_of),
+        Ok((Err(TransferError::InsufficientFunds { .. }),)) => Err(AnimaError::InsufficientBalance),
+        Ok((Err(TransferError::TemporarilyUnavailable),)) => Err(AnimaError::NetworkError("Ledger temporarily unavailable".to_string())),
+        // Past the deduplication window the ledger can no longer say whether
+        // an earlier attempt landed.
+        Ok((Err(TransferError::TooOld),)) => Err(AnimaError::TimeoutError),
+        Ok((Err(e),)) => Err(AnimaError::TransactionFailed(format!("{:?}", e))),
+        Err(e) => Err(AnimaError::from(e)),
+    }
+}
+
+/// Whether a failed ledger call certainly moved no funds. Network errors and
+/// timeouts leave the outcome unknown; retry those with the same
+/// `created_at_time` instead of treating the funds as unspent.
+pub fn is_definite_rejection(error: &AnimaError) -> bool {
+    !matches!(error, AnimaError::NetworkError(_) | AnimaError::TimeoutError)
+}
+
+/// Pulls `amount` from `from` into `to` using an ICRC-2 allowance granted to
+/// this canister. Returns the block index.
+pub async fn icrc2_transfer_from(
+    ledger: Principal,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    memo: Option<Vec<u8>>,
+) -> Result<Nat> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from,
+        to,
+        amount,
+        fee: None,
+        memo,
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let result: CallResult<(std::result::Result<Nat, TransferFromError>,)> =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await;
+
+    match result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(TransferFromError::InsufficientFunds { .. }),)) => Err(AnimaError::InsufficientBalance),
+        Ok((Err(TransferFromError::InsufficientAllowance { allowance }),)) => Err(AnimaError::PaymentFailed(
+            format!("Insufficient allowance: {}", allowance)
+        )),
+        Ok((Err(e),)) => Err(AnimaError::PaymentFailed(format!("{:?}", e))),
+        Err(e) => Err(AnimaError::from(e)),
+    }
+}
@@ -1,16 +1,28 @@
-use candid::{Nat, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
 use ic_cdk::api::call::CallResult;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
 use crate::error::{Result, AnimaError};
-use crate::icrc::types::TransactionDetails;
+use crate::icrc::account_id::AccountIdentifier;
+use crate::icrc::types::Subaccount;
+use crate::stable::{get_memory, Memory, CONSUMED_PAYMENTS_MEMORY_ID};
 
 pub const LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
 
+/// Memo a mint payment must carry ("ANIMA").
+pub const MINT_PAYMENT_MEMO: u64 = 0x41_4E_49_4D_41;
+/// Subaccount of this canister that mint payments are sent to.
+pub const MINT_PAYMENT_SUBACCOUNT: Subaccount = *b"anima-mint-payments\0\0\0\0\0\0\0\0\0\0\0\0\0";
+
 #[derive(Debug)]
 pub enum PaymentVerificationError {
     InsufficientFunds,
     TransferFailed(String),
     InvalidAmount,
     Timeout,
+    BlockNotFound(u64),
+    AlreadyUsed(u64),
+    Mismatch(String),
 }
 
 impl From<PaymentVerificationError> for AnimaError {
@@ -20,72 +32,260 @@ impl From<PaymentVerificationError> for AnimaError {
             PaymentVerificationError::TransferFailed(msg) => AnimaError::PaymentFailed(msg),
             PaymentVerificationError::InvalidAmount => AnimaError::InvalidAmount("Invalid payment amount".to_string()),
             PaymentVerificationError::Timeout => AnimaError::PaymentTimeout,
+            PaymentVerificationError::BlockNotFound(block) => AnimaError::PaymentFailed(format!("Ledger block {} not found", block)),
+            PaymentVerificationError::AlreadyUsed(block) => AnimaError::PaymentFailed(format!("Payment block {} was already used", block)),
+            PaymentVerificationError::Mismatch(msg) => AnimaError::PaymentFailed(msg),
         }
     }
 }
 
-pub async fn verify_icp_transfer(
-    from: Principal,
-    amount: &Nat,
-    block_height: Option<u64>,
-) -> Result<bool> {
-    validate_payment_amount(amount, &get_required_amount())?;
-
-    let block = match block_height {
-        Some(h) => h,
-        None => {
-            let ledger = Principal::from_text(LEDGER_CANISTER_ID)
-                .map_err(|_| AnimaError::InvalidCanister)?;
-
-            let result: CallResult<(Nat,)> = ic_cdk::api::call::call(
-                ledger,
-                "icrc1_total_supply",
-                (),
-            ).await;
-
-            match result {
-                Ok((height,)) => {
-                    let height_str = height.to_string();
-                    height_str.parse::<u64>()
-                        .map_err(|_| AnimaError::PaymentFailed("Invalid block height".to_string()))?
-                },
-                Err((_, msg)) => return Err(AnimaError::PaymentFailed(msg))
-            }
+// Subset of the ICP ledger's block interface needed to read a transfer back.
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Tokens {
+    pub e8s: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TimeStamp {
+    pub timestamp_nanos: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Operation {
+    Mint { to: Vec<u8>, amount: Tokens },
+    Burn { from: Vec<u8>, amount: Tokens },
+    Transfer { from: Vec<u8>, to: Vec<u8>, amount: Tokens, fee: Tokens },
+    Approve { from: Vec<u8>, spender: Vec<u8> },
+    TransferFrom { from: Vec<u8>, to: Vec<u8>, spender: Vec<u8>, amount: Tokens, fee: Tokens },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Transaction {
+    pub memo: u64,
+    pub icrc1_memo: Option<Vec<u8>>,
+    pub operation: Option<Operation>,
+    pub created_at_time: TimeStamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Block {
+    pub parent_hash: Option<Vec<u8>>,
+    pub transaction: Transaction,
+    pub timestamp: TimeStamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: u64,
+    pub length: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockRange {
+    pub blocks: Vec<Block>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum GetBlocksError {
+    BadFirstBlockIndex { requested_index: u64, first_valid_index: u64 },
+    Other { error_code: u64, error_message: String },
+}
+
+pub type QueryArchiveResult = std::result::Result<BlockRange, GetBlocksError>;
+
+candid::define_function!(pub QueryArchiveFn : (GetBlocksArgs) -> (QueryArchiveResult) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocksRange {
+    pub start: u64,
+    pub length: u64,
+    pub callback: QueryArchiveFn,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct QueryBlocksResponse {
+    pub chain_length: u64,
+    pub certificate: Option<Vec<u8>>,
+    pub blocks: Vec<Block>,
+    pub first_block_index: u64,
+    pub archived_blocks: Vec<ArchivedBlocksRange>,
+}
+
+/// Arguments of the ICP ledger's legacy `transfer`, which addresses the
+/// recipient by account identifier.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LegacyTransferArgs {
+    pub memo: u64,
+    pub amount: Tokens,
+    pub fee: Tokens,
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Vec<u8>,
+    pub created_at_time: Option<TimeStamp>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum LegacyTransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+}
+
+/// What a block must show for it to count as a given payment.
+#[derive(Clone, Debug)]
+pub struct ExpectedPayment {
+    pub from: AccountIdentifier,
+    pub to: AccountIdentifier,
+    pub min_amount: u64,
+    pub memo: u64,
+}
+
+impl ExpectedPayment {
+    /// A mint payment of at least `price_e8s` from `payer` into this
+    /// canister's mint subaccount.
+    pub fn mint(payer: &Principal, from_subaccount: Option<&Subaccount>, price_e8s: u64) -> Self {
+        Self {
+            from: AccountIdentifier::new(payer, from_subaccount),
+            to: AccountIdentifier::new(&ic_cdk::id(), Some(&MINT_PAYMENT_SUBACCOUNT)),
+            min_amount: price_e8s,
+            memo: MINT_PAYMENT_MEMO,
         }
+    }
+}
+
+/// Checks a ledger block against an expected payment and returns the amount
+/// it transferred. The memo may arrive as the legacy `u64` memo or, for
+/// ICRC-1 transfers, as its big-endian bytes.
+pub fn check_block(block: &Block, expected: &ExpectedPayment) -> std::result::Result<u64, PaymentVerificationError> {
+    let (from, to, amount) = match &block.transaction.operation {
+        Some(Operation::Transfer { from, to, amount, .. }) => (from, to, amount.e8s),
+        _ => return Err(PaymentVerificationError::Mismatch("Block is not a transfer".to_string())),
     };
 
-    let transfer_valid = verify_block_transfer(block, from, amount).await?;
+    if AccountIdentifier::from_slice(from) != Some(expected.from) {
+        return Err(PaymentVerificationError::Mismatch("Payment was not sent by the caller".to_string()));
+    }
+    if AccountIdentifier::from_slice(to) != Some(expected.to) {
+        return Err(PaymentVerificationError::Mismatch("Payment was not sent to the minting account".to_string()));
+    }
+    let memo_bytes = expected.memo.to_be_bytes();
+    let memo_matches = block.transaction.memo == expected.memo
+        || block.transaction.icrc1_memo.as_deref() == Some(&memo_bytes[..]);
+    if !memo_matches {
+        return Err(PaymentVerificationError::Mismatch("Payment memo does not match".to_string()));
+    }
+    if amount < expected.min_amount {
+        return Err(PaymentVerificationError::InsufficientFunds);
+    }
+
+    Ok(amount)
+}
+
+/// Fetches one block from the ICP ledger, following it into the archive if
+/// the ledger no longer holds it.
+pub async fn fetch_block(block_index: u64) -> Result<Block> {
+    let ledger = Principal::from_text(LEDGER_CANISTER_ID)
+        .map_err(|_| AnimaError::InvalidCanister)?;
+    let args = GetBlocksArgs { start: block_index, length: 1 };
+
+    let result: CallResult<(QueryBlocksResponse,)> =
+        ic_cdk::call(ledger, "query_blocks", (args.clone(),)).await;
+    let (response,) = result.map_err(AnimaError::from)?;
 
-    if !transfer_valid {
-        return Err(AnimaError::PaymentValidationFailed);
+    if block_index >= response.first_block_index {
+        let offset = (block_index - response.first_block_index) as usize;
+        return response.blocks.into_iter().nth(offset)
+            .ok_or_else(|| PaymentVerificationError::BlockNotFound(block_index).into());
     }
 
-    Ok(true)
+    let archive = response.archived_blocks.into_iter()
+        .find(|range| range.start <= block_index && block_index < range.start + range.length)
+        .ok_or(PaymentVerificationError::BlockNotFound(block_index))?;
+    let result: CallResult<(QueryArchiveResult,)> =
+        ic_cdk::call(archive.callback.0.principal, &archive.callback.0.method, (args,)).await;
+
+    match result.map_err(AnimaError::from)? {
+        (Ok(range),) => range.blocks.into_iter().next()
+            .ok_or_else(|| PaymentVerificationError::BlockNotFound(block_index).into()),
+        (Err(e),) => Err(AnimaError::PaymentFailed(format!("Archive lookup failed: {:?}", e))),
+    }
 }
 
-async fn verify_block_transfer(block: u64, from: Principal, expected_amount: &Nat) -> Result<bool> {
+/// Fetches up to `length` blocks starting at `start`, reading archived
+/// ranges from their archive canisters. Returns the ledger's chain length and
+/// the blocks found, each with its index.
+pub async fn fetch_blocks(start: u64, length: u64) -> Result<(u64, Vec<(u64, Block)>)> {
     let ledger = Principal::from_text(LEDGER_CANISTER_ID)
         .map_err(|_| AnimaError::InvalidCanister)?;
+    let result: CallResult<(QueryBlocksResponse,)> =
+        ic_cdk::call(ledger, "query_blocks", (GetBlocksArgs { start, length },)).await;
+    let (response,) = result.map_err(AnimaError::from)?;
 
-    let args = (block,);
-    let result: CallResult<(TransactionDetails,)> = ic_cdk::api::call::call(
-        ledger, 
-        "get_transaction",
-        args,
-    ).await;
+    let mut blocks = Vec::new();
+    for range in response.archived_blocks {
+        let args = GetBlocksArgs { start: range.start, length: range.length };
+        let result: CallResult<(QueryArchiveResult,)> =
+            ic_cdk::call(range.callback.0.principal, &range.callback.0.method, (args,)).await;
+        match result.map_err(AnimaError::from)? {
+            (Ok(archived),) => blocks.extend((range.start..).zip(archived.blocks)),
+            (Err(e),) => return Err(AnimaError::PaymentFailed(format!("Archive lookup failed: {:?}", e))),
+        }
+    }
+    blocks.extend((response.first_block_index..).zip(response.blocks));
+    Ok((response.chain_length, blocks))
+}
+
+/// Sends ICP from one of this canister's subaccounts to an account
+/// identifier. `created_at_time` makes retries idempotent: a duplicate
+/// resolves to the block of the original transfer.
+pub async fn transfer_icp(
+    from_subaccount: Option<Subaccount>,
+    to: AccountIdentifier,
+    e8s: u64,
+    fee_e8s: u64,
+    memo: u64,
+    created_at_time: u64,
+) -> Result<u64> {
+    let ledger = Principal::from_text(LEDGER_CANISTER_ID)
+        .map_err(|_| AnimaError::InvalidCanister)?;
+    let args = LegacyTransferArgs {
+        memo,
+        amount: Tokens { e8s },
+        fee: Tokens { e8s: fee_e8s },
+        from_subaccount,
+        to: to.as_bytes().to_vec(),
+        created_at_time: Some(TimeStamp { timestamp_nanos: created_at_time }),
+    };
+    let result: CallResult<(std::result::Result<u64, LegacyTransferError>,)> =
+        ic_cdk::call(ledger, "transfer", (args,)).await;
 
-    match result {
-        Ok((tx,)) => {
-            if tx.from == from && tx.amount == *expected_amount {
-                Ok(true)
-            } else {
-                Ok(false)
-            }
-        },
-        Err((_, msg)) => Err(AnimaError::PaymentFailed(msg))
+    match result.map_err(AnimaError::from)? {
+        (Ok(block_index),) => Ok(block_index),
+        (Err(LegacyTransferError::TxDuplicate { duplicate_of }),) => Ok(duplicate_of),
+        (Err(LegacyTransferError::InsufficientFunds { .. }),) => Err(AnimaError::InsufficientBalance),
+        (Err(e),) => Err(AnimaError::TransactionFailed(format!("{:?}", e))),
     }
 }
 
+/// Verifies that `block_index` is an unused mint payment of at least
+/// `price_e8s` from `payer`. Returns the amount paid; the block still has to
+/// be consumed with `consume_payment_block` when the mint happens.
+pub async fn verify_icp_transfer(
+    payer: Principal,
+    from_subaccount: Option<Subaccount>,
+    block_index: u64,
+    price_e8s: u64,
+) -> Result<u64> {
+    if is_consumed(block_index) {
+        return Err(PaymentVerificationError::AlreadyUsed(block_index).into());
+    }
+    let block = fetch_block(block_index).await?;
+    let amount = check_block(&block, &ExpectedPayment::mint(&payer, from_subaccount.as_ref(), price_e8s))?;
+    Ok(amount)
+}
+
 pub fn validate_payment_amount(amount: &Nat, required_amount: &Nat) -> Result<()> {
     if amount < required_amount {
         Err(PaymentVerificationError::InsufficientFunds.into())
@@ -94,6 +294,97 @@ pub fn validate_payment_amount(amount: &Nat, required_amount: &Nat) -> Result<()
     }
 }
 
-fn get_required_amount() -> Nat {
-    Nat::from(100_000_000u64) // 1 ICP in e8s
-}
\ No newline at end of file
+thread_local! {
+    /// Ledger blocks already spent on a mint. The set only grows, so it lives
+    /// directly in stable memory, one row per block, rather than on the heap
+    /// behind a save/restore that would grow with every payment.
+    static CONSUMED_BLOCKS: RefCell<StableBTreeMap<u64, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(get_memory(CONSUMED_PAYMENTS_MEMORY_ID)));
+}
+
+pub fn is_consumed(block_index: u64) -> bool {
+    CONSUMED_BLOCKS.with(|blocks| blocks.borrow().contains_key(&block_index))
+}
+
+/// Marks a payment block as spent. Fails if it already was, which makes the
+/// check-and-mint in `mint_anima` safe against concurrent calls.
+pub fn consume_payment_block(block_index: u64) -> Result<()> {
+    CONSUMED_BLOCKS.with(|blocks| {
+        if blocks.borrow_mut().insert(block_index, ()).is_none() {
+            Ok(())
+        } else {
+            Err(PaymentVerificationError::AlreadyUsed(block_index).into())
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINT_PRICE_E8S: u64 = 100_000_000;
+
+    fn payer() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn mint_account() -> AccountIdentifier {
+        AccountIdentifier::new(&Principal::from_slice(&[9]), Some(&MINT_PAYMENT_SUBACCOUNT))
+    }
+
+    fn expected() -> ExpectedPayment {
+        ExpectedPayment {
+            from: AccountIdentifier::new(&payer(), None),
+            to: mint_account(),
+            min_amount: MINT_PRICE_E8S,
+            memo: MINT_PAYMENT_MEMO,
+        }
+    }
+
+    fn transfer(from: AccountIdentifier, to: AccountIdentifier, e8s: u64, memo: u64) -> Block {
+        Block {
+            parent_hash: None,
+            transaction: Transaction {
+                memo,
+                icrc1_memo: None,
+                operation: Some(Operation::Transfer {
+                    from: from.as_bytes().to_vec(),
+                    to: to.as_bytes().to_vec(),
+                    amount: Tokens { e8s },
+                    fee: Tokens { e8s: 10_000 },
+                }),
+                created_at_time: TimeStamp { timestamp_nanos: 0 },
+            },
+            timestamp: TimeStamp { timestamp_nanos: 0 },
+        }
+    }
+
+    #[test]
+    fn test_valid_payment() {
+        let block = transfer(expected().from, mint_account(), MINT_PRICE_E8S, MINT_PAYMENT_MEMO);
+        assert_eq!(check_block(&block, &expected()).unwrap(), MINT_PRICE_E8S);
+    }
+
+    #[test]
+    fn test_icrc1_memo_accepted() {
+        let mut block = transfer(expected().from, mint_account(), MINT_PRICE_E8S, 0);
+        block.transaction.icrc1_memo = Some(MINT_PAYMENT_MEMO.to_be_bytes().to_vec());
+        assert!(check_block(&block, &expected()).is_ok());
+    }
+
+    #[test]
+    fn test_mismatched_payments_rejected() {
+        let stranger = AccountIdentifier::new(&Principal::from_slice(&[2]), None);
+        assert!(check_block(&transfer(stranger, mint_account(), MINT_PRICE_E8S, MINT_PAYMENT_MEMO), &expected()).is_err());
+        assert!(check_block(&transfer(expected().from, stranger, MINT_PRICE_E8S, MINT_PAYMENT_MEMO), &expected()).is_err());
+        assert!(check_block(&transfer(expected().from, mint_account(), MINT_PRICE_E8S - 1, MINT_PAYMENT_MEMO), &expected()).is_err());
+        assert!(check_block(&transfer(expected().from, mint_account(), MINT_PRICE_E8S, 7), &expected()).is_err());
+    }
+
+    #[test]
+    fn test_block_consumed_once() {
+        assert!(consume_payment_block(42).is_ok());
+        assert!(is_consumed(42));
+        assert!(consume_payment_block(42).is_err());
+    }
+}
@@ -1,6 +1,8 @@
 pub mod types;
 pub mod ledger;
+pub mod client;
+pub mod account_id;
 
 // Re-export specific types needed by other modules
-pub use types::{TransferArgs, Memo, BlockIndex, AcceptedToken};
+pub use types::{TransferArgs, Memo, BlockIndex, AcceptedToken, Account, Subaccount, Value, SupportedStandard};
 pub use ledger::PaymentVerificationError;
@@ -18,10 +18,14 @@ pub struct TransactionDetails {
     pub timestamp: u64,
 }
 
-#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+/// A token the canister takes payment in. Which ones are accepted, and at
+/// what price, is managed in `payments::token_registry`.
+#[derive(Debug, Clone, Copy, CandidType, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum AcceptedToken {
     ICP,
-    ANIMA
+    ANIMA,
+    /// Any other ICRC-1 token (ckBTC, ...), named by its ledger canister.
+    Icrc1(Principal),
 }
 
 #[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
@@ -34,4 +38,76 @@ pub struct PaymentRecord {
 
 pub type BlockIndex = u64;
 pub type Memo = Vec<u8>;
-pub type Tokens = u128;
\ No newline at end of file
+pub type Tokens = u128;
+pub type Subaccount = [u8; 32];
+
+pub const DEFAULT_SUBACCOUNT: Subaccount = [0; 32];
+
+/// ICRC-1 account. A missing subaccount and the all-zero subaccount name the
+/// same account, so equality and hashing normalise them.
+#[derive(Debug, Clone, Copy, CandidType, Serialize, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+impl Account {
+    pub fn new(owner: Principal, subaccount: Option<Subaccount>) -> Self {
+        Self { owner, subaccount }
+    }
+
+    pub fn effective_subaccount(&self) -> &Subaccount {
+        self.subaccount.as_ref().unwrap_or(&DEFAULT_SUBACCOUNT)
+    }
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Self { owner, subaccount: None }
+    }
+}
+
+impl PartialEq for Account {
+    fn eq(&self, other: &Self) -> bool {
+        self.owner == other.owner && self.effective_subaccount() == other.effective_subaccount()
+    }
+}
+
+impl Eq for Account {}
+
+impl std::hash::Hash for Account {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.owner.hash(state);
+        self.effective_subaccount().hash(state);
+    }
+}
+
+impl PartialOrd for Account {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Account {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.owner.cmp(&other.owner)
+            .then_with(|| self.effective_subaccount().cmp(other.effective_subaccount()))
+    }
+}
+
+/// Generic metadata value shared by the ICRC-3/7/37 interfaces.
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(candid::Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
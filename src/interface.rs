@@ -0,0 +1,46 @@
+//! The canister's Candid interface. `export_candid!` names argument and
+//! result types exactly as the endpoints spell them, so every one of them
+//! has to be in scope here.
+
+use candid::{Nat, Principal};
+use crate::admin::cycles::{Conversion, CyclesConfig, CyclesStatus};
+use crate::admin::metrics::{Alert, AlertLevel, HttpRequest, HttpResponse};
+use crate::admin::{AdminProposal, MetricsConfig, Role};
+use crate::anima_token::rewards::distributor::{Allocation, ClaimProof, EpochSummary, RewardConfig, RewardMetrics, RewardPool};
+use crate::anima_token::staking::pool::{LockTier, PoolMetrics, StakePosition, StakingRewardPool};
+use crate::anima_token::swap::pool::{LiquidityReceipt, SwapConfig, SwapPoolInfo, SwapQuote, SwapReceipt};
+use crate::icrc::{AcceptedToken, Account, SupportedStandard, Value};
+use crate::nft::auction::{Auction, AuctionKind};
+use crate::nft::collection::CollectionStats;
+use crate::nft::TokenId;
+use crate::nft::icrc37::{
+    ApproveCollectionArg, ApproveCollectionResult, ApproveTokenArg, ApproveTokenResult, IsApprovedArg,
+    RevokeCollectionApprovalArg, RevokeCollectionApprovalResult, RevokeTokenApprovalArg, RevokeTokenApprovalResult,
+    TransferFromArg, TransferFromResult,
+};
+use crate::nft::icrc7::{TransferArg, TransferResult};
+use crate::nft::market_stats::{MarketStats, Sale};
+use crate::nft::marketplace::{Listing, ListingCursor, ListingFilter, ListingPage, ListingSort, Offer, PendingPayout};
+use crate::nft::royalties::{RoyaltyPayout, RoyaltyShare};
+use crate::payments::pricing_config::PricingTiers;
+use crate::payments::token_registry::TokenConfig;
+use crate::types::security::SecurityEvent;
+use crate::wallet::{Reconciliation, SwapParams, Transaction, WalletState};
+use crate::{icrc, neural, AnimaRecord, InitArgs, MintingResult, PaymentVerification, QuantumMetrics, QuantumState};
+use ic_cdk::api::management_canister::http_request::TransformArgs;
+
+/// Endpoints return either the crate's `Result<T>` or `Result<T, String>`.
+type Result<T, E = crate::error::AnimaError> = std::result::Result<T, E>;
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candid_file_matches_interface() {
+        // Regenerate with `__export_service()` when an endpoint changes.
+        assert_eq!(__export_service().trim(), include_str!("lib.did").trim());
+    }
+}
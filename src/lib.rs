@@ -2,7 +2,7 @@ use std::cell::RefCell;
 use ic_stable_structures::memory_manager::MemoryManager;
 use ic_stable_structures::DefaultMemoryImpl;
 use ic_cdk_macros::*;
-use candid::{CandidType, Principal};
+use candid::{CandidType, Deserialize, Principal};
 
 mod quantum;
 mod consciousness;
@@ -19,6 +19,9 @@ mod payments;
 mod memory;
 mod neural;
 mod icrc;
+mod stable;
+mod anima_token;
+mod wallet;
 
 pub use quantum::{QuantumState, QuantumMetrics};
 pub use error::{Result, AnimaError};
@@ -36,6 +39,9 @@ pub use personality::evolution::PersonalityEvolution;
 pub use growth::GrowthSystem;
 pub use payments::types::{PaymentVerification, AcceptedToken};
 pub use payments::transaction_processor::PaymentProcessor;
+pub use nft::{AnimaRecord, TokenId};
+
+use stable::{RegionKey, StableRegion, QUANTUM_STATE_MEMORY_ID, GROWTH_SYSTEM_MEMORY_ID};
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
@@ -47,6 +53,120 @@ thread_local! {
     static GROWTH_SYSTEM: RefCell<GrowthSystem> = RefCell::new(GrowthSystem::new());
 }
 
+const QUANTUM_STATE_REGION: StableRegion<QuantumState> = StableRegion::new(QUANTUM_STATE_MEMORY_ID);
+const GROWTH_SYSTEM_REGION: StableRegion<GrowthSystem> = StableRegion::new(GROWTH_SYSTEM_MEMORY_ID);
+const QUANTUM_STATE_KEY: &str = "quantum_state";
+const GROWTH_SYSTEM_KEY: &str = "growth_system";
+
+#[derive(CandidType, Deserialize)]
+pub struct InitArgs {
+    /// Ledger of the ANIMA token, registered as an accepted token. Staking,
+    /// swaps, epoch rewards and ANIMA withdrawals all pay through it; when it
+    /// is omitted, a PricingManager has to `register_accepted_token` ANIMA
+    /// before they work.
+    pub anima_ledger: Option<Principal>,
+}
+
+#[init]
+fn init(args: Option<InitArgs>) {
+    if let Err(e) = stable::write_schema_header(ic_cdk::api::time()) {
+        ic_cdk::trap(&format!("Failed to initialize stable memory: {:?}", e));
+    }
+    if let Some(ledger) = args.and_then(|args| args.anima_ledger) {
+        payments::token_registry::with_tokens_mut(|tokens| tokens.register_anima(ledger));
+    }
+    nft::auction::start_settlement_timer();
+    anima_token::staking::pool::start_coherence_timer();
+    anima_token::rewards::distributor::start_epoch_timer();
+    wallet::start_deposit_scanner();
+    admin::metrics::start_collection_timer();
+    admin::alerts::start_alert_timer();
+    admin::cycles::start_cycles_timer();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    if let Err(e) = save_stable_state() {
+        ic_cdk::trap(&format!("Failed to persist state before upgrade: {:?}", e));
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Trapping here rolls the upgrade back, which is preferable to running with lost state.
+    if let Err(e) = restore_stable_state() {
+        ic_cdk::trap(&format!("Failed to restore state after upgrade: {:?}", e));
+    }
+    nft::auction::start_settlement_timer();
+    anima_token::staking::pool::start_coherence_timer();
+    anima_token::rewards::distributor::start_epoch_timer();
+    wallet::start_deposit_scanner();
+    admin::metrics::start_collection_timer();
+    admin::alerts::start_alert_timer();
+    admin::cycles::start_cycles_timer();
+    wallet::resume_withdrawals();
+}
+
+fn save_stable_state() -> Result<()> {
+    QUANTUM_STATE.with(|state| {
+        QUANTUM_STATE_REGION.save([(RegionKey::singleton(QUANTUM_STATE_KEY), &*state.borrow())])
+    })?;
+    GROWTH_SYSTEM.with(|growth| {
+        GROWTH_SYSTEM_REGION.save([(RegionKey::singleton(GROWTH_SYSTEM_KEY), &*growth.borrow())])
+    })?;
+    nft::registry::save_stable()?;
+    nft::collection::save_stable()?;
+    nft::marketplace::save_stable()?;
+    nft::auction::save_stable()?;
+    nft::royalties::save_stable()?;
+    memory::save_stable()?;
+    consciousness::save_stable()?;
+    anima_token::staking::pool::save_stable()?;
+    anima_token::rewards::distributor::save_stable()?;
+    anima_token::swap::pool::save_stable()?;
+    wallet::save_stable()?;
+    payments::token_registry::save_stable()?;
+    payments::pricing_config::save_stable()?;
+    admin::save_stable()?;
+    admin::metrics::save_stable()?;
+    admin::alerts::save_stable()?;
+    admin::cycles::save_stable()?;
+    stable::write_schema_header(ic_cdk::api::time())
+}
+
+fn restore_stable_state() -> Result<()> {
+    let header = stable::read_schema_header();
+    if header.version == 0 {
+        // Upgrading from a build that never wrote stable state: start fresh.
+        return stable::write_schema_header(ic_cdk::api::time());
+    }
+
+    if let Some(restored) = QUANTUM_STATE_REGION.load_singleton(QUANTUM_STATE_KEY)? {
+        QUANTUM_STATE.with(|state| *state.borrow_mut() = restored);
+    }
+    if let Some(restored) = GROWTH_SYSTEM_REGION.load_singleton(GROWTH_SYSTEM_KEY)? {
+        GROWTH_SYSTEM.with(|growth| *growth.borrow_mut() = restored);
+    }
+    nft::registry::restore_stable()?;
+    nft::collection::restore_stable()?;
+    nft::marketplace::restore_stable()?;
+    nft::auction::restore_stable()?;
+    nft::royalties::restore_stable()?;
+    memory::restore_stable()?;
+    consciousness::restore_stable()?;
+    anima_token::staking::pool::restore_stable()?;
+    anima_token::rewards::distributor::restore_stable()?;
+    anima_token::swap::pool::restore_stable()?;
+    wallet::restore_stable()?;
+    payments::token_registry::restore_stable()?;
+    payments::pricing_config::restore_stable()?;
+    admin::restore_stable()?;
+    admin::metrics::restore_stable()?;
+    admin::alerts::restore_stable()?;
+    admin::cycles::restore_stable()?;
+    stable::write_schema_header(ic_cdk::api::time())
+}
+
 #[derive(CandidType)]
 pub struct MintingResult {
     pub token_id: u64,
@@ -54,30 +174,167 @@ pub struct MintingResult {
     pub neural_signature: String,
 }
 
+/// Mints an ANIMA for `owner`, paid for by the caller. `payment_block` is the
+/// ICP ledger block of the caller's transfer into the minting account, of at
+/// least the current tier's price; each block can pay for one mint only.
 #[update]
-pub async fn mint_anima(owner: Principal, name: String) -> Result<MintingResult> {
-    QUANTUM_STATE.with(|state| {
-        let mut quantum_state = state.borrow_mut();
-        quantum_state.initialize_resonance_patterns()?;
-        
-        Ok(MintingResult {
-            token_id: 0, // This will be replaced with actual token ID generation
-            quantum_signature: quantum_state.quantum_signature.clone(),
-            neural_signature: "initialized".to_string()
-        })
+pub async fn mint_anima(
+    owner: Principal,
+    name: String,
+    payment_block: u64,
+    from_subaccount: Option<icrc::Subaccount>,
+) -> Result<MintingResult> {
+    let payer = ic_cdk::caller();
+    let tier = payments::pricing_config::next_mint_tier();
+    let price = payments::pricing_config::mint_price_e8s();
+    icrc::ledger::verify_icp_transfer(payer, from_subaccount, payment_block, price).await?;
+
+    // Spent only once every check has passed, so a rejected mint leaves the
+    // payment usable for a retry.
+    let record = create_anima(owner, name, payer, payment_block, || {
+        icrc::ledger::consume_payment_block(payment_block)
+    })?;
+    admin::metrics::record_mint(tier, AcceptedToken::ICP);
+    Ok(minting_result(record))
+}
+
+/// Mints an ANIMA for `owner`, paid for by the caller in any accepted token.
+/// The tier's ICP price is converted at the registry's rate and pulled with an
+/// ICRC-2 allowance; `max_amount` bounds what the caller is willing to pay.
+/// A mint that fails after the payment is refunded less the ledger fee; a
+/// refund that fails is queued with the pending payouts for retry.
+#[update]
+pub async fn mint_anima_with_token(
+    owner: Principal,
+    name: String,
+    token: AcceptedToken,
+    max_amount: u128,
+) -> Result<MintingResult> {
+    let payer = ic_cdk::caller();
+    let tier = payments::pricing_config::next_mint_tier();
+    let price = payments::pricing_config::quote_mint(&token)?;
+    if price > max_amount {
+        return Err(AnimaError::InvalidAmount(format!(
+            "Mint costs {} which exceeds the maximum of {}",
+            price, max_amount
+        )));
+    }
+
+    // Checked again when the anima is created; this only spares a sold-out
+    // mint the payment and its refund.
+    nft::registry::with_registry(check_supply_cap)?;
+
+    let ledger = payments::transaction_processor::token_canister(&token)?;
+    let payment_account = icrc::Account::new(ic_cdk::id(), Some(icrc::ledger::MINT_PAYMENT_SUBACCOUNT));
+    let block = icrc::client::icrc2_transfer_from(
+        ledger,
+        icrc::Account::from(payer),
+        payment_account,
+        candid::Nat::from(price),
+        None,
+    ).await?;
+
+    match create_anima(owner, name, payer, nft::marketplace::nat_to_u64(&block), || Ok(())) {
+        Ok(record) => {
+            admin::metrics::record_mint(tier, token);
+            Ok(minting_result(record))
+        }
+        Err(e) => {
+            let fee = nft::marketplace::ledger_fee(token).await as u128;
+            let refund = price.saturating_sub(fee);
+            let reason = format!("refund of failed mint paid in block {}", block);
+            match u64::try_from(refund) {
+                Ok(amount) => {
+                    let mint_payments = Some(icrc::ledger::MINT_PAYMENT_SUBACCOUNT);
+                    nft::marketplace::pay_from(mint_payments, payer, amount, token, &reason).await;
+                }
+                // Payouts are queued in u64 units; leave larger refunds to an operator.
+                Err(_) => admin::record_event(
+                    types::security::SecurityEventType::TokenTransfer,
+                    format!("Unpaid {} of {} {:?}: too large to queue", reason, refund, token),
+                    Some(payer),
+                    true,
+                ),
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Fails once the collection's supply cap is reached.
+fn check_supply_cap(registry: &nft::registry::TokenRegistry) -> Result<()> {
+    let supply_cap = nft::collection::with_collection(|collection| collection.metadata.supply_cap);
+    match supply_cap {
+        Some(cap) if registry.total_supply() >= cap => {
+            Err(AnimaError::InvalidInput(format!("Supply cap of {} reached", cap)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Builds and registers a new anima once its payment has been verified.
+/// `spend_payment` runs after every other check, inside the same update.
+fn create_anima(
+    owner: Principal,
+    name: String,
+    payer: Principal,
+    genesis_block: u64,
+    spend_payment: impl FnOnce() -> Result<()>,
+) -> Result<AnimaRecord> {
+    // New animas start from the canister's genesis quantum template.
+    let mut quantum_state = QUANTUM_STATE.with(|state| state.borrow().clone());
+    quantum_state.initialize_resonance_patterns()?;
+    let personality = types::personality::NFTPersonality::default();
+
+    nft::registry::with_registry_mut(|registry| {
+        check_supply_cap(registry)?;
+        spend_payment()?;
+        let token_id = registry.allocate_token_id();
+        let mut birth_certificate = nft::provenance::AnimaBirthCertificate::genesis(
+            token_id.to_string(),
+            payer,
+            &quantum_state,
+            &personality,
+        );
+        birth_certificate.genesis_block = genesis_block;
+        let record = AnimaRecord {
+            token_id,
+            owner: icrc::Account::from(owner),
+            name,
+            quantum_state,
+            personality,
+            birth_certificate,
+            minted_at: ic_cdk::api::time(),
+            approvals: Vec::new(),
+        };
+        registry.mint(record.clone());
+        Ok(record)
     })
 }
 
+fn minting_result(record: AnimaRecord) -> MintingResult {
+    MintingResult {
+        token_id: record.token_id,
+        quantum_signature: record.quantum_state.quantum_signature,
+        neural_signature: "initialized".to_string()
+    }
+}
+
 #[query]
-pub fn get_quantum_state() -> Result<QuantumMetrics> {
-    QUANTUM_STATE.with(|state| {
-        let quantum_state = state.borrow();
-        Ok(quantum_state.get_metrics())
+pub fn get_quantum_state(token_id: TokenId) -> Result<QuantumMetrics> {
+    nft::registry::with_registry(|registry| {
+        Ok(registry.get(token_id)?.quantum_state.get_metrics())
     })
 }
 
+#[query]
+pub fn get_anima(token_id: TokenId) -> Result<AnimaRecord> {
+    nft::registry::with_registry(|registry| registry.get(token_id).cloned())
+}
+
 #[update]
 pub async fn initialize_quantum_state(coherence_threshold: f64) -> Result<QuantumState> {
+    admin::require_role(admin::Role::SuperAdmin, &format!("initialize_quantum_state {}", coherence_threshold))?;
     QUANTUM_STATE.with(|state| {
         let mut quantum_state = state.borrow_mut();
         quantum_state.set_coherence_level(coherence_threshold)?;
@@ -90,18 +347,36 @@ pub async fn initialize_quantum_state(coherence_threshold: f64) -> Result<Quantu
 pub fn get_minting_requirements() -> PaymentVerification {
     PaymentVerification {
         payment_required: true,
-        fee: 100_000_000u64.into() // 1 ICP
+        fee: payments::pricing_config::mint_price_e8s().into()
     }
 }
 
+/// Checks whether `block_index` is a valid, unused mint payment from `payer`.
 #[update]
-pub async fn verify_payment(owner: Principal, amount: candid::Nat) -> bool {
-    // Payment verification logic will be implemented here
-    true
+pub async fn verify_payment(
+    payer: Principal,
+    block_index: u64,
+    from_subaccount: Option<icrc::Subaccount>,
+) -> Result<bool> {
+    icrc::ledger::verify_icp_transfer(payer, from_subaccount, block_index, payments::pricing_config::mint_price_e8s()).await?;
+    Ok(true)
+}
+
+/// Where mint payments go: this canister's minting subaccount, and the memo
+/// the transfer must carry.
+#[query]
+pub fn get_minting_account() -> (icrc::Account, u64) {
+    (
+        icrc::Account::new(ic_cdk::id(), Some(icrc::ledger::MINT_PAYMENT_SUBACCOUNT)),
+        icrc::ledger::MINT_PAYMENT_MEMO,
+    )
 }
 
 #[update]
 pub async fn initialize_neural_pathways(token_id: u64, config: neural::NeuralConfig) -> Result<()> {
     // Neural pathway initialization logic will be implemented here
     Ok(())
-}
\ No newline at end of file
+}
+
+// Declared last: the Candid export only sees endpoints expanded before it.
+mod interface;
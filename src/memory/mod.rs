@@ -5,11 +5,40 @@ use std::collections::HashMap;
 use crate::types::personality::NFTPersonality;
 use crate::quantum::QuantumState;
 use crate::error::Result;
+use crate::types::now;
+use crate::stable::{RegionKey, StableRegion, MEMORIES_MEMORY_ID};
 
 thread_local! {
     static MEMORIES: RefCell<HashMap<String, Vec<Memory>>> = RefCell::new(HashMap::new());
 }
 
+const MEMORIES_REGION: StableRegion<Memory> = StableRegion::new(MEMORIES_MEMORY_ID);
+
+/// Writes every anima's memories to stable memory, one entry per memory.
+pub fn save_stable() -> Result<()> {
+    MEMORIES.with(|memories| {
+        let memories = memories.borrow();
+        MEMORIES_REGION.save(memories.iter().flat_map(|(anima_id, anima_memories)| {
+            anima_memories.iter()
+                .enumerate()
+                .map(move |(i, memory)| (RegionKey::new(anima_id.clone(), i as u64), memory))
+        }))
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    let entries = MEMORIES_REGION.load()?;
+    MEMORIES.with(|memories| {
+        let mut memories = memories.borrow_mut();
+        memories.clear();
+        // Keys iterate in (anima_id, seq) order, so pushing preserves the original ordering.
+        for (key, memory) in entries {
+            memories.entry(key.id).or_default().push(memory);
+        }
+        Ok(())
+    })
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct Memory {
     pub content: String,
@@ -52,7 +81,7 @@ impl Memory {
             emotional_impact,
             importance_score: 0.0,
             keywords: Vec::new(),
-            timestamp: ic_cdk::api::time(),
+            timestamp: now(),
             resonance_signature: Vec::new(),
         }
     }
@@ -128,7 +157,7 @@ impl Memory {
 
     pub fn update_resonance_signature(&mut self, current_quantum_state: &QuantumState) {
         let resonance = self.calculate_resonance(current_quantum_state);
-        let timestamp = ic_cdk::api::time();
+        let timestamp = now();
         
         let signature = vec![
             ((resonance * 255.0) as u8),
@@ -153,7 +182,7 @@ impl Memory {
     }
 
     fn calculate_time_decay(&self) -> f64 {
-        let current_time = ic_cdk::api::time();
+        let current_time = now();
         let age = current_time - self.timestamp;
         let decay_rate = 0.1; // Adjustable decay rate
         
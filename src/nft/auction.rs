@@ -0,0 +1,641 @@
+use candid::{CandidType, Nat, Principal};
+use serde::{Deserialize, Serialize};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::icrc::client;
+use crate::icrc::{Account, AcceptedToken};
+use crate::nft::marketplace::{
+    escrow_account, marketplace_spender, nat_to_u64, pay_out_sale, refund_escrow, with_marketplace,
+    with_marketplace_mut, MarketplaceOperations, MarketplaceState,
+};
+use crate::nft::market_stats::SaleKind;
+use crate::nft::registry::{with_registry, with_registry_mut, TokenId};
+use crate::payments::transaction_processor::token_canister;
+use crate::stable::{RegionKey, StableRegion, AUCTIONS_MEMORY_ID, AUCTIONS_META_MEMORY_ID};
+
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+
+/// Longest an auction may run.
+pub const MAX_AUCTION_DURATION: u64 = 30 * 24 * 60 * NANOS_PER_MINUTE;
+/// How often ended auctions are looked for and settled.
+pub const SETTLEMENT_INTERVAL: Duration = Duration::from_secs(60);
+/// Closed auctions stay queryable this long after they end, then are pruned.
+/// Their sales live on in the marketplace's sales history.
+pub const CLOSED_AUCTION_RETENTION: u64 = 30 * 24 * 60 * NANOS_PER_MINUTE;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AuctionKind {
+    /// Ascending bids. The token only sells if the highest bid meets
+    /// `reserve_price`; a bid within `extension_window` of the end pushes the
+    /// end out so late bidders can respond.
+    English {
+        starting_price: u64,
+        reserve_price: u64,
+        min_increment: u64,
+        extension_window: u64,
+    },
+    /// Price falls linearly from `start_price` to `floor_price` over the
+    /// auction; the first buyer takes the token at the current price.
+    Dutch {
+        start_price: u64,
+        floor_price: u64,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AuctionStatus {
+    Open,
+    Settled { winner: Principal, price: u64 },
+    Unsold,
+    Cancelled,
+}
+
+/// A bid whose funds are held in marketplace escrow.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Bid {
+    pub bidder: Principal,
+    pub amount: u64,
+    pub escrow_block: u64,
+    pub placed_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Auction {
+    pub id: u64,
+    pub token_id: TokenId,
+    pub seller: Principal,
+    pub payment_token: AcceptedToken,
+    pub kind: AuctionKind,
+    pub starts_at: u64,
+    pub ends_at: u64,
+    pub highest_bid: Option<Bid>,
+    pub status: AuctionStatus,
+}
+
+impl Auction {
+    pub fn is_open(&self, now: u64) -> bool {
+        self.status == AuctionStatus::Open && now < self.ends_at
+    }
+
+    /// Current asking price: the Dutch price at `now`, or the lowest bid an
+    /// English auction will accept next.
+    pub fn current_price(&self, now: u64) -> u64 {
+        match &self.kind {
+            AuctionKind::English { starting_price, min_increment, .. } => match &self.highest_bid {
+                Some(bid) => bid.amount.saturating_add((*min_increment).max(1)),
+                None => *starting_price,
+            },
+            AuctionKind::Dutch { start_price, floor_price } => {
+                if now >= self.ends_at {
+                    return *floor_price;
+                }
+                let elapsed = now.saturating_sub(self.starts_at) as u128;
+                let duration = (self.ends_at - self.starts_at) as u128;
+                let drop = (start_price - floor_price) as u128 * elapsed / duration;
+                start_price - drop as u64
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AuctionState {
+    pub next_auction_id: u64,
+    pub auctions: BTreeMap<u64, Auction>,
+}
+
+impl AuctionState {
+    /// Opens an auction for a token the seller owns. A token is sold one way
+    /// at a time, so one with a live fixed-price listing is refused.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create<M: MarketplaceOperations>(
+        &mut self,
+        ops: &M,
+        marketplace: &MarketplaceState,
+        token_id: TokenId,
+        seller: Principal,
+        payment_token: AcceptedToken,
+        kind: AuctionKind,
+        ends_at: u64,
+        now: u64,
+    ) -> Result<u64, String> {
+        if ends_at <= now || ends_at - now > MAX_AUCTION_DURATION {
+            return Err("Invalid auction end time".to_string());
+        }
+        match &kind {
+            AuctionKind::English { starting_price, .. } if *starting_price == 0 => {
+                return Err("Starting price must be greater than 0".to_string());
+            }
+            AuctionKind::Dutch { start_price, floor_price } if *floor_price == 0 || floor_price >= start_price => {
+                return Err("Dutch auctions need a start price above a non-zero floor".to_string());
+            }
+            _ => {}
+        }
+        if !ops.verify_token_ownership(token_id, &seller) {
+            return Err("Caller does not own this token".to_string());
+        }
+        if self.is_on_auction(token_id) {
+            return Err("Token is already up for auction".to_string());
+        }
+        if marketplace.is_listed(ops, token_id, now) {
+            return Err("Token is listed for sale; cancel the listing first".to_string());
+        }
+
+        let id = self.next_auction_id;
+        self.next_auction_id += 1;
+        self.auctions.insert(id, Auction {
+            id,
+            token_id,
+            seller,
+            payment_token,
+            kind,
+            starts_at: now,
+            ends_at,
+            highest_bid: None,
+            status: AuctionStatus::Open,
+        });
+        Ok(id)
+    }
+
+    /// Whether `token_id` has an auction that has not been closed yet, even
+    /// if its end has passed and settlement is still due.
+    pub fn is_on_auction(&self, token_id: TokenId) -> bool {
+        self.auctions.values().any(|a| a.token_id == token_id && a.status == AuctionStatus::Open)
+    }
+
+    /// Drops auctions closed more than `CLOSED_AUCTION_RETENTION` ago.
+    /// Returns how many were dropped.
+    pub fn prune_closed(&mut self, now: u64) -> usize {
+        let before = self.auctions.len();
+        self.auctions.retain(|_, a| {
+            a.status == AuctionStatus::Open || now.saturating_sub(a.ends_at) < CLOSED_AUCTION_RETENTION
+        });
+        before - self.auctions.len()
+    }
+
+    pub fn get(&self, auction_id: u64) -> Result<&Auction, String> {
+        self.auctions.get(&auction_id).ok_or_else(|| "Auction not found".to_string())
+    }
+
+    /// Checks an English bid without recording it, so funds are only
+    /// escrowed for bids that can win.
+    pub fn validate_bid(&self, auction_id: u64, bidder: Principal, amount: u64, now: u64) -> Result<(), String> {
+        let auction = self.get(auction_id)?;
+        if !matches!(auction.kind, AuctionKind::English { .. }) {
+            return Err("Dutch auctions are bought, not bid on".to_string());
+        }
+        if !auction.is_open(now) {
+            return Err("Auction is not open".to_string());
+        }
+        if auction.seller == bidder {
+            return Err("Cannot bid on your own auction".to_string());
+        }
+        let minimum = auction.current_price(now);
+        if amount < minimum {
+            return Err(format!("Bid must be at least {}", minimum));
+        }
+        Ok(())
+    }
+
+    /// Records an escrowed English bid and returns the bid it outbid, whose
+    /// funds are now owed back to that bidder.
+    pub fn place_bid(&mut self, auction_id: u64, bid: Bid, now: u64) -> Result<Option<Bid>, String> {
+        self.validate_bid(auction_id, bid.bidder, bid.amount, now)?;
+        let auction = self.auctions.get_mut(&auction_id).ok_or("Auction not found")?;
+
+        if let AuctionKind::English { extension_window, .. } = auction.kind {
+            if auction.ends_at - now < extension_window {
+                auction.ends_at = now + extension_window;
+            }
+        }
+        Ok(auction.highest_bid.replace(bid))
+    }
+
+    /// Sells a Dutch auction's token to a buyer whose escrowed `bid` covers
+    /// the current price. The token moves before this returns.
+    pub fn buy_now<M: MarketplaceOperations>(
+        &mut self,
+        ops: &mut M,
+        auction_id: u64,
+        bid: Bid,
+        now: u64,
+    ) -> Result<Auction, String> {
+        let auction = self.get(auction_id)?;
+        if !matches!(auction.kind, AuctionKind::Dutch { .. }) {
+            return Err("English auctions are settled by bidding".to_string());
+        }
+        if !auction.is_open(now) {
+            return Err("Auction is not open".to_string());
+        }
+        if auction.seller == bid.bidder {
+            return Err("Cannot buy your own auction".to_string());
+        }
+        if bid.amount < auction.current_price(now) {
+            return Err("Payment does not cover the current price".to_string());
+        }
+        ops.transfer_token(auction.token_id, &auction.seller, &bid.bidder)?;
+
+        let auction = self.auctions.get_mut(&auction_id).ok_or("Auction not found")?;
+        auction.status = AuctionStatus::Settled { winner: bid.bidder, price: bid.amount };
+        auction.highest_bid = Some(bid);
+        Ok(auction.clone())
+    }
+
+    /// Seller withdraws an auction that nobody has bid on yet.
+    pub fn cancel(&mut self, auction_id: u64, seller: Principal) -> Result<(), String> {
+        let auction = self.auctions.get_mut(&auction_id).ok_or("Auction not found")?;
+        if auction.seller != seller {
+            return Err("Only the seller can cancel this auction".to_string());
+        }
+        if auction.status != AuctionStatus::Open {
+            return Err("Auction is not open".to_string());
+        }
+        if auction.highest_bid.is_some() {
+            return Err("Cannot cancel an auction with bids".to_string());
+        }
+        auction.status = AuctionStatus::Cancelled;
+        Ok(())
+    }
+
+    /// Closes every open auction past its end. English auctions whose highest
+    /// bid meets the reserve are sold, moving the token to the winner;
+    /// everything else ends unsold. Returns the closed auctions so their
+    /// escrow can be paid out or refunded.
+    pub fn finalize_ended<M: MarketplaceOperations>(&mut self, ops: &mut M, now: u64) -> Vec<Auction> {
+        let mut closed = Vec::new();
+        for auction in self.auctions.values_mut() {
+            if auction.status != AuctionStatus::Open || now < auction.ends_at {
+                continue;
+            }
+
+            auction.status = AuctionStatus::Unsold;
+            if let (AuctionKind::English { reserve_price, .. }, Some(bid)) = (&auction.kind, &auction.highest_bid) {
+                let sold = bid.amount >= *reserve_price
+                    && ops.transfer_token(auction.token_id, &auction.seller, &bid.bidder).is_ok();
+                if sold {
+                    auction.status = AuctionStatus::Settled { winner: bid.bidder, price: bid.amount };
+                }
+            }
+            closed.push(auction.clone());
+        }
+        closed
+    }
+}
+
+thread_local! {
+    static AUCTIONS: RefCell<AuctionState> = RefCell::new(AuctionState::default());
+}
+
+/// Auctions are persisted one row each; only the id counter lives here.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct AuctionsMeta {
+    next_auction_id: u64,
+}
+
+const AUCTIONS_REGION: StableRegion<Auction> = StableRegion::new(AUCTIONS_MEMORY_ID);
+const AUCTIONS_META_REGION: StableRegion<AuctionsMeta> = StableRegion::new(AUCTIONS_META_MEMORY_ID);
+const AUCTION_KEY: &str = "auction";
+const AUCTIONS_META_KEY: &str = "auctions";
+
+pub fn with_auctions<R>(f: impl FnOnce(&AuctionState) -> R) -> R {
+    AUCTIONS.with(|auctions| f(&auctions.borrow()))
+}
+
+pub fn with_auctions_mut<R>(f: impl FnOnce(&mut AuctionState) -> R) -> R {
+    AUCTIONS.with(|auctions| f(&mut auctions.borrow_mut()))
+}
+
+pub fn save_stable() -> crate::Result<()> {
+    AUCTIONS.with(|auctions| {
+        let auctions = auctions.borrow();
+        AUCTIONS_REGION.save(auctions.auctions.iter().map(|(id, auction)| (RegionKey::new(AUCTION_KEY, *id), auction)))?;
+        let meta = AuctionsMeta { next_auction_id: auctions.next_auction_id };
+        AUCTIONS_META_REGION.save([(RegionKey::singleton(AUCTIONS_META_KEY), &meta)])
+    })
+}
+
+pub fn restore_stable() -> crate::Result<()> {
+    let auctions = AUCTIONS_REGION.load()?;
+    let meta = AUCTIONS_META_REGION.load_singleton(AUCTIONS_META_KEY)?.unwrap_or_default();
+    AUCTIONS.with(|state| {
+        *state.borrow_mut() = AuctionState {
+            next_auction_id: meta.next_auction_id,
+            auctions: auctions.into_iter().map(|(key, auction)| (key.seq, auction)).collect(),
+        };
+    });
+    Ok(())
+}
+
+/// Starts the periodic settlement task. Timers do not survive upgrades, so
+/// this runs from both `init` and `post_upgrade`.
+pub fn start_settlement_timer() {
+    ic_cdk_timers::set_timer_interval(SETTLEMENT_INTERVAL, || {
+        ic_cdk::spawn(finalize_ended_auctions());
+    });
+}
+
+/// Settles every ended auction: winners' escrow is paid out through the
+/// royalty path, unsold auctions refund their highest bidder. Auctions past
+/// their retention are pruned.
+pub async fn finalize_ended_auctions() {
+    let now = time();
+    let closed = with_auctions_mut(|auctions| {
+        auctions.prune_closed(now);
+        with_registry_mut(|registry| auctions.finalize_ended(registry, now))
+    });
+
+    for auction in closed {
+        settle(auction).await;
+    }
+}
+
+async fn settle(auction: Auction) {
+    let label = format!("auction {}", auction.id);
+    match (&auction.status, &auction.highest_bid) {
+        (AuctionStatus::Settled { winner, price }, _) => {
+            with_marketplace_mut(|marketplace| {
+                marketplace.record_sale(
+                    auction.token_id,
+                    auction.seller,
+                    *winner,
+                    *price,
+                    auction.payment_token,
+                    SaleKind::Auction,
+                    time(),
+                )
+            });
+            pay_out_sale(auction.token_id, auction.seller, *price, auction.payment_token, &label).await;
+        }
+        (_, Some(bid)) => {
+            refund_escrow(bid.bidder, bid.amount, auction.payment_token, &format!("unsold {}", label)).await;
+        }
+        _ => {}
+    }
+}
+
+/// Pulls `amount` from the caller into marketplace escrow via ICRC-2.
+async fn escrow_bid(bidder: Principal, amount: u64, payment_token: AcceptedToken) -> Result<Bid, String> {
+    let ledger = token_canister(&payment_token).map_err(|e| format!("{:?}", e))?;
+    let block = client::icrc2_transfer_from(
+        ledger,
+        Account::from(bidder),
+        escrow_account(),
+        Nat::from(amount),
+        None,
+    ).await.map_err(|e| format!("Escrow failed: {:?}", e))?;
+
+    Ok(Bid {
+        bidder,
+        amount,
+        escrow_block: nat_to_u64(&block),
+        placed_at: time(),
+    })
+}
+
+/// Puts a token up for auction. As with listings, the seller must approve
+/// this canister through ICRC-37 so the sale can settle.
+#[update]
+fn create_auction(
+    token_id: TokenId,
+    payment_token: AcceptedToken,
+    kind: AuctionKind,
+    ends_at: u64,
+) -> Result<u64, String> {
+    let seller = ic_cdk::caller();
+    let now = time();
+    token_canister(&payment_token).map_err(|e| format!("{:?}", e))?;
+    with_registry(|registry| {
+        if !registry.is_approved(token_id, &marketplace_spender(), now) {
+            return Err("Marketplace is not approved to transfer this token".to_string());
+        }
+        with_marketplace(|marketplace| {
+            with_auctions_mut(|auctions| {
+                auctions.create(registry, marketplace, token_id, seller, payment_token, kind, ends_at, now)
+            })
+        })
+    })
+}
+
+#[update]
+fn cancel_auction(auction_id: u64) -> Result<(), String> {
+    let seller = ic_cdk::caller();
+    with_auctions_mut(|auctions| auctions.cancel(auction_id, seller))
+}
+
+/// Bids on an English auction. The bid is escrowed up front; an outbid
+/// bidder is refunded as soon as they are overtaken.
+#[update]
+async fn place_bid(auction_id: u64, amount: u64) -> Result<(), String> {
+    let bidder = ic_cdk::caller();
+    let payment_token = with_auctions(|auctions| {
+        auctions.validate_bid(auction_id, bidder, amount, time())?;
+        auctions.get(auction_id).map(|a| a.payment_token)
+    })?;
+
+    let bid = escrow_bid(bidder, amount, payment_token).await?;
+
+    // A higher bid may have landed, or the auction closed, while escrowing.
+    match with_auctions_mut(|auctions| auctions.place_bid(auction_id, bid, time())) {
+        Ok(Some(outbid)) => {
+            refund_escrow(outbid.bidder, outbid.amount, payment_token, &format!("outbid auction {}", auction_id)).await;
+            Ok(())
+        }
+        Ok(None) => Ok(()),
+        Err(e) => {
+            refund_escrow(bidder, amount, payment_token, &format!("rejected bid auction {}", auction_id)).await;
+            Err(e)
+        }
+    }
+}
+
+/// Buys a Dutch auction at its current price.
+#[update]
+async fn buy_auction(auction_id: u64) -> Result<u64, String> {
+    let buyer = ic_cdk::caller();
+    let (price, payment_token) = with_auctions(|auctions| {
+        let auction = auctions.get(auction_id)?;
+        if !auction.is_open(time()) {
+            return Err("Auction is not open".to_string());
+        }
+        Ok((auction.current_price(time()), auction.payment_token))
+    })?;
+
+    let bid = escrow_bid(buyer, price, payment_token).await?;
+
+    let sold = with_auctions_mut(|auctions| {
+        with_registry_mut(|registry| auctions.buy_now(registry, auction_id, bid, time()))
+    });
+    match sold {
+        Ok(auction) => {
+            settle(auction).await;
+            Ok(price)
+        }
+        Err(e) => {
+            refund_escrow(buyer, price, payment_token, &format!("rejected purchase auction {}", auction_id)).await;
+            Err(e)
+        }
+    }
+}
+
+#[query]
+fn get_auction(auction_id: u64) -> Result<Auction, String> {
+    with_auctions(|auctions| auctions.get(auction_id).cloned())
+}
+
+#[query]
+fn get_open_auctions() -> Vec<Auction> {
+    let now = time();
+    with_auctions(|auctions| auctions.auctions.values().filter(|a| a.is_open(now)).cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Owners(Vec<(TokenId, Principal)>);
+
+    impl MarketplaceOperations for Owners {
+        fn verify_token_ownership(&self, token_id: TokenId, owner: &Principal) -> bool {
+            self.0.iter().any(|(id, o)| *id == token_id && o == owner)
+        }
+
+        fn transfer_token(&mut self, token_id: TokenId, from: &Principal, to: &Principal) -> Result<(), String> {
+            let entry = self.0.iter_mut()
+                .find(|(id, o)| *id == token_id && o == from)
+                .ok_or("Not owner")?;
+            entry.1 = *to;
+            Ok(())
+        }
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn bid(bidder: u8, amount: u64) -> Bid {
+        Bid { bidder: principal(bidder), amount, escrow_block: 0, placed_at: 0 }
+    }
+
+    fn english() -> AuctionKind {
+        AuctionKind::English {
+            starting_price: 100,
+            reserve_price: 500,
+            min_increment: 10,
+            extension_window: 50,
+        }
+    }
+
+    #[test]
+    fn test_dutch_price_decays_linearly() {
+        let ops = Owners(vec![(1, principal(1))]);
+        let mut state = AuctionState::default();
+        let kind = AuctionKind::Dutch { start_price: 1_000, floor_price: 200 };
+        let id = state.create(&ops, &MarketplaceState::default(), 1, principal(1), AcceptedToken::ICP, kind, 100, 0).unwrap();
+
+        let auction = state.get(id).unwrap();
+        assert_eq!(auction.current_price(0), 1_000);
+        assert_eq!(auction.current_price(50), 600);
+        assert_eq!(auction.current_price(100), 200);
+    }
+
+    #[test]
+    fn test_english_bids_outbid_and_extend() {
+        let ops = Owners(vec![(1, principal(1))]);
+        let mut state = AuctionState::default();
+        let id = state.create(&ops, &MarketplaceState::default(), 1, principal(1), AcceptedToken::ICP, english(), 1_000, 0).unwrap();
+
+        assert!(state.place_bid(id, bid(2, 99), 10).is_err());
+        assert!(state.place_bid(id, bid(2, 100), 10).unwrap().is_none());
+        assert!(state.place_bid(id, bid(3, 105), 20).is_err());
+
+        // A bid inside the extension window pushes the end out.
+        let outbid = state.place_bid(id, bid(3, 110), 980).unwrap().unwrap();
+        assert_eq!(outbid.bidder, principal(2));
+        assert_eq!(state.get(id).unwrap().ends_at, 1_030);
+    }
+
+    #[test]
+    fn test_finalize_respects_reserve() {
+        let mut ops = Owners(vec![(1, principal(1)), (2, principal(1))]);
+        let mut state = AuctionState::default();
+        let below = state.create(&ops, &MarketplaceState::default(), 1, principal(1), AcceptedToken::ICP, english(), 100, 0).unwrap();
+        let above = state.create(&ops, &MarketplaceState::default(), 2, principal(1), AcceptedToken::ICP, english(), 100, 0).unwrap();
+        state.place_bid(below, bid(2, 400), 10).unwrap();
+        state.place_bid(above, bid(2, 600), 10).unwrap();
+
+        assert!(state.finalize_ended(&mut ops, 50).is_empty());
+        let closed = state.finalize_ended(&mut ops, 100);
+        assert_eq!(closed.len(), 2);
+        assert_eq!(state.get(below).unwrap().status, AuctionStatus::Unsold);
+        assert_eq!(state.get(above).unwrap().status, AuctionStatus::Settled { winner: principal(2), price: 600 });
+        assert!(ops.verify_token_ownership(2, &principal(2)));
+        assert!(ops.verify_token_ownership(1, &principal(1)));
+    }
+
+    #[test]
+    fn test_listed_tokens_cannot_be_auctioned() {
+        let ops = Owners(vec![(1, principal(1))]);
+        let mut marketplace = MarketplaceState::default();
+        marketplace.list_token(&ops, 1, principal(1), 100, AcceptedToken::ICP, Some(50), 0).unwrap();
+        let mut state = AuctionState::default();
+
+        assert!(state.create(&ops, &marketplace, 1, principal(1), AcceptedToken::ICP, english(), 100, 0).is_err());
+        // An expired listing no longer holds the token.
+        assert!(state.create(&ops, &marketplace, 1, principal(1), AcceptedToken::ICP, english(), 100, 50).is_ok());
+        assert!(state.is_on_auction(1));
+    }
+
+    #[test]
+    fn test_closed_auctions_are_pruned_after_retention() {
+        let mut ops = Owners(vec![(1, principal(1)), (2, principal(1))]);
+        let mut state = AuctionState::default();
+        let marketplace = MarketplaceState::default();
+        let sold = state.create(&ops, &marketplace, 1, principal(1), AcceptedToken::ICP, english(), 100, 0).unwrap();
+        let open = state.create(&ops, &marketplace, 2, principal(1), AcceptedToken::ICP, english(), 200, 0).unwrap();
+        state.place_bid(sold, bid(2, 600), 10).unwrap();
+        assert_eq!(state.finalize_ended(&mut ops, 100).len(), 1);
+
+        assert_eq!(state.prune_closed(100 + CLOSED_AUCTION_RETENTION - 1), 0);
+        assert_eq!(state.prune_closed(100 + CLOSED_AUCTION_RETENTION), 1);
+        assert!(state.get(sold).is_err());
+        assert!(state.get(open).is_ok());
+    }
+
+    #[test]
+    fn test_auctions_persist_as_rows() {
+        let ops = Owners(vec![(1, principal(1)), (2, principal(1))]);
+        with_auctions_mut(|state| {
+            *state = AuctionState::default();
+            for token_id in [1, 2] {
+                state.create(&ops, &MarketplaceState::default(), token_id, principal(1), AcceptedToken::ICP, english(), 100, 0).unwrap();
+            }
+            state.auctions.remove(&0);
+        });
+        save_stable().unwrap();
+        with_auctions_mut(|state| *state = AuctionState::default());
+
+        restore_stable().unwrap();
+        with_auctions(|state| {
+            assert_eq!(state.auctions.keys().copied().collect::<Vec<_>>(), vec![1]);
+            assert_eq!(state.get(1).unwrap().token_id, 2);
+            assert_eq!(state.next_auction_id, 2);
+        });
+    }
+
+    #[test]
+    fn test_dutch_buy_now() {
+        let mut ops = Owners(vec![(1, principal(1))]);
+        let mut state = AuctionState::default();
+        let kind = AuctionKind::Dutch { start_price: 1_000, floor_price: 200 };
+        let id = state.create(&ops, &MarketplaceState::default(), 1, principal(1), AcceptedToken::ICP, kind, 100, 0).unwrap();
+
+        assert!(state.buy_now(&mut ops, id, bid(2, 500), 10).is_err());
+        let sold = state.buy_now(&mut ops, id, bid(2, 920), 10).unwrap();
+        assert_eq!(sold.status, AuctionStatus::Settled { winner: principal(2), price: 920 });
+        assert!(ops.verify_token_ownership(1, &principal(2)));
+    }
+}
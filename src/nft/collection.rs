@@ -1,5 +1,15 @@
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use ic_cdk_macros::*;
+use crate::error::Result;
+use crate::icrc::AcceptedToken;
+use crate::nft::market_stats::MarketStats;
+use crate::nft::marketplace::with_marketplace;
+use crate::nft::registry::{with_registry, TokenRegistry};
+use crate::stable::{RegionKey, StableRegion, COLLECTION_MEMORY_ID};
+
+pub const DEFAULT_SUPPLY_CAP: u64 = 10_000;
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
 pub struct CollectionMetadata {
@@ -19,6 +29,7 @@ pub struct CollectionStats {
     pub unique_holders: u64,
     pub floor_price: Option<u64>,
     pub volume_24h: u64,
+    pub volume_7d: u64,
     pub royalties_earned: u64,
 }
 
@@ -28,6 +39,21 @@ pub struct CollectionState {
     pub stats: CollectionStats,
 }
 
+impl CollectionMetadata {
+    pub fn anima() -> Self {
+        Self {
+            name: "ANIMA".to_string(),
+            symbol: "ANIMA".to_string(),
+            description: Some("Quantum-conscious digital beings that grow with their owners".to_string()),
+            image: None,
+            supply_cap: Some(DEFAULT_SUPPLY_CAP),
+            creator: None,
+            website: None,
+            royalties: Some(250),
+        }
+    }
+}
+
 impl CollectionState {
     pub fn new(metadata: CollectionMetadata) -> Self {
         Self {
@@ -36,11 +62,14 @@ impl CollectionState {
         }
     }
 
-    pub fn update_stats(&mut self, supply: u64, holders: u64, floor_price: Option<u64>, volume: u64) {
-        self.stats.total_supply = supply;
-        self.stats.unique_holders = holders;
-        self.stats.floor_price = floor_price;
-        self.stats.volume_24h = volume;
+    /// Refreshes the derived stats from the registry and the ICP market.
+    /// Royalties are accumulated as they are paid, so they are kept as is.
+    pub fn update_stats(&mut self, registry: &TokenRegistry, market: &MarketStats) {
+        self.stats.total_supply = registry.total_supply();
+        self.stats.unique_holders = registry.holder_count();
+        self.stats.floor_price = market.floor_price;
+        self.stats.volume_24h = market.volume_24h;
+        self.stats.volume_7d = market.volume_7d;
     }
 
     pub fn add_royalties(&mut self, amount: u64) {
@@ -56,4 +85,42 @@ pub struct CollectionConfig {
     pub supply_cap: Option<u64>,
     pub royalties: Option<u16>,
     pub website: Option<String>,
-}
\ No newline at end of file
+}
+
+thread_local! {
+    static COLLECTION: RefCell<CollectionState> = RefCell::new(CollectionState::new(CollectionMetadata::anima()));
+}
+
+const COLLECTION_REGION: StableRegion<CollectionState> = StableRegion::new(COLLECTION_MEMORY_ID);
+const COLLECTION_KEY: &str = "collection";
+
+pub fn with_collection<R>(f: impl FnOnce(&CollectionState) -> R) -> R {
+    COLLECTION.with(|collection| f(&collection.borrow()))
+}
+
+pub fn with_collection_mut<R>(f: impl FnOnce(&mut CollectionState) -> R) -> R {
+    COLLECTION.with(|collection| f(&mut collection.borrow_mut()))
+}
+
+pub fn save_stable() -> Result<()> {
+    COLLECTION.with(|collection| {
+        COLLECTION_REGION.save([(RegionKey::singleton(COLLECTION_KEY), &*collection.borrow())])
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    if let Some(restored) = COLLECTION_REGION.load_singleton(COLLECTION_KEY)? {
+        COLLECTION.with(|collection| *collection.borrow_mut() = restored);
+    }
+    Ok(())
+}
+
+/// Collection stats as of now; floor price and volume are quoted in ICP.
+#[query]
+fn get_collection_stats() -> CollectionStats {
+    let market = with_marketplace(|marketplace| marketplace.market_stats(AcceptedToken::ICP, ic_cdk::api::time()));
+    with_collection_mut(|collection| {
+        with_registry(|registry| collection.update_stats(registry, &market));
+        collection.stats.clone()
+    })
+}
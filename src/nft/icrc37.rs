@@ -0,0 +1,551 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use serde::Serialize;
+use crate::error::AnimaError;
+use crate::icrc::{Account, Subaccount, SupportedStandard};
+use crate::nft::icrc7::{self, MAX_MEMO_SIZE, MAX_UPDATE_BATCH_SIZE, TX_WINDOW, PERMITTED_DRIFT};
+use crate::nft::registry::{with_registry, with_registry_mut, Approval, TokenRegistry, MAX_APPROVALS_PER_TOKEN_OR_COLLECTION};
+
+pub const MAX_REVOKE_APPROVALS: usize = 20;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApprovalInfo {
+    pub spender: Account,
+    pub from_subaccount: Option<Subaccount>,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApproveTokenArg {
+    pub token_id: Nat,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApproveCollectionArg {
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RevokeTokenApprovalArg {
+    pub spender: Option<Account>,
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RevokeCollectionApprovalArg {
+    pub spender: Option<Account>,
+    pub from_subaccount: Option<Subaccount>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct IsApprovedArg {
+    pub spender: Account,
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: Nat,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransferFromArg {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type ApproveTokenResult = std::result::Result<Nat, ApproveTokenError>;
+pub type ApproveCollectionResult = std::result::Result<Nat, ApproveCollectionError>;
+pub type RevokeTokenApprovalResult = std::result::Result<Nat, RevokeTokenApprovalError>;
+pub type RevokeCollectionApprovalResult = std::result::Result<Nat, RevokeCollectionApprovalError>;
+pub type TransferFromResult = std::result::Result<Nat, TransferFromError>;
+
+const ERROR_MEMO_TOO_LONG: u64 = 1;
+const ERROR_BATCH_TOO_LARGE: u64 = 2;
+const ERROR_TOO_MANY_APPROVALS: u64 = 3;
+
+/// Common pre-checks shared by every ICRC-37 call: memo size and
+/// `created_at_time` window. Errors are mapped into each call's error type.
+enum PreCheck {
+    MemoTooLong,
+    TooOld,
+    CreatedInFuture(u64),
+}
+
+fn pre_check(memo: &Option<Vec<u8>>, created_at_time: Option<u64>, now: u64) -> std::result::Result<(), PreCheck> {
+    if memo.as_ref().map(|m| m.len() > MAX_MEMO_SIZE).unwrap_or(false) {
+        return Err(PreCheck::MemoTooLong);
+    }
+    icrc7::check_created_at_time(created_at_time, now).map_err(|e| match e {
+        icrc7::TransferError::CreatedInFuture { ledger_time } => PreCheck::CreatedInFuture(ledger_time),
+        _ => PreCheck::TooOld,
+    })
+}
+
+fn memo_error() -> (Nat, String) {
+    (Nat::from(ERROR_MEMO_TOO_LONG), format!("Memo exceeds {} bytes", MAX_MEMO_SIZE))
+}
+
+fn batch_error(max: usize) -> (Nat, String) {
+    (Nat::from(ERROR_BATCH_TOO_LARGE), format!("Batch exceeds {} entries", max))
+}
+
+fn approval_from(info: &ApprovalInfo, now: u64) -> Approval {
+    Approval {
+        spender: info.spender,
+        expires_at: info.expires_at,
+        memo: info.memo.clone(),
+        created_at: info.created_at_time.unwrap_or(now),
+    }
+}
+
+pub fn apply_approve_token(registry: &mut TokenRegistry, caller: Principal, arg: ApproveTokenArg, now: u64) -> ApproveTokenResult {
+    let info = &arg.approval_info;
+    pre_check(&info.memo, info.created_at_time, now).map_err(|e| match e {
+        PreCheck::MemoTooLong => {
+            let (error_code, message) = memo_error();
+            ApproveTokenError::GenericError { error_code, message }
+        }
+        PreCheck::TooOld => ApproveTokenError::TooOld,
+        PreCheck::CreatedInFuture(ledger_time) => ApproveTokenError::CreatedInFuture { ledger_time },
+    })?;
+
+    let owner = Account::new(caller, info.from_subaccount);
+    if info.spender == owner || info.expires_at.map(|e| e <= now).unwrap_or(false) {
+        return Err(ApproveTokenError::InvalidSpender);
+    }
+    let token_id = icrc7::to_token_id(&arg.token_id).ok_or(ApproveTokenError::NonExistingTokenId)?;
+    match registry.owner_of(token_id) {
+        None => return Err(ApproveTokenError::NonExistingTokenId),
+        Some(current) if current != owner => return Err(ApproveTokenError::Unauthorized),
+        Some(_) => {}
+    }
+
+    registry.approve_token(token_id, owner, approval_from(info, now), now)
+        .map(Nat::from)
+        .map_err(|e| match e {
+            AnimaError::NotAuthorized => ApproveTokenError::Unauthorized,
+            other => ApproveTokenError::GenericError {
+                error_code: Nat::from(ERROR_TOO_MANY_APPROVALS),
+                message: format!("{:?}", other),
+            },
+        })
+}
+
+pub fn apply_approve_collection(registry: &mut TokenRegistry, caller: Principal, arg: ApproveCollectionArg, now: u64) -> ApproveCollectionResult {
+    let info = &arg.approval_info;
+    pre_check(&info.memo, info.created_at_time, now).map_err(|e| match e {
+        PreCheck::MemoTooLong => {
+            let (error_code, message) = memo_error();
+            ApproveCollectionError::GenericError { error_code, message }
+        }
+        PreCheck::TooOld => ApproveCollectionError::TooOld,
+        PreCheck::CreatedInFuture(ledger_time) => ApproveCollectionError::CreatedInFuture { ledger_time },
+    })?;
+
+    let owner = Account::new(caller, info.from_subaccount);
+    if info.spender == owner || info.expires_at.map(|e| e <= now).unwrap_or(false) {
+        return Err(ApproveCollectionError::InvalidSpender);
+    }
+
+    registry.approve_collection(owner, approval_from(info, now), now)
+        .map(Nat::from)
+        .map_err(|e| ApproveCollectionError::GenericError {
+            error_code: Nat::from(ERROR_TOO_MANY_APPROVALS),
+            message: format!("{:?}", e),
+        })
+}
+
+pub fn apply_revoke_token(registry: &mut TokenRegistry, caller: Principal, arg: RevokeTokenApprovalArg, now: u64) -> RevokeTokenApprovalResult {
+    pre_check(&arg.memo, arg.created_at_time, now).map_err(|e| match e {
+        PreCheck::MemoTooLong => {
+            let (error_code, message) = memo_error();
+            RevokeTokenApprovalError::GenericError { error_code, message }
+        }
+        PreCheck::TooOld => RevokeTokenApprovalError::TooOld,
+        PreCheck::CreatedInFuture(ledger_time) => RevokeTokenApprovalError::CreatedInFuture { ledger_time },
+    })?;
+
+    let owner = Account::new(caller, arg.from_subaccount);
+    let token_id = icrc7::to_token_id(&arg.token_id).ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+    let record = registry.get(token_id).map_err(|_| RevokeTokenApprovalError::NonExistingTokenId)?;
+    if record.owner != owner {
+        return Err(RevokeTokenApprovalError::Unauthorized);
+    }
+    let exists = match &arg.spender {
+        Some(spender) => record.approvals.iter().any(|a| &a.spender == spender),
+        None => !record.approvals.is_empty(),
+    };
+    if !exists {
+        return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+    }
+
+    registry.revoke_token_approvals(token_id, owner, arg.spender, arg.memo, arg.created_at_time, now)
+        .map(|(_, index)| Nat::from(index))
+        .map_err(|_| RevokeTokenApprovalError::Unauthorized)
+}
+
+pub fn apply_revoke_collection(registry: &mut TokenRegistry, caller: Principal, arg: RevokeCollectionApprovalArg, now: u64) -> RevokeCollectionApprovalResult {
+    pre_check(&arg.memo, arg.created_at_time, now).map_err(|e| match e {
+        PreCheck::MemoTooLong => {
+            let (error_code, message) = memo_error();
+            RevokeCollectionApprovalError::GenericError { error_code, message }
+        }
+        PreCheck::TooOld => RevokeCollectionApprovalError::TooOld,
+        PreCheck::CreatedInFuture(ledger_time) => RevokeCollectionApprovalError::CreatedInFuture { ledger_time },
+    })?;
+
+    let owner = Account::new(caller, arg.from_subaccount);
+    let (removed, index) = registry.revoke_collection_approvals(owner, arg.spender, arg.memo, arg.created_at_time, now);
+    if removed == 0 {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+    Ok(Nat::from(index))
+}
+
+pub fn apply_transfer_from(registry: &mut TokenRegistry, spender: Account, arg: TransferFromArg, now: u64) -> TransferFromResult {
+    pre_check(&arg.memo, arg.created_at_time, now).map_err(|e| match e {
+        PreCheck::MemoTooLong => {
+            let (error_code, message) = memo_error();
+            TransferFromError::GenericError { error_code, message }
+        }
+        PreCheck::TooOld => TransferFromError::TooOld,
+        PreCheck::CreatedInFuture(ledger_time) => TransferFromError::CreatedInFuture { ledger_time },
+    })?;
+
+    let token_id = icrc7::to_token_id(&arg.token_id).ok_or(TransferFromError::NonExistingTokenId)?;
+    let owner = registry.owner_of(token_id).ok_or(TransferFromError::NonExistingTokenId)?;
+    if owner != arg.from || !registry.is_approved(token_id, &spender, now) {
+        return Err(TransferFromError::Unauthorized);
+    }
+    if arg.to == arg.from || arg.to.owner == Principal::anonymous() {
+        return Err(TransferFromError::InvalidRecipient);
+    }
+
+    if let Some(created_at) = arg.created_at_time {
+        let window_start = now.saturating_sub(TX_WINDOW + PERMITTED_DRIFT);
+        if let Some(duplicate_of) = registry.find_duplicate_transfer(
+            token_id, &arg.from, &arg.to, &arg.memo, created_at, window_start,
+        ) {
+            return Err(TransferFromError::Duplicate { duplicate_of: Nat::from(duplicate_of) });
+        }
+    }
+
+    registry.transfer(token_id, arg.from, arg.to, Some(spender), arg.memo, arg.created_at_time, now)
+        .map(Nat::from)
+        .map_err(|_| TransferFromError::Unauthorized)
+}
+
+#[update]
+fn icrc37_approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(ApproveTokenError::GenericBatchError { error_code, message }))];
+    }
+    let caller = ic_cdk::caller();
+    let now = time();
+    with_registry_mut(|registry| {
+        args.into_iter()
+            .map(|arg| Some(apply_approve_token(registry, caller, arg, now)))
+            .collect()
+    })
+}
+
+#[update]
+fn icrc37_approve_collection(args: Vec<ApproveCollectionArg>) -> Vec<Option<ApproveCollectionResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(ApproveCollectionError::GenericBatchError { error_code, message }))];
+    }
+    let caller = ic_cdk::caller();
+    let now = time();
+    with_registry_mut(|registry| {
+        args.into_iter()
+            .map(|arg| Some(apply_approve_collection(registry, caller, arg, now)))
+            .collect()
+    })
+}
+
+#[update]
+fn icrc37_revoke_token_approvals(args: Vec<RevokeTokenApprovalArg>) -> Vec<Option<RevokeTokenApprovalResult>> {
+    if args.len() > MAX_REVOKE_APPROVALS {
+        let (error_code, message) = batch_error(MAX_REVOKE_APPROVALS);
+        return vec![Some(Err(RevokeTokenApprovalError::GenericBatchError { error_code, message }))];
+    }
+    let caller = ic_cdk::caller();
+    let now = time();
+    with_registry_mut(|registry| {
+        args.into_iter()
+            .map(|arg| Some(apply_revoke_token(registry, caller, arg, now)))
+            .collect()
+    })
+}
+
+#[update]
+fn icrc37_revoke_collection_approvals(args: Vec<RevokeCollectionApprovalArg>) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    if args.len() > MAX_REVOKE_APPROVALS {
+        let (error_code, message) = batch_error(MAX_REVOKE_APPROVALS);
+        return vec![Some(Err(RevokeCollectionApprovalError::GenericBatchError { error_code, message }))];
+    }
+    let caller = ic_cdk::caller();
+    let now = time();
+    with_registry_mut(|registry| {
+        args.into_iter()
+            .map(|arg| Some(apply_revoke_collection(registry, caller, arg, now)))
+            .collect()
+    })
+}
+
+#[update]
+fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFromResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        let (error_code, message) = batch_error(MAX_UPDATE_BATCH_SIZE);
+        return vec![Some(Err(TransferFromError::GenericBatchError { error_code, message }))];
+    }
+    let caller = ic_cdk::caller();
+    let now = time();
+    with_registry_mut(|registry| {
+        args.into_iter()
+            .map(|arg| {
+                let spender = Account::new(caller, arg.spender_subaccount);
+                Some(apply_transfer_from(registry, spender, arg, now))
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    if let Err(e) = icrc7::check_query_batch(args.len()) {
+        ic_cdk::trap(&e);
+    }
+    let now = time();
+    with_registry(|registry| {
+        args.iter()
+            .map(|arg| {
+                icrc7::to_token_id(&arg.token_id)
+                    .filter(|&id| {
+                        registry.owner_of(id)
+                            .map(|owner| owner.effective_subaccount() == &arg.from_subaccount.unwrap_or_default())
+                            .unwrap_or(false)
+                    })
+                    .map(|id| registry.is_approved(id, &arg.spender, now))
+                    .unwrap_or(false)
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc37_max_approvals_per_token_or_collection() -> Option<Nat> {
+    Some(Nat::from(MAX_APPROVALS_PER_TOKEN_OR_COLLECTION))
+}
+
+#[query]
+fn icrc37_max_revoke_approvals() -> Option<Nat> {
+    Some(Nat::from(MAX_REVOKE_APPROVALS))
+}
+
+pub fn supported_standards() -> Vec<SupportedStandard> {
+    vec![SupportedStandard {
+        name: "ICRC-37".to_string(),
+        url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-37".to_string(),
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nft::registry::test_record;
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn account(id: u8) -> Account {
+        Account::from(Principal::from_slice(&[id]))
+    }
+
+    fn approval_info(spender: Account, expires_at: Option<u64>) -> ApprovalInfo {
+        ApprovalInfo { spender, from_subaccount: None, expires_at, memo: None, created_at_time: None }
+    }
+
+    fn transfer_from_arg(from: Account, to: Account, token_id: u64) -> TransferFromArg {
+        TransferFromArg {
+            spender_subaccount: None,
+            from,
+            to,
+            token_id: Nat::from(token_id),
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    /// Token 0 owned by account 1, with account 2 approved to move it.
+    fn registry_with_approval(expires_at: Option<u64>) -> TokenRegistry {
+        let mut registry = TokenRegistry::default();
+        registry.mint(test_record(0, account(1)));
+        let arg = ApproveTokenArg { token_id: Nat::from(0u64), approval_info: approval_info(account(2), expires_at) };
+        assert!(apply_approve_token(&mut registry, account(1).owner, arg, 0).is_ok());
+        registry
+    }
+
+    #[test]
+    fn test_approve_missing_token() {
+        let mut registry = TokenRegistry::default();
+        let arg = ApproveTokenArg {
+            token_id: Nat::from(1u64),
+            approval_info: ApprovalInfo {
+                spender: Account::from(Principal::management_canister()),
+                from_subaccount: None,
+                expires_at: None,
+                memo: None,
+                created_at_time: None,
+            },
+        };
+
+        let result = apply_approve_token(&mut registry, Principal::anonymous(), arg, 0);
+        assert_eq!(result, Err(ApproveTokenError::NonExistingTokenId));
+    }
+
+    #[test]
+    fn test_self_approval_is_invalid() {
+        let mut registry = TokenRegistry::default();
+        let caller = Principal::management_canister();
+        let arg = ApproveCollectionArg {
+            approval_info: ApprovalInfo {
+                spender: Account::from(caller),
+                from_subaccount: None,
+                expires_at: None,
+                memo: None,
+                created_at_time: None,
+            },
+        };
+
+        let result = apply_approve_collection(&mut registry, caller, arg, 0);
+        assert_eq!(result, Err(ApproveCollectionError::InvalidSpender));
+    }
+
+    #[test]
+    fn test_approved_spender_can_transfer_from() {
+        let mut registry = registry_with_approval(None);
+
+        let result = apply_transfer_from(&mut registry, account(2), transfer_from_arg(account(1), account(3), 0), HOUR);
+        assert_eq!(result, Ok(Nat::from(2u64)));
+        assert_eq!(registry.owner_of(0), Some(account(3)));
+        assert_eq!(registry.transactions()[2].spender, Some(account(2)));
+
+        // The approval went with the old owner.
+        let again = apply_transfer_from(&mut registry, account(2), transfer_from_arg(account(3), account(1), 0), HOUR);
+        assert_eq!(again, Err(TransferFromError::Unauthorized));
+    }
+
+    #[test]
+    fn test_expired_approval_is_rejected() {
+        let mut registry = registry_with_approval(Some(HOUR));
+
+        let result = apply_transfer_from(&mut registry, account(2), transfer_from_arg(account(1), account(3), 0), 2 * HOUR);
+        assert_eq!(result, Err(TransferFromError::Unauthorized));
+        assert_eq!(registry.owner_of(0), Some(account(1)));
+    }
+
+    #[test]
+    fn test_icrc7_transfer_clears_approvals() {
+        let mut registry = registry_with_approval(None);
+        assert!(registry.is_approved(0, &account(2), HOUR));
+
+        let arg = icrc7::TransferArg {
+            from_subaccount: None,
+            to: account(3),
+            token_id: Nat::from(0u64),
+            memo: None,
+            created_at_time: None,
+        };
+        assert!(icrc7::apply_transfer(&mut registry, account(1), arg, HOUR).is_ok());
+        assert!(registry.get(0).unwrap().approvals.is_empty());
+        assert!(!registry.is_approved(0, &account(2), HOUR));
+    }
+
+    #[test]
+    fn test_revoked_collection_approval_stops_transfer_from() {
+        let mut registry = TokenRegistry::default();
+        registry.mint(test_record(0, account(1)));
+        let approve = ApproveCollectionArg { approval_info: approval_info(account(2), None) };
+        assert!(apply_approve_collection(&mut registry, account(1).owner, approve, 0).is_ok());
+        assert!(registry.is_approved(0, &account(2), HOUR));
+
+        let revoke = RevokeCollectionApprovalArg {
+            spender: Some(account(2)),
+            from_subaccount: None,
+            memo: None,
+            created_at_time: None,
+        };
+        assert!(apply_revoke_collection(&mut registry, account(1).owner, revoke.clone(), HOUR).is_ok());
+        assert_eq!(
+            apply_revoke_collection(&mut registry, account(1).owner, revoke, HOUR),
+            Err(RevokeCollectionApprovalError::ApprovalDoesNotExist)
+        );
+
+        let result = apply_transfer_from(&mut registry, account(2), transfer_from_arg(account(1), account(3), 0), HOUR);
+        assert_eq!(result, Err(TransferFromError::Unauthorized));
+    }
+}
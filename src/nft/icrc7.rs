@@ -0,0 +1,428 @@
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use num_traits::ToPrimitive;
+use serde::Serialize;
+use crate::icrc::{Account, Subaccount, SupportedStandard, Value};
+use crate::nft::collection::with_collection;
+use crate::nft::registry::{with_registry, with_registry_mut, AnimaRecord, TokenId, TokenRegistry};
+
+pub const MAX_QUERY_BATCH_SIZE: usize = 100;
+pub const MAX_UPDATE_BATCH_SIZE: usize = 20;
+pub const DEFAULT_TAKE_VALUE: usize = 100;
+pub const MAX_TAKE_VALUE: usize = 500;
+pub const MAX_MEMO_SIZE: usize = 32;
+pub const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000; // 24 hours in nanoseconds
+pub const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000; // 2 minutes in nanoseconds
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type TransferResult = std::result::Result<Nat, TransferError>;
+
+pub fn to_token_id(token_id: &Nat) -> Option<TokenId> {
+    token_id.0.to_u64()
+}
+
+pub fn take_limit(take: Option<Nat>) -> usize {
+    take.and_then(|t| t.0.to_usize())
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(MAX_TAKE_VALUE)
+}
+
+/// Rejects `created_at_time` values outside the deduplication window.
+pub fn check_created_at_time(created_at_time: Option<u64>, now: u64) -> std::result::Result<(), TransferError> {
+    if let Some(created_at) = created_at_time {
+        if created_at.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now {
+            return Err(TransferError::TooOld);
+        }
+        if created_at > now.saturating_add(PERMITTED_DRIFT) {
+            return Err(TransferError::CreatedInFuture { ledger_time: now });
+        }
+    }
+    Ok(())
+}
+
+/// Validates and applies a single ICRC-7 transfer from `from`.
+pub fn apply_transfer(
+    registry: &mut TokenRegistry,
+    from: Account,
+    arg: TransferArg,
+    now: u64,
+) -> TransferResult {
+    if arg.memo.as_ref().map(|m| m.len() > MAX_MEMO_SIZE).unwrap_or(false) {
+        return Err(TransferError::GenericError {
+            error_code: Nat::from(1u64),
+            message: format!("Memo exceeds {} bytes", MAX_MEMO_SIZE),
+        });
+    }
+    check_created_at_time(arg.created_at_time, now)?;
+
+    let token_id = to_token_id(&arg.token_id).ok_or(TransferError::NonExistingTokenId)?;
+    let owner = registry.owner_of(token_id).ok_or(TransferError::NonExistingTokenId)?;
+    if owner != from {
+        return Err(TransferError::Unauthorized);
+    }
+    if arg.to == from || arg.to.owner == candid::Principal::anonymous() {
+        return Err(TransferError::InvalidRecipient);
+    }
+
+    if let Some(created_at) = arg.created_at_time {
+        let window_start = now.saturating_sub(TX_WINDOW + PERMITTED_DRIFT);
+        if let Some(duplicate_of) = registry.find_duplicate_transfer(
+            token_id, &from, &arg.to, &arg.memo, created_at, window_start,
+        ) {
+            return Err(TransferError::Duplicate { duplicate_of: Nat::from(duplicate_of) });
+        }
+    }
+
+    registry.transfer(token_id, from, arg.to, None, arg.memo, arg.created_at_time, now)
+        .map(Nat::from)
+        .map_err(|_| TransferError::Unauthorized)
+}
+
+/// Rejects query batches longer than `MAX_QUERY_BATCH_SIZE`. The query
+/// endpoints trap with this message instead of answering only a prefix.
+pub fn check_query_batch(len: usize) -> std::result::Result<(), String> {
+    if len > MAX_QUERY_BATCH_SIZE {
+        return Err(format!("Batch exceeds {} entries", MAX_QUERY_BATCH_SIZE));
+    }
+    Ok(())
+}
+
+/// Non-atomic batch transfer from `caller`: each argument succeeds or fails
+/// on its own.
+pub fn apply_transfers(
+    registry: &mut TokenRegistry,
+    caller: candid::Principal,
+    args: Vec<TransferArg>,
+    now: u64,
+) -> Vec<Option<TransferResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: Nat::from(2u64),
+            message: format!("Batch exceeds {} transfers", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+    args.into_iter()
+        .map(|arg| {
+            let from = Account::new(caller, arg.from_subaccount);
+            Some(apply_transfer(registry, from, arg, now))
+        })
+        .collect()
+}
+
+pub fn token_metadata(record: &AnimaRecord) -> Vec<(String, Value)> {
+    vec![
+        ("icrc7:name".to_string(), Value::Text(record.name.clone())),
+        ("anima:quantum_signature".to_string(), Value::Text(record.quantum_state.quantum_signature.clone())),
+        ("anima:coherence_level".to_string(), Value::Text(format!("{:.4}", record.quantum_state.coherence_level))),
+        ("anima:consciousness_level".to_string(), Value::Text(format!("{:.4}", record.personality.consciousness_level))),
+        ("anima:evolution_stage".to_string(), Value::Nat(Nat::from(record.personality.evolution_stage as u64))),
+        ("anima:consciousness_seed".to_string(), Value::Text(record.birth_certificate.consciousness_seed.clone())),
+        ("anima:genesis_rarity".to_string(), Value::Text(format!("{:.4}", record.birth_certificate.genesis_rarity))),
+        ("anima:minted_at".to_string(), Value::Nat(Nat::from(record.minted_at))),
+    ]
+}
+
+#[query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    let mut metadata = with_collection(|collection| {
+        let meta = &collection.metadata;
+        let mut entries = vec![
+            ("icrc7:name".to_string(), Value::Text(meta.name.clone())),
+            ("icrc7:symbol".to_string(), Value::Text(meta.symbol.clone())),
+        ];
+        if let Some(description) = &meta.description {
+            entries.push(("icrc7:description".to_string(), Value::Text(description.clone())));
+        }
+        if let Some(logo) = &meta.image {
+            entries.push(("icrc7:logo".to_string(), Value::Text(logo.clone())));
+        }
+        if let Some(supply_cap) = meta.supply_cap {
+            entries.push(("icrc7:supply_cap".to_string(), Value::Nat(Nat::from(supply_cap))));
+        }
+        entries
+    });
+
+    metadata.extend([
+        ("icrc7:total_supply".to_string(), Value::Nat(icrc7_total_supply())),
+        ("icrc7:max_query_batch_size".to_string(), Value::Nat(Nat::from(MAX_QUERY_BATCH_SIZE))),
+        ("icrc7:max_update_batch_size".to_string(), Value::Nat(Nat::from(MAX_UPDATE_BATCH_SIZE))),
+        ("icrc7:default_take_value".to_string(), Value::Nat(Nat::from(DEFAULT_TAKE_VALUE))),
+        ("icrc7:max_take_value".to_string(), Value::Nat(Nat::from(MAX_TAKE_VALUE))),
+        ("icrc7:max_memo_size".to_string(), Value::Nat(Nat::from(MAX_MEMO_SIZE))),
+        ("icrc7:tx_window".to_string(), Value::Nat(Nat::from(TX_WINDOW))),
+        ("icrc7:permitted_drift".to_string(), Value::Nat(Nat::from(PERMITTED_DRIFT))),
+    ]);
+    metadata
+}
+
+#[query]
+fn icrc7_name() -> String {
+    with_collection(|collection| collection.metadata.name.clone())
+}
+
+#[query]
+fn icrc7_symbol() -> String {
+    with_collection(|collection| collection.metadata.symbol.clone())
+}
+
+#[query]
+fn icrc7_description() -> Option<String> {
+    with_collection(|collection| collection.metadata.description.clone())
+}
+
+#[query]
+fn icrc7_logo() -> Option<String> {
+    with_collection(|collection| collection.metadata.image.clone())
+}
+
+#[query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(with_registry(|registry| registry.total_supply()))
+}
+
+#[query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    with_collection(|collection| collection.metadata.supply_cap.map(Nat::from))
+}
+
+#[query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_QUERY_BATCH_SIZE))
+}
+
+#[query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_UPDATE_BATCH_SIZE))
+}
+
+#[query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(DEFAULT_TAKE_VALUE))
+}
+
+#[query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(MAX_TAKE_VALUE))
+}
+
+#[query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(MAX_MEMO_SIZE))
+}
+
+#[query]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[query]
+fn icrc7_tx_window() -> Option<Nat> {
+    Some(Nat::from(TX_WINDOW))
+}
+
+#[query]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    Some(Nat::from(PERMITTED_DRIFT))
+}
+
+#[query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    if let Err(e) = check_query_batch(token_ids.len()) {
+        ic_cdk::trap(&e);
+    }
+    with_registry(|registry| {
+        token_ids.iter()
+            .map(|token_id| {
+                to_token_id(token_id)
+                    .and_then(|id| registry.get(id).ok())
+                    .map(token_metadata)
+            })
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    if let Err(e) = check_query_batch(token_ids.len()) {
+        ic_cdk::trap(&e);
+    }
+    with_registry(|registry| {
+        token_ids.iter()
+            .map(|token_id| to_token_id(token_id).and_then(|id| registry.owner_of(id)))
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    if let Err(e) = check_query_batch(accounts.len()) {
+        ic_cdk::trap(&e);
+    }
+    with_registry(|registry| {
+        accounts.iter()
+            .map(|account| Nat::from(registry.balance_of(account)))
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let prev = prev.as_ref().and_then(to_token_id);
+    with_registry(|registry| {
+        registry.token_ids(prev)
+            .take(take_limit(take))
+            .map(Nat::from)
+            .collect()
+    })
+}
+
+#[query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let prev = prev.as_ref().and_then(to_token_id);
+    with_registry(|registry| {
+        registry.tokens_of(&account, prev, take_limit(take))
+            .into_iter()
+            .map(Nat::from)
+            .collect()
+    })
+}
+
+#[update]
+fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
+    let caller = ic_cdk::caller();
+    let now = time();
+    with_registry_mut(|registry| apply_transfers(registry, caller, args, now))
+}
+
+#[query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    let mut standards = vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+    ];
+    standards.extend(crate::nft::icrc37::supported_standards());
+    standards
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nft::registry::test_record;
+
+    #[test]
+    fn test_created_at_time_window() {
+        let now = 10 * TX_WINDOW;
+
+        assert_eq!(check_created_at_time(None, now), Ok(()));
+        assert_eq!(check_created_at_time(Some(now), now), Ok(()));
+        assert_eq!(check_created_at_time(Some(now - 2 * TX_WINDOW), now), Err(TransferError::TooOld));
+        assert_eq!(
+            check_created_at_time(Some(now + 2 * PERMITTED_DRIFT), now),
+            Err(TransferError::CreatedInFuture { ledger_time: now })
+        );
+    }
+
+    #[test]
+    fn test_take_limit_is_clamped() {
+        assert_eq!(take_limit(None), DEFAULT_TAKE_VALUE);
+        assert_eq!(take_limit(Some(Nat::from(10u64))), 10);
+        assert_eq!(take_limit(Some(Nat::from(10_000u64))), MAX_TAKE_VALUE);
+    }
+
+    #[test]
+    fn test_transfer_of_missing_token() {
+        let mut registry = TokenRegistry::default();
+        let arg = TransferArg {
+            from_subaccount: None,
+            to: Account::from(candid::Principal::management_canister()),
+            token_id: Nat::from(3u64),
+            memo: None,
+            created_at_time: None,
+        };
+
+        let result = apply_transfer(&mut registry, Account::from(candid::Principal::anonymous()), arg, 0);
+        assert_eq!(result, Err(TransferError::NonExistingTokenId));
+    }
+
+    fn account(id: u8) -> Account {
+        Account::from(candid::Principal::from_slice(&[id]))
+    }
+
+    fn transfer_arg(to: Account, token_id: u64, created_at_time: Option<u64>) -> TransferArg {
+        TransferArg { from_subaccount: None, to, token_id: Nat::from(token_id), memo: None, created_at_time }
+    }
+
+    #[test]
+    fn test_oversized_query_batch_is_rejected() {
+        assert_eq!(check_query_batch(MAX_QUERY_BATCH_SIZE), Ok(()));
+        assert!(check_query_batch(MAX_QUERY_BATCH_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn test_batch_transfer() {
+        let mut registry = TokenRegistry::default();
+        registry.mint(test_record(0, account(1)));
+        registry.mint(test_record(1, account(1)));
+
+        let args = vec![
+            transfer_arg(account(2), 0, None),
+            transfer_arg(account(3), 1, None),
+            transfer_arg(account(2), 7, None),
+        ];
+        let results = apply_transfers(&mut registry, account(1).owner, args, 0);
+        assert_eq!(results, vec![
+            Some(Ok(Nat::from(2u64))),
+            Some(Ok(Nat::from(3u64))),
+            Some(Err(TransferError::NonExistingTokenId)),
+        ]);
+        assert_eq!(registry.owner_of(0), Some(account(2)));
+        assert_eq!(registry.owner_of(1), Some(account(3)));
+
+        let oversized = (0..=MAX_UPDATE_BATCH_SIZE as u64).map(|id| transfer_arg(account(2), id, None)).collect();
+        let results = apply_transfers(&mut registry, account(1).owner, oversized, 0);
+        assert!(matches!(results.as_slice(), [Some(Err(TransferError::GenericBatchError { .. }))]));
+    }
+
+    #[test]
+    fn test_retried_transfer_is_deduplicated() {
+        let mut registry = TokenRegistry::default();
+        registry.mint(test_record(0, account(1)));
+        let now = TX_WINDOW;
+
+        let first = apply_transfer(&mut registry, account(1), transfer_arg(account(2), 0, Some(now)), now);
+        assert_eq!(first, Ok(Nat::from(1u64)));
+        // Account 2 hands the token back, so only deduplication can stop the retry.
+        assert!(apply_transfer(&mut registry, account(2), transfer_arg(account(1), 0, None), now).is_ok());
+
+        let retry = apply_transfer(&mut registry, account(1), transfer_arg(account(2), 0, Some(now)), now + 1);
+        assert_eq!(retry, Err(TransferError::Duplicate { duplicate_of: Nat::from(1u64) }));
+        assert_eq!(registry.owner_of(0), Some(account(1)));
+    }
+}
@@ -0,0 +1,176 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::icrc::AcceptedToken;
+use crate::nft::registry::TokenId;
+
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+pub const DAY: u64 = 24 * NANOS_PER_HOUR;
+pub const WEEK: u64 = 7 * DAY;
+/// Sales kept for the history view; volume is tracked separately in hourly
+/// buckets so trimming history never skews the rolling totals.
+pub const MAX_SALES_HISTORY: usize = 10_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaleKind {
+    Listing,
+    Offer,
+    Auction,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Sale {
+    pub seq: u64,
+    pub token_id: TokenId,
+    pub seller: Principal,
+    pub buyer: Principal,
+    pub price: u64,
+    pub payment_token: AcceptedToken,
+    pub kind: SaleKind,
+    pub timestamp: u64,
+}
+
+/// Market figures for one payment token.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct MarketStats {
+    pub payment_token: AcceptedToken,
+    pub floor_price: Option<u64>,
+    pub listed_count: u64,
+    pub volume_24h: u64,
+    pub volume_7d: u64,
+}
+
+/// Everything in `SalesHistory` but the sales themselves, which are persisted
+/// one row each.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SalesTotals {
+    next_seq: u64,
+    /// Volume per (payment token, hour since epoch), pruned to the last week.
+    hourly_volume: BTreeMap<(AcceptedToken, u64), u64>,
+    /// All-time volume per payment token.
+    total_volume: BTreeMap<AcceptedToken, u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SalesHistory {
+    totals: SalesTotals,
+    sales: Vec<Sale>,
+}
+
+impl SalesHistory {
+    pub fn restore(totals: SalesTotals, sales: Vec<Sale>) -> Self {
+        Self { totals, sales }
+    }
+
+    pub fn totals(&self) -> &SalesTotals {
+        &self.totals
+    }
+
+    /// Oldest first.
+    pub fn sales(&self) -> &[Sale] {
+        &self.sales
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &mut self,
+        token_id: TokenId,
+        seller: Principal,
+        buyer: Principal,
+        price: u64,
+        payment_token: AcceptedToken,
+        kind: SaleKind,
+        now: u64,
+    ) -> u64 {
+        let seq = self.totals.next_seq;
+        self.totals.next_seq += 1;
+        self.sales.push(Sale { seq, token_id, seller, buyer, price, payment_token, kind, timestamp: now });
+        if self.sales.len() > MAX_SALES_HISTORY {
+            let excess = self.sales.len() - MAX_SALES_HISTORY;
+            self.sales.drain(..excess);
+        }
+
+        let totals = &mut self.totals;
+        *totals.hourly_volume.entry((payment_token, now / NANOS_PER_HOUR)).or_insert(0) += price;
+        *totals.total_volume.entry(payment_token).or_insert(0) += price;
+        let oldest_hour = now.saturating_sub(WEEK) / NANOS_PER_HOUR;
+        totals.hourly_volume.retain(|(_, hour), _| *hour >= oldest_hour);
+        seq
+    }
+
+    /// Volume in `payment_token` over the trailing `window`, at hour granularity.
+    pub fn volume(&self, payment_token: AcceptedToken, window: u64, now: u64) -> u64 {
+        let since = now.saturating_sub(window) / NANOS_PER_HOUR;
+        self.totals.hourly_volume
+            .range((payment_token, since)..=(payment_token, u64::MAX))
+            .map(|(_, volume)| volume)
+            .sum()
+    }
+
+    /// All-time volume per payment token.
+    pub fn total_volume(&self) -> impl Iterator<Item = (AcceptedToken, u64)> + '_ {
+        self.totals.total_volume.iter().map(|(token, volume)| (*token, *volume))
+    }
+
+    /// Newest-first page of sales older than `before` (a sale `seq`),
+    /// optionally for a single token.
+    pub fn page(&self, token_id: Option<TokenId>, before: Option<u64>, limit: usize) -> Vec<Sale> {
+        self.sales.iter()
+            .rev()
+            .filter(|sale| before.map(|seq| sale.seq < seq).unwrap_or(true))
+            .filter(|sale| token_id.map(|id| sale.token_id == id).unwrap_or(true))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(history: &mut SalesHistory, token_id: TokenId, price: u64, token: AcceptedToken, now: u64) {
+        let seller = Principal::from_slice(&[1]);
+        let buyer = Principal::from_slice(&[2]);
+        history.record(token_id, seller, buyer, price, token, SaleKind::Listing, now);
+    }
+
+    #[test]
+    fn test_rolling_volume() {
+        let mut history = SalesHistory::default();
+        record(&mut history, 1, 100, AcceptedToken::ICP, 0);
+        record(&mut history, 2, 200, AcceptedToken::ICP, 2 * DAY);
+        record(&mut history, 3, 400, AcceptedToken::ANIMA, 2 * DAY);
+        record(&mut history, 4, 800, AcceptedToken::ICP, 2 * DAY + 3 * NANOS_PER_HOUR);
+
+        let now = 2 * DAY + 4 * NANOS_PER_HOUR;
+        assert_eq!(history.volume(AcceptedToken::ICP, DAY, now), 1_000);
+        assert_eq!(history.volume(AcceptedToken::ICP, WEEK, now), 1_100);
+        assert_eq!(history.volume(AcceptedToken::ANIMA, WEEK, now), 400);
+
+        // Buckets older than a week are pruned on the next sale.
+        record(&mut history, 5, 1, AcceptedToken::ICP, 10 * DAY);
+        assert_eq!(history.volume(AcceptedToken::ICP, 30 * DAY, 10 * DAY), 1);
+        // All-time totals are never pruned.
+        assert_eq!(
+            history.total_volume().collect::<Vec<_>>(),
+            vec![(AcceptedToken::ICP, 1_101), (AcceptedToken::ANIMA, 400)]
+        );
+    }
+
+    #[test]
+    fn test_history_pages_newest_first() {
+        let mut history = SalesHistory::default();
+        for token_id in 0..5 {
+            record(&mut history, token_id % 2, 100, AcceptedToken::ICP, token_id);
+        }
+
+        let first = history.page(None, None, 2);
+        assert_eq!(first.iter().map(|s| s.seq).collect::<Vec<_>>(), vec![4, 3]);
+        let next = history.page(None, Some(3), 2);
+        assert_eq!(next.iter().map(|s| s.seq).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(history.page(Some(1), None, 10).len(), 2);
+    }
+}
@@ -1,133 +1,1154 @@
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
 use serde::{Deserialize, Serialize};
 use ic_cdk::api::time;
-use ic_stable_structures::Storable;
+use ic_cdk_macros::*;
+use num_traits::ToPrimitive;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 
-use crate::nft::types::TokenIdentifier;
+use crate::admin::{require_role, Role};
+use crate::icrc::client;
+use crate::icrc::{Account, AcceptedToken, Subaccount};
+use crate::nft::auction::with_auctions;
+use crate::nft::collection::with_collection_mut;
+use crate::nft::market_stats::{MarketStats, Sale, SaleKind, SalesHistory, SalesTotals, DAY, WEEK};
+use crate::nft::registry::{with_registry, with_registry_mut, TokenId, TokenRegistry};
+use crate::nft::royalties::with_royalties_mut;
+use crate::payments::pricing_config::ServiceFees;
+use crate::payments::transaction_processor::token_canister;
+use crate::stable::{
+    RegionKey, StableRegion, MARKETPLACE_MEMORY_ID, MARKETPLACE_META_MEMORY_ID, MARKETPLACE_OFFERS_MEMORY_ID,
+    MARKETPLACE_PAYOUTS_MEMORY_ID, MARKETPLACE_SALES_MEMORY_ID,
+};
+
+/// Subaccount of this canister that holds buyer funds for open offers and bids,
+/// and royalties accrued but not yet claimed.
+pub const ESCROW_SUBACCOUNT: Subaccount = *b"anima-marketplace-escrow\0\0\0\0\0\0\0\0";
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct Listing {
-    pub token_id: TokenIdentifier,
+    pub token_id: TokenId,
     pub seller: Principal,
     pub price: u64,
+    pub payment_token: AcceptedToken,
     pub created_at: u64,
     pub expires_at: Option<u64>,
 }
 
+impl Listing {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.map(|exp| exp > now).unwrap_or(true)
+    }
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct Offer {
-    pub token_id: TokenIdentifier,
+    pub id: u64,
+    pub token_id: TokenId,
     pub buyer: Principal,
     pub price: u64,
+    pub payment_token: AcceptedToken,
+    /// Ledger block that moved the buyer's funds into escrow.
+    pub escrow_block: u64,
     pub created_at: u64,
     pub expires_at: u64,
 }
 
+/// A payout out of escrow that failed and is waiting to be retried.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PendingPayout {
+    pub to: Principal,
+    pub amount: u64,
+    pub payment_token: AcceptedToken,
+    /// Subaccount the payout is sent from; `None` for the marketplace escrow.
+    /// Other subsystems queue refunds of their own funds here too.
+    pub from_subaccount: Option<Subaccount>,
+    pub reason: String,
+    /// Set by the first attempt and reused by every retry, so a transfer that
+    /// timed out but landed is deduplicated by the ledger instead of paid twice.
+    pub created_at_time: u64,
+    pub attempts: u32,
+    pub last_error: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListingSort {
+    PriceAsc,
+    PriceDesc,
+    Newest,
+    TokenId,
+}
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
-pub struct MarketplaceState {
+pub struct ListingFilter {
+    pub seller: Option<Principal>,
+    pub payment_token: Option<AcceptedToken>,
+    pub min_price: Option<u64>,
+    pub max_price: Option<u64>,
+}
+
+impl ListingFilter {
+    fn matches(&self, listing: &Listing) -> bool {
+        self.seller.map(|seller| listing.seller == seller).unwrap_or(true)
+            && self.payment_token.map(|token| listing.payment_token == token).unwrap_or(true)
+            && self.min_price.map(|min| listing.price >= min).unwrap_or(true)
+            && self.max_price.map(|max| listing.price <= max).unwrap_or(true)
+    }
+}
+
+/// Position after the last listing of a page: the listing's sort key under
+/// the requested sort, and its token id as a tiebreaker.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListingCursor {
+    pub key: u64,
+    pub token_id: TokenId,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ListingPage {
     pub listings: Vec<Listing>,
-    pub offers: Vec<Offer>,
-    pub sales_volume: u64,
+    pub next_cursor: Option<ListingCursor>,
+}
+
+pub const DEFAULT_PAGE_SIZE: usize = 50;
+pub const MAX_PAGE_SIZE: usize = 200;
+
+/// Listings and offers are stored by id, with secondary indexes by seller,
+/// buyer, token and price kept in step by the insert/remove helpers below.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MarketplaceState {
+    listings: BTreeMap<TokenId, Listing>,
+    offers: BTreeMap<u64, Offer>,
+    listings_by_seller: BTreeMap<Principal, BTreeSet<TokenId>>,
+    listings_by_price: BTreeSet<(u64, TokenId)>,
+    listings_by_created: BTreeSet<(u64, TokenId)>,
+    offers_by_token: BTreeMap<TokenId, BTreeSet<u64>>,
+    offers_by_buyer: BTreeMap<Principal, BTreeSet<u64>>,
+    pub sales: SalesHistory,
     pub transaction_count: u64,
+    pub next_offer_id: u64,
+    pub pending_payouts: Vec<PendingPayout>,
 }
 
-impl Storable for MarketplaceState {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let bytes = candid::encode_one(self).unwrap();
-        std::borrow::Cow::Owned(bytes)
+impl MarketplaceState {
+    fn insert_listing(&mut self, id: TokenId, listing: Listing) {
+        self.remove_listing(id);
+        self.listings_by_seller.entry(listing.seller).or_default().insert(id);
+        self.listings_by_price.insert((listing.price, id));
+        self.listings_by_created.insert((listing.created_at, id));
+        self.listings.insert(id, listing);
     }
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        candid::decode_one(&bytes).unwrap()
+    fn remove_listing(&mut self, id: TokenId) -> Option<Listing> {
+        let listing = self.listings.remove(&id)?;
+        if let Some(ids) = self.listings_by_seller.get_mut(&listing.seller) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.listings_by_seller.remove(&listing.seller);
+            }
+        }
+        self.listings_by_price.remove(&(listing.price, id));
+        self.listings_by_created.remove(&(listing.created_at, id));
+        Some(listing)
     }
-}
 
-impl MarketplaceState {
-    pub fn list_token(
+    fn insert_offer(&mut self, offer: Offer) {
+        self.offers_by_token.entry(offer.token_id).or_default().insert(offer.id);
+        self.offers_by_buyer.entry(offer.buyer).or_default().insert(offer.id);
+        self.offers.insert(offer.id, offer);
+    }
+
+    fn remove_offer(&mut self, offer_id: u64) -> Option<Offer> {
+        let offer = self.offers.remove(&offer_id)?;
+        if let Some(ids) = self.offers_by_token.get_mut(&offer.token_id) {
+            ids.remove(&offer_id);
+            if ids.is_empty() {
+                self.offers_by_token.remove(&offer.token_id);
+            }
+        }
+        if let Some(ids) = self.offers_by_buyer.get_mut(&offer.buyer) {
+            ids.remove(&offer_id);
+            if ids.is_empty() {
+                self.offers_by_buyer.remove(&offer.buyer);
+            }
+        }
+        Some(offer)
+    }
+
+    /// Lists a token the seller owns at a fixed price. A token can only have
+    /// one live listing; a listing left behind by a previous owner or past
+    /// its expiry is dropped first.
+    #[allow(clippy::too_many_arguments)]
+    pub fn list_token<M: MarketplaceOperations>(
         &mut self,
-        token_id: TokenIdentifier,
+        ops: &M,
+        token_id: TokenId,
         seller: Principal,
         price: u64,
+        payment_token: AcceptedToken,
         expires_at: Option<u64>,
+        now: u64,
     ) -> Result<(), String> {
-        // Add listing
+        if price == 0 {
+            return Err("Listing price must be greater than 0".to_string());
+        }
+        if expires_at.map(|exp| exp <= now).unwrap_or(false) {
+            return Err("Invalid expiry time".to_string());
+        }
+        if !ops.verify_token_ownership(token_id, &seller) {
+            return Err("Caller does not own this token".to_string());
+        }
+
+        if self.is_listed(ops, token_id, now) {
+            return Err("Token is already listed".to_string());
+        }
+        self.remove_listing(token_id);
+
         let listing = Listing {
             token_id,
             seller,
             price,
-            created_at: time(),
+            payment_token,
+            created_at: now,
             expires_at,
         };
 
-        self.listings.push(listing);
+        self.insert_listing(token_id, listing);
         Ok(())
     }
 
+    /// Whether `token_id` has a live listing: unexpired, and by the seller
+    /// who still owns the token.
+    pub fn is_listed<M: MarketplaceOperations>(&self, ops: &M, token_id: TokenId, now: u64) -> bool {
+        self.listings.get(&token_id)
+            .is_some_and(|listing| listing.is_active(now) && ops.verify_token_ownership(token_id, &listing.seller))
+    }
+
+    pub fn get_listing(&self, token_id: TokenId) -> Option<&Listing> {
+        self.listings.get(&token_id)
+    }
+
     pub fn cancel_listing(
         &mut self,
-        token_id: TokenIdentifier,
+        token_id: TokenId,
         seller: Principal,
     ) -> Result<(), String> {
-        let listing_idx = self.listings
-            .iter()
-            .position(|l| l.token_id == token_id && l.seller == seller)
-            .ok_or("Listing not found")?;
-
-        self.listings.remove(listing_idx);
+        if self.listings.get(&token_id).map(|l| l.seller) != Some(seller) {
+            return Err("Listing not found".to_string());
+        }
+        self.remove_listing(token_id);
         Ok(())
     }
 
+    /// Records an offer whose funds are already held in escrow.
     pub fn make_offer(
         &mut self,
-        token_id: TokenIdentifier,
+        token_id: TokenId,
         buyer: Principal,
         price: u64,
+        payment_token: AcceptedToken,
+        escrow_block: u64,
         expires_at: u64,
-    ) -> Result<(), String> {
+    ) -> Result<u64, String> {
         if expires_at <= time() {
             return Err("Invalid expiry time".to_string());
         }
 
+        let id = self.next_offer_id;
+        self.next_offer_id += 1;
+
         let offer = Offer {
+            id,
             token_id,
             buyer,
             price,
+            payment_token,
+            escrow_block,
             created_at: time(),
             expires_at,
         };
 
-        self.offers.push(offer);
-        Ok(())
+        self.insert_offer(offer);
+        Ok(id)
     }
 
-    pub fn accept_offer(
+    pub fn get_offer(&self, offer_id: u64) -> Option<&Offer> {
+        self.offers.get(&offer_id)
+    }
+
+    pub fn offers_for_token(&self, token_id: TokenId) -> Vec<Offer> {
+        self.offers_by_token.get(&token_id)
+            .map(|ids| ids.iter().filter_map(|id| self.offers.get(id)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn offers_by_buyer(&self, buyer: &Principal) -> Vec<Offer> {
+        self.offers_by_buyer.get(buyer)
+            .map(|ids| ids.iter().filter_map(|id| self.offers.get(id)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Checks the offer against the token's current owner and moves the token
+    /// to the buyer. Nothing is changed if any check fails. Once this returns
+    /// the escrowed funds are owed to the seller.
+    pub fn accept_offer<M: MarketplaceOperations>(
+        &mut self,
+        ops: &mut M,
+        offer_id: u64,
+        seller: Principal,
+        now: u64,
+    ) -> Result<Offer, String> {
+        let offer = self.offers.get(&offer_id).ok_or("Offer not found")?;
+        if offer.expires_at <= now {
+            return Err("Offer has expired".to_string());
+        }
+        if !ops.verify_token_ownership(offer.token_id, &seller) {
+            return Err("Caller does not own this token".to_string());
+        }
+        ops.transfer_token(offer.token_id, &seller, &offer.buyer)?;
+
+        let offer = self.remove_offer(offer_id).ok_or("Offer not found")?;
+        self.record_sale(offer.token_id, seller, offer.buyer, offer.price, offer.payment_token, SaleKind::Offer, now);
+
+        Ok(offer)
+    }
+
+    /// Completes a purchase of a listing the buyer has already paid for.
+    /// `expected` is the listing the payment was taken against; if it was
+    /// cancelled, repriced or sold in the meantime nothing changes and the
+    /// caller must refund the buyer.
+    pub fn purchase_listing<M: MarketplaceOperations>(
         &mut self,
-        token_id: TokenIdentifier,
-        _seller: Principal,
+        ops: &mut M,
+        expected: &Listing,
         buyer: Principal,
-    ) -> Result<(), String> {
-        let offer_idx = self.offers
-            .iter()
-            .position(|o| o.token_id == token_id && o.buyer == buyer)
-            .ok_or("Offer not found")?;
-
-        let offer = self.offers.remove(offer_idx);
-        
-        self.sales_volume += offer.price;
+        now: u64,
+    ) -> Result<Listing, String> {
+        let id = expected.token_id;
+        let listing = self.listings.get(&id)
+            .filter(|l| {
+                l.seller == expected.seller
+                    && l.price == expected.price
+                    && l.payment_token == expected.payment_token
+            })
+            .ok_or("Listing changed or no longer available")?;
+
+        if !listing.is_active(now) {
+            return Err("Listing has expired".to_string());
+        }
+        if listing.seller == buyer {
+            return Err("Cannot buy your own listing".to_string());
+        }
+        if !ops.verify_token_ownership(id, &listing.seller) {
+            return Err("Seller no longer owns this token".to_string());
+        }
+        ops.transfer_token(id, &listing.seller, &buyer)?;
+
+        let listing = self.remove_listing(id).ok_or("Listing not found")?;
+        self.record_sale(id, listing.seller, buyer, listing.price, listing.payment_token, SaleKind::Listing, now);
+
+        Ok(listing)
+    }
+
+    /// Books a completed sale of `token_id` and drops its now stale listing.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record_sale(
+        &mut self,
+        token_id: TokenId,
+        seller: Principal,
+        buyer: Principal,
+        price: u64,
+        payment_token: AcceptedToken,
+        kind: SaleKind,
+        now: u64,
+    ) {
+        self.remove_listing(token_id);
         self.transaction_count += 1;
+        self.sales.record(token_id, seller, buyer, price, payment_token, kind, now);
+    }
 
-        Ok(())
+    pub fn withdraw_offer(&mut self, offer_id: u64, buyer: Principal) -> Result<Offer, String> {
+        if self.offers.get(&offer_id).map(|o| o.buyer) != Some(buyer) {
+            return Err("Offer not found".to_string());
+        }
+        self.remove_offer(offer_id).ok_or_else(|| "Offer not found".to_string())
     }
 
-    pub fn clean_expired(&mut self) {
+    /// Drops expired listings and returns expired offers so their escrow can
+    /// be refunded.
+    pub fn clean_expired(&mut self) -> Vec<Offer> {
         let now = time();
-        self.listings.retain(|l| l.expires_at.map(|exp| exp > now).unwrap_or(true));
-        self.offers.retain(|o| o.expires_at > now);
+        let expired_listings: Vec<TokenId> = self.listings.iter()
+            .filter(|(_, l)| !l.is_active(now))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired_listings {
+            self.remove_listing(id);
+        }
+
+        let expired_offers: Vec<u64> = self.offers.values()
+            .filter(|o| o.expires_at <= now)
+            .map(|o| o.id)
+            .collect();
+        expired_offers.into_iter().filter_map(|id| self.remove_offer(id)).collect()
+    }
+
+    fn sort_key(listing: &Listing, id: TokenId, sort: ListingSort) -> ListingCursor {
+        let key = match sort {
+            ListingSort::PriceAsc | ListingSort::PriceDesc => listing.price,
+            ListingSort::Newest => listing.created_at,
+            ListingSort::TokenId => id,
+        };
+        ListingCursor { key, token_id: id }
+    }
+
+    /// One page of active listings in `sort` order, starting after `cursor`.
+    /// Seller-filtered pages are served from the seller index; the rest walk
+    /// the price, creation or token id index from the cursor.
+    pub fn listings_page(
+        &self,
+        cursor: Option<ListingCursor>,
+        limit: usize,
+        sort: ListingSort,
+        filter: &ListingFilter,
+        now: u64,
+    ) -> ListingPage {
+        let after = |c: &ListingCursor| match cursor {
+            None => true,
+            Some(cursor) => match sort {
+                ListingSort::PriceDesc | ListingSort::Newest => (c.key, c.token_id) < (cursor.key, cursor.token_id),
+                _ => (c.key, c.token_id) > (cursor.key, cursor.token_id),
+            },
+        };
+
+        let ordered: Box<dyn Iterator<Item = ListingCursor> + '_> = if let Some(seller) = filter.seller {
+            let mut keys: Vec<ListingCursor> = self.listings_by_seller.get(&seller)
+                .into_iter()
+                .flatten()
+                .filter_map(|id| self.listings.get(id).map(|l| Self::sort_key(l, *id, sort)))
+                .collect();
+            keys.sort_by_key(|c| (c.key, c.token_id));
+            if matches!(sort, ListingSort::PriceDesc | ListingSort::Newest) {
+                keys.reverse();
+            }
+            Box::new(keys.into_iter())
+        } else {
+            let as_cursor = |&(key, token_id): &(u64, TokenId)| ListingCursor { key, token_id };
+            match sort {
+                ListingSort::PriceAsc => Box::new(self.listings_by_price.iter().map(as_cursor)),
+                ListingSort::PriceDesc => Box::new(self.listings_by_price.iter().rev().map(as_cursor)),
+                ListingSort::Newest => Box::new(self.listings_by_created.iter().rev().map(as_cursor)),
+                ListingSort::TokenId => Box::new(self.listings.keys().map(|id| ListingCursor { key: *id, token_id: *id })),
+            }
+        };
+
+        let mut page: Vec<(ListingCursor, &Listing)> = ordered
+            .skip_while(|c| !after(c))
+            .filter_map(|c| self.listings.get(&c.token_id).map(|l| (c, l)))
+            .filter(|(_, l)| l.is_active(now) && filter.matches(l))
+            .take(limit + 1)
+            .collect();
+
+        let next_cursor = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|(c, _)| *c)
+        } else {
+            None
+        };
+
+        ListingPage {
+            listings: page.into_iter().map(|(_, l)| l.clone()).collect(),
+            next_cursor,
+        }
+    }
+
+    /// Cheapest active listing in `payment_token`.
+    pub fn floor_price(&self, payment_token: AcceptedToken, now: u64) -> Option<u64> {
+        self.listings_by_price.iter()
+            .filter_map(|(_, id)| self.listings.get(id))
+            .find(|l| l.payment_token == payment_token && l.is_active(now))
+            .map(|l| l.price)
+    }
+
+    pub fn market_stats(&self, payment_token: AcceptedToken, now: u64) -> MarketStats {
+        MarketStats {
+            payment_token,
+            floor_price: self.floor_price(payment_token, now),
+            listed_count: self.listings.values()
+                .filter(|l| l.payment_token == payment_token && l.is_active(now))
+                .count() as u64,
+            volume_24h: self.sales.volume(payment_token, DAY, now),
+            volume_7d: self.sales.volume(payment_token, WEEK, now),
+        }
     }
 }
 
 // Public API for marketplace operations
 pub trait MarketplaceOperations {
-    fn verify_token_ownership(&self, token_id: &TokenIdentifier, owner: &Principal) -> bool;
-    fn transfer_token(&mut self, token_id: &TokenIdentifier, from: &Principal, to: &Principal) -> Result<(), String>;
-}
\ No newline at end of file
+    fn verify_token_ownership(&self, token_id: TokenId, owner: &Principal) -> bool;
+    fn transfer_token(&mut self, token_id: TokenId, from: &Principal, to: &Principal) -> Result<(), String>;
+}
+
+/// The marketplace settles sales as an ICRC-37 spender: sellers approve this
+/// canister for a token (or the whole collection) instead of handing it over.
+pub fn marketplace_spender() -> Account {
+    Account::from(ic_cdk::id())
+}
+
+impl MarketplaceOperations for TokenRegistry {
+    fn verify_token_ownership(&self, token_id: TokenId, owner: &Principal) -> bool {
+        self.owner_of(token_id)
+            .map(|account| &account.owner == owner)
+            .unwrap_or(false)
+    }
+
+    fn transfer_token(&mut self, id: TokenId, from: &Principal, to: &Principal) -> Result<(), String> {
+        let now = time();
+        let spender = marketplace_spender();
+        if !self.is_approved(id, &spender, now) {
+            return Err("Marketplace is not approved to transfer this token".to_string());
+        }
+        let owner = self.owner_of(id).ok_or("Token not found")?;
+        if &owner.owner != from {
+            return Err("Seller no longer owns this token".to_string());
+        }
+
+        self.transfer(id, owner, Account::from(*to), Some(spender), None, None, now)
+            .map(|_| ())
+            .map_err(|e| format!("Transfer failed: {:?}", e))
+    }
+}
+
+thread_local! {
+    static MARKETPLACE: RefCell<MarketplaceState> = RefCell::new(MarketplaceState::default());
+}
+
+/// Counters and rolling totals; listings, offers, sales and pending payouts
+/// are persisted one row each.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+struct MarketplaceMeta {
+    transaction_count: u64,
+    next_offer_id: u64,
+    sales: SalesTotals,
+}
+
+const LISTINGS_REGION: StableRegion<Listing> = StableRegion::new(MARKETPLACE_MEMORY_ID);
+const OFFERS_REGION: StableRegion<Offer> = StableRegion::new(MARKETPLACE_OFFERS_MEMORY_ID);
+const SALES_REGION: StableRegion<Sale> = StableRegion::new(MARKETPLACE_SALES_MEMORY_ID);
+const PAYOUTS_REGION: StableRegion<PendingPayout> = StableRegion::new(MARKETPLACE_PAYOUTS_MEMORY_ID);
+const META_REGION: StableRegion<MarketplaceMeta> = StableRegion::new(MARKETPLACE_META_MEMORY_ID);
+const LISTING_KEY: &str = "listing";
+const OFFER_KEY: &str = "offer";
+const SALE_KEY: &str = "sale";
+const PAYOUT_KEY: &str = "payout";
+const META_KEY: &str = "marketplace";
+
+pub fn with_marketplace<R>(f: impl FnOnce(&MarketplaceState) -> R) -> R {
+    MARKETPLACE.with(|marketplace| f(&marketplace.borrow()))
+}
+
+pub fn with_marketplace_mut<R>(f: impl FnOnce(&mut MarketplaceState) -> R) -> R {
+    MARKETPLACE.with(|marketplace| f(&mut marketplace.borrow_mut()))
+}
+
+pub fn save_stable() -> crate::Result<()> {
+    MARKETPLACE.with(|marketplace| {
+        let marketplace = marketplace.borrow();
+        LISTINGS_REGION.save(marketplace.listings.iter().map(|(id, listing)| {
+            (RegionKey::new(LISTING_KEY, *id), listing)
+        }))?;
+        OFFERS_REGION.save(marketplace.offers.iter().map(|(id, offer)| {
+            (RegionKey::new(OFFER_KEY, *id), offer)
+        }))?;
+        SALES_REGION.save(marketplace.sales.sales().iter().map(|sale| {
+            (RegionKey::new(SALE_KEY, sale.seq), sale)
+        }))?;
+        PAYOUTS_REGION.save(marketplace.pending_payouts.iter().enumerate().map(|(index, payout)| {
+            (RegionKey::new(PAYOUT_KEY, index as u64), payout)
+        }))?;
+        let meta = MarketplaceMeta {
+            transaction_count: marketplace.transaction_count,
+            next_offer_id: marketplace.next_offer_id,
+            sales: marketplace.sales.totals().clone(),
+        };
+        META_REGION.save([(RegionKey::singleton(META_KEY), &meta)])
+    })
+}
+
+pub fn restore_stable() -> crate::Result<()> {
+    let listings = LISTINGS_REGION.load()?;
+    let offers = OFFERS_REGION.load()?;
+    let sales = SALES_REGION.load()?;
+    let payouts = PAYOUTS_REGION.load()?;
+    let meta = META_REGION.load_singleton(META_KEY)?.unwrap_or_default();
+    MARKETPLACE.with(|marketplace| {
+        let mut marketplace = marketplace.borrow_mut();
+        *marketplace = MarketplaceState::default();
+        // Rows iterate in key order, so sales and payouts keep their original order.
+        for (key, listing) in listings {
+            marketplace.insert_listing(key.seq, listing);
+        }
+        for (_, offer) in offers {
+            marketplace.insert_offer(offer);
+        }
+        marketplace.sales = SalesHistory::restore(meta.sales, sales.into_iter().map(|(_, sale)| sale).collect());
+        marketplace.pending_payouts = payouts.into_iter().map(|(_, payout)| payout).collect();
+        marketplace.transaction_count = meta.transaction_count;
+        marketplace.next_offer_id = meta.next_offer_id;
+    });
+    Ok(())
+}
+
+pub(crate) fn escrow_account() -> Account {
+    Account::new(ic_cdk::id(), Some(ESCROW_SUBACCOUNT))
+}
+
+pub(crate) fn nat_to_u64(value: &Nat) -> u64 {
+    value.0.to_u64().unwrap_or(u64::MAX)
+}
+
+/// Splits a sale price into (marketplace fee, seller proceeds), each net of
+/// the ledger fee its payout costs. `royalty` is left in escrow for its
+/// recipients to claim. A fee too small to cover its ledger fee is folded
+/// into the seller's share.
+pub fn split_sale(price: u64, royalty: u64, marketplace_fee: u64, ledger_fee: u64) -> (u64, u64) {
+    let royalty = royalty.min(price);
+    let marketplace_fee = marketplace_fee.min(price - royalty);
+    let marketplace_fee = if marketplace_fee > ledger_fee { marketplace_fee } else { 0 };
+    let seller = (price - royalty - marketplace_fee).saturating_sub(ledger_fee);
+    (marketplace_fee.saturating_sub(ledger_fee), seller)
+}
+
+async fn transfer_payout(payout: &PendingPayout) -> crate::Result<()> {
+    let ledger = token_canister(&payout.payment_token)?;
+    client::icrc1_transfer_at(
+        ledger,
+        Some(payout.from_subaccount.unwrap_or(ESCROW_SUBACCOUNT)),
+        Account::from(payout.to),
+        Nat::from(payout.amount),
+        None,
+        payout.created_at_time,
+    ).await.map(|_| ())
+}
+
+/// Pays `amount` out of escrow, queueing it for retry if the ledger call fails.
+pub(crate) async fn pay_from_escrow(to: Principal, amount: u64, payment_token: AcceptedToken, reason: &str) {
+    pay_from(None, to, amount, payment_token, reason).await;
+}
+
+/// Pays `amount` out of one of this canister's subaccounts, `None` being the
+/// escrow, queueing it with the pending payouts if the ledger call fails.
+/// Returns whether it was paid now.
+pub(crate) async fn pay_from(
+    from_subaccount: Option<Subaccount>,
+    to: Principal,
+    amount: u64,
+    payment_token: AcceptedToken,
+    reason: &str,
+) -> bool {
+    if amount == 0 {
+        return true;
+    }
+    let mut payout = PendingPayout {
+        to,
+        amount,
+        payment_token,
+        from_subaccount,
+        reason: reason.to_string(),
+        created_at_time: time(),
+        attempts: 1,
+        last_error: String::new(),
+    };
+    match transfer_payout(&payout).await {
+        Ok(()) => true,
+        Err(e) => {
+            payout.last_error = format!("{:?}", e);
+            with_marketplace_mut(|marketplace| marketplace.pending_payouts.push(payout));
+            false
+        }
+    }
+}
+
+pub(crate) async fn ledger_fee(payment_token: AcceptedToken) -> u64 {
+    match token_canister(&payment_token) {
+        Ok(ledger) => client::icrc1_fee(ledger).await.map(|fee| nat_to_u64(&fee)).unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+/// Returns escrowed funds to the buyer, minus the ledger fee for the refund.
+pub(crate) async fn refund_escrow(buyer: Principal, amount: u64, payment_token: AcceptedToken, reason: &str) {
+    let fee = ledger_fee(payment_token).await;
+    pay_from_escrow(buyer, amount.saturating_sub(fee), payment_token, reason).await;
+}
+
+/// Pays a completed sale out of escrow. The royalty split is credited to its
+/// recipients, who claim it later; the marketplace fee and the seller's
+/// proceeds are paid out now.
+pub(crate) async fn pay_out_sale(
+    token_id: u64,
+    seller: Principal,
+    price: u64,
+    payment_token: AcceptedToken,
+    sale: &str,
+) {
+    // Credit royalties before the first await so the sale and its accrual
+    // land in the same message.
+    let minter = with_registry(|registry| {
+        registry.get(token_id).ok().map(|record| record.birth_certificate.minting_principal)
+    });
+    let royalty = with_royalties_mut(|royalties| royalties.accrue(token_id, price, payment_token, minter, time()));
+    with_collection_mut(|collection| collection.add_royalties(royalty));
+
+    let marketplace_fee = ServiceFees::default().marketplace_fee(price);
+    let fee = ledger_fee(payment_token).await;
+    let (fee_payout, seller_payout) = split_sale(price, royalty, marketplace_fee, fee);
+
+    pay_from_escrow(ic_cdk::id(), fee_payout, payment_token, &format!("marketplace fee {}", sale)).await;
+    pay_from_escrow(seller, seller_payout, payment_token, &format!("proceeds {}", sale)).await;
+}
+
+/// Locks `price` of the buyer's funds in escrow via ICRC-2 and records the
+/// offer. The buyer must first approve this canister on the payment ledger.
+#[update]
+async fn make_offer(
+    token_id: u64,
+    price: u64,
+    payment_token: AcceptedToken,
+    expires_at: u64,
+) -> Result<u64, String> {
+    let buyer = ic_cdk::caller();
+    if price == 0 {
+        return Err("Offer price must be greater than 0".to_string());
+    }
+    if expires_at <= time() {
+        return Err("Invalid expiry time".to_string());
+    }
+    let owner = with_registry(|registry| registry.owner_of(token_id))
+        .ok_or("Token not found")?;
+    if owner.owner == buyer {
+        return Err("Cannot make an offer on your own token".to_string());
+    }
+
+    let ledger = token_canister(&payment_token).map_err(|e| format!("{:?}", e))?;
+    let escrow_block = client::icrc2_transfer_from(
+        ledger,
+        Account::from(buyer),
+        escrow_account(),
+        Nat::from(price),
+        None,
+    ).await.map_err(|e| format!("Escrow failed: {:?}", e))?;
+
+    let recorded = with_marketplace_mut(|marketplace| {
+        marketplace.make_offer(
+            token_id,
+            buyer,
+            price,
+            payment_token,
+            nat_to_u64(&escrow_block),
+            expires_at,
+        )
+    });
+
+    // The expiry may have passed while the escrow call was in flight.
+    if let Err(e) = recorded {
+        refund_escrow(buyer, price, payment_token, &format!("rejected offer on token {}", token_id)).await;
+        return Err(e);
+    }
+    recorded
+}
+
+/// Sells the token to the offer's buyer: ownership is verified and the token
+/// moved in one step, then the sale is paid out of escrow.
+#[update]
+async fn accept_offer(offer_id: u64) -> Result<(), String> {
+    let seller = ic_cdk::caller();
+    let now = time();
+    let token_id = with_marketplace(|marketplace| marketplace.get_offer(offer_id).map(|offer| offer.token_id))
+        .ok_or("Offer not found")?;
+    if with_auctions(|auctions| auctions.is_on_auction(token_id)) {
+        return Err("Token is up for auction".to_string());
+    }
+
+    let offer = with_marketplace_mut(|marketplace| {
+        with_registry_mut(|registry| marketplace.accept_offer(registry, offer_id, seller, now))
+    })?;
+
+    pay_out_sale(offer.token_id, seller, offer.price, offer.payment_token, &format!("offer {}", offer.id)).await;
+
+    Ok(())
+}
+
+#[update]
+async fn cancel_offer(offer_id: u64) -> Result<(), String> {
+    let buyer = ic_cdk::caller();
+    let offer = with_marketplace_mut(|marketplace| marketplace.withdraw_offer(offer_id, buyer))?;
+    refund_escrow(offer.buyer, offer.price, offer.payment_token, &format!("cancelled offer {}", offer.id)).await;
+    Ok(())
+}
+
+/// Refunds every expired offer. Safe for anyone to call: funds only ever go
+/// back to the buyer that escrowed them.
+#[update]
+async fn refund_expired_offers() -> u64 {
+    let expired = with_marketplace_mut(|marketplace| marketplace.clean_expired());
+    for offer in &expired {
+        refund_escrow(offer.buyer, offer.price, offer.payment_token, &format!("expired offer {}", offer.id)).await;
+    }
+    expired.len() as u64
+}
+
+/// Retries queued payouts. Payouts that fail again stay queued; one
+/// still unpaid once the ledger's deduplication window has passed is rejected
+/// as too old and is left for an operator to reconcile.
+#[update]
+async fn retry_pending_payouts() -> u64 {
+    let pending = with_marketplace_mut(|marketplace| std::mem::take(&mut marketplace.pending_payouts));
+    let mut paid = 0;
+    for mut payout in pending {
+        match transfer_payout(&payout).await {
+            Ok(()) => paid += 1,
+            Err(e) => {
+                payout.attempts += 1;
+                payout.last_error = format!("{:?}", e);
+                with_marketplace_mut(|marketplace| marketplace.pending_payouts.push(payout));
+            }
+        }
+    }
+    paid
+}
+
+thread_local! {
+    /// Listings with a buyer's payment in flight; not persisted since a
+    /// purchase never spans an upgrade.
+    static PURCHASES_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Holds a listing for one buyer while their payment is collected, so a
+/// second buyer is turned away before paying rather than refunded after.
+struct PurchaseLock(u64);
+
+impl PurchaseLock {
+    fn acquire(token_id: u64) -> Result<Self, String> {
+        PURCHASES_IN_FLIGHT.with(|in_flight| {
+            if in_flight.borrow_mut().insert(token_id) {
+                Ok(Self(token_id))
+            } else {
+                Err("A purchase of this token is already in progress".to_string())
+            }
+        })
+    }
+}
+
+impl Drop for PurchaseLock {
+    fn drop(&mut self) {
+        PURCHASES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Lists a token at a fixed price. The seller must also approve this
+/// canister through ICRC-37 so a buyer's purchase can settle.
+#[update]
+fn list_token(
+    token_id: u64,
+    price: u64,
+    payment_token: AcceptedToken,
+    expires_at: Option<u64>,
+) -> Result<(), String> {
+    let seller = ic_cdk::caller();
+    let now = time();
+    token_canister(&payment_token).map_err(|e| format!("{:?}", e))?;
+    if with_auctions(|auctions| auctions.is_on_auction(token_id)) {
+        return Err("Token is up for auction".to_string());
+    }
+
+    with_marketplace_mut(|marketplace| {
+        with_registry(|registry| {
+            marketplace.list_token(registry, token_id, seller, price, payment_token, expires_at, now)
+        })
+    })
+}
+
+/// Withdraws a listing. Moderators may take down anyone's listing.
+#[update]
+fn cancel_listing(token_id: u64) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    let seller = with_marketplace(|marketplace| marketplace.get_listing(token_id).map(|l| l.seller))
+        .ok_or("Listing not found")?;
+    if seller != caller {
+        require_role(Role::Moderator, &format!("cancel_listing {}", token_id)).map_err(|e| format!("{:?}", e))?;
+    }
+    with_marketplace_mut(|marketplace| marketplace.cancel_listing(token_id, seller))
+}
+
+/// Buys a listed token at its asking price. The buyer must first approve this
+/// canister for the price on the listing's payment ledger. Payment is pulled
+/// into escrow, the token moved, then the sale is paid out of escrow.
+#[update]
+async fn buy_listing(token_id: u64) -> Result<(), String> {
+    let buyer = ic_cdk::caller();
+    let now = time();
+
+    let listing = with_marketplace(|marketplace| marketplace.get_listing(token_id).cloned())
+        .ok_or("Listing not found")?;
+    if !listing.is_active(now) {
+        return Err("Listing has expired".to_string());
+    }
+    if listing.seller == buyer {
+        return Err("Cannot buy your own listing".to_string());
+    }
+    with_registry(|registry| {
+        if !registry.verify_token_ownership(token_id, &listing.seller) {
+            return Err("Seller no longer owns this token".to_string());
+        }
+        if !registry.is_approved(token_id, &marketplace_spender(), now) {
+            return Err("Marketplace is not approved to transfer this token".to_string());
+        }
+        Ok(())
+    })?;
+
+    let _lock = PurchaseLock::acquire(token_id)?;
+    let ledger = token_canister(&listing.payment_token).map_err(|e| format!("{:?}", e))?;
+    client::icrc2_transfer_from(
+        ledger,
+        Account::from(buyer),
+        escrow_account(),
+        Nat::from(listing.price),
+        None,
+    ).await.map_err(|e| format!("Payment failed: {:?}", e))?;
+
+    // Everything is re-checked after the await: the seller may have moved the
+    // token, revoked the approval or changed the listing meanwhile.
+    let purchased = with_marketplace_mut(|marketplace| {
+        with_registry_mut(|registry| marketplace.purchase_listing(registry, &listing, buyer, time()))
+    });
+    let listing = match purchased {
+        Ok(listing) => listing,
+        Err(e) => {
+            refund_escrow(buyer, listing.price, listing.payment_token, &format!("failed purchase of token {}", token_id)).await;
+            return Err(e);
+        }
+    };
+
+    pay_out_sale(token_id, listing.seller, listing.price, listing.payment_token, &format!("listing {}", token_id)).await;
+
+    Ok(())
+}
+
+#[query]
+fn get_listing(token_id: u64) -> Option<Listing> {
+    with_marketplace(|marketplace| marketplace.get_listing(token_id).cloned())
+}
+
+#[query]
+fn get_listings(
+    cursor: Option<ListingCursor>,
+    limit: Option<u64>,
+    sort: Option<ListingSort>,
+    filter: Option<ListingFilter>,
+) -> ListingPage {
+    let limit = limit.map(|l| l as usize).unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let filter = filter.unwrap_or_default();
+    with_marketplace(|marketplace| {
+        marketplace.listings_page(cursor, limit, sort.unwrap_or(ListingSort::PriceAsc), &filter, time())
+    })
+}
+
+#[query]
+fn get_offers(token_id: u64) -> Vec<Offer> {
+    with_marketplace(|marketplace| marketplace.offers_for_token(token_id))
+}
+
+#[query]
+fn get_offers_by_buyer(buyer: Principal) -> Vec<Offer> {
+    with_marketplace(|marketplace| marketplace.offers_by_buyer(&buyer))
+}
+
+/// Newest-first sales, optionally for one token. Pass the last `seq` seen as
+/// `before` to fetch the next page.
+#[query]
+fn get_sales_history(token_id: Option<u64>, before: Option<u64>, limit: Option<u64>) -> Vec<Sale> {
+    let limit = limit.map(|l| l as usize).unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    with_marketplace(|marketplace| marketplace.sales.page(token_id, before, limit))
+}
+
+#[query]
+fn get_market_stats(payment_token: AcceptedToken) -> MarketStats {
+    with_marketplace(|marketplace| marketplace.market_stats(payment_token, time()))
+}
+
+#[query]
+fn get_pending_payouts() -> Vec<PendingPayout> {
+    with_marketplace(|marketplace| marketplace.pending_payouts.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_sale_keeps_royalty_in_escrow() {
+        // 2.5% royalty and 2% fee on 100_000_000 with a 10_000 fee per payout.
+        assert_eq!(split_sale(100_000_000, 2_500_000, 2_000_000, 10_000), (1_990_000, 95_490_000));
+    }
+
+    #[test]
+    fn test_dust_fee_goes_to_seller() {
+        assert_eq!(split_sale(100_000, 2_500, 5_000, 10_000), (0, 87_500));
+    }
+
+    struct Owners(Vec<(TokenId, Principal)>);
+
+    impl MarketplaceOperations for Owners {
+        fn verify_token_ownership(&self, token_id: TokenId, owner: &Principal) -> bool {
+            self.0.iter().any(|(id, o)| *id == token_id && o == owner)
+        }
+
+        fn transfer_token(&mut self, token_id: TokenId, from: &Principal, to: &Principal) -> Result<(), String> {
+            let entry = self.0.iter_mut()
+                .find(|(id, o)| *id == token_id && o == from)
+                .ok_or("Not owner")?;
+            entry.1 = *to;
+            Ok(())
+        }
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn test_list_token_rejects_non_owner_and_duplicates() {
+        let ops = Owners(vec![(1, principal(1))]);
+        let mut state = MarketplaceState::default();
+
+        assert!(state.list_token(&ops, 1, principal(2), 100, AcceptedToken::ICP, None, 0).is_err());
+        assert!(state.list_token(&ops, 1, principal(1), 100, AcceptedToken::ICP, None, 0).is_ok());
+        assert!(state.list_token(&ops, 1, principal(1), 200, AcceptedToken::ICP, None, 0).is_err());
+    }
+
+    #[test]
+    fn test_stale_listing_does_not_block_new_owner() {
+        let mut ops = Owners(vec![(1, principal(1))]);
+        let mut state = MarketplaceState::default();
+        state.list_token(&ops, 1, principal(1), 100, AcceptedToken::ICP, None, 0).unwrap();
+
+        ops.transfer_token(1, &principal(1), &principal(2)).unwrap();
+        assert!(state.list_token(&ops, 1, principal(2), 150, AcceptedToken::ICP, None, 0).is_ok());
+        assert_eq!(state.listings.len(), 1);
+    }
+
+    #[test]
+    fn test_purchase_listing() {
+        let mut ops = Owners(vec![(1, principal(1))]);
+        let mut state = MarketplaceState::default();
+        state.list_token(&ops, 1, principal(1), 100, AcceptedToken::ICP, Some(50), 0).unwrap();
+        let listing = state.get_listing(1).cloned().unwrap();
+
+        assert!(state.purchase_listing(&mut ops, &listing, principal(2), 50).is_err());
+
+        let repriced = Listing { price: 90, ..listing.clone() };
+        assert!(state.purchase_listing(&mut ops, &repriced, principal(2), 10).is_err());
+
+        state.purchase_listing(&mut ops, &listing, principal(2), 10).unwrap();
+        assert!(ops.verify_token_ownership(1, &principal(2)));
+        assert!(state.listings.is_empty());
+        assert_eq!(state.sales.total_volume().collect::<Vec<_>>(), vec![(AcceptedToken::ICP, 100)]);
+    }
+
+    fn listed_state(ops: &Owners, prices: &[u64]) -> MarketplaceState {
+        let mut state = MarketplaceState::default();
+        for (id, price) in prices.iter().enumerate() {
+            state.list_token(ops, id as TokenId, principal(1 + (id % 2) as u8), *price, AcceptedToken::ICP, None, id as u64).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn test_listings_page_by_price() {
+        let ops = Owners((0..5).map(|id| (id, principal(1 + (id % 2) as u8))).collect());
+        let state = listed_state(&ops, &[500, 100, 300, 200, 400]);
+        let filter = ListingFilter::default();
+
+        let first = state.listings_page(None, 2, ListingSort::PriceAsc, &filter, 10);
+        assert_eq!(first.listings.iter().map(|l| l.price).collect::<Vec<_>>(), vec![100, 200]);
+        let second = state.listings_page(first.next_cursor, 2, ListingSort::PriceAsc, &filter, 10);
+        assert_eq!(second.listings.iter().map(|l| l.price).collect::<Vec<_>>(), vec![300, 400]);
+        let last = state.listings_page(second.next_cursor, 2, ListingSort::PriceAsc, &filter, 10);
+        assert_eq!(last.listings.iter().map(|l| l.price).collect::<Vec<_>>(), vec![500]);
+        assert!(last.next_cursor.is_none());
+
+        let desc = state.listings_page(None, 3, ListingSort::PriceDesc, &filter, 10);
+        assert_eq!(desc.listings.iter().map(|l| l.price).collect::<Vec<_>>(), vec![500, 400, 300]);
+    }
+
+    #[test]
+    fn test_listings_page_by_seller() {
+        let ops = Owners((0..5).map(|id| (id, principal(1 + (id % 2) as u8))).collect());
+        let state = listed_state(&ops, &[500, 100, 300, 200, 400]);
+        let filter = ListingFilter { seller: Some(principal(1)), ..Default::default() };
+
+        let newest = state.listings_page(None, 2, ListingSort::Newest, &filter, 10);
+        assert_eq!(newest.listings.iter().map(|l| l.token_id).collect::<Vec<_>>(), vec![4, 2]);
+        let rest = state.listings_page(newest.next_cursor, 2, ListingSort::Newest, &filter, 10);
+        assert_eq!(rest.listings.iter().map(|l| l.token_id).collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn test_floor_price_tracks_sales() {
+        let mut ops = Owners((0..3).map(|id| (id, principal(1))).collect());
+        let mut state = MarketplaceState::default();
+        for (id, price) in [300, 100, 200].iter().enumerate() {
+            state.list_token(&ops, id as TokenId, principal(1), *price, AcceptedToken::ICP, None, 0).unwrap();
+        }
+        assert_eq!(state.floor_price(AcceptedToken::ICP, 0), Some(100));
+
+        let cheapest = state.get_listing(1).cloned().unwrap();
+        state.purchase_listing(&mut ops, &cheapest, principal(2), 5).unwrap();
+        let stats = state.market_stats(AcceptedToken::ICP, 5);
+        assert_eq!(stats.floor_price, Some(200));
+        assert_eq!(stats.listed_count, 2);
+        assert_eq!(stats.volume_24h, 100);
+        assert_eq!(state.sales.page(Some(1), None, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_state_persists_as_rows() {
+        let mut ops = Owners((0..3).map(|id| (id, principal(1))).collect());
+        let state = with_marketplace_mut(|state| {
+            for (id, price) in [300, 100, 200].iter().enumerate() {
+                state.list_token(&ops, id as TokenId, principal(1), *price, AcceptedToken::ICP, None, 0).unwrap();
+            }
+            let sold = state.get_listing(2).cloned().unwrap();
+            state.purchase_listing(&mut ops, &sold, principal(2), 5).unwrap();
+            state.insert_offer(Offer {
+                id: 7,
+                token_id: 0,
+                buyer: principal(3),
+                price: 50,
+                payment_token: AcceptedToken::ANIMA,
+                escrow_block: 1,
+                created_at: 0,
+                expires_at: 100,
+            });
+            state.next_offer_id = 8;
+            state.clone()
+        });
+
+        save_stable().unwrap();
+        with_marketplace_mut(|state| *state = MarketplaceState::default());
+        restore_stable().unwrap();
+
+        with_marketplace(|restored| {
+            assert_eq!(restored.listings_page(None, 10, ListingSort::PriceAsc, &ListingFilter::default(), 10)
+                .listings.iter().map(|l| l.price).collect::<Vec<_>>(), vec![100, 300]);
+            assert_eq!(restored.offers_by_buyer(&principal(3)).len(), 1);
+            assert_eq!(restored.offers_for_token(0).len(), 1);
+            assert_eq!(restored.sales.page(None, None, 10).len(), 1);
+            assert_eq!(restored.sales.total_volume().collect::<Vec<_>>(), state.sales.total_volume().collect::<Vec<_>>());
+            assert_eq!(restored.market_stats(AcceptedToken::ICP, 5).volume_24h, 200);
+            assert_eq!(restored.transaction_count, 1);
+            assert_eq!(restored.next_offer_id, 8);
+        });
+    }
+}
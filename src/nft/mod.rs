@@ -1,3 +1,13 @@
 pub mod types;
+pub mod provenance;
+pub mod registry;
+pub mod collection;
+pub mod icrc7;
+pub mod icrc37;
+pub mod marketplace;
+pub mod market_stats;
+pub mod auction;
+pub mod royalties;
 
-pub use types::TokenIdentifier;
\ No newline at end of file
+pub use types::TokenIdentifier;
+pub use registry::{AnimaRecord, TokenId};
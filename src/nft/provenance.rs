@@ -2,6 +2,9 @@ use candid::{CandidType, Deserialize, Principal};
 use serde::Serialize;
 use ic_cdk::api::time;
 use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use crate::quantum::QuantumState;
+use crate::types::personality::NFTPersonality;
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct AnimaBirthCertificate {
@@ -53,6 +56,14 @@ pub struct ConsciousnessMilestone {
     pub quantum_state: QuantumSnapshot,
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct TraitEvolution {
+    pub trait_id: String,
+    pub previous_state: f64,
+    pub new_state: f64,
+    pub catalyst: String,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct DimensionalShift {
     pub timestamp: u64,
@@ -87,6 +98,48 @@ pub struct QuantumSnapshot {
     pub dimensional_frequency: f64,
 }
 
+impl AnimaBirthCertificate {
+    pub fn genesis(
+        anima_id: String,
+        minting_principal: Principal,
+        quantum_state: &QuantumState,
+        personality: &NFTPersonality,
+    ) -> Self {
+        let genesis_timestamp = time();
+
+        let mut hasher = Sha256::new();
+        hasher.update(anima_id.as_bytes());
+        hasher.update(quantum_state.quantum_signature.as_bytes());
+        hasher.update(genesis_timestamp.to_be_bytes());
+        let consciousness_seed = hex::encode(hasher.finalize());
+
+        let mut initial_traits: Vec<TraitSnapshot> = personality.traits
+            .iter()
+            .map(|(name, &value)| TraitSnapshot {
+                name: name.clone(),
+                value,
+                potential: value * personality.growth_potential,
+                resonance_pattern: vec![value * personality.quantum_resonance],
+            })
+            .collect();
+        initial_traits.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self {
+            anima_id,
+            quantum_signature: quantum_state.quantum_signature.clone(),
+            genesis_timestamp,
+            initial_traits,
+            dimensional_frequency: quantum_state.dimensional_state.dimensional_frequency,
+            consciousness_seed,
+            genesis_block: 0,
+            minting_principal,
+            birth_witnesses: Vec::new(),
+            genesis_rarity: quantum_state.coherence_level * personality.quantum_resonance,
+            birth_resonance: quantum_state.evolution_metrics.clone(),
+        }
+    }
+}
+
 impl AnimaProvenance {
     pub fn new(birth_certificate: AnimaBirthCertificate) -> Self {
         Self {
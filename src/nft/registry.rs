@@ -0,0 +1,464 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use crate::error::{AnimaError, Result};
+use crate::icrc::Account;
+use crate::nft::provenance::AnimaBirthCertificate;
+use crate::quantum::QuantumState;
+use crate::stable::{
+    RegionKey, StableRegion, TOKEN_REGISTRY_MEMORY_ID, NFT_TRANSACTIONS_MEMORY_ID, NFT_APPROVALS_MEMORY_ID,
+};
+use crate::types::personality::NFTPersonality;
+
+pub type TokenId = u64;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AnimaRecord {
+    pub token_id: TokenId,
+    pub owner: Account,
+    pub name: String,
+    pub quantum_state: QuantumState,
+    pub personality: NFTPersonality,
+    pub birth_certificate: AnimaBirthCertificate,
+    pub minted_at: u64,
+    /// Token-level ICRC-37 approvals. Cleared whenever the token moves.
+    pub approvals: Vec<Approval>,
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize, Serialize)]
+pub struct Approval {
+    pub spender: Account,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at: u64,
+}
+
+impl Approval {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.map(|expires_at| expires_at > now).unwrap_or(true)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize, Serialize)]
+pub enum NftOperation {
+    Mint,
+    Transfer,
+    Approve,
+    Revoke,
+    ApproveCollection,
+    RevokeCollection,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct NftTransaction {
+    pub operation: NftOperation,
+    pub token_id: Option<TokenId>,
+    pub from: Option<Account>,
+    pub to: Option<Account>,
+    pub spender: Option<Account>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+struct CollectionApproval {
+    owner: Account,
+    approval: Approval,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct TokenRegistry {
+    next_token_id: TokenId,
+    tokens: BTreeMap<TokenId, AnimaRecord>,
+    transactions: Vec<NftTransaction>,
+    collection_approvals: HashMap<Account, Vec<Approval>>,
+}
+
+impl TokenRegistry {
+    /// Reserves the next token id. Ids are never handed out twice, even if the
+    /// mint that reserved one fails afterwards.
+    pub fn allocate_token_id(&mut self) -> TokenId {
+        let token_id = self.next_token_id;
+        self.next_token_id += 1;
+        token_id
+    }
+
+    /// Registers a freshly minted anima and logs the mint. Returns the
+    /// transaction index.
+    pub fn mint(&mut self, record: AnimaRecord) -> u64 {
+        let tx = NftTransaction {
+            operation: NftOperation::Mint,
+            token_id: Some(record.token_id),
+            from: None,
+            to: Some(record.owner),
+            spender: None,
+            memo: None,
+            created_at_time: None,
+            timestamp: record.minted_at,
+        };
+        self.restore_record(record);
+        self.push_transaction(tx)
+    }
+
+    fn restore_record(&mut self, record: AnimaRecord) {
+        self.next_token_id = self.next_token_id.max(record.token_id + 1);
+        self.tokens.insert(record.token_id, record);
+    }
+
+    /// Moves `token_id` from `from` to `to` and drops its token-level
+    /// approvals. Callers are expected to have checked authorization; this
+    /// only verifies current ownership. `spender` is recorded for
+    /// `transfer_from`-style moves.
+    #[allow(clippy::too_many_arguments)]
+    pub fn transfer(
+        &mut self,
+        token_id: TokenId,
+        from: Account,
+        to: Account,
+        spender: Option<Account>,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+        now: u64,
+    ) -> Result<u64> {
+        let record = self.get_mut(token_id)?;
+        if record.owner != from {
+            return Err(AnimaError::NotAuthorized);
+        }
+        record.owner = to;
+        record.approvals.clear();
+
+        Ok(self.push_transaction(NftTransaction {
+            operation: NftOperation::Transfer,
+            token_id: Some(token_id),
+            from: Some(from),
+            to: Some(to),
+            spender,
+            memo,
+            created_at_time,
+            timestamp: now,
+        }))
+    }
+
+    /// Grants or refreshes `approval.spender`'s right to move `token_id`.
+    pub fn approve_token(&mut self, token_id: TokenId, owner: Account, approval: Approval, now: u64) -> Result<u64> {
+        let record = self.get_mut(token_id)?;
+        if record.owner != owner {
+            return Err(AnimaError::NotAuthorized);
+        }
+        record.approvals.retain(|a| a.spender != approval.spender && a.is_active(now));
+        if record.approvals.len() >= MAX_APPROVALS_PER_TOKEN_OR_COLLECTION {
+            return Err(AnimaError::InvalidInput(format!(
+                "At most {} approvals per token", MAX_APPROVALS_PER_TOKEN_OR_COLLECTION
+            )));
+        }
+        let tx = NftTransaction {
+            operation: NftOperation::Approve,
+            token_id: Some(token_id),
+            from: Some(owner),
+            to: None,
+            spender: Some(approval.spender),
+            memo: approval.memo.clone(),
+            created_at_time: Some(approval.created_at),
+            timestamp: now,
+        };
+        record.approvals.push(approval);
+        Ok(self.push_transaction(tx))
+    }
+
+    pub fn approve_collection(&mut self, owner: Account, approval: Approval, now: u64) -> Result<u64> {
+        let approvals = self.collection_approvals.entry(owner).or_default();
+        approvals.retain(|a| a.spender != approval.spender && a.is_active(now));
+        if approvals.len() >= MAX_APPROVALS_PER_TOKEN_OR_COLLECTION {
+            return Err(AnimaError::InvalidInput(format!(
+                "At most {} collection approvals per account", MAX_APPROVALS_PER_TOKEN_OR_COLLECTION
+            )));
+        }
+        let tx = NftTransaction {
+            operation: NftOperation::ApproveCollection,
+            token_id: None,
+            from: Some(owner),
+            to: None,
+            spender: Some(approval.spender),
+            memo: approval.memo.clone(),
+            created_at_time: Some(approval.created_at),
+            timestamp: now,
+        };
+        approvals.push(approval);
+        Ok(self.push_transaction(tx))
+    }
+
+    /// Removes the approval for `spender`, or every approval when `spender` is
+    /// `None`. Returns the number of approvals removed alongside the tx index.
+    pub fn revoke_token_approvals(
+        &mut self,
+        token_id: TokenId,
+        owner: Account,
+        spender: Option<Account>,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+        now: u64,
+    ) -> Result<(usize, u64)> {
+        let record = self.get_mut(token_id)?;
+        if record.owner != owner {
+            return Err(AnimaError::NotAuthorized);
+        }
+        let before = record.approvals.len();
+        record.approvals.retain(|a| spender.map(|s| a.spender != s).unwrap_or(false));
+        let removed = before - record.approvals.len();
+
+        let index = self.push_transaction(NftTransaction {
+            operation: NftOperation::Revoke,
+            token_id: Some(token_id),
+            from: Some(owner),
+            to: None,
+            spender,
+            memo,
+            created_at_time,
+            timestamp: now,
+        });
+        Ok((removed, index))
+    }
+
+    pub fn revoke_collection_approvals(
+        &mut self,
+        owner: Account,
+        spender: Option<Account>,
+        memo: Option<Vec<u8>>,
+        created_at_time: Option<u64>,
+        now: u64,
+    ) -> (usize, u64) {
+        let removed = match self.collection_approvals.get_mut(&owner) {
+            Some(approvals) => {
+                let before = approvals.len();
+                approvals.retain(|a| spender.map(|s| a.spender != s).unwrap_or(false));
+                before - approvals.len()
+            }
+            None => 0,
+        };
+        if self.collection_approvals.get(&owner).map(|a| a.is_empty()).unwrap_or(false) {
+            self.collection_approvals.remove(&owner);
+        }
+
+        let index = self.push_transaction(NftTransaction {
+            operation: NftOperation::RevokeCollection,
+            token_id: None,
+            from: Some(owner),
+            to: None,
+            spender,
+            memo,
+            created_at_time,
+            timestamp: now,
+        });
+        (removed, index)
+    }
+
+    /// True if `spender` may move `token_id` on behalf of its current owner,
+    /// through either a token-level or a collection-level approval.
+    pub fn is_approved(&self, token_id: TokenId, spender: &Account, now: u64) -> bool {
+        let Some(record) = self.tokens.get(&token_id) else {
+            return false;
+        };
+        let token_level = record.approvals.iter()
+            .any(|a| &a.spender == spender && a.is_active(now));
+        let collection_level = self.collection_approvals.get(&record.owner)
+            .map(|approvals| approvals.iter().any(|a| &a.spender == spender && a.is_active(now)))
+            .unwrap_or(false);
+        token_level || collection_level
+    }
+
+    fn push_transaction(&mut self, tx: NftTransaction) -> u64 {
+        self.transactions.push(tx);
+        (self.transactions.len() - 1) as u64
+    }
+
+    /// Finds an earlier transfer with identical arguments, for deduplication.
+    pub fn find_duplicate_transfer(
+        &self,
+        token_id: TokenId,
+        from: &Account,
+        to: &Account,
+        memo: &Option<Vec<u8>>,
+        created_at_time: u64,
+        window_start: u64,
+    ) -> Option<u64> {
+        self.transactions.iter()
+            .enumerate()
+            .rev()
+            .take_while(|(_, tx)| tx.timestamp >= window_start)
+            .find(|(_, tx)| {
+                tx.operation == NftOperation::Transfer
+                    && tx.token_id == Some(token_id)
+                    && tx.from.as_ref() == Some(from)
+                    && tx.to.as_ref() == Some(to)
+                    && &tx.memo == memo
+                    && tx.created_at_time == Some(created_at_time)
+            })
+            .map(|(index, _)| index as u64)
+    }
+
+    pub fn transactions(&self) -> &[NftTransaction] {
+        &self.transactions
+    }
+
+    pub fn get(&self, token_id: TokenId) -> Result<&AnimaRecord> {
+        self.tokens.get(&token_id).ok_or_else(|| token_not_found(token_id))
+    }
+
+    pub fn get_mut(&mut self, token_id: TokenId) -> Result<&mut AnimaRecord> {
+        self.tokens.get_mut(&token_id).ok_or_else(|| token_not_found(token_id))
+    }
+
+    pub fn owner_of(&self, token_id: TokenId) -> Option<Account> {
+        self.tokens.get(&token_id).map(|record| record.owner)
+    }
+
+    pub fn balance_of(&self, owner: &Account) -> u64 {
+        self.tokens.values().filter(|record| &record.owner == owner).count() as u64
+    }
+
+    /// Token ids owned by `owner` in ascending order, starting after `prev`.
+    pub fn tokens_of(&self, owner: &Account, prev: Option<TokenId>, take: usize) -> Vec<TokenId> {
+        self.token_ids(prev)
+            .filter(|token_id| self.tokens.get(token_id).map(|r| &r.owner) == Some(owner))
+            .take(take)
+            .collect()
+    }
+
+    /// All token ids in ascending order, starting after `prev`.
+    pub fn token_ids(&self, prev: Option<TokenId>) -> impl Iterator<Item = TokenId> + '_ {
+        let start = match prev {
+            Some(prev) => std::ops::Bound::Excluded(prev),
+            None => std::ops::Bound::Unbounded,
+        };
+        self.tokens.range((start, std::ops::Bound::Unbounded)).map(|(token_id, _)| *token_id)
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &AnimaRecord> {
+        self.tokens.values()
+    }
+
+    pub fn holder_count(&self) -> u64 {
+        self.tokens.values().map(|record| record.owner).collect::<BTreeSet<_>>().len() as u64
+    }
+
+    pub fn total_supply(&self) -> u64 {
+        self.tokens.len() as u64
+    }
+}
+
+pub const MAX_APPROVALS_PER_TOKEN_OR_COLLECTION: usize = 16;
+
+fn token_not_found(token_id: TokenId) -> AnimaError {
+    AnimaError::InvalidToken(format!("Token {} does not exist", token_id))
+}
+
+thread_local! {
+    static REGISTRY: RefCell<TokenRegistry> = RefCell::new(TokenRegistry::default());
+}
+
+const REGISTRY_REGION: StableRegion<AnimaRecord> = StableRegion::new(TOKEN_REGISTRY_MEMORY_ID);
+const TRANSACTIONS_REGION: StableRegion<NftTransaction> = StableRegion::new(NFT_TRANSACTIONS_MEMORY_ID);
+const APPROVALS_REGION: StableRegion<CollectionApproval> = StableRegion::new(NFT_APPROVALS_MEMORY_ID);
+const TOKEN_KEY: &str = "token";
+const TRANSACTION_KEY: &str = "tx";
+const APPROVAL_KEY: &str = "collection_approval";
+
+pub fn with_registry<R>(f: impl FnOnce(&TokenRegistry) -> R) -> R {
+    REGISTRY.with(|registry| f(&registry.borrow()))
+}
+
+pub fn with_registry_mut<R>(f: impl FnOnce(&mut TokenRegistry) -> R) -> R {
+    REGISTRY.with(|registry| f(&mut registry.borrow_mut()))
+}
+
+pub fn save_stable() -> Result<()> {
+    REGISTRY.with(|registry| {
+        let registry = registry.borrow();
+        REGISTRY_REGION.save(registry.records().map(|record| {
+            (RegionKey::new(TOKEN_KEY, record.token_id), record)
+        }))?;
+        TRANSACTIONS_REGION.save(registry.transactions.iter().enumerate().map(|(index, tx)| {
+            (RegionKey::new(TRANSACTION_KEY, index as u64), tx)
+        }))?;
+        let collection_approvals: Vec<CollectionApproval> = registry.collection_approvals.iter()
+            .flat_map(|(owner, approvals)| approvals.iter().map(move |approval| CollectionApproval {
+                owner: *owner,
+                approval: approval.clone(),
+            }))
+            .collect();
+        APPROVALS_REGION.save(collection_approvals.iter().enumerate().map(|(index, entry)| {
+            (RegionKey::new(APPROVAL_KEY, index as u64), entry)
+        }))
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    let entries = REGISTRY_REGION.load()?;
+    let transactions = TRANSACTIONS_REGION.load()?;
+    let collection_approvals = APPROVALS_REGION.load()?;
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        *registry = TokenRegistry::default();
+        // Tokens are never removed, so the counter resumes after the highest stored id.
+        for (_, record) in entries {
+            registry.restore_record(record);
+        }
+        registry.transactions = transactions.into_iter().map(|(_, tx)| tx).collect();
+        for (_, entry) in collection_approvals {
+            registry.collection_approvals.entry(entry.owner).or_default().push(entry.approval);
+        }
+        Ok(())
+    })
+}
+
+/// A freshly minted anima for tests, owned by `owner`.
+#[cfg(test)]
+pub(crate) fn test_record(token_id: TokenId, owner: Account) -> AnimaRecord {
+    AnimaRecord {
+        token_id,
+        owner,
+        name: format!("Anima #{}", token_id),
+        quantum_state: QuantumState::default(),
+        personality: NFTPersonality::default(),
+        birth_certificate: AnimaBirthCertificate {
+            anima_id: token_id.to_string(),
+            quantum_signature: String::new(),
+            genesis_timestamp: 0,
+            initial_traits: Vec::new(),
+            dimensional_frequency: 1.0,
+            consciousness_seed: String::new(),
+            genesis_block: 0,
+            minting_principal: owner.owner,
+            birth_witnesses: Vec::new(),
+            genesis_rarity: 0.0,
+            birth_resonance: Default::default(),
+        },
+        minted_at: 0,
+        approvals: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_ids_are_monotonic() {
+        let mut registry = TokenRegistry::default();
+
+        assert_eq!(registry.allocate_token_id(), 0);
+        assert_eq!(registry.allocate_token_id(), 1);
+        assert_eq!(registry.allocate_token_id(), 2);
+        assert_eq!(registry.total_supply(), 0);
+    }
+
+    #[test]
+    fn test_missing_token_is_an_error() {
+        let registry = TokenRegistry::default();
+
+        assert!(matches!(registry.get(7), Err(AnimaError::InvalidToken(_))));
+        assert_eq!(registry.owner_of(7), None);
+    }
+}
@@ -1,76 +1,510 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
 use serde::Serialize;
-use std::collections::HashMap;
+use ic_cdk_macros::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use crate::admin::{require_role, Role};
+use crate::error::Result;
+use crate::icrc::client;
+use crate::icrc::{Account, AcceptedToken};
+use crate::nft::collection::CollectionMetadata;
+use crate::nft::marketplace::{ledger_fee, nat_to_u64, ESCROW_SUBACCOUNT};
+use crate::payments::transaction_processor::token_canister;
+use crate::stable::{RegionKey, StableRegion, ROYALTIES_MEMORY_ID};
+
+/// Cap on the combined royalty of a split, in basis points.
+pub const MAX_ROYALTY_BASIS_POINTS: u16 = 2_000;
+pub const MAX_SPLIT_SHARES: usize = 8;
+/// Accrual and payout records kept for the history views.
+pub const MAX_ROYALTY_HISTORY: usize = 10_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoyaltyRole {
+    Creator,
+    PlatformTreasury,
+    /// Paid to whoever minted the token; the recipient is looked up per sale.
+    OriginalMinter,
+    CommunityPool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RoyaltyShare {
+    pub role: RoyaltyRole,
+    /// Required for every role except `OriginalMinter`.
+    pub recipient: Option<Principal>,
+    pub basis_points: u16,
+}
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct RoyaltyConfig {
-    pub percentage: u16,  // Basis points (e.g., 250 = 2.50%)
-    pub recipient: Principal,
+    pub shares: Vec<RoyaltyShare>,
     pub is_enabled: bool,
 }
 
+/// A royalty credited to a recipient from one sale.
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct RoyaltyPayment {
     pub token_id: u64,
+    pub role: RoyaltyRole,
+    pub amount: u64,
+    pub recipient: Principal,
+    pub payment_token: AcceptedToken,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PayoutStatus {
+    Paid { block_index: u64 },
+    /// The ledger rejected the claim and its amount was credited back.
+    Failed { error: String },
+    /// The ledger's answer was lost. The claim stays pending and the next
+    /// claim retries the same transfer.
+    Unknown { error: String },
+}
+
+/// A claim sent to the ledger and not yet known to be paid or rejected.
+/// Every attempt reuses `created_at_time`, so a transfer that landed is
+/// deduplicated by the ledger instead of paid twice.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingClaim {
+    /// Taken out of the accrued balance, ledger fee included.
     pub amount: u64,
+    pub fee: u64,
+    pub created_at_time: u64,
+}
+
+/// One attempt to pay a recipient's accrued royalties out of escrow.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct RoyaltyPayout {
+    pub id: u64,
     pub recipient: Principal,
+    pub payment_token: AcceptedToken,
+    pub amount: u64,
+    pub status: PayoutStatus,
     pub timestamp: u64,
 }
 
-#[derive(Default)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
 pub struct RoyaltyState {
     pub config: Option<RoyaltyConfig>,
     pub payments: Vec<RoyaltyPayment>,
-    pub token_royalties: HashMap<u64, u16>,  // Custom royalties per token
+    pub token_splits: HashMap<u64, Vec<RoyaltyShare>>,  // Custom splits per token
+    /// Royalties owed to each recipient, held in marketplace escrow.
+    pub accrued: BTreeMap<(Principal, AcceptedToken), u64>,
+    pub payouts: Vec<RoyaltyPayout>,
+    pub next_payout_id: u64,
+    pub pending_claims: BTreeMap<(Principal, AcceptedToken), PendingClaim>,
+}
+
+fn trim_history<T>(history: &mut Vec<T>) {
+    if history.len() > MAX_ROYALTY_HISTORY {
+        let excess = history.len() - MAX_ROYALTY_HISTORY;
+        history.drain(..excess);
+    }
+}
+
+pub fn validate_split(shares: &[RoyaltyShare]) -> std::result::Result<(), String> {
+    if shares.len() > MAX_SPLIT_SHARES {
+        return Err(format!("A split can have at most {} shares", MAX_SPLIT_SHARES));
+    }
+    let mut total: u32 = 0;
+    for share in shares {
+        if share.basis_points == 0 {
+            return Err("Every share needs a non-zero percentage".to_string());
+        }
+        if share.recipient.is_none() && share.role != RoyaltyRole::OriginalMinter {
+            return Err(format!("{:?} share needs a recipient", share.role));
+        }
+        total += share.basis_points as u32;
+    }
+    if total > MAX_ROYALTY_BASIS_POINTS as u32 {
+        return Err(format!("Royalties total {} basis points, the cap is {}", total, MAX_ROYALTY_BASIS_POINTS));
+    }
+    Ok(())
 }
 
 impl RoyaltyState {
     pub fn new(default_percentage: u16, recipient: Principal) -> Self {
         Self {
             config: Some(RoyaltyConfig {
-                percentage: default_percentage,
-                recipient,
+                shares: vec![RoyaltyShare {
+                    role: RoyaltyRole::Creator,
+                    recipient: Some(recipient),
+                    basis_points: default_percentage,
+                }],
                 is_enabled: true,
             }),
-            payments: Vec::new(),
-            token_royalties: HashMap::new(),
+            ..Self::default()
+        }
+    }
+
+    /// Default royalty config for a collection: its basis points, paid to the
+    /// creator or, when none is set, to `treasury`.
+    pub fn from_collection(metadata: &CollectionMetadata, treasury: Principal) -> Self {
+        match metadata.royalties {
+            Some(percentage) => Self::new(percentage, metadata.creator.unwrap_or(treasury)),
+            None => Self::default(),
+        }
+    }
+
+    /// The split that applies to `token_id`: its own if set, else the
+    /// collection's.
+    pub fn split_for(&self, token_id: u64) -> Vec<RoyaltyShare> {
+        match &self.config {
+            Some(config) if config.is_enabled => self.token_splits
+                .get(&token_id)
+                .cloned()
+                .unwrap_or_else(|| config.shares.clone()),
+            _ => Vec::new(),
         }
     }
 
     pub fn calculate_royalty(&self, token_id: u64, sale_price: u64) -> Option<u64> {
-        if let Some(config) = &self.config {
-            if !config.is_enabled {
-                return None;
+        let shares = self.split_for(token_id);
+        if shares.is_empty() {
+            return None;
+        }
+        Some(shares.iter().map(|share| share_amount(sale_price, share.basis_points)).sum())
+    }
+
+    /// Credits each share of a sale's royalty to its recipient and returns
+    /// the total credited. That total must stay in escrow until claimed.
+    pub fn accrue(
+        &mut self,
+        token_id: u64,
+        sale_price: u64,
+        payment_token: AcceptedToken,
+        minter: Option<Principal>,
+        now: u64,
+    ) -> u64 {
+        let mut total = 0;
+        for share in self.split_for(token_id) {
+            let recipient = match share.role {
+                RoyaltyRole::OriginalMinter => share.recipient.or(minter),
+                _ => share.recipient,
+            };
+            let (Some(recipient), amount) = (recipient, share_amount(sale_price, share.basis_points)) else {
+                continue;
+            };
+            if amount == 0 {
+                continue;
             }
 
-            let percentage = self.token_royalties
-                .get(&token_id)
-                .copied()
-                .unwrap_or(config.percentage);
+            *self.accrued.entry((recipient, payment_token)).or_insert(0) += amount;
+            self.payments.push(RoyaltyPayment {
+                token_id,
+                role: share.role,
+                amount,
+                recipient,
+                payment_token,
+                timestamp: now,
+            });
+            total += amount;
+        }
+        trim_history(&mut self.payments);
+        total
+    }
+
+    pub fn accrued_for(&self, recipient: &Principal) -> Vec<(AcceptedToken, u64)> {
+        self.accrued.iter()
+            .filter(|((owner, _), amount)| owner == recipient && **amount > 0)
+            .map(|((_, token), amount)| (*token, *amount))
+            .collect()
+    }
+
+    /// Takes the recipient's whole balance in `payment_token` for payout.
+    pub fn take_accrued(&mut self, recipient: Principal, payment_token: AcceptedToken) -> u64 {
+        self.accrued.remove(&(recipient, payment_token)).unwrap_or(0)
+    }
+
+    pub fn credit(&mut self, recipient: Principal, payment_token: AcceptedToken, amount: u64) {
+        *self.accrued.entry((recipient, payment_token)).or_insert(0) += amount;
+    }
+
+    /// Starts a claim of the recipient's balance in `payment_token`, or
+    /// returns the claim still pending for it so it is retried unchanged.
+    pub fn begin_claim(
+        &mut self,
+        recipient: Principal,
+        payment_token: AcceptedToken,
+        fee: u64,
+        now: u64,
+    ) -> std::result::Result<PendingClaim, String> {
+        if let Some(pending) = self.pending_claims.get(&(recipient, payment_token)) {
+            return Ok(pending.clone());
+        }
+        let amount = self.take_accrued(recipient, payment_token);
+        if amount <= fee {
+            self.credit(recipient, payment_token, amount);
+            return Err(format!("Accrued royalties of {} do not cover the ledger fee of {}", amount, fee));
+        }
+        let claim = PendingClaim { amount, fee, created_at_time: now };
+        self.pending_claims.insert((recipient, payment_token), claim.clone());
+        Ok(claim)
+    }
+
+    /// Settles the pending claim with the ledger's answer. A definite
+    /// rejection credits the amount back; an unknown outcome leaves the
+    /// claim pending.
+    pub fn finish_claim(
+        &mut self,
+        recipient: Principal,
+        payment_token: AcceptedToken,
+        outcome: Result<u64>,
+        now: u64,
+    ) {
+        let Some(claim) = self.pending_claims.get(&(recipient, payment_token)).cloned() else { return };
+        let status = match outcome {
+            Ok(block_index) => PayoutStatus::Paid { block_index },
+            Err(e) if client::is_definite_rejection(&e) => {
+                self.credit(recipient, payment_token, claim.amount);
+                PayoutStatus::Failed { error: format!("{:?}", e) }
+            }
+            Err(e) => PayoutStatus::Unknown { error: format!("{:?}", e) },
+        };
+        if !matches!(status, PayoutStatus::Unknown { .. }) {
+            self.pending_claims.remove(&(recipient, payment_token));
+        }
+        self.record_payout(recipient, payment_token, claim.amount, status, now);
+    }
+
+    pub fn record_payout(
+        &mut self,
+        recipient: Principal,
+        payment_token: AcceptedToken,
+        amount: u64,
+        status: PayoutStatus,
+        now: u64,
+    ) -> u64 {
+        let id = self.next_payout_id;
+        self.next_payout_id += 1;
+        self.payouts.push(RoyaltyPayout { id, recipient, payment_token, amount, status, timestamp: now });
+        trim_history(&mut self.payouts);
+        id
+    }
 
-            Some((sale_price * percentage as u64) / 10_000)
-        } else {
-            None
+    pub fn set_collection_split(&mut self, shares: Vec<RoyaltyShare>) -> std::result::Result<(), String> {
+        validate_split(&shares)?;
+        self.config = Some(RoyaltyConfig { shares, is_enabled: true });
+        Ok(())
+    }
+
+    pub fn set_token_split(&mut self, token_id: u64, shares: Option<Vec<RoyaltyShare>>) -> std::result::Result<(), String> {
+        match shares {
+            Some(shares) => {
+                validate_split(&shares)?;
+                self.token_splits.insert(token_id, shares);
+            }
+            None => {
+                self.token_splits.remove(&token_id);
+            }
         }
+        Ok(())
     }
+}
+
+fn share_amount(sale_price: u64, basis_points: u16) -> u64 {
+    (sale_price as u128 * basis_points as u128 / 10_000) as u64
+}
 
-    pub fn record_payment(&mut self, token_id: u64, amount: u64, recipient: Principal) {
-        self.payments.push(RoyaltyPayment {
-            token_id,
-            amount,
-            recipient,
-            timestamp: ic_cdk::api::time(),
+thread_local! {
+    static ROYALTIES: RefCell<Option<RoyaltyState>> = const { RefCell::new(None) };
+}
+
+const ROYALTIES_REGION: StableRegion<RoyaltyState> = StableRegion::new(ROYALTIES_MEMORY_ID);
+const ROYALTIES_KEY: &str = "royalties";
+
+/// Runs `f` against the royalty state, seeding it from the collection
+/// metadata on first use.
+pub fn with_royalties_mut<R>(f: impl FnOnce(&mut RoyaltyState) -> R) -> R {
+    ROYALTIES.with(|royalties| {
+        let mut royalties = royalties.borrow_mut();
+        let state = royalties.get_or_insert_with(|| {
+            crate::nft::collection::with_collection(|collection| {
+                RoyaltyState::from_collection(&collection.metadata, ic_cdk::id())
+            })
         });
+        f(state)
+    })
+}
+
+pub fn save_stable() -> Result<()> {
+    ROYALTIES.with(|royalties| match &*royalties.borrow() {
+        Some(state) => ROYALTIES_REGION.save([(RegionKey::singleton(ROYALTIES_KEY), state)]),
+        None => Ok(()),
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    let restored = ROYALTIES_REGION.load_singleton(ROYALTIES_KEY)?;
+    ROYALTIES.with(|royalties| *royalties.borrow_mut() = restored);
+    Ok(())
+}
+
+/// Pays `recipient`'s accrued royalties in `payment_token` out of escrow.
+/// A claim whose outcome was lost is retried as is before anything newly
+/// accrued is paid.
+async fn pay_accrued(recipient: Principal, payment_token: AcceptedToken) -> std::result::Result<u64, String> {
+    let ledger = token_canister(&payment_token).map_err(|e| format!("{:?}", e))?;
+    let fee = ledger_fee(payment_token).await;
+    let now = ic_cdk::api::time();
+    let claim = with_royalties_mut(|royalties| royalties.begin_claim(recipient, payment_token, fee, now))?;
+
+    let outcome = client::icrc1_transfer_at(
+        ledger,
+        Some(ESCROW_SUBACCOUNT),
+        Account::from(recipient),
+        Nat::from(claim.amount - claim.fee),
+        None,
+        claim.created_at_time,
+    ).await.map(|block_index| nat_to_u64(&block_index));
+
+    let result = match &outcome {
+        Ok(_) => Ok(claim.amount - claim.fee),
+        Err(e) => Err(format!("{:?}", e)),
+    };
+    with_royalties_mut(|royalties| royalties.finish_claim(recipient, payment_token, outcome, ic_cdk::api::time()));
+    result
+}
+
+/// Pays the caller's accrued royalties; returns the amount received after
+/// the ledger fee.
+#[update]
+async fn claim_royalties(payment_token: AcceptedToken) -> std::result::Result<u64, String> {
+    pay_accrued(ic_cdk::caller(), payment_token).await
+}
+
+/// Moves the treasury's royalties, accrued to this canister, out of escrow
+/// into the canister's main account.
+#[update]
+async fn claim_treasury_royalties(payment_token: AcceptedToken) -> std::result::Result<u64, String> {
+    require_role(Role::Treasury, "claim_treasury_royalties").map_err(|e| format!("{:?}", e))?;
+    pay_accrued(ic_cdk::id(), payment_token).await
+}
+
+#[update]
+fn set_collection_royalty_split(shares: Vec<RoyaltyShare>) -> std::result::Result<(), String> {
+    require_role(Role::Treasury, "set_collection_royalty_split").map_err(|e| format!("{:?}", e))?;
+    with_royalties_mut(|royalties| royalties.set_collection_split(shares))
+}
+
+/// Overrides the split for one token; `None` falls back to the collection's.
+#[update]
+fn set_token_royalty_split(token_id: u64, shares: Option<Vec<RoyaltyShare>>) -> std::result::Result<(), String> {
+    require_role(Role::Treasury, "set_token_royalty_split").map_err(|e| format!("{:?}", e))?;
+    with_royalties_mut(|royalties| royalties.set_token_split(token_id, shares))
+}
+
+#[query]
+fn get_royalty_split(token_id: u64) -> Vec<RoyaltyShare> {
+    with_royalties_mut(|royalties| royalties.split_for(token_id))
+}
+
+#[query]
+fn get_accrued_royalties(recipient: Principal) -> Vec<(AcceptedToken, u64)> {
+    with_royalties_mut(|royalties| royalties.accrued_for(&recipient))
+}
+
+/// Newest-first payout attempts, optionally for one recipient. Pass the last
+/// `id` seen as `before` to fetch the next page.
+#[query]
+fn get_royalty_payouts(recipient: Option<Principal>, before: Option<u64>, limit: Option<u64>) -> Vec<RoyaltyPayout> {
+    let limit = limit.unwrap_or(50).min(200) as usize;
+    with_royalties_mut(|royalties| {
+        royalties.payouts.iter()
+            .rev()
+            .filter(|p| before.map(|id| p.id < id).unwrap_or(true))
+            .filter(|p| recipient.map(|r| p.recipient == r).unwrap_or(true))
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
     }
 
-    pub fn set_token_royalty(&mut self, token_id: u64, percentage: u16) {
-        self.token_royalties.insert(token_id, percentage);
+    fn share(role: RoyaltyRole, recipient: Option<u8>, basis_points: u16) -> RoyaltyShare {
+        RoyaltyShare { role, recipient: recipient.map(principal), basis_points }
     }
 
-    pub fn get_token_royalty(&self, token_id: u64) -> Option<u16> {
-        self.token_royalties
-            .get(&token_id)
-            .copied()
-            .or_else(|| self.config.as_ref().map(|c| c.percentage))
+    #[test]
+    fn test_split_accrues_per_recipient() {
+        let mut state = RoyaltyState::new(250, principal(1));
+        state.set_collection_split(vec![
+            share(RoyaltyRole::Creator, Some(1), 150),
+            share(RoyaltyRole::PlatformTreasury, Some(2), 50),
+            share(RoyaltyRole::OriginalMinter, None, 50),
+        ]).unwrap();
+
+        let total = state.accrue(7, 1_000_000, AcceptedToken::ICP, Some(principal(3)), 0);
+        assert_eq!(total, 25_000);
+        assert_eq!(state.accrued_for(&principal(1)), vec![(AcceptedToken::ICP, 15_000)]);
+        assert_eq!(state.accrued_for(&principal(3)), vec![(AcceptedToken::ICP, 5_000)]);
+        assert_eq!(state.calculate_royalty(7, 1_000_000), Some(25_000));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_token_split_overrides_collection() {
+        let mut state = RoyaltyState::new(250, principal(1));
+        state.set_token_split(7, Some(vec![share(RoyaltyRole::CommunityPool, Some(4), 500)])).unwrap();
+
+        assert_eq!(state.calculate_royalty(7, 10_000), Some(500));
+        assert_eq!(state.calculate_royalty(8, 10_000), Some(250));
+
+        state.set_token_split(7, None).unwrap();
+        assert_eq!(state.calculate_royalty(7, 10_000), Some(250));
+    }
+
+    #[test]
+    fn test_invalid_splits_rejected() {
+        assert!(validate_split(&[share(RoyaltyRole::Creator, None, 100)]).is_err());
+        assert!(validate_split(&[share(RoyaltyRole::Creator, Some(1), 0)]).is_err());
+        assert!(validate_split(&[
+            share(RoyaltyRole::Creator, Some(1), 1_500),
+            share(RoyaltyRole::CommunityPool, Some(2), 600),
+        ]).is_err());
+    }
+
+    #[test]
+    fn test_rejected_claim_is_credited_back() {
+        let mut state = RoyaltyState::new(250, principal(1));
+        state.accrue(1, 1_000_000, AcceptedToken::ICP, None, 0);
+
+        let claim = state.begin_claim(principal(1), AcceptedToken::ICP, 10, 5).unwrap();
+        assert_eq!(claim, PendingClaim { amount: 25_000, fee: 10, created_at_time: 5 });
+        assert!(state.accrued_for(&principal(1)).is_empty());
+
+        state.finish_claim(principal(1), AcceptedToken::ICP, Err(crate::AnimaError::InsufficientBalance), 6);
+        assert_eq!(state.accrued_for(&principal(1)), vec![(AcceptedToken::ICP, 25_000)]);
+        assert!(state.pending_claims.is_empty());
+        assert!(matches!(state.payouts[0].status, PayoutStatus::Failed { .. }));
+    }
+
+    #[test]
+    fn test_claim_with_unknown_outcome_is_retried_unchanged() {
+        let mut state = RoyaltyState::new(250, principal(1));
+        state.accrue(1, 1_000_000, AcceptedToken::ICP, None, 0);
+        let claim = state.begin_claim(principal(1), AcceptedToken::ICP, 10, 5).unwrap();
+
+        let lost = crate::AnimaError::NetworkError("timed out".to_string());
+        state.finish_claim(principal(1), AcceptedToken::ICP, Err(lost), 6);
+        assert!(state.accrued_for(&principal(1)).is_empty());
+        assert!(matches!(state.payouts[0].status, PayoutStatus::Unknown { .. }));
+
+        // Royalties accrued meanwhile wait for the next claim.
+        state.accrue(2, 1_000_000, AcceptedToken::ICP, None, 7);
+        assert_eq!(state.begin_claim(principal(1), AcceptedToken::ICP, 10, 8).unwrap(), claim);
+
+        state.finish_claim(principal(1), AcceptedToken::ICP, Ok(42), 9);
+        assert!(state.pending_claims.is_empty());
+        assert_eq!(state.payouts[1].status, PayoutStatus::Paid { block_index: 42 });
+        assert_eq!(state.accrued_for(&principal(1)), vec![(AcceptedToken::ICP, 25_000)]);
+        assert_eq!(state.begin_claim(principal(1), AcceptedToken::ICP, 10, 10).unwrap().created_at_time, 10);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod pricing_config;
+pub mod token_registry;
 pub mod transaction_processor;
 pub mod types;
\ No newline at end of file
@@ -1,5 +1,17 @@
 use candid::{CandidType, Deserialize};
-use ic_cdk::api::management_canister::provisional::CanisterId;
+use ic_cdk_macros::*;
+use serde::Serialize;
+use std::cell::RefCell;
+use crate::admin::{require_role, Role};
+use crate::error::{AnimaError, Result};
+use crate::icrc::AcceptedToken;
+use crate::nft::registry::with_registry;
+use crate::payments::token_registry::{with_tokens, TokenRegistry};
+use crate::stable::{RegionKey, StableRegion, PRICING_TIERS_MEMORY_ID};
+
+/// Mints made while fewer than this many ANIMA exist are priced at the
+/// genesis tier.
+pub const GENESIS_SUPPLY: u64 = 100;
 
 #[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct PricingConfig {
@@ -9,7 +21,7 @@ pub struct PricingConfig {
     pub payment_settings: PaymentSettings,
 }
 
-#[derive(Debug, Clone, CandidType, Deserialize)]
+#[derive(Debug, Clone, CandidType, Serialize, Deserialize)]
 pub struct PricingTiers {
     // Base prices in e8s (1 ICP = 100_000_000 e8s)
     pub genesis: u64,      // 500 ICP - Genesis tier (first 100 Animas)
@@ -20,6 +32,55 @@ pub struct PricingTiers {
     pub common: u64,       // 5 ICP - Common tier
 }
 
+#[derive(Debug, Clone, Copy, CandidType, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PricingTier {
+    Genesis,
+    Mythic,
+    Legendary,
+    Epic,
+    Rare,
+    Common,
+}
+
+impl PricingTier {
+    /// Tier of the next mint when `minted` ANIMA exist. Rarity is only known
+    /// once an ANIMA is minted, so every mint after genesis is priced common.
+    pub fn for_mint(minted: u64) -> Self {
+        if minted < GENESIS_SUPPLY {
+            PricingTier::Genesis
+        } else {
+            PricingTier::Common
+        }
+    }
+}
+
+impl PricingTiers {
+    pub fn price_e8s(&self, tier: PricingTier) -> u64 {
+        match tier {
+            PricingTier::Genesis => self.genesis,
+            PricingTier::Mythic => self.mythic,
+            PricingTier::Legendary => self.legendary,
+            PricingTier::Epic => self.epic,
+            PricingTier::Rare => self.rare,
+            PricingTier::Common => self.common,
+        }
+    }
+
+    /// Price of a `tier` mint in any accepted token, converted from its ICP
+    /// price at the registry's current rate.
+    pub fn quote(&self, tier: PricingTier, token: &AcceptedToken, registry: &TokenRegistry, now: u64) -> Result<u128> {
+        registry.quote(token, self.price_e8s(tier), now)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let prices = [self.genesis, self.mythic, self.legendary, self.epic, self.rare, self.common];
+        if prices.contains(&0) {
+            return Err(AnimaError::InvalidAmount("Tier prices must be greater than 0".to_string()));
+        }
+        Ok(())
+    }
+}
+
 impl Default for PricingTiers {
     fn default() -> Self {
         Self {
@@ -44,6 +105,9 @@ pub struct ServiceFees {
     // Dynamic fees
     pub complexity_multiplier: f64,    // Multiplier for complex quantum states
     pub evolution_potential_fee: u64,  // Fee for evolution capabilities
+
+    // Secondary market
+    pub marketplace_fee_percentage: f64, // Fee on marketplace sales (2%)
 }
 
 impl Default for ServiceFees {
@@ -55,10 +119,18 @@ impl Default for ServiceFees {
             maintenance_fee: 25_000_000,       // 0.25 ICP
             complexity_multiplier: 1.2,
             evolution_potential_fee: 75_000_000, // 0.75 ICP
+            marketplace_fee_percentage: 0.02,  // 2%
         }
     }
 }
 
+impl ServiceFees {
+    /// Marketplace cut of a secondary sale
+    pub fn marketplace_fee(&self, sale_price: u64) -> u64 {
+        (sale_price as f64 * self.marketplace_fee_percentage).floor() as u64
+    }
+}
+
 #[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct RoyaltyConfig {
     pub creator_royalty: f64,         // Creator royalty percentage
@@ -78,47 +150,76 @@ impl Default for RoyaltyConfig {
     }
 }
 
+/// Accepted tokens and their ledgers live in `payments::token_registry`.
 #[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct PaymentSettings {
-    pub accepted_tokens: Vec<AcceptedToken>,
-    pub default_token: TokenType,
+    pub default_token: AcceptedToken,
     pub minimum_payment: u64,
     pub refund_window: u64,  // Time window for refunds in nanoseconds
 }
 
-#[derive(Debug, Clone, CandidType, Deserialize)]
-pub struct AcceptedToken {
-    pub token_type: TokenType,
-    pub canister_id: CanisterId,
-    pub decimals: u8,
-    pub minimum_amount: u64,
-}
-
-#[derive(Debug, Clone, CandidType, Deserialize, PartialEq)]
-pub enum TokenType {
-    ICP,
-    ICRC1,
-    ICRC2,
-}
-
 impl Default for PaymentSettings {
     fn default() -> Self {
         Self {
-            accepted_tokens: vec![
-                AcceptedToken {
-                    token_type: TokenType::ICP,
-                    canister_id: CanisterId::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(), // ICP Ledger
-                    decimals: 8,
-                    minimum_amount: 100_000_000, // 1 ICP
-                }
-            ],
-            default_token: TokenType::ICP,
+            default_token: AcceptedToken::ICP,
             minimum_payment: 100_000_000, // 1 ICP
             refund_window: 24 * 60 * 60 * 1_000_000_000, // 24 hours
         }
     }
 }
 
+thread_local! {
+    static PRICING_TIERS: RefCell<PricingTiers> = RefCell::new(PricingTiers::default());
+}
+
+const PRICING_TIERS_REGION: StableRegion<PricingTiers> = StableRegion::new(PRICING_TIERS_MEMORY_ID);
+const PRICING_TIERS_KEY: &str = "pricing_tiers";
+
+pub fn save_stable() -> Result<()> {
+    PRICING_TIERS.with(|tiers| {
+        PRICING_TIERS_REGION.save([(RegionKey::singleton(PRICING_TIERS_KEY), &*tiers.borrow())])
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    if let Some(restored) = PRICING_TIERS_REGION.load_singleton(PRICING_TIERS_KEY)? {
+        PRICING_TIERS.with(|tiers| *tiers.borrow_mut() = restored);
+    }
+    Ok(())
+}
+
+/// Tier the next mint is priced at.
+pub fn next_mint_tier() -> PricingTier {
+    PricingTier::for_mint(with_registry(|registry| registry.total_supply()))
+}
+
+/// ICP price of the next mint, in e8s.
+pub fn mint_price_e8s() -> u64 {
+    let tier = next_mint_tier();
+    PRICING_TIERS.with(|tiers| tiers.borrow().price_e8s(tier))
+}
+
+/// Price of the next mint in `token`.
+pub fn quote_mint(token: &AcceptedToken) -> Result<u128> {
+    let tier = next_mint_tier();
+    PRICING_TIERS.with(|tiers| {
+        with_tokens(|tokens| tiers.borrow().quote(tier, token, tokens, ic_cdk::api::time()))
+    })
+}
+
+#[query]
+fn get_pricing_tiers() -> PricingTiers {
+    PRICING_TIERS.with(|tiers| tiers.borrow().clone())
+}
+
+#[update]
+fn set_pricing_tiers(tiers: PricingTiers) -> Result<()> {
+    require_role(Role::PricingManager, &format!("set_pricing_tiers {:?}", tiers))?;
+    tiers.validate()?;
+    PRICING_TIERS.with(|current| *current.borrow_mut() = tiers);
+    Ok(())
+}
+
 /// Calculate the total cost including R&D fees
 pub fn calculate_total_cost(
     base_price: u64,
@@ -186,6 +287,30 @@ mod tests {
         assert!(total > config.fees.quantum_compute_fee); // Should include compute fee
     }
 
+    #[test]
+    fn test_tier_quotes_follow_registry() {
+        let tiers = PricingTiers::default();
+        let registry = TokenRegistry::default();
+        assert_eq!(tiers.quote(PricingTier::Rare, &AcceptedToken::ICP, &registry, 0).unwrap(), tiers.rare as u128);
+        assert!(tiers.quote(PricingTier::Rare, &AcceptedToken::ANIMA, &registry, 0).is_err());
+    }
+
+    #[test]
+    fn test_mint_tier_follows_supply() {
+        assert_eq!(PricingTier::for_mint(0), PricingTier::Genesis);
+        assert_eq!(PricingTier::for_mint(GENESIS_SUPPLY - 1), PricingTier::Genesis);
+        assert_eq!(PricingTier::for_mint(GENESIS_SUPPLY), PricingTier::Common);
+        assert!(PricingTiers { rare: 0, ..PricingTiers::default() }.validate().is_err());
+        assert!(PricingTiers::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_marketplace_fee() {
+        let fees = ServiceFees::default();
+        assert_eq!(fees.marketplace_fee(10_000_000_000), 200_000_000); // 2 ICP on 100 ICP
+        assert_eq!(fees.marketplace_fee(0), 0);
+    }
+
     #[test]
     fn test_royalty_calculation() {
         let config = RoyaltyConfig::default();
@@ -0,0 +1,347 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_macros::*;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use crate::admin::{require_role, Role};
+use crate::error::{AnimaError, Result};
+use crate::icrc::ledger::LEDGER_CANISTER_ID;
+use crate::icrc::AcceptedToken;
+use crate::stable::{RegionKey, StableRegion, ACCEPTED_TOKENS_MEMORY_ID};
+
+const E8S_PER_ICP: u128 = 100_000_000;
+/// Quotes refuse prices older than this, so a stalled oracle cannot sell
+/// mints at a stale rate.
+pub const MAX_PRICE_AGE: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// An accepted ICRC-1 token and its price against ICP.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenConfig {
+    pub token: AcceptedToken,
+    pub symbol: String,
+    pub ledger: Principal,
+    pub decimals: u8,
+    /// Smallest payment accepted, in the token's own units.
+    pub minimum_amount: u128,
+    /// Units of this token worth one ICP; `None` until priced.
+    pub units_per_icp: Option<u128>,
+    pub price_updated_at: u64,
+    pub enabled: bool,
+}
+
+impl TokenConfig {
+    fn icp() -> Self {
+        Self {
+            token: AcceptedToken::ICP,
+            symbol: "ICP".to_string(),
+            ledger: Principal::from_text(LEDGER_CANISTER_ID).expect("valid ICP ledger id"),
+            decimals: 8,
+            minimum_amount: 100_000_000,
+            units_per_icp: Some(E8S_PER_ICP),
+            price_updated_at: 0,
+            enabled: true,
+        }
+    }
+}
+
+impl TokenConfig {
+    /// The ANIMA token on `ledger`, accepted for payment once an oracle
+    /// prices it.
+    pub fn anima(ledger: Principal) -> Self {
+        Self {
+            token: AcceptedToken::ANIMA,
+            symbol: "ANIMA".to_string(),
+            ledger,
+            decimals: 8,
+            minimum_amount: 100_000_000,
+            units_per_icp: None,
+            price_updated_at: 0,
+            enabled: true,
+        }
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TokenRegistry {
+    tokens: BTreeMap<AcceptedToken, TokenConfig>,
+}
+
+impl Default for TokenRegistry {
+    fn default() -> Self {
+        let icp = TokenConfig::icp();
+        Self {
+            tokens: BTreeMap::from([(icp.token, icp)]),
+        }
+    }
+}
+
+impl TokenRegistry {
+    /// Adds a token or replaces its configuration. Once ANIMA is registered
+    /// its ledger is fixed; only `register_anima` moves it.
+    pub fn register(&mut self, config: TokenConfig) -> Result<()> {
+        let anima_ledger = self.get(&AcceptedToken::ANIMA).map(|anima| anima.ledger);
+        match config.token {
+            AcceptedToken::ICP => {
+                return Err(AnimaError::InvalidToken("ICP is always accepted and priced at par".to_string()));
+            }
+            AcceptedToken::Icrc1(ledger) if ledger != config.ledger => {
+                return Err(AnimaError::InvalidToken("ICRC-1 tokens are named by their own ledger".to_string()));
+            }
+            AcceptedToken::ANIMA if anima_ledger.is_some_and(|ledger| ledger != config.ledger) => {
+                return Err(AnimaError::InvalidToken(
+                    "The ANIMA ledger can only be changed with set_anima_ledger".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        if config.units_per_icp == Some(0) {
+            return Err(AnimaError::InvalidAmount("Price must be greater than 0".to_string()));
+        }
+        self.tokens.insert(config.token, config);
+        Ok(())
+    }
+
+    /// Points ANIMA at `ledger`, replacing its whole configuration.
+    pub fn register_anima(&mut self, ledger: Principal) {
+        self.tokens.insert(AcceptedToken::ANIMA, TokenConfig::anima(ledger));
+    }
+
+    pub fn remove(&mut self, token: AcceptedToken) -> Result<()> {
+        match token {
+            AcceptedToken::ICP => return Err(AnimaError::InvalidToken("ICP cannot be removed".to_string())),
+            // Staking, rewards and wallets hold ANIMA on its ledger.
+            AcceptedToken::ANIMA => {
+                return Err(AnimaError::InvalidToken("ANIMA cannot be removed; disable it instead".to_string()))
+            }
+            _ => {}
+        }
+        self.tokens.remove(&token)
+            .map(|_| ())
+            .ok_or_else(|| AnimaError::InvalidToken(format!("{:?} is not registered", token)))
+    }
+
+    pub fn set_price(&mut self, token: AcceptedToken, units_per_icp: u128, now: u64) -> Result<()> {
+        if token == AcceptedToken::ICP {
+            return Err(AnimaError::InvalidToken("ICP is priced at par".to_string()));
+        }
+        if units_per_icp == 0 {
+            return Err(AnimaError::InvalidAmount("Price must be greater than 0".to_string()));
+        }
+        let config = self.tokens.get_mut(&token)
+            .ok_or_else(|| AnimaError::InvalidToken(format!("{:?} is not registered", token)))?;
+        config.units_per_icp = Some(units_per_icp);
+        config.price_updated_at = now;
+        Ok(())
+    }
+
+    pub fn get(&self, token: &AcceptedToken) -> Option<&TokenConfig> {
+        self.tokens.get(token)
+    }
+
+    pub fn tokens(&self) -> impl Iterator<Item = &TokenConfig> {
+        self.tokens.values()
+    }
+
+    /// Ledger canister of a registered token, enabled or not, so funds
+    /// already held in it can still be paid out.
+    pub fn ledger(&self, token: &AcceptedToken) -> Result<Principal> {
+        match self.get(token) {
+            Some(config) => Ok(config.ledger),
+            None if *token == AcceptedToken::ANIMA => Err(AnimaError::InvalidToken(
+                "The ANIMA ledger is not registered: install with `anima_ledger` set, or register it with \
+                 register_accepted_token"
+                    .to_string(),
+            )),
+            None => Err(AnimaError::InvalidToken(format!("{:?} is not registered", token))),
+        }
+    }
+
+    pub fn minimum_amount(&self, token: &AcceptedToken) -> u128 {
+        self.get(token).map(|config| config.minimum_amount).unwrap_or(0)
+    }
+
+    fn accepted(&self, token: &AcceptedToken) -> Result<&TokenConfig> {
+        self.get(token)
+            .filter(|config| config.enabled)
+            .ok_or_else(|| AnimaError::InvalidToken(format!("{:?} is not accepted", token)))
+    }
+
+    /// Price of `icp_e8s` in `token`, rounded up so a payment never falls
+    /// short of the ICP amount it stands for.
+    pub fn quote(&self, token: &AcceptedToken, icp_e8s: u64, now: u64) -> Result<u128> {
+        let config = self.accepted(token)?;
+        let units_per_icp = config.units_per_icp
+            .ok_or_else(|| AnimaError::InvalidToken(format!("{} has no price yet", config.symbol)))?;
+        if *token != AcceptedToken::ICP && now.saturating_sub(config.price_updated_at) > MAX_PRICE_AGE {
+            return Err(AnimaError::InvalidToken(format!("{} price is stale", config.symbol)));
+        }
+        let amount = (icp_e8s as u128)
+            .checked_mul(units_per_icp)
+            .ok_or_else(|| AnimaError::InvalidAmount("Quote overflowed".to_string()))?;
+        Ok(amount.div_ceil(E8S_PER_ICP).max(config.minimum_amount))
+    }
+}
+
+thread_local! {
+    static TOKEN_REGISTRY: RefCell<TokenRegistry> = RefCell::new(TokenRegistry::default());
+}
+
+const TOKEN_REGISTRY_REGION: StableRegion<TokenRegistry> = StableRegion::new(ACCEPTED_TOKENS_MEMORY_ID);
+const TOKEN_REGISTRY_KEY: &str = "accepted_tokens";
+
+pub fn with_tokens<R>(f: impl FnOnce(&TokenRegistry) -> R) -> R {
+    TOKEN_REGISTRY.with(|registry| f(&registry.borrow()))
+}
+
+pub fn with_tokens_mut<R>(f: impl FnOnce(&mut TokenRegistry) -> R) -> R {
+    TOKEN_REGISTRY.with(|registry| f(&mut registry.borrow_mut()))
+}
+
+pub fn save_stable() -> Result<()> {
+    TOKEN_REGISTRY.with(|registry| {
+        TOKEN_REGISTRY_REGION.save([(RegionKey::singleton(TOKEN_REGISTRY_KEY), &*registry.borrow())])
+    })
+}
+
+pub fn restore_stable() -> Result<()> {
+    if let Some(restored) = TOKEN_REGISTRY_REGION.load_singleton(TOKEN_REGISTRY_KEY)? {
+        TOKEN_REGISTRY.with(|registry| *registry.borrow_mut() = restored);
+    }
+    Ok(())
+}
+
+#[query]
+fn get_accepted_tokens() -> Vec<TokenConfig> {
+    with_tokens(|tokens| tokens.tokens().cloned().collect())
+}
+
+/// Price of the next mint in `token`, at its pricing tier.
+#[query]
+fn get_mint_quote(token: AcceptedToken) -> Result<u128> {
+    crate::payments::pricing_config::quote_mint(&token)
+}
+
+#[update]
+fn register_accepted_token(config: TokenConfig) -> Result<()> {
+    require_role(Role::PricingManager, &format!("register_accepted_token {:?}", config.token))?;
+    let config = TokenConfig { price_updated_at: ic_cdk::api::time(), ..config };
+    with_tokens_mut(|tokens| tokens.register(config))
+}
+
+/// Moves ANIMA to another ledger. Every ANIMA balance the canister keeps
+/// books for lives on the old one, so this is reserved to super admins.
+#[update]
+fn set_anima_ledger(ledger: Principal) -> Result<()> {
+    require_role(Role::SuperAdmin, &format!("set_anima_ledger {}", ledger))?;
+    with_tokens_mut(|tokens| tokens.register_anima(ledger));
+    Ok(())
+}
+
+#[update]
+fn remove_accepted_token(token: AcceptedToken) -> Result<()> {
+    require_role(Role::PricingManager, &format!("remove_accepted_token {:?}", token))?;
+    with_tokens_mut(|tokens| tokens.remove(token))
+}
+
+/// Records how many units of `token` one ICP is worth.
+#[update]
+fn set_token_price(token: AcceptedToken, units_per_icp: u128) -> Result<()> {
+    require_role(Role::Oracle, &format!("set_token_price {:?} {}", token, units_per_icp))?;
+    with_tokens_mut(|tokens| tokens.set_price(token, units_per_icp, ic_cdk::api::time()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+    const MINT_PRICE_E8S: u64 = 100_000_000;
+
+    fn ckbtc() -> TokenConfig {
+        let ledger = Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap();
+        TokenConfig {
+            token: AcceptedToken::Icrc1(ledger),
+            symbol: "ckBTC".to_string(),
+            ledger,
+            decimals: 8,
+            minimum_amount: 1_000,
+            units_per_icp: None,
+            price_updated_at: 0,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_icp_quotes_at_par() {
+        let registry = TokenRegistry::default();
+        assert_eq!(registry.quote(&AcceptedToken::ICP, MINT_PRICE_E8S, 0).unwrap(), MINT_PRICE_E8S as u128);
+        assert!(registry.quote(&AcceptedToken::ANIMA, MINT_PRICE_E8S, 0).is_err());
+    }
+
+    #[test]
+    fn test_icrc1_token_quotes_once_priced() {
+        let mut registry = TokenRegistry::default();
+        let token = ckbtc().token;
+        registry.register(ckbtc()).unwrap();
+        assert!(registry.quote(&token, MINT_PRICE_E8S, 0).is_err());
+
+        // 1 ICP = 0.00012345 BTC.
+        registry.set_price(token, 12_345, HOUR).unwrap();
+        assert_eq!(registry.quote(&token, MINT_PRICE_E8S, HOUR).unwrap(), 12_345);
+        // Rounds up, and never below the token's minimum.
+        assert_eq!(registry.quote(&token, 50_000_000, HOUR).unwrap(), 6_173);
+        assert_eq!(registry.quote(&token, 1, HOUR).unwrap(), 1_000);
+    }
+
+    #[test]
+    fn test_stale_and_disabled_prices_refused() {
+        let mut registry = TokenRegistry::default();
+        let token = ckbtc().token;
+        registry.register(TokenConfig { units_per_icp: Some(12_345), ..ckbtc() }).unwrap();
+        assert!(registry.quote(&token, MINT_PRICE_E8S, MAX_PRICE_AGE + 1).is_err());
+
+        registry.register(TokenConfig { units_per_icp: Some(12_345), enabled: false, ..ckbtc() }).unwrap();
+        assert!(registry.quote(&token, MINT_PRICE_E8S, 0).is_err());
+        assert!(registry.ledger(&token).is_ok());
+    }
+
+    #[test]
+    fn test_anima_ledger_is_registered_at_install() {
+        let mut registry = TokenRegistry::default();
+        let error = registry.ledger(&AcceptedToken::ANIMA).unwrap_err();
+        assert!(format!("{:?}", error).contains("register_accepted_token"));
+
+        let ledger = Principal::from_slice(&[7]);
+        registry.register_anima(ledger);
+        assert_eq!(registry.ledger(&AcceptedToken::ANIMA).unwrap(), ledger);
+        assert!(registry.quote(&AcceptedToken::ANIMA, MINT_PRICE_E8S, 0).is_err());
+    }
+
+    #[test]
+    fn test_anima_ledger_is_fixed_once_registered() {
+        let mut registry = TokenRegistry::default();
+        let ledger = Principal::from_slice(&[7]);
+        registry.register_anima(ledger);
+
+        let moved = TokenConfig::anima(Principal::from_slice(&[8]));
+        assert!(registry.register(moved).is_err());
+        assert!(registry.remove(AcceptedToken::ANIMA).is_err());
+        assert_eq!(registry.ledger(&AcceptedToken::ANIMA).unwrap(), ledger);
+
+        // Its other settings stay with pricing managers.
+        registry.register(TokenConfig { minimum_amount: 1, ..TokenConfig::anima(ledger) }).unwrap();
+        assert_eq!(registry.minimum_amount(&AcceptedToken::ANIMA), 1);
+    }
+
+    #[test]
+    fn test_registration_rules() {
+        let mut registry = TokenRegistry::default();
+        assert!(registry.register(TokenConfig { token: AcceptedToken::ICP, ..ckbtc() }).is_err());
+        assert!(registry.register(TokenConfig { ledger: Principal::anonymous(), ..ckbtc() }).is_err());
+        assert!(registry.remove(AcceptedToken::ICP).is_err());
+        assert!(registry.set_price(AcceptedToken::ICP, 1, 0).is_err());
+        registry.register(ckbtc()).unwrap();
+        assert!(registry.remove(ckbtc().token).is_ok());
+        assert!(registry.ledger(&ckbtc().token).is_err());
+    }
+}
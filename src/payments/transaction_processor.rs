@@ -2,23 +2,15 @@ use candid::{Principal, Nat};
 use ic_cdk::api::call::CallResult;
 use crate::error::{Result, AnimaError};
 use crate::icrc::types::{TransferArgs, AcceptedToken};
+use crate::payments::token_registry::with_tokens;
 
 pub struct PaymentProcessor {
     owner: Principal,
-    accepted_tokens: Vec<(AcceptedToken, u64)>,
 }
 
 impl PaymentProcessor {
     pub fn new(owner: Principal) -> Self {
-        let accepted_tokens = vec![
-            (AcceptedToken::ICP, 100_000_000),   // 1 ICP in e8s
-            (AcceptedToken::ANIMA, 1_000_000),   // 1 ANIMA token
-        ];
-
-        Self {
-            owner,
-            accepted_tokens,
-        }
+        Self { owner }
     }
 
     pub async fn transfer(
@@ -61,19 +53,15 @@ impl PaymentProcessor {
     }
 
     fn get_minimum_amount(&self, token_type: &AcceptedToken) -> u128 {
-        self.accepted_tokens
-            .iter()
-            .find(|(t, _)| t == token_type)
-            .map(|(_, min)| *min as u128)
-            .unwrap_or(0)
+        with_tokens(|tokens| tokens.minimum_amount(token_type))
     }
 
     fn get_token_canister(&self, token_type: &AcceptedToken) -> Result<Principal> {
-        match token_type {
-            AcceptedToken::ICP => Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai")
-                .map_err(|_| AnimaError::InvalidInput("Invalid ICP ledger canister ID".to_string())),
-            AcceptedToken::ANIMA => Principal::from_text("aanaa-xaaaa-aaaaa-aaa")
-                .map_err(|_| AnimaError::InvalidInput("Invalid ANIMA token canister ID".to_string())),
-        }
+        token_canister(token_type)
     }
-}
\ No newline at end of file
+}
+
+/// Ledger canister of `token_type`, from the accepted-token registry.
+pub fn token_canister(token_type: &AcceptedToken) -> Result<Principal> {
+    with_tokens(|tokens| tokens.ledger(token_type))
+}
@@ -1,5 +1,7 @@
 use candid::{CandidType, Deserialize, Principal, Nat};
 
+pub use crate::icrc::types::AcceptedToken;
+
 #[derive(Debug, Clone, CandidType, Deserialize)]
 pub struct PaymentVerification {
     pub payment_required: bool,
@@ -14,12 +16,6 @@ pub struct PaymentDetails {
     pub memo: u64,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
-pub enum AcceptedToken {
-    ICP,
-    ANIMA
-}
-
 #[derive(Debug, Clone)]
 pub struct QuantumPaymentMetrics {
     pub coherence_level: f64,
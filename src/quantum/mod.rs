@@ -87,5 +87,3 @@ mod evolution {
     }
 }
 
-#[cfg(test)]
-mod tests;
\ No newline at end of file
@@ -122,7 +122,7 @@ impl Default for QuantumState {
             resonance_patterns: Vec::new(),
             stability_status: StabilityStatus::Stable,
             consciousness_alignment: true,
-            last_update: ic_cdk::api::time(),
+            last_update: crate::types::now(),
             pattern_coherence: 1.0,
             temporal_stability: 1.0,
             evolution_metrics: HashMap::new(),
@@ -0,0 +1,355 @@
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use crate::error::{AnimaError, Result};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Layout version written in front of every persisted value. Bump it whenever a
+/// persisted struct changes shape, and teach `migrate` to lift older payloads.
+pub const SCHEMA_VERSION: u16 = 1;
+
+// One region per subsystem. Ids are part of the on-chain layout: never reuse or
+// renumber them, only append.
+pub const SCHEMA_HEADER_MEMORY_ID: MemoryId = MemoryId::new(0);
+pub const QUANTUM_STATE_MEMORY_ID: MemoryId = MemoryId::new(1);
+pub const GROWTH_SYSTEM_MEMORY_ID: MemoryId = MemoryId::new(2);
+pub const MEMORIES_MEMORY_ID: MemoryId = MemoryId::new(3);
+pub const EVOLUTIONS_MEMORY_ID: MemoryId = MemoryId::new(4);
+pub const STAKES_MEMORY_ID: MemoryId = MemoryId::new(5);
+pub const POOL_METRICS_MEMORY_ID: MemoryId = MemoryId::new(6);
+pub const TOKEN_REGISTRY_MEMORY_ID: MemoryId = MemoryId::new(7);
+pub const NFT_TRANSACTIONS_MEMORY_ID: MemoryId = MemoryId::new(8);
+pub const COLLECTION_MEMORY_ID: MemoryId = MemoryId::new(9);
+pub const NFT_APPROVALS_MEMORY_ID: MemoryId = MemoryId::new(10);
+pub const MARKETPLACE_MEMORY_ID: MemoryId = MemoryId::new(11);
+pub const ROYALTIES_MEMORY_ID: MemoryId = MemoryId::new(12);
+pub const AUCTIONS_MEMORY_ID: MemoryId = MemoryId::new(13);
+pub const CONSUMED_PAYMENTS_MEMORY_ID: MemoryId = MemoryId::new(14);
+pub const REWARDS_MEMORY_ID: MemoryId = MemoryId::new(15);
+pub const REWARD_EPOCHS_MEMORY_ID: MemoryId = MemoryId::new(16);
+pub const SWAP_POOL_MEMORY_ID: MemoryId = MemoryId::new(17);
+pub const WALLETS_MEMORY_ID: MemoryId = MemoryId::new(18);
+pub const WALLET_SCANNER_MEMORY_ID: MemoryId = MemoryId::new(19);
+pub const WALLET_WITHDRAWALS_MEMORY_ID: MemoryId = MemoryId::new(20);
+pub const ACCEPTED_TOKENS_MEMORY_ID: MemoryId = MemoryId::new(21);
+pub const ADMIN_MEMORY_ID: MemoryId = MemoryId::new(22);
+pub const SECURITY_LOG_MEMORY_ID: MemoryId = MemoryId::new(23);
+pub const METRICS_MEMORY_ID: MemoryId = MemoryId::new(24);
+pub const ALERTS_MEMORY_ID: MemoryId = MemoryId::new(25);
+pub const CYCLES_MEMORY_ID: MemoryId = MemoryId::new(26);
+pub const MARKETPLACE_OFFERS_MEMORY_ID: MemoryId = MemoryId::new(27);
+pub const MARKETPLACE_SALES_MEMORY_ID: MemoryId = MemoryId::new(28);
+pub const MARKETPLACE_PAYOUTS_MEMORY_ID: MemoryId = MemoryId::new(29);
+pub const MARKETPLACE_META_MEMORY_ID: MemoryId = MemoryId::new(30);
+pub const REWARD_ALLOCATIONS_MEMORY_ID: MemoryId = MemoryId::new(31);
+pub const PRICING_TIERS_MEMORY_ID: MemoryId = MemoryId::new(32);
+pub const AUCTIONS_META_MEMORY_ID: MemoryId = MemoryId::new(33);
+
+const MAX_KEY_SIZE: u32 = 256;
+/// Persisted values are split into chunks of at most this many bytes, so a
+/// subsystem's state can grow past any single B-tree entry. Every node reserves
+/// room for 11 entries at the maximum size, which keeps nodes near 48 KiB.
+const CHUNK_SIZE: u32 = 4 * 1024;
+
+pub fn get_memory(id: MemoryId) -> Memory {
+    crate::MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Version 0, the default, means nothing has been persisted yet.
+#[derive(Debug, Clone, Copy, Default, CandidType, Deserialize, PartialEq)]
+pub struct SchemaHeader {
+    pub version: u16,
+    pub upgraded_at: u64,
+}
+
+impl Storable for SchemaHeader {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_default()
+    }
+}
+
+pub fn read_schema_header() -> SchemaHeader {
+    StableCell::init(get_memory(SCHEMA_HEADER_MEMORY_ID), SchemaHeader::default())
+        .map(|cell| *cell.get())
+        .unwrap_or_default()
+}
+
+pub fn write_schema_header(upgraded_at: u64) -> Result<()> {
+    let header = SchemaHeader { version: SCHEMA_VERSION, upgraded_at };
+    let mut cell = StableCell::init(get_memory(SCHEMA_HEADER_MEMORY_ID), header)
+        .map_err(|e| AnimaError::StorageError(format!("Schema header init failed: {:?}", e)))?;
+    cell.set(header)
+        .map_err(|e| AnimaError::StorageError(format!("Schema header write failed: {:?}", e)))?;
+    Ok(())
+}
+
+/// Key inside a region: the owning id (anima id, principal text, ...) plus a
+/// sequence number for subsystems that persist lists.
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RegionKey {
+    pub id: String,
+    pub seq: u64,
+}
+
+impl RegionKey {
+    pub fn new(id: impl Into<String>, seq: u64) -> Self {
+        Self { id: id.into(), seq }
+    }
+
+    pub fn singleton(id: &str) -> Self {
+        Self::new(id, 0)
+    }
+}
+
+/// One chunk of the value stored under `key`. Chunks of a value sort together
+/// and in order, since keys compare on the decoded struct.
+#[derive(Debug, Clone, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ChunkKey {
+    key: RegionKey,
+    chunk: u32,
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Candid encoding of a chunk key cannot fail"))
+    }
+
+    /// Keys are only ever written by `to_bytes` above, and `from_bytes` has no
+    /// way to report an error, so bytes that fail to decode mean the region's
+    /// memory is corrupt; the trap names the region key rather than unwinding
+    /// silently.
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self)
+            .unwrap_or_else(|e| panic!("Corrupt stable region key ({} bytes): {}", bytes.len(), e))
+    }
+}
+
+impl BoundedStorable for ChunkKey {
+    const MAX_SIZE: u32 = MAX_KEY_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Chunk(Vec<u8>);
+
+impl Storable for Chunk {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for Chunk {
+    const MAX_SIZE: u32 = CHUNK_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// A candid payload prefixed with the schema version it was written under.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionedValue {
+    pub version: u16,
+    pub payload: Vec<u8>,
+}
+
+impl VersionedValue {
+    pub fn encode<T: CandidType>(value: &T) -> Result<Self> {
+        let payload = candid::encode_one(value)
+            .map_err(|e| AnimaError::StorageError(format!("Encode failed: {}", e)))?;
+        Ok(Self { version: SCHEMA_VERSION, payload })
+    }
+
+    pub fn decode<T: CandidType + DeserializeOwned>(&self) -> Result<T> {
+        let payload = migrate(self.version, &self.payload)?;
+        candid::decode_one(&payload)
+            .map_err(|e| AnimaError::StorageError(format!("Decode failed at schema v{}: {}", self.version, e)))
+    }
+}
+
+impl Storable for VersionedValue {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(2 + self.payload.len());
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        if bytes.len() < 2 {
+            return Self { version: 0, payload: Vec::new() };
+        }
+        Self {
+            version: u16::from_be_bytes([bytes[0], bytes[1]]),
+            payload: bytes[2..].to_vec(),
+        }
+    }
+}
+
+/// Brings a payload written under `version` up to the current schema.
+fn migrate(version: u16, payload: &[u8]) -> Result<Vec<u8>> {
+    match version {
+        1..=SCHEMA_VERSION => Ok(payload.to_vec()),
+        v if v > SCHEMA_VERSION => Err(AnimaError::StorageError(format!(
+            "Stable data written by schema v{} is newer than this build (v{})", v, SCHEMA_VERSION
+        ))),
+        v => Err(AnimaError::StorageError(format!(
+            "No migration path from schema v{} to v{}", v, SCHEMA_VERSION
+        ))),
+    }
+}
+
+/// Typed view over one subsystem's stable region.
+pub struct StableRegion<T> {
+    memory_id: MemoryId,
+    _marker: PhantomData<T>,
+}
+
+impl<T: CandidType + DeserializeOwned> StableRegion<T> {
+    pub const fn new(memory_id: MemoryId) -> Self {
+        Self { memory_id, _marker: PhantomData }
+    }
+
+    /// Replaces the region's contents with `entries`.
+    pub fn save<'a, I>(&self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (RegionKey, &'a T)>,
+        T: 'a,
+    {
+        let mut map: StableBTreeMap<ChunkKey, Chunk, Memory> =
+            StableBTreeMap::new(get_memory(self.memory_id));
+        for (key, value) in entries {
+            let bytes = VersionedValue::encode(value)?.to_bytes().into_owned();
+            for (chunk, part) in bytes.chunks(CHUNK_SIZE as usize).enumerate() {
+                let chunk = u32::try_from(chunk)
+                    .map_err(|_| AnimaError::StorageError(format!("Value under {:?} has too many chunks", key)))?;
+                insert_chunk(&mut map, ChunkKey { key: key.clone(), chunk }, part)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(&self) -> Result<Vec<(RegionKey, T)>> {
+        let map: StableBTreeMap<ChunkKey, Chunk, Memory> =
+            StableBTreeMap::init(get_memory(self.memory_id));
+        assemble(map.iter())?
+            .into_iter()
+            .map(|(key, value)| value.decode().map(|decoded| (key, decoded)))
+            .collect()
+    }
+
+    pub fn load_singleton(&self, id: &str) -> Result<Option<T>> {
+        let map: StableBTreeMap<ChunkKey, Chunk, Memory> =
+            StableBTreeMap::init(get_memory(self.memory_id));
+        let key = RegionKey::singleton(id);
+        let first = ChunkKey { key: key.clone(), chunk: 0 };
+        assemble(map.range(first..).take_while(|(chunk_key, _)| chunk_key.key == key))?
+            .pop()
+            .map(|(_, value)| value.decode())
+            .transpose()
+    }
+}
+
+/// Checks the B-tree's bounds up front: `StableBTreeMap::insert` asserts on
+/// them, and a trap in `pre_upgrade` would leave the canister unable to upgrade.
+fn insert_chunk(map: &mut StableBTreeMap<ChunkKey, Chunk, Memory>, key: ChunkKey, bytes: &[u8]) -> Result<()> {
+    let key_size = key.to_bytes().len();
+    if key_size > MAX_KEY_SIZE as usize {
+        return Err(AnimaError::StorageError(format!(
+            "Region key {:?} is {} bytes, over the {} byte limit", key.key, key_size, MAX_KEY_SIZE
+        )));
+    }
+    if bytes.len() > CHUNK_SIZE as usize {
+        return Err(AnimaError::StorageError(format!(
+            "Chunk of {} bytes exceeds the {} byte limit", bytes.len(), CHUNK_SIZE
+        )));
+    }
+    map.insert(key, Chunk(bytes.to_vec()));
+    Ok(())
+}
+
+/// Joins consecutive chunks back into whole values, checking none is missing.
+fn assemble(chunks: impl Iterator<Item = (ChunkKey, Chunk)>) -> Result<Vec<(RegionKey, VersionedValue)>> {
+    let mut values: Vec<(RegionKey, u32, Vec<u8>)> = Vec::new();
+    for (ChunkKey { key, chunk }, Chunk(bytes)) in chunks {
+        match values.last_mut() {
+            Some((last, next, buf)) if *last == key && *next == chunk => {
+                buf.extend_from_slice(&bytes);
+                *next += 1;
+            }
+            _ if chunk == 0 => values.push((key, 1, bytes)),
+            _ => {
+                return Err(AnimaError::StorageError(format!(
+                    "Value under {:?} is missing chunks before {}", key, chunk
+                )));
+            }
+        }
+    }
+    Ok(values.into_iter()
+        .map(|(key, _, bytes)| (key, VersionedValue::from_bytes(Cow::Owned(bytes))))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versioned_value_roundtrip() {
+        let value = VersionedValue::encode(&(42u64, "anima".to_string())).unwrap();
+        let restored = VersionedValue::from_bytes(value.to_bytes());
+
+        assert_eq!(restored.version, SCHEMA_VERSION);
+        assert_eq!(restored.decode::<(u64, String)>().unwrap(), (42, "anima".to_string()));
+    }
+
+    #[test]
+    fn test_values_larger_than_a_chunk_roundtrip() {
+        let region: StableRegion<Vec<u64>> = StableRegion::new(MemoryId::new(200));
+        let big: Vec<u64> = (0..(CHUNK_SIZE as u64)).collect();
+        let small = vec![7u64];
+        region.save([
+            (RegionKey::new("a", 0), &big),
+            (RegionKey::new("a", 1), &small),
+            (RegionKey::new("b", 0), &big),
+        ]).unwrap();
+
+        let loaded = region.load().unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded[0], (RegionKey::new("a", 0), big.clone()));
+        assert_eq!(loaded[1], (RegionKey::new("a", 1), small));
+        assert_eq!(loaded[2], (RegionKey::new("b", 0), big.clone()));
+
+        region.save([(RegionKey::singleton("b"), &big)]).unwrap();
+        assert_eq!(region.load_singleton("b").unwrap(), Some(big));
+        assert_eq!(region.load_singleton("a").unwrap(), None);
+    }
+
+    #[test]
+    fn test_oversized_key_is_an_error() {
+        let region: StableRegion<u64> = StableRegion::new(MemoryId::new(201));
+        let id = "k".repeat(MAX_KEY_SIZE as usize);
+
+        assert!(matches!(
+            region.save([(RegionKey::singleton(&id), &1)]),
+            Err(AnimaError::StorageError(_))
+        ));
+    }
+
+    #[test]
+    fn test_newer_schema_is_rejected() {
+        let mut value = VersionedValue::encode(&7u64).unwrap();
+        value.version = SCHEMA_VERSION + 1;
+
+        assert!(matches!(value.decode::<u64>(), Err(AnimaError::StorageError(_))));
+    }
+}
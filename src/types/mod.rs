@@ -1,10 +1,24 @@
 pub mod interaction;
 pub mod personality;
+pub mod security;
 
 use candid::{CandidType, Deserialize, Principal};
 use serde::Serialize;
 use crate::quantum::QuantumState;
 
+/// Canister time in nanoseconds. Off-chain, e.g. under `cargo test`, there is
+/// no system API to ask, so it reads as zero.
+pub fn now() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::time()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct AnimaState {
     pub id: String,
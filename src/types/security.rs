@@ -1,9 +1,11 @@
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
 use ic_stable_structures::Storable;
 use std::borrow::Cow;
 
+/// Oldest events are dropped past this many, so the log stays bounded.
+pub const MAX_SECURITY_EVENTS: usize = 10_000;
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
 pub struct SecurityMetrics {
     pub total_events: u64,
@@ -21,7 +23,7 @@ pub struct SecurityEvent {
     pub actor: Option<Principal>,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum SecurityEventType {
     AuthenticationAttempt,
     StateModification,
@@ -30,6 +32,60 @@ pub enum SecurityEventType {
     SystemAlert,
 }
 
+impl SecurityMetrics {
+    pub fn new(now: u64) -> Self {
+        Self {
+            total_events: 0,
+            critical_events: 0,
+            warning_events: 0,
+            last_update: now,
+            event_log: Vec::new(),
+        }
+    }
+
+    /// Appends `event`; its id is its position in the full history.
+    pub fn record(&mut self, event: SecurityEvent, critical: bool) -> u64 {
+        let id = self.total_events;
+        self.total_events += 1;
+        if critical {
+            self.critical_events += 1;
+        }
+        self.last_update = event.timestamp;
+        self.event_log.push(event);
+        if self.event_log.len() > MAX_SECURITY_EVENTS {
+            let excess = self.event_log.len() - MAX_SECURITY_EVENTS;
+            self.event_log.drain(..excess);
+        }
+        id
+    }
+
+    /// Counts a refused privileged call. Refusals are not logged, so callers
+    /// without a role cannot push real events out of the log.
+    pub fn record_denied(&mut self, now: u64) {
+        self.warning_events += 1;
+        self.last_update = now;
+    }
+
+    /// Id of the oldest event still held.
+    fn first_id(&self) -> u64 {
+        self.total_events - self.event_log.len() as u64
+    }
+
+    /// Events after `start_after`, oldest first, with their ids.
+    pub fn events(&self, start_after: Option<u64>, limit: usize) -> Vec<(u64, SecurityEvent)> {
+        let first = self.first_id();
+        let skip = start_after
+            .map(|after| after.saturating_add(1).saturating_sub(first) as usize)
+            .unwrap_or(0);
+        self.event_log.iter()
+            .enumerate()
+            .skip(skip)
+            .take(limit)
+            .map(|(i, event)| (first + i as u64, event.clone()))
+            .collect()
+    }
+}
+
 impl Storable for SecurityMetrics {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
         let bytes = candid::encode_one(self).unwrap();
@@ -43,12 +99,6 @@ impl Storable for SecurityMetrics {
 
 impl Default for SecurityMetrics {
     fn default() -> Self {
-        Self {
-            total_events: 0,
-            critical_events: 0,
-            warning_events: 0,
-            last_update: ic_cdk::api::time(),
-            event_log: Vec::new(),
-        }
+        Self::new(ic_cdk::api::time())
     }
-}
\ No newline at end of file
+}
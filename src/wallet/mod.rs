@@ -1,14 +1,28 @@
-use candid::{CandidType, Principal};
-use ic_cdk::api::{caller, time};
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::*;
+use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use sha2::{Sha224, Digest};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use crate::anima_token::swap::pool::{pool_account, settle_deposited_swap, with_swap_pool};
+use crate::icrc::account_id::AccountIdentifier;
+use crate::icrc::ledger::{Block, Operation, LEDGER_CANISTER_ID};
+use crate::nft::marketplace::ledger_fee;
+use crate::payments::transaction_processor::token_canister;
+use crate::icrc::{client, AcceptedToken, Account, Subaccount};
+use crate::stable::{RegionKey, StableRegion, WALLETS_MEMORY_ID, WALLET_SCANNER_MEMORY_ID, WALLET_WITHDRAWALS_MEMORY_ID};
 
-// ICP Ledger canister ID
-const ICP_LEDGER_CANISTER_ID: Principal = Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap();
-const SUB_ACCOUNT_ZERO: [u8; 32] = [0; 32];
 const ICP_FEE: u64 = 10_000;
-const ACCOUNT_DOMAIN_SEPARATOR: &[u8] = b"\x0Aaccount-id";
+/// Blocks read from the ICP ledger per scan.
+const SCAN_BATCH_SIZE: u64 = 1_000;
+const DEPOSIT_SCAN_INTERVAL: Duration = Duration::from_secs(30);
+/// Withdrawal retries back off exponentially from this delay.
+const RETRY_DELAY_MS: u64 = 1000;
+const MAX_RETRIES: u32 = 3;
+/// Memo on outbound ICP withdrawals ("WDRAW").
+const WITHDRAWAL_MEMO: u64 = 0x57_44_52_41_57;
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct WalletState {
@@ -19,37 +33,6 @@ pub struct WalletState {
     pub transactions: Vec<Transaction>,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-pub struct Tokens {
-    pub e8s: u64,
-}
-
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-pub struct TimeStamp {
-    pub timestamp_nanos: u64,
-}
-
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-pub struct Account {
-    pub owner: Principal,
-    pub subaccount: Option<Vec<u8>>,
-}
-
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-pub struct AccountBalanceArgs {
-    pub account: Account,
-}
-
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-pub struct TransferArgs {
-    pub memo: u64,
-    pub amount: Tokens,
-    pub fee: Tokens,
-    pub from_subaccount: Option<Vec<u8>>,
-    pub to: String,
-    pub created_at_time: Option<TimeStamp>,
-}
-
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct Transaction {
     pub id: String,
@@ -61,9 +44,27 @@ pub struct Transaction {
     pub memo: Option<String>,
     pub from: Option<String>,
     pub to: Option<String>,
+    /// Token moved; ICP when unset.
+    #[serde(default)]
+    pub token: Option<AcceptedToken>,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+impl Transaction {
+    fn is_pending_icp(&self) -> bool {
+        self.status == TransactionStatus::Pending && self.token != Some(AcceptedToken::ANIMA)
+    }
+
+    /// What the transaction took out of the wallet balance.
+    fn debit(&self) -> u128 {
+        match self.transaction_type {
+            // Swaps pay the ledger fee on top; withdrawals take it out of `amount`.
+            TransactionType::Swap => self.amount + ICP_FEE as u128,
+            _ => self.amount,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
@@ -71,7 +72,7 @@ pub enum TransactionType {
     Mint,
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
 pub enum TransactionStatus {
     Pending,
     Completed,
@@ -81,22 +82,83 @@ pub enum TransactionStatus {
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct SwapParams {
     pub icp_amount: u128,
+    /// Slippage bound: the swap is refunded if it would pay out less.
     pub min_anima_amount: u128,
-    pub slippage_tolerance: f64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum WithdrawalDestination {
+    /// Legacy ICP ledger account identifier, hex encoded.
+    AccountIdentifier(String),
+    Account(Account),
+}
+
+/// A withdrawal whose ledger transfer has not resolved yet.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct PendingWithdrawal {
+    pub id: String,
+    pub user: Principal,
+    pub token: AcceptedToken,
+    pub destination: WithdrawalDestination,
+    /// Debited from the wallet; the recipient receives it less `fee`.
+    pub amount: u128,
+    pub fee: u128,
+    /// Reused by every attempt so the ledger deduplicates retries.
+    pub created_at_time: u64,
+    pub attempts: u32,
+}
+
+/// Result of comparing a wallet with its deposit account on the ledger.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct Reconciliation {
+    pub ledger_balance: u128,
+    pub wallet_balance: u128,
+    /// ICP debited from the wallet whose transfer has not settled yet.
+    pub pending: u128,
+    /// Ledger funds not yet credited by the scanner. They are picked up by a
+    /// later scan rather than here, so no block is ever credited twice.
+    pub uncredited: u128,
+    /// Amount the wallet was written down by because the ledger held less.
+    pub corrected: u128,
+}
+
+/// Per-user deposit subaccount: the principal's length followed by its bytes,
+/// so distinct principals can never share one.
+pub fn deposit_subaccount(user: &Principal) -> Subaccount {
+    let bytes = user.as_slice();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..=bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+fn balance_mut(wallet: &mut WalletState, token: AcceptedToken) -> Option<&mut u128> {
+    match token {
+        AcceptedToken::ICP => Some(&mut wallet.icp_balance),
+        AcceptedToken::ANIMA => Some(&mut wallet.anima_balance),
+        AcceptedToken::Icrc1(_) => None,
+    }
 }
 
 pub struct WalletService {
     wallets: HashMap<Principal, WalletState>,
-    conversion_rate: f64,
+    /// Deposit account of every wallet, for matching ledger blocks.
+    deposit_accounts: HashMap<AccountIdentifier, Principal>,
     canister_id: Principal,
+    /// Next ICP ledger block to scan; `None` until the first scan sets it to
+    /// the chain tip.
+    next_block: Option<u64>,
+    withdrawals: BTreeMap<String, PendingWithdrawal>,
 }
 
 impl WalletService {
     pub fn new(canister_id: Principal) -> Self {
         Self {
             wallets: HashMap::new(),
-            conversion_rate: 100.0,
+            deposit_accounts: HashMap::new(),
             canister_id,
+            next_block: None,
+            withdrawals: BTreeMap::new(),
         }
     }
 
@@ -105,7 +167,7 @@ impl WalletService {
             return Err("Wallet already exists".to_string());
         }
 
-        let account_identifier = self.generate_account_identifier(user, None);
+        let account_identifier = self.deposit_account(&user).to_hex();
         let wallet_state = WalletState {
             icp_balance: 0,
             anima_balance: 0,
@@ -114,137 +176,669 @@ impl WalletService {
             transactions: Vec::new(),
         };
 
-        self.wallets.insert(user, wallet_state.clone());
+        self.insert_wallet(user, wallet_state.clone());
         Ok(wallet_state)
     }
 
-    fn generate_account_identifier(&self, owner: Principal, subaccount: Option<[u8; 32]>) -> String {
-        let mut hash = Sha224::new();
-        hash.update(ACCOUNT_DOMAIN_SEPARATOR);
-        hash.update(&owner.as_slice());
-        hash.update(&subaccount.unwrap_or(SUB_ACCOUNT_ZERO));
-        hex::encode(hash.finalize())
+    fn insert_wallet(&mut self, user: Principal, wallet: WalletState) {
+        self.deposit_accounts.insert(self.deposit_account(&user), user);
+        self.wallets.insert(user, wallet);
     }
 
-    pub async fn get_icp_balance(&self, account_id: &str) -> Result<u128, String> {
-        let account = Account {
-            owner: self.canister_id,
-            subaccount: Some(hex::decode(account_id).map_err(|e| e.to_string())?),
-        };
+    pub fn deposit_account(&self, user: &Principal) -> AccountIdentifier {
+        AccountIdentifier::new(&self.canister_id, Some(&deposit_subaccount(user)))
+    }
+
+    pub fn next_block(&self) -> Option<u64> {
+        self.next_block
+    }
 
-        let args = AccountBalanceArgs { account };
-        let balance: Tokens = ic_cdk::call(ICP_LEDGER_CANISTER_ID, "account_balance", (args,))
-            .await
-            .map_err(|e| format!("Failed to get balance: {:?}", e))?;
+    /// Credits deposits found in `blocks`, which must be sorted by index.
+    /// Blocks behind the cursor are ignored and the cursor only advances over
+    /// a contiguous run, so each block is credited exactly once.
+    pub fn apply_blocks(&mut self, chain_length: u64, blocks: Vec<(u64, Block)>, now: u64) -> Vec<Principal> {
+        let mut cursor = match self.next_block {
+            Some(cursor) => cursor,
+            None => {
+                // Deposits start counting from the first scan.
+                self.next_block = Some(chain_length);
+                return Vec::new();
+            }
+        };
 
-        Ok(balance.e8s as u128)
+        let mut credited = Vec::new();
+        for (index, block) in blocks {
+            if index < cursor {
+                continue;
+            }
+            if index > cursor {
+                break;
+            }
+            if let Some(user) = self.credit_deposit(index, &block, now) {
+                credited.push(user);
+            }
+            cursor += 1;
+        }
+        self.next_block = Some(cursor);
+        credited
     }
 
-    pub async fn process_deposit(&mut self, account_id: &str, block_index: u64) -> Result<(), String> {
-        let balance = self.get_icp_balance(account_id).await?;
-        
-        let wallet = self.wallets.values_mut()
-            .find(|w| w.account_identifier == account_id)
-            .ok_or("Wallet not found for this account")?;
+    fn credit_deposit(&mut self, index: u64, block: &Block, now: u64) -> Option<Principal> {
+        let (to, amount) = match &block.transaction.operation {
+            Some(Operation::Mint { to, amount })
+            | Some(Operation::Transfer { to, amount, .. })
+            | Some(Operation::TransferFrom { to, amount, .. }) => (to, amount.e8s),
+            _ => return None,
+        };
+        let account = AccountIdentifier::from_slice(to)?;
+        let user = *self.deposit_accounts.get(&account)?;
+        let wallet = self.wallets.get_mut(&user)?;
 
-        wallet.icp_balance = balance;
+        wallet.icp_balance += amount as u128;
         wallet.transactions.push(Transaction {
-            id: format!("dep-{}-{}", time(), block_index),
+            id: format!("dep-{}-{}", now, index),
             transaction_type: TransactionType::Deposit,
-            amount: balance,
-            timestamp: time(),
+            amount: amount as u128,
+            timestamp: now,
             status: TransactionStatus::Completed,
-            block_index: Some(block_index),
-            memo: Some(format!("Block height: {}", block_index)),
+            block_index: Some(index),
+            memo: Some(format!("Block height: {}", index)),
             from: None,
-            to: Some(account_id.to_string()),
+            to: Some(account.to_hex()),
+            token: Some(AcceptedToken::ICP),
         });
+        Some(user)
+    }
 
-        Ok(())
+    /// ICP debited from `user` for transfers that have not settled.
+    pub fn pending_icp(&self, user: &Principal) -> u128 {
+        self.wallets.get(user)
+            .map(|wallet| {
+                wallet.transactions.iter()
+                    .filter(|tx| tx.is_pending_icp())
+                    .map(Transaction::debit)
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    /// Compares `user`'s wallet with the balance of their deposit account.
+    /// A wallet claiming more than the ledger holds is written down to it once
+    /// no transfer is pending.
+    pub fn reconcile(&mut self, user: Principal, ledger_balance: u128) -> Result<Reconciliation, String> {
+        let pending = self.pending_icp(&user);
+        let wallet = self.wallets.get_mut(&user).ok_or("Wallet not found")?;
+        let expected = wallet.icp_balance + pending;
+
+        let mut report = Reconciliation {
+            ledger_balance,
+            wallet_balance: wallet.icp_balance,
+            pending,
+            uncredited: ledger_balance.saturating_sub(expected),
+            corrected: 0,
+        };
+        // While a transfer is in flight the ledger may or may not show it yet.
+        if pending == 0 && wallet.icp_balance > ledger_balance {
+            report.corrected = wallet.icp_balance - ledger_balance;
+            wallet.icp_balance = ledger_balance;
+        }
+        Ok(report)
     }
 
-    pub async fn swap_icp_to_anima(&mut self, user: Principal, params: SwapParams) -> Result<u128, String> {
+    /// Debits a swap's ICP and its ledger fee, recording it as pending.
+    pub fn begin_swap(&mut self, user: Principal, icp_amount: u128, now: u64) -> Result<String, String> {
         let wallet = self.wallets.get_mut(&user)
             .ok_or("Wallet not found")?;
 
-        if wallet.icp_balance < params.icp_amount {
+        let total = icp_amount + ICP_FEE as u128;
+        if wallet.icp_balance < total {
             return Err("Insufficient ICP balance".to_string());
         }
 
-        let anima_amount = (params.icp_amount as f64 * self.conversion_rate) as u128;
-        let min_amount_with_slippage = 
-            (params.min_anima_amount as f64 * (1.0 - params.slippage_tolerance)) as u128;
-
-        if anima_amount < min_amount_with_slippage {
-            return Err(format!(
-                "Slippage too high. Expected: {}, Got: {}", 
-                params.min_anima_amount, 
-                anima_amount
-            ));
-        }
-
-        // Transfer ICP to project reserve
-        let transfer_args = TransferArgs {
-            memo: time() as u64,
-            amount: Tokens { e8s: params.icp_amount as u64 },
-            fee: Tokens { e8s: ICP_FEE },
-            from_subaccount: Some(hex::decode(&wallet.account_identifier)
-                .map_err(|e| e.to_string())?),
-            to: self.canister_id.to_text(),
-            created_at_time: Some(TimeStamp {
-                timestamp_nanos: time(),
-            }),
+        let id = format!("swap-{}-{}", now, wallet.transactions.len());
+        wallet.icp_balance -= total;
+        wallet.transactions.push(Transaction {
+            id: id.clone(),
+            transaction_type: TransactionType::Swap,
+            amount: icp_amount,
+            timestamp: now,
+            status: TransactionStatus::Pending,
+            block_index: None,
+            memo: None,
+            from: Some(wallet.account_identifier.clone()),
+            to: None,
+            token: Some(AcceptedToken::ICP),
+        });
+        Ok(id)
+    }
+
+    /// Settles a pending swap. A swap whose ICP never left the deposit
+    /// account is credited back.
+    pub fn finish_swap(&mut self, user: Principal, id: &str, outcome: Result<(u64, String, u128), String>) {
+        let Some(wallet) = self.wallets.get_mut(&user) else { return };
+        let Some(tx) = wallet.transactions.iter_mut().find(|tx| tx.id == id) else { return };
+        match outcome {
+            Ok((block_index, memo, anima_received)) => {
+                wallet.anima_balance += anima_received;
+                tx.status = TransactionStatus::Completed;
+                tx.block_index = Some(block_index);
+                tx.memo = Some(memo);
+                tx.to = Some(self.canister_id.to_text());
+            }
+            Err(e) => {
+                tx.status = TransactionStatus::Failed;
+                tx.memo = Some(e);
+                wallet.icp_balance += tx.amount + ICP_FEE as u128;
+            }
+        }
+    }
+
+    pub fn get_wallet_state(&self, user: Principal) -> Option<&WalletState> {
+        self.wallets.get(&user)
+    }
+
+    /// Debits a withdrawal and records it as pending until the ledger
+    /// answers. `amount` includes the ledger `fee`.
+    pub fn begin_withdrawal(
+        &mut self,
+        user: Principal,
+        token: AcceptedToken,
+        destination: WithdrawalDestination,
+        amount: u128,
+        fee: u128,
+        now: u64,
+    ) -> Result<PendingWithdrawal, String> {
+        if amount <= fee {
+            return Err(format!("Withdrawal must exceed the ledger fee of {}", fee));
+        }
+        let wallet = self.wallets.get_mut(&user).ok_or("Wallet not found")?;
+        let balance = balance_mut(wallet, token)
+            .ok_or_else(|| format!("Wallets do not hold {:?}", token))?;
+        if *balance < amount {
+            return Err(format!("Insufficient {:?} balance", token));
+        }
+        *balance -= amount;
+
+        let id = format!("wd-{}-{}", now, wallet.transactions.len());
+        let to = match &destination {
+            WithdrawalDestination::AccountIdentifier(hex) => hex.clone(),
+            WithdrawalDestination::Account(account) => account.owner.to_text(),
+        };
+        wallet.transactions.push(Transaction {
+            id: id.clone(),
+            transaction_type: TransactionType::Withdrawal,
+            amount,
+            timestamp: now,
+            status: TransactionStatus::Pending,
+            block_index: None,
+            memo: None,
+            from: Some(wallet.account_identifier.clone()),
+            to: Some(to),
+            token: Some(token),
+        });
+
+        let withdrawal = PendingWithdrawal {
+            id: id.clone(),
+            user,
+            token,
+            destination,
+            amount,
+            fee,
+            created_at_time: now,
+            attempts: 0,
         };
+        self.withdrawals.insert(id, withdrawal.clone());
+        Ok(withdrawal)
+    }
 
-        let result: Result<u64, String> = ic_cdk::call(
-            ICP_LEDGER_CANISTER_ID,
-            "transfer",
-            (transfer_args,),
-        ).await.map_err(|e| format!("Transfer failed: {:?}", e))?;
+    /// Counts an attempt at a pending withdrawal and returns it.
+    pub fn start_withdrawal_attempt(&mut self, id: &str) -> Option<PendingWithdrawal> {
+        let withdrawal = self.withdrawals.get_mut(id)?;
+        withdrawal.attempts += 1;
+        Some(withdrawal.clone())
+    }
 
-        match result {
+    /// Finalizes a withdrawal with its ledger block, or refunds it.
+    pub fn finish_withdrawal(&mut self, id: &str, outcome: Result<u64, String>) {
+        let Some(withdrawal) = self.withdrawals.remove(id) else { return };
+        let Some(wallet) = self.wallets.get_mut(&withdrawal.user) else { return };
+        let Some(tx) = wallet.transactions.iter_mut().find(|tx| tx.id == id) else { return };
+        match outcome {
             Ok(block_index) => {
-                wallet.icp_balance -= params.icp_amount;
-                wallet.anima_balance += anima_amount;
-
-                wallet.transactions.push(Transaction {
-                    id: format!("swap-{}-{}", time(), block_index),
-                    transaction_type: TransactionType::Swap,
-                    amount: params.icp_amount,
-                    timestamp: time(),
-                    status: TransactionStatus::Completed,
-                    block_index: Some(block_index),
-                    memo: Some(format!(
-                        "Swapped {} ICP for {} ANIMA", 
-                        params.icp_amount, 
-                        anima_amount
-                    )),
-                    from: Some(wallet.account_identifier.clone()),
-                    to: Some(self.canister_id.to_text()),
-                });
-
-                Ok(anima_amount)
-            },
-            Err(e) => Err(format!("Swap failed: {}", e)),
+                tx.status = TransactionStatus::Completed;
+                tx.block_index = Some(block_index);
+            }
+            Err(e) => {
+                tx.status = TransactionStatus::Failed;
+                tx.memo = Some(e);
+                if let Some(balance) = balance_mut(wallet, withdrawal.token) {
+                    *balance += withdrawal.amount;
+                }
+            }
         }
     }
 
-    pub fn get_wallet_state(&self, user: Principal) -> Option<&WalletState> {
-        self.wallets.get(&user)
+    pub fn pending_withdrawals(&self) -> impl Iterator<Item = &PendingWithdrawal> {
+        self.withdrawals.values()
+    }
+
+    pub fn transaction(&self, user: &Principal, id: &str) -> Option<&Transaction> {
+        self.wallets.get(user)?.transactions.iter().find(|tx| tx.id == id)
     }
 
+    /// Hex-encoded ICP account identifier with a valid checksum.
     pub fn validate_account_identifier(&self, account_id: &str) -> bool {
         if account_id.len() != 64 {
             return false;
         }
-        hex::decode(account_id).is_ok()
+        hex::decode(account_id)
+            .ok()
+            .and_then(|bytes| AccountIdentifier::from_slice(&bytes))
+            .is_some()
     }
+}
+
+thread_local! {
+    static WALLETS: RefCell<WalletService> = RefCell::new(WalletService::new(ic_cdk::id()));
+    /// Set while a scan is awaiting the ledger, so timer ticks never overlap.
+    static SCANNING: Cell<bool> = const { Cell::new(false) };
+}
 
-    pub fn update_conversion_rate(&mut self, new_rate: f64) -> Result<(), String> {
-        if new_rate <= 0.0 {
-            return Err("Invalid conversion rate".to_string());
+const WALLETS_REGION: StableRegion<WalletState> = StableRegion::new(WALLETS_MEMORY_ID);
+const SCANNER_REGION: StableRegion<Option<u64>> = StableRegion::new(WALLET_SCANNER_MEMORY_ID);
+const WITHDRAWALS_REGION: StableRegion<PendingWithdrawal> = StableRegion::new(WALLET_WITHDRAWALS_MEMORY_ID);
+const SCANNER_KEY: &str = "next_block";
+
+pub fn with_wallets<R>(f: impl FnOnce(&WalletService) -> R) -> R {
+    WALLETS.with(|wallets| f(&wallets.borrow()))
+}
+
+pub fn with_wallets_mut<R>(f: impl FnOnce(&mut WalletService) -> R) -> R {
+    WALLETS.with(|wallets| f(&mut wallets.borrow_mut()))
+}
+
+pub fn save_stable() -> crate::Result<()> {
+    with_wallets(|service| {
+        WALLETS_REGION.save(service.wallets.iter().map(|(user, wallet)| (RegionKey::new(user.to_text(), 0), wallet)))?;
+        SCANNER_REGION.save([(RegionKey::singleton(SCANNER_KEY), &service.next_block)])?;
+        WITHDRAWALS_REGION.save(service.withdrawals.iter().map(|(id, withdrawal)| (RegionKey::new(id.clone(), 0), withdrawal)))
+    })
+}
+
+pub fn restore_stable() -> crate::Result<()> {
+    let wallets = WALLETS_REGION.load()?;
+    let next_block = SCANNER_REGION.load_singleton(SCANNER_KEY)?.flatten();
+    let withdrawals = WITHDRAWALS_REGION.load()?;
+    with_wallets_mut(|service| {
+        *service = WalletService::new(ic_cdk::id());
+        service.next_block = next_block;
+        service.withdrawals = withdrawals.into_iter().map(|(key, withdrawal)| (key.id, withdrawal)).collect();
+        for (key, wallet) in wallets {
+            let user = Principal::from_text(&key.id)
+                .map_err(|e| crate::AnimaError::StorageError(format!("Invalid wallet key {}: {}", key.id, e)))?;
+            service.insert_wallet(user, wallet);
         }
-        self.conversion_rate = new_rate;
         Ok(())
+    })
+}
+
+/// Starts the deposit scanner. Timers do not survive upgrades, so this runs
+/// from both `init` and `post_upgrade`.
+pub fn start_deposit_scanner() {
+    ic_cdk_timers::set_timer_interval(DEPOSIT_SCAN_INTERVAL, || {
+        ic_cdk::spawn(scan_deposits());
+    });
+}
+
+/// Reads the next batch of ICP ledger blocks and credits any deposits into
+/// wallet deposit accounts.
+pub async fn scan_deposits() {
+    if SCANNING.with(|scanning| scanning.replace(true)) {
+        return;
+    }
+    // Before the first scan only the chain length is needed.
+    let (start, length) = match with_wallets(|service| service.next_block()) {
+        Some(next_block) => (next_block, SCAN_BATCH_SIZE),
+        None => (0, 0),
+    };
+    match crate::icrc::ledger::fetch_blocks(start, length).await {
+        Ok((chain_length, blocks)) => {
+            with_wallets_mut(|service| service.apply_blocks(chain_length, blocks, time()));
+        }
+        Err(e) => ic_cdk::println!("Deposit scan from block {} failed: {:?}", start, e),
+    }
+    SCANNING.with(|scanning| scanning.set(false));
+}
+
+fn icp_ledger() -> Result<Principal, String> {
+    Principal::from_text(LEDGER_CANISTER_ID).map_err(|e| e.to_string())
+}
+
+#[update]
+fn create_wallet() -> Result<WalletState, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous callers cannot hold a wallet".to_string());
+    }
+    with_wallets_mut(|service| service.initialize_wallet(caller))
+}
+
+#[query]
+fn get_wallet() -> Option<WalletState> {
+    with_wallets(|service| service.get_wallet_state(ic_cdk::caller()).cloned())
+}
+
+/// The caller's ICP deposit account, as an ICRC-1 account and as the legacy
+/// account identifier.
+#[query]
+fn get_deposit_account() -> (Account, String) {
+    let caller = ic_cdk::caller();
+    let account = Account::new(ic_cdk::id(), Some(deposit_subaccount(&caller)));
+    (account, with_wallets(|service| service.deposit_account(&caller).to_hex()))
+}
+
+#[query]
+fn get_deposit_scan_position() -> Option<u64> {
+    with_wallets(|service| service.next_block())
+}
+
+/// Checks the caller's wallet against their deposit account on the ledger.
+#[update]
+async fn reconcile_wallet() -> Result<Reconciliation, String> {
+    let caller = ic_cdk::caller();
+    let account = Account::new(ic_cdk::id(), Some(deposit_subaccount(&caller)));
+    let balance = client::icrc1_balance_of(icp_ledger()?, account)
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    let balance = balance.0.to_u128().ok_or("Balance out of range")?;
+    with_wallets_mut(|service| service.reconcile(caller, balance))
+}
+
+/// Swaps wallet ICP for ANIMA through the swap pool. The ANIMA lands in the
+/// wallet's deposit account and is credited to its ANIMA balance.
+#[update]
+async fn swap_icp_to_anima(params: SwapParams) -> Result<u128, String> {
+    let caller = ic_cdk::caller();
+
+    // Checked again by the pool once the ICP has landed.
+    let quote = with_swap_pool(|pool| pool.quote(AcceptedToken::ICP, params.icp_amount))?;
+    if quote.amount_out < params.min_anima_amount {
+        return Err(format!(
+            "Slippage too high. Expected: {}, Got: {}",
+            params.min_anima_amount,
+            quote.amount_out
+        ));
+    }
+
+    let id = with_wallets_mut(|service| service.begin_swap(caller, params.icp_amount, time()))?;
+    let transfer = client::icrc1_transfer(
+        icp_ledger()?,
+        Some(deposit_subaccount(&caller)),
+        pool_account(),
+        Nat::from(params.icp_amount),
+        None,
+    ).await;
+
+    let block_index = match transfer {
+        Ok(block_index) => crate::nft::marketplace::nat_to_u64(&block_index),
+        Err(e) => {
+            let error = format!("Swap failed: {:?}", e);
+            with_wallets_mut(|service| service.finish_swap(caller, &id, Err(error.clone())));
+            return Err(error);
+        }
+    };
+
+    // The ICP is in the pool now; from here a failed swap is refunded by the
+    // pool to the caller's own account.
+    let settled = settle_deposited_swap(
+        caller,
+        Account::new(ic_cdk::id(), Some(deposit_subaccount(&caller))),
+        AcceptedToken::ICP,
+        params.icp_amount,
+        params.min_anima_amount,
+    ).await;
+    let (memo, anima_received) = match &settled {
+        Ok(receipt) => (
+            format!("Swapped {} ICP for {} ANIMA", params.icp_amount, receipt.quote.amount_out),
+            receipt.received.unwrap_or(0),
+        ),
+        Err(e) => (format!("Swap refunded by the pool: {}", e), 0),
+    };
+    with_wallets_mut(|service| service.finish_swap(caller, &id, Ok((block_index, memo, anima_received))));
+    settled.map(|receipt| receipt.quote.amount_out)
+}
+
+/// Sends ICP from the caller's wallet to a legacy account identifier.
+/// `amount` is debited in full and the ledger fee comes out of it.
+#[update]
+async fn withdraw_icp(to_account_id: String, amount: u128) -> Result<Transaction, String> {
+    let caller = ic_cdk::caller();
+    if !with_wallets(|service| service.validate_account_identifier(&to_account_id)) {
+        return Err("Invalid destination account identifier".to_string());
+    }
+    let destination = WithdrawalDestination::AccountIdentifier(to_account_id);
+    let withdrawal = with_wallets_mut(|service| {
+        service.begin_withdrawal(caller, AcceptedToken::ICP, destination, amount, ICP_FEE as u128, time())
+    })?;
+    attempt_withdrawal(withdrawal.id.clone()).await;
+    with_wallets(|service| service.transaction(&caller, &withdrawal.id).cloned())
+        .ok_or_else(|| "Withdrawal not found".to_string())
+}
+
+/// Sends ANIMA from the caller's wallet to an ICRC-1 account. `amount` is
+/// debited in full and the ledger fee comes out of it.
+#[update]
+async fn withdraw_anima(account: Account, amount: u128) -> Result<Transaction, String> {
+    let caller = ic_cdk::caller();
+    let fee = ledger_fee(AcceptedToken::ANIMA).await as u128;
+    let destination = WithdrawalDestination::Account(account);
+    let withdrawal = with_wallets_mut(|service| {
+        service.begin_withdrawal(caller, AcceptedToken::ANIMA, destination, amount, fee, time())
+    })?;
+    attempt_withdrawal(withdrawal.id.clone()).await;
+    with_wallets(|service| service.transaction(&caller, &withdrawal.id).cloned())
+        .ok_or_else(|| "Withdrawal not found".to_string())
+}
+
+async fn send_withdrawal(withdrawal: &PendingWithdrawal) -> crate::Result<u64> {
+    let from_subaccount = Some(deposit_subaccount(&withdrawal.user));
+    let net = withdrawal.amount - withdrawal.fee;
+    match &withdrawal.destination {
+        WithdrawalDestination::AccountIdentifier(hex) => {
+            let to = hex::decode(hex)
+                .ok()
+                .and_then(|bytes| AccountIdentifier::from_slice(&bytes))
+                .ok_or_else(|| crate::AnimaError::InvalidInput("Invalid account identifier".to_string()))?;
+            crate::icrc::ledger::transfer_icp(
+                from_subaccount,
+                to,
+                net as u64,
+                withdrawal.fee as u64,
+                WITHDRAWAL_MEMO,
+                withdrawal.created_at_time,
+            ).await
+        }
+        WithdrawalDestination::Account(account) => {
+            let ledger = token_canister(&withdrawal.token)?;
+            client::icrc1_transfer_at(ledger, from_subaccount, *account, Nat::from(net), None, withdrawal.created_at_time)
+                .await
+                .map(|block_index| crate::nft::marketplace::nat_to_u64(&block_index))
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Makes one attempt at a pending withdrawal. Transient failures are retried
+/// with exponential backoff; a rejection by the ledger, or running out of
+/// retries, refunds the wallet.
+async fn attempt_withdrawal(id: String) {
+    let Some(withdrawal) = with_wallets_mut(|service| service.start_withdrawal_attempt(&id)) else { return };
+    match send_withdrawal(&withdrawal).await {
+        Ok(block_index) => with_wallets_mut(|service| service.finish_withdrawal(&id, Ok(block_index))),
+        Err(crate::AnimaError::NetworkError(_) | crate::AnimaError::TimeoutError) if withdrawal.attempts < MAX_RETRIES => {
+            let delay = Duration::from_millis(RETRY_DELAY_MS * (1 << withdrawal.attempts));
+            ic_cdk_timers::set_timer(delay, move || ic_cdk::spawn(attempt_withdrawal(id)));
+        }
+        Err(e) => with_wallets_mut(|service| service.finish_withdrawal(&id, Err(format!("{:?}", e)))),
+    }
+}
+
+/// Reschedules withdrawals left pending by an upgrade, whose retry timers
+/// were dropped with the old code.
+pub fn resume_withdrawals() {
+    let ids: Vec<String> = with_wallets(|service| service.pending_withdrawals().map(|w| w.id.clone()).collect());
+    for id in ids {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || ic_cdk::spawn(attempt_withdrawal(id)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icrc::ledger::{TimeStamp, Tokens, Transaction as LedgerTransaction};
+
+    fn canister() -> Principal {
+        Principal::from_slice(&[0xca])
+    }
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    fn transfer_to(to: AccountIdentifier, e8s: u64) -> Block {
+        Block {
+            parent_hash: None,
+            transaction: LedgerTransaction {
+                memo: 0,
+                icrc1_memo: None,
+                operation: Some(Operation::Transfer {
+                    from: AccountIdentifier::new(&user(99), None).as_bytes().to_vec(),
+                    to: to.as_bytes().to_vec(),
+                    amount: Tokens { e8s },
+                    fee: Tokens { e8s: ICP_FEE },
+                }),
+                created_at_time: TimeStamp { timestamp_nanos: 0 },
+            },
+            timestamp: TimeStamp { timestamp_nanos: 0 },
+        }
+    }
+
+    fn service_with_wallet() -> WalletService {
+        let mut service = WalletService::new(canister());
+        service.initialize_wallet(user(1)).unwrap();
+        service.apply_blocks(10, Vec::new(), 0);
+        service
+    }
+
+    #[test]
+    fn test_deposit_subaccounts_are_distinct() {
+        assert_ne!(deposit_subaccount(&user(1)), deposit_subaccount(&user(2)));
+        assert_eq!(deposit_subaccount(&user(1))[..2], [1, 1]);
+        let service = WalletService::new(canister());
+        let account = service.deposit_account(&user(1));
+        assert_eq!(AccountIdentifier::from_slice(account.as_bytes()), Some(account));
+    }
+
+    #[test]
+    fn test_first_scan_starts_at_chain_tip() {
+        let mut service = WalletService::new(canister());
+        service.initialize_wallet(user(1)).unwrap();
+        let deposit = transfer_to(service.deposit_account(&user(1)), 500);
+        assert!(service.apply_blocks(10, vec![(9, deposit)], 0).is_empty());
+        assert_eq!(service.next_block(), Some(10));
+        assert_eq!(service.get_wallet_state(user(1)).unwrap().icp_balance, 0);
+    }
+
+    #[test]
+    fn test_each_block_credited_once() {
+        let mut service = service_with_wallet();
+        let account = service.deposit_account(&user(1));
+        let other = AccountIdentifier::new(&user(2), None);
+        let blocks = vec![(10, transfer_to(account, 500)), (11, transfer_to(other, 700))];
+
+        assert_eq!(service.apply_blocks(12, blocks.clone(), 0), vec![user(1)]);
+        assert!(service.apply_blocks(12, blocks, 0).is_empty());
+        assert_eq!(service.next_block(), Some(12));
+
+        let wallet = service.get_wallet_state(user(1)).unwrap();
+        assert_eq!(wallet.icp_balance, 500);
+        assert_eq!(wallet.transactions.len(), 1);
+        assert_eq!(wallet.transactions[0].block_index, Some(10));
+    }
+
+    #[test]
+    fn test_scan_stops_at_gap() {
+        let mut service = service_with_wallet();
+        let account = service.deposit_account(&user(1));
+        service.apply_blocks(20, vec![(10, transfer_to(account, 1)), (12, transfer_to(account, 2))], 0);
+        assert_eq!(service.next_block(), Some(11));
+        assert_eq!(service.get_wallet_state(user(1)).unwrap().icp_balance, 1);
+    }
+
+    #[test]
+    fn test_failed_swap_is_credited_back() {
+        let mut service = service_with_wallet();
+        let account = service.deposit_account(&user(1));
+        service.apply_blocks(11, vec![(10, transfer_to(account, 100_000))], 0);
+
+        let id = service.begin_swap(user(1), 50_000, 1).unwrap();
+        assert_eq!(service.get_wallet_state(user(1)).unwrap().icp_balance, 40_000);
+        assert_eq!(service.pending_icp(&user(1)), 60_000);
+        assert!(service.begin_swap(user(1), 40_000, 1).is_err());
+
+        service.finish_swap(user(1), &id, Err("ledger down".to_string()));
+        assert_eq!(service.get_wallet_state(user(1)).unwrap().icp_balance, 100_000);
+        assert_eq!(service.pending_icp(&user(1)), 0);
+    }
+
+    #[test]
+    fn test_reconcile_writes_down_but_never_credits() {
+        let mut service = service_with_wallet();
+        let account = service.deposit_account(&user(1));
+        service.apply_blocks(11, vec![(10, transfer_to(account, 100_000))], 0);
+
+        let report = service.reconcile(user(1), 150_000).unwrap();
+        assert_eq!(report.uncredited, 50_000);
+        assert_eq!(service.get_wallet_state(user(1)).unwrap().icp_balance, 100_000);
+
+        let report = service.reconcile(user(1), 80_000).unwrap();
+        assert_eq!(report.corrected, 20_000);
+        assert_eq!(service.get_wallet_state(user(1)).unwrap().icp_balance, 80_000);
+    }
+
+    #[test]
+    fn test_withdrawal_finalized_or_refunded() {
+        let mut service = service_with_wallet();
+        let account = service.deposit_account(&user(1));
+        service.apply_blocks(11, vec![(10, transfer_to(account, 100_000))], 0);
+        let destination = WithdrawalDestination::AccountIdentifier(AccountIdentifier::new(&user(2), None).to_hex());
+
+        assert!(service.begin_withdrawal(user(1), AcceptedToken::ICP, destination.clone(), ICP_FEE as u128, ICP_FEE as u128, 1).is_err());
+        assert!(service.begin_withdrawal(user(1), AcceptedToken::ANIMA, destination.clone(), 50_000, 10, 1).is_err());
+
+        let failed = service.begin_withdrawal(user(1), AcceptedToken::ICP, destination.clone(), 60_000, ICP_FEE as u128, 1).unwrap();
+        assert_eq!(service.get_wallet_state(user(1)).unwrap().icp_balance, 40_000);
+        assert_eq!(service.pending_icp(&user(1)), 60_000);
+        assert_eq!(service.start_withdrawal_attempt(&failed.id).unwrap().attempts, 1);
+        service.finish_withdrawal(&failed.id, Err("bad fee".to_string()));
+        assert_eq!(service.get_wallet_state(user(1)).unwrap().icp_balance, 100_000);
+        assert_eq!(service.transaction(&user(1), &failed.id).unwrap().status, TransactionStatus::Failed);
+
+        let sent = service.begin_withdrawal(user(1), AcceptedToken::ICP, destination, 60_000, ICP_FEE as u128, 2).unwrap();
+        service.finish_withdrawal(&sent.id, Ok(77));
+        let tx = service.transaction(&user(1), &sent.id).unwrap();
+        assert_eq!((tx.status.clone(), tx.block_index), (TransactionStatus::Completed, Some(77)));
+        assert_eq!(service.get_wallet_state(user(1)).unwrap().icp_balance, 40_000);
+        assert_eq!(service.pending_withdrawals().count(), 0);
+    }
+
+    #[test]
+    fn test_account_identifier_checksum_validated() {
+        let service = WalletService::new(canister());
+        let valid = AccountIdentifier::new(&user(1), None).to_hex();
+        assert!(service.validate_account_identifier(&valid));
+        let mut corrupted = valid.into_bytes();
+        corrupted[0] = if corrupted[0] == b'0' { b'1' } else { b'0' };
+        assert!(!service.validate_account_identifier(&String::from_utf8(corrupted).unwrap()));
+        assert!(!service.validate_account_identifier("abc"));
+    }
+}